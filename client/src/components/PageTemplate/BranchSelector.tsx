@@ -10,10 +10,11 @@ import { SearchContext } from '../../context/searchContext';
 import { RepositoriesContext } from '../../context/repositoriesContext';
 import { UIContext } from '../../context/uiContext';
 import TextInput from '../TextInput';
-import { RepoType, SyncStatus } from '../../types/general';
+import { RepoType } from '../../types/general';
 import { DeviceContext } from '../../context/deviceContext';
 import CloudFeaturePopup from '../CloudFeaturePopup';
 import BranchItem from './BranchItem';
+import { isDoneSyncing } from '../../utils';
 
 let eventSource: EventSource;
 
@@ -87,12 +88,12 @@ const BranchSelector = () => {
               ...newRepos[index],
               sync_status: data.ev?.status_change.status,
               last_index:
-                data.ev?.status_change === SyncStatus.Done
+                isDoneSyncing(data.ev?.status_change.status)
                   ? new Date().toISOString()
                   : '',
               branch_filter: {
                 select:
-                  data.b && data.ev.status_change.status === SyncStatus.Done
+                  data.b && isDoneSyncing(data.ev.status_change.status)
                     ? Array.from(
                         new Set([
                           ...(newRepos[index].branch_filter?.select || []),
@@ -45,6 +45,10 @@ export const STATUS_MAP = {
   [SyncStatus.Indexing]: { text: 'Indexing...', color: 'bg-yellow' },
   [SyncStatus.Syncing]: { text: 'Cloning...', color: 'bg-yellow' },
   [SyncStatus.Done]: { text: 'Last updated ', color: 'bg-green-500' },
+  [SyncStatus.DoneEmpty]: {
+    text: 'No indexable files found',
+    color: 'bg-bg-shade',
+  },
   [SyncStatus.RemoteRemoved]: { text: 'Remote removed ', color: 'bg-red-500' },
 };
 
@@ -7,11 +7,12 @@ import React, {
 } from 'react';
 import { Trans } from 'react-i18next';
 import RepoCard from '../../../components/RepoCard';
-import { RepoType, SyncStatus } from '../../../types/general';
+import { RepoType } from '../../../types/general';
 import { DeviceContext } from '../../../context/deviceContext';
 import RepoCardSkeleton from '../../../components/RepoCard/RepoCardSkeleton';
 import NoRepos from '../../../components/RepoCard/NoRepos';
 import { RepositoriesContext } from '../../../context/repositoriesContext';
+import { isDoneSyncing } from '../../../utils';
 
 type Props = {
   reposToShow: RepoType[];
@@ -48,7 +49,7 @@ const ReposSection = ({ reposToShow, setReposToShow, repositories }: Props) => {
               ...newRepos[index],
               sync_status: data.ev?.status_change,
               last_index:
-                data.ev?.status_change === SyncStatus.Done
+                isDoneSyncing(data.ev?.status_change)
                   ? new Date().toISOString()
                   : '',
             };
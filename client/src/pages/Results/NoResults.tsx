@@ -3,7 +3,7 @@ import { Trans } from 'react-i18next';
 import Button from '../../components/Button';
 import PageHeader from '../../components/ResultsPageHeader';
 import { RepositoriesContext } from '../../context/repositoriesContext';
-import { SyncStatus } from '../../types/general';
+import { isDoneSyncing } from '../../utils';
 
 type Props = {
   suggestions: string[];
@@ -37,7 +37,7 @@ const NoResults = ({
 
   useEffect(() => {
     if (
-      repoState === SyncStatus.Done &&
+      isDoneSyncing(repoState) &&
       refetchRepo &&
       isRepo &&
       n < MAX_RETRIES
@@ -49,6 +49,7 @@ export enum SyncStatus {
   Uninitialized = 'uninitialized',
   Queued = 'queued',
   Done = 'done',
+  DoneEmpty = 'done_empty',
   Error = 'error',
   Removed = 'removed',
   Indexing = 'indexing',
@@ -1,6 +1,6 @@
 import { MouseEvent, useCallback } from 'react';
 import { v4 as uuidv4 } from 'uuid';
-import { RepoType, RepoUi } from '../types/general';
+import { RepoType, RepoUi, SyncStatus } from '../types/general';
 import langs from './langs.json';
 
 export const copyToClipboard = (value: string) => {
@@ -74,6 +74,10 @@ export const getFileExtensionForLang = (lang: string) => {
 
 export const isWindowsPath = (path: string) => path.includes('\\');
 
+// repositories with no indexable files are done indexing too
+export const isDoneSyncing = (status?: SyncStatus) =>
+  status === SyncStatus.Done || status === SyncStatus.DoneEmpty;
+
 export const breadcrumbsItemPath = (
   array: string[],
   index: number,
@@ -9,6 +9,8 @@ pub fn criterion_benchmark(c: &mut Criterion) {
         let doc = bleep::indexes::reader::ContentDocument {
             content: JS_SAMPLE.into(),
             lang: Some("JavaScript".into()),
+            content_type: Some("text/javascript".into()),
+            summary: None,
             relative_path: "js-sample.js".into(),
             repo_ref: "/path/to/myRepo".into(),
             repo_name: "myRepo".into(),
@@ -18,6 +20,7 @@ pub fn criterion_benchmark(c: &mut Criterion) {
                 .collect(),
             symbol_locations: SymbolLocations::Empty,
             branches: None,
+            line_blame: None,
         };
 
         b.iter(|| snipper.all_for_doc(black_box("context"), black_box(&doc)));
@@ -11,6 +11,7 @@ use std::{
 struct Language {
     r#type: String,
     aliases: Option<Vec<String>>,
+    extensions: Option<Vec<String>>,
 }
 fn main() {
     set_index_version();
@@ -19,24 +20,49 @@ fn main() {
 }
 
 fn set_index_version() {
-    use std::fs::{read_dir, read_to_string};
+    // the tantivy indexes, which are cheap to rebuild
+    let lexical = hash_sources(
+        &[
+            "src/indexes/schema.rs",
+            "src/indexes/analyzer.rs",
+            "src/intelligence/scope_resolution.rs",
+            "../languages.yml",
+        ],
+        &["src/intelligence/scope_resolution"],
+    );
+
+    // the chunks embedded into qdrant, which are expensive to rebuild
+    let semantic = hash_sources(
+        &[
+            "src/semantic/schema.rs",
+            "src/semantic/chunk.rs",
+            "../languages.yml",
+        ],
+        &[],
+    );
+
+    let schema = blake3::hash(format!("{lexical}{semantic}").as_bytes());
+
+    let version_file = Path::new(&env::var("OUT_DIR").unwrap()).join("schema_version.rs");
+    write!(
+        File::create(version_file).unwrap(),
+        r#"pub const SCHEMA_VERSION: &str = "{schema}";
+pub const LEXICAL_VERSION: &str = "{lexical}";
+pub const SEMANTIC_VERSION: &str = "{semantic}";"#,
+    )
+    .unwrap();
+}
 
-    let model_directories = &["src/intelligence/scope_resolution"];
-    let model_files = &[
-        "src/semantic/schema.rs",
-        "src/semantic/chunk.rs",
-        "src/indexes/schema.rs",
-        "src/intelligence/scope_resolution.rs",
-        "../languages.yml",
-    ];
+fn hash_sources(files: &[&str], directories: &[&str]) -> blake3::Hash {
+    use std::fs::{read_dir, read_to_string};
 
     let mut hasher = blake3::Hasher::new();
-    for path in model_files {
+    for path in files {
         hasher.update(read_to_string(path).unwrap().as_bytes());
         println!("cargo:rerun-if-changed={path}");
     }
 
-    for path in model_directories
+    for path in directories
         .iter()
         .flat_map(|dir| read_dir(dir).unwrap())
         .filter_map(Result::ok)
@@ -53,13 +79,7 @@ fn set_index_version() {
         println!("cargo:rerun-if-changed={}", path.to_string_lossy());
     }
 
-    let version_file = Path::new(&env::var("OUT_DIR").unwrap()).join("schema_version.rs");
-    write!(
-        File::create(version_file).unwrap(),
-        r#"pub const SCHEMA_VERSION: &str = "{}";"#,
-        hasher.finalize()
-    )
-    .unwrap();
+    hasher.finalize()
 }
 
 fn process_languages() {
@@ -67,22 +87,53 @@ fn process_languages() {
     let langs: HashMap<String, Language> = serde_yaml::from_reader(langs_file).unwrap();
 
     let languages_path = Path::new(&env::var("OUT_DIR").unwrap()).join("languages.rs");
-    let mut ext_map = phf_codegen::Map::new();
     let mut case_map = phf_codegen::Map::new();
 
-    for (name, data) in langs
+    let langs = langs
         .into_iter()
         .filter(|(_, d)| d.r#type == "programming" || d.r#type == "prose")
-    {
+        .collect::<Vec<_>>();
+
+    // map every alias to the lowercase language name. in increasing order of precedence:
+    //  - extensions that belong to a single language, like `cxx` for `c++`
+    //  - the aliases listed in `languages.yml`
+    //  - the language names themselves
+    let mut aliases = HashMap::<String, Option<String>>::new();
+    for (name, data) in &langs {
         let name_lower = name.to_ascii_lowercase();
+        for ext in data.extensions.iter().flatten() {
+            let ext = ext.trim_start_matches('.').to_ascii_lowercase();
+            aliases
+                .entry(ext)
+                .and_modify(|lang| {
+                    if lang.as_deref() != Some(name_lower.as_str()) {
+                        *lang = None
+                    }
+                })
+                .or_insert_with(|| Some(name_lower.clone()));
+        }
+    }
 
-        for alias in data.aliases.unwrap_or_default() {
-            ext_map.entry(alias, &format!("\"{name_lower}\""));
+    for (name, data) in &langs {
+        let name_lower = name.to_ascii_lowercase();
+        for alias in data.aliases.iter().flatten() {
+            aliases.insert(alias.to_ascii_lowercase(), Some(name_lower.clone()));
         }
+    }
 
+    for (name, _) in &langs {
+        let name_lower = name.to_ascii_lowercase();
+        aliases.insert(name_lower.clone(), Some(name_lower.clone()));
         case_map.entry(name_lower, &format!("\"{name}\""));
     }
 
+    let mut ext_map = phf_codegen::Map::new();
+    for (alias, name) in aliases {
+        if let Some(name) = name {
+            ext_map.entry(alias, &format!("{name:?}"));
+        }
+    }
+
     write!(
         BufWriter::new(File::create(languages_path).unwrap()),
         "static EXT_MAP: phf::Map<&str, &str> = \n{};\n\
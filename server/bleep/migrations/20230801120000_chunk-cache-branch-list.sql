@@ -0,0 +1,6 @@
+-- Keep the plain list of branches next to its hash, so we can tell
+-- which branches a file is searchable on without asking qdrant.
+--
+-- Rows written before this migration have an empty list until the
+-- branches of the chunk change.
+ALTER TABLE chunk_cache ADD COLUMN branch_list TEXT NOT NULL DEFAULT '';
@@ -0,0 +1,14 @@
+-- One row for every successful indexing run of a repository, so
+-- indexing performance can be followed over time.
+--
+-- Only the most recent runs of each repository are kept.
+CREATE TABLE index_runs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    repo_ref TEXT NOT NULL,
+    started_at INTEGER NOT NULL,
+    duration_ms INTEGER NOT NULL,
+    files_indexed INTEGER NOT NULL,
+    bytes INTEGER NOT NULL
+);
+
+CREATE INDEX index_runs_repo_ref ON index_runs (repo_ref, id);
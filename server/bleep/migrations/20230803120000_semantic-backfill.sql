@@ -0,0 +1,6 @@
+-- The last file embedded by an interrupted semantic backfill of each
+-- repository, so the next run can resume after it.
+CREATE TABLE semantic_backfill (
+    repo_ref TEXT PRIMARY KEY NOT NULL,
+    last_file_hash TEXT NOT NULL
+);
@@ -0,0 +1,10 @@
+-- The process currently indexing each repository, so processes sharing
+-- the database don't index the same repository concurrently.
+--
+-- Leases are renewed while the run lasts, so the lease of a process
+-- that died without releasing it can be taken over once it expires.
+CREATE TABLE index_leases (
+    repo_ref TEXT PRIMARY KEY NOT NULL,
+    holder TEXT NOT NULL,
+    expires_at INTEGER NOT NULL
+);
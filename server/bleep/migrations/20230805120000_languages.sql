@@ -0,0 +1,7 @@
+-- The languages of the file index, which documents refer to by id.
+--
+-- Id 0 is left out, as it stands for documents without a language.
+CREATE TABLE languages (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE
+);
@@ -0,0 +1,8 @@
+-- Repositories whose file cache may not match the file index.
+--
+-- A row is written before documents of the repository are committed to
+-- the index, and removed when the matching file cache is persisted.
+-- Rows left behind mean the process stopped in between.
+CREATE TABLE file_cache_commits (
+    repo_ref TEXT PRIMARY KEY NOT NULL
+);
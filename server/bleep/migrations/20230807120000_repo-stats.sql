@@ -0,0 +1,16 @@
+-- The size of every file in the file cache, and their sums by
+-- repository, so the stats of a repository can be read without going
+-- through its files.
+--
+-- Rows written before this migration count for nothing until their
+-- files change.
+ALTER TABLE file_cache ADD COLUMN is_file INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE file_cache ADD COLUMN lines INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE file_cache ADD COLUMN bytes INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE repo_stats (
+    repo_ref TEXT PRIMARY KEY NOT NULL,
+    files INTEGER NOT NULL,
+    lines INTEGER NOT NULL,
+    bytes INTEGER NOT NULL
+);
@@ -1,6 +1,6 @@
 use either::Either;
 use tokio::sync::OwnedSemaphorePermit;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 use crate::{
     cache::FileCache,
@@ -19,6 +19,8 @@ pub(crate) struct SyncHandle {
     pub(crate) new_branch_filters: Option<crate::repo::BranchFilter>,
     pub(crate) app: Application,
     pub(super) pipes: SyncPipes,
+    /// The status of the repository before this sync, to go back to when indexing is skipped
+    initial_status: SyncStatus,
     exited: flume::Sender<SyncStatus>,
     exit_signal: flume::Receiver<SyncStatus>,
 }
@@ -50,6 +52,9 @@ pub(super) enum SyncError {
     #[error("sql: {0:?}")]
     Sql(anyhow::Error),
 
+    #[error("repository is being indexed by another process")]
+    Leased,
+
     #[error("cancelled by user")]
     Cancelled,
 }
@@ -118,20 +123,25 @@ impl SyncHandle {
                         last_commit_unix_secs: 0,
                         most_common_lang: None,
                         branch_filter: None,
+                        semantic: true,
+                        semantic_status: Default::default(),
+                        path_root: None,
                     }
                 }
             });
 
+        let initial_status = current.get().sync_status.clone();
         let sh = Self {
             app: app.clone(),
             reporef: reporef.clone(),
             pipes,
             new_branch_filters,
+            initial_status,
             exited,
             exit_signal,
         };
 
-        sh.pipes.status(current.get().sync_status.clone());
+        sh.pipes.status(sh.initial_status.clone());
         sh.into()
     }
 
@@ -167,14 +177,21 @@ impl SyncHandle {
         let indexed = self.index().await;
         let status = match indexed {
             Ok(Either::Left(status)) => Some(status),
-            Ok(Either::Right(state)) => {
+            Ok(Either::Right((state, indexable_files, semantic_failures))) => {
                 info!("commit complete; indexing done");
                 self.app.repo_pool.update(&self.reporef, |_k, repo| {
-                    repo.sync_done_with(self.new_branch_filters.as_ref(), state)
+                    let semantic = repo.semantic && self.app.config.semantic_enabled(&self.reporef);
+                    repo.sync_done_with(
+                        self.new_branch_filters.as_ref(),
+                        state,
+                        indexable_files,
+                        semantic,
+                        semantic_failures,
+                    )
                 });
 
                 // technically `sync_done_with` does this, but we want to send notifications
-                self.set_status(|_| SyncStatus::Done)
+                self.set_status(|repo| repo.sync_status.clone())
             }
             Err(SyncError::Cancelled) => self.set_status(|_| SyncStatus::Cancelled),
             Err(err) => {
@@ -188,11 +205,12 @@ impl SyncHandle {
         Ok(status.expect("failed to update repo status"))
     }
 
-    async fn index(&self) -> Result<Either<SyncStatus, Arc<RepoMetadata>>> {
+    async fn index(&self) -> Result<Either<SyncStatus, (Arc<RepoMetadata>, u64, u64)>> {
         use SyncStatus::*;
         let Application {
             ref indexes,
             ref repo_pool,
+            ref sql,
             ..
         } = self.app;
 
@@ -209,6 +227,8 @@ impl SyncHandle {
             orig
         };
 
+        // held until the index is committed, or rolled back
+        let lease;
         let indexed = match repo.sync_status {
             current @ (Uninitialized | Syncing | Indexing) => return Ok(Either::Left(current)),
             Removed => return self.delete_repo(&repo, writers).await,
@@ -222,14 +242,29 @@ impl SyncHandle {
                 return Ok(Either::Left(RemoteRemoved));
             }
             _ => {
+                lease = FileCache::for_repo(sql, &self.reporef)
+                    .lease()
+                    .await
+                    .map_err(SyncError::Sql)?
+                    .ok_or(SyncError::Leased)?;
+
                 self.set_status(|_| Indexing).unwrap();
-                writers.index(self, &repo).await.map(Either::Right)
+                writers
+                    .index(self, &repo)
+                    .await
+                    .map(|indexed| match indexed {
+                        Some(indexed) => Either::Right(indexed),
+                        None => Either::Left(self.skip_index()),
+                    })
             }
         };
 
         match indexed {
             Ok(_) => {
                 writers.commit().await.map_err(SyncError::Tantivy)?;
+                if let Err(err) = lease.release().await {
+                    warn!(?err, ?self.reporef, "failed to release index lease");
+                }
                 indexed.map_err(SyncError::Indexing)
             }
             Err(_) if self.pipes.is_removed() => self.delete_repo(&repo, writers).await,
@@ -245,11 +280,21 @@ impl SyncHandle {
         }
     }
 
+    /// Leave a missing repository as it was before the sync, keeping its documents.
+    fn skip_index(&self) -> SyncStatus {
+        let previous = self.initial_status.clone();
+        self.app
+            .repo_pool
+            .update(&self.reporef, |_k, repo| repo.sync_skipped(previous));
+
+        self.set_status(|repo| repo.sync_status.clone()).unwrap()
+    }
+
     async fn delete_repo(
         &self,
         repo: &Repository,
         writers: indexes::GlobalWriteHandle<'_>,
-    ) -> Result<Either<SyncStatus, Arc<RepoMetadata>>> {
+    ) -> Result<Either<SyncStatus, (Arc<RepoMetadata>, u64, u64)>> {
         self.app.repo_pool.remove(&self.reporef);
 
         let deleted = self.delete_repo_indexes(repo, &writers).await;
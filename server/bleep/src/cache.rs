@@ -1,16 +1,25 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    collections::{BTreeSet, HashMap},
+    ops::Range,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
+use once_cell::sync::Lazy;
 use qdrant_client::{
     prelude::QdrantClient,
-    qdrant::{PointId, PointStruct},
+    qdrant::{
+        with_payload_selector, with_vectors_selector, PointId, PointStruct, WithPayloadSelector,
+        WithVectorsSelector, WriteOrdering,
+    },
 };
 use sqlx::Sqlite;
-use tracing::trace;
+use tracing::{trace, warn};
 use uuid::Uuid;
 
 use crate::{
     repo::RepoRef,
-    semantic::{self, Embedding, Payload},
+    semantic::{self, minhash::NearDuplicates, Embedder, Embedding, Payload, PayloadSchema},
 };
 
 use super::db::SqlDb;
@@ -49,7 +58,80 @@ impl<T> From<T> for FreshValue<T> {
 /// Snapshot of the current state of a FileCache
 /// Since it's atomically (as in ACID) read from SQLite, this will be
 /// representative at a single point in time
-pub(crate) type FileCacheSnapshot = Arc<scc::HashMap<String, FreshValue<()>>>;
+pub(crate) type FileCacheSnapshot = Arc<scc::HashMap<String, FreshValue<RepoStats>>>;
+
+/// The size of the files of a repository.
+///
+/// Every entry of a file cache keeps the stats of its file, with directories counting for
+/// nothing, and the stats of the repository are the sum of its entries. The sum is updated with
+/// the entries added and removed each time the cache is persisted, so it can be read without
+/// going through the files.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RepoStats {
+    pub files: u64,
+    pub lines: u64,
+    pub bytes: u64,
+}
+
+impl RepoStats {
+    /// The stats of a single file with `content`.
+    pub fn file(content: &str) -> Self {
+        Self {
+            files: 1,
+            lines: content.lines().count() as u64,
+            bytes: content.len() as u64,
+        }
+    }
+
+    /// The mean size of the files, in bytes.
+    pub fn mean_file_bytes(&self) -> Option<f64> {
+        (self.files > 0).then(|| self.bytes as f64 / self.files as f64)
+    }
+
+    /// The mean length of the lines, in bytes, newlines included.
+    pub fn mean_line_length(&self) -> Option<f64> {
+        (self.lines > 0).then(|| self.bytes as f64 / self.lines as f64)
+    }
+}
+
+impl std::ops::AddAssign for RepoStats {
+    fn add_assign(&mut self, other: Self) {
+        self.files += other.files;
+        self.lines += other.lines;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Number of indexing runs kept in the history of each repository.
+pub const INDEX_HISTORY_LEN: usize = 100;
+
+/// Timing and volume of a single successful indexing run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexRun {
+    /// Unix timestamp of the start of the run, in seconds
+    pub started_at: i64,
+    pub duration_ms: i64,
+    /// Number of files written to the index, not counting the ones
+    /// that were fresh in the cache
+    pub files_indexed: i64,
+    /// Total size of the files written to the index
+    pub bytes: i64,
+}
+
+/// How long an [`IndexLease`] lasts without being renewed.
+const LEASE_TTL: Duration = Duration::from_secs(60);
+
+/// Identifies this process as the holder of its leases.
+static LEASE_HOLDER: Lazy<String> = Lazy::new(|| Uuid::new_v4().to_string());
+
+/// Number of rows written by a [`FileCache::persist`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PersistStats {
+    pub(crate) inserted: usize,
+    pub(crate) deleted: usize,
+    /// Keys that weren't inserted, as another repository already holds them
+    pub(crate) ignored: usize,
+}
 
 /// Manage the SQL cache for a repository, establishing a
 /// content-addressed space for files in it.
@@ -57,7 +139,10 @@ pub(crate) type FileCacheSnapshot = Arc<scc::HashMap<String, FreshValue<()>>>;
 /// The cache keys are should be directly mirrored in Tantivy for each
 /// file entry, as Tantivy can't upsert content.
 ///
-/// NB: consistency with Tantivy state is NOT ensured here.
+/// NB: consistency with Tantivy state is NOT ensured here. Callers
+/// persist the cache once the matching documents are committed, and
+/// bracket the commit with [`FileCache::begin_commit`], so a commit
+/// interrupted halfway can be detected and reconciled.
 pub(crate) struct FileCache<'a> {
     db: &'a SqlDb,
     reporef: &'a RepoRef,
@@ -71,7 +156,7 @@ impl<'a> FileCache<'a> {
     pub(crate) async fn retrieve(&self) -> FileCacheSnapshot {
         let repo_str = self.reporef.to_string();
         let rows = sqlx::query! {
-            "SELECT cache_hash FROM file_cache \
+            "SELECT cache_hash, is_file, lines, bytes FROM file_cache \
              WHERE repo_ref = ?",
             repo_str,
         }
@@ -80,44 +165,292 @@ impl<'a> FileCache<'a> {
 
         let output = scc::HashMap::default();
         for row in rows.into_iter().flatten() {
-            _ = output.insert(row.cache_hash, FreshValue::stale(()));
+            let stats = RepoStats {
+                files: row.is_file as u64,
+                lines: row.lines as u64,
+                bytes: row.bytes as u64,
+            };
+            _ = output.insert(row.cache_hash, FreshValue::stale(stats));
         }
 
         output.into()
     }
 
-    pub(crate) async fn persist(&self, cache: FileCacheSnapshot) -> anyhow::Result<()> {
+    /// Replace the cached keys of the repository with the ones in the
+    /// snapshot.
+    ///
+    /// The snapshot is diffed against the stored keys, and only the
+    /// difference is written, so re-indexing an unchanged repository
+    /// doesn't write to the table at all. The [`RepoStats`] of the
+    /// repository are updated with the difference in the same
+    /// transaction.
+    ///
+    /// This clears the record of [`FileCache::begin_commit`].
+    pub(crate) async fn persist(&self, cache: FileCacheSnapshot) -> anyhow::Result<PersistStats> {
+        let repo_str = self.reporef.to_string();
         let mut tx = self.db.begin().await?;
-        self.delete_files(&mut tx).await?;
 
-        let keys = {
-            let mut keys = vec![];
-            cache.scan_async(|k, _v| keys.push(k.clone())).await;
-            keys
+        let stored = sqlx::query! {
+            "SELECT cache_hash, is_file, lines, bytes FROM file_cache \
+             WHERE repo_ref = ?",
+            repo_str,
+        }
+        .fetch_all(&mut tx)
+        .await?
+        .into_iter()
+        .map(|row| {
+            let stats = RepoStats {
+                files: row.is_file as u64,
+                lines: row.lines as u64,
+                bytes: row.bytes as u64,
+            };
+            (row.cache_hash, stats)
+        })
+        .collect::<HashMap<_, _>>();
+
+        let mut new = vec![];
+        cache
+            .scan_async(|k, v| {
+                if !stored.contains_key(k) {
+                    new.push((k.clone(), v.value));
+                }
+            })
+            .await;
+
+        let removed = stored
+            .into_iter()
+            .filter(|(k, _)| !cache.contains(k))
+            .collect::<Vec<_>>();
+
+        let mut stats = PersistStats {
+            deleted: removed.len(),
+            ..Default::default()
         };
 
-        for hash in keys {
-            let repo_str = self.reporef.to_string();
-            sqlx::query!(
-                "INSERT INTO file_cache \
-		 (repo_ref, cache_hash) \
-                 VALUES (?, ?)",
+        let (mut added, mut dropped) = (RepoStats::default(), RepoStats::default());
+        for (hash, file) in removed {
+            sqlx::query! {
+                "DELETE FROM file_cache \
+                 WHERE cache_hash = ? AND repo_ref = ?",
+                hash,
+                repo_str,
+            }
+            .execute(&mut tx)
+            .await?;
+            dropped += file;
+        }
+
+        for (hash, file) in new {
+            let (is_file, lines, bytes) = (file.files as i64, file.lines as i64, file.bytes as i64);
+            let written = sqlx::query!(
+                "INSERT OR IGNORE INTO file_cache \
+                 (repo_ref, cache_hash, is_file, lines, bytes) \
+                 VALUES (?, ?, ?, ?, ?)",
                 repo_str,
                 hash,
+                is_file,
+                lines,
+                bytes,
             )
             .execute(&mut tx)
+            .await?
+            .rows_affected();
+
+            // keys are unique across repositories, so the row can belong to another one
+            if written == 0 {
+                warn!(
+                    repo_ref = %repo_str,
+                    %hash,
+                    "file cache key is held by another repository"
+                );
+                stats.ignored += 1;
+                continue;
+            }
+
+            stats.inserted += 1;
+            added += file;
+        }
+
+        // a modified file is one entry added and one removed, which often leaves the sum as it was
+        if added != dropped {
+            let files = added.files as i64 - dropped.files as i64;
+            let lines = added.lines as i64 - dropped.lines as i64;
+            let bytes = added.bytes as i64 - dropped.bytes as i64;
+            sqlx::query! {
+                "INSERT INTO repo_stats (repo_ref, files, lines, bytes) VALUES (?, ?, ?, ?) \
+                 ON CONFLICT (repo_ref) DO UPDATE SET \
+                 files = files + excluded.files, \
+                 lines = lines + excluded.lines, \
+                 bytes = bytes + excluded.bytes",
+                repo_str,
+                files,
+                lines,
+                bytes,
+            }
+            .execute(&mut tx)
             .await?;
         }
 
+        // the cache matches the index again
+        sqlx::query! {
+            "DELETE FROM file_cache_commits WHERE repo_ref = ?",
+            repo_str,
+        }
+        .execute(&mut tx)
+        .await?;
+
         tx.commit().await?;
 
+        Ok(stats)
+    }
+
+    /// Whether an entry with `cache_hash` is in the persisted cache of the repository.
+    pub async fn is_fresh(&self, cache_hash: &str) -> anyhow::Result<bool> {
+        let repo_str = self.reporef.to_string();
+        let row = sqlx::query! {
+            "SELECT cache_hash FROM file_cache WHERE repo_ref = ? AND cache_hash = ?",
+            repo_str,
+            cache_hash,
+        }
+        .fetch_optional(self.db.as_ref())
+        .await?;
+
+        Ok(row.is_some())
+    }
+
+    /// The stats of the files in the cache of the repository.
+    ///
+    /// Entries written before stats were kept count for nothing until their files change.
+    pub async fn repo_stats(&self) -> anyhow::Result<RepoStats> {
+        let repo_str = self.reporef.to_string();
+        let row = sqlx::query! {
+            "SELECT files, lines, bytes FROM repo_stats WHERE repo_ref = ?",
+            repo_str,
+        }
+        .fetch_optional(self.db.as_ref())
+        .await?;
+
+        Ok(row
+            .map(|row| RepoStats {
+                files: row.files as u64,
+                lines: row.lines as u64,
+                bytes: row.bytes as u64,
+            })
+            .unwrap_or_default())
+    }
+
+    /// Record that documents of the repository are about to be committed
+    /// to the file index, ahead of the cache.
+    ///
+    /// This must be called before the commit, and the cache persisted
+    /// after it, which clears the record. A record left behind means the
+    /// two may not match, see [`FileCache::interrupted_commits`].
+    pub(crate) async fn begin_commit(&self) -> anyhow::Result<()> {
+        let repo_str = self.reporef.to_string();
+        sqlx::query! {
+            "INSERT OR IGNORE INTO file_cache_commits (repo_ref) VALUES (?)",
+            repo_str,
+        }
+        .execute(self.db.as_ref())
+        .await?;
+
         Ok(())
     }
 
+    /// The repositories whose file cache wasn't persisted after a commit
+    /// of the file index was started, because the process stopped in
+    /// between.
+    ///
+    /// Their cache may list files the index doesn't have, or miss files
+    /// it does.
+    pub(crate) async fn interrupted_commits(db: &SqlDb) -> anyhow::Result<Vec<RepoRef>> {
+        let rows = sqlx::query! {
+            "SELECT repo_ref FROM file_cache_commits",
+        }
+        .fetch_all(db.as_ref())
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| row.repo_ref.parse().ok())
+            .collect())
+    }
+
+    /// The repositories leased for indexing by any process, most recently renewed first.
+    pub(crate) async fn leased_repos(db: &SqlDb) -> anyhow::Result<Vec<RepoRef>> {
+        let now = unix_now();
+        let rows = sqlx::query! {
+            "SELECT repo_ref FROM index_leases \
+             WHERE expires_at > ? ORDER BY expires_at DESC",
+            now,
+        }
+        .fetch_all(db.as_ref())
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| row.repo_ref.parse().ok())
+            .collect())
+    }
+
+    /// Rebuild the database file to release the space of deleted rows,
+    /// returning the number of bytes reclaimed.
+    ///
+    /// This is not limited to the repository: the whole database is
+    /// rebuilt, and query planner statistics are refreshed.
+    pub(crate) async fn vacuum(&self) -> anyhow::Result<u64> {
+        async fn size(db: &SqlDb) -> anyhow::Result<i64> {
+            let page_count = sqlx::query_scalar::<_, i64>("PRAGMA page_count")
+                .fetch_one(db.as_ref())
+                .await?;
+            let page_size = sqlx::query_scalar::<_, i64>("PRAGMA page_size")
+                .fetch_one(db.as_ref())
+                .await?;
+
+            Ok(page_count * page_size)
+        }
+
+        let before = size(self.db).await?;
+
+        // `VACUUM` can't run within a transaction
+        sqlx::query("VACUUM").execute(self.db.as_ref()).await?;
+        sqlx::query("PRAGMA optimize")
+            .execute(self.db.as_ref())
+            .await?;
+
+        let after = size(self.db).await?;
+        Ok((before - after).max(0) as u64)
+    }
+
     pub(crate) async fn delete(&self) -> anyhow::Result<()> {
         let mut tx = self.db.begin().await?;
         self.delete_files(&mut tx).await?;
         self.delete_chunks(&mut tx).await?;
+        self.delete_backfill_cursor(&mut tx).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Delete the cached keys of the files, but keep the chunk cache.
+    ///
+    /// Every file is indexed again, while the embeddings of its chunks are reused.
+    pub(crate) async fn delete_files_only(&self) -> anyhow::Result<()> {
+        let mut tx = self.db.begin().await?;
+        self.delete_files(&mut tx).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Delete the chunk cache, but keep the cached keys of the files.
+    ///
+    /// This is for repositories whose points were removed from qdrant, which the chunk cache
+    /// would otherwise claim are still there.
+    pub(crate) async fn delete_chunks_only(&self) -> anyhow::Result<()> {
+        let mut tx = self.db.begin().await?;
+        self.delete_chunks(&mut tx).await?;
+        self.delete_backfill_cursor(&mut tx).await?;
         tx.commit().await?;
 
         Ok(())
@@ -133,6 +466,13 @@ impl<'a> FileCache<'a> {
         .execute(&mut *tx)
         .await?;
 
+        sqlx::query! {
+            "DELETE FROM repo_stats WHERE repo_ref = ?",
+            repo_str
+        }
+        .execute(&mut *tx)
+        .await?;
+
         Ok(())
     }
 
@@ -149,8 +489,456 @@ impl<'a> FileCache<'a> {
         Ok(())
     }
 
-    pub async fn chunks_for_file(&self, key: &'a str) -> ChunkCache<'a> {
-        ChunkCache::for_file(self.db, self.reporef, key).await
+    /// List the branches in which any chunk of the file is searchable.
+    ///
+    /// qdrant is the authoritative source for this, as it's what search
+    /// queries run against. The SQL cache mirrors the branch list of each
+    /// chunk and is only committed after the qdrant writes have succeeded,
+    /// so the answer here may briefly lag behind, but never lead.
+    pub async fn branches_for_file(&self, file_hash: &str) -> anyhow::Result<Vec<String>> {
+        let repo_str = self.reporef.to_string();
+        let rows = sqlx::query! {
+            "SELECT DISTINCT branch_list FROM chunk_cache \
+             WHERE file_hash = ? AND repo_ref = ?",
+            file_hash,
+            repo_str,
+        }
+        .fetch_all(self.db.as_ref())
+        .await?;
+
+        let branches = rows
+            .iter()
+            .flat_map(|row| row.branch_list.lines())
+            .filter(|branch| !branch.is_empty())
+            .map(str::to_owned)
+            .collect::<BTreeSet<_>>();
+
+        Ok(branches.into_iter().collect())
+    }
+
+    /// Record a successful indexing run, dropping all but the most
+    /// recent [`INDEX_HISTORY_LEN`] runs of the repository.
+    pub(crate) async fn record_index_run(&self, run: &IndexRun) -> anyhow::Result<()> {
+        let repo_str = self.reporef.to_string();
+        let history_len = INDEX_HISTORY_LEN as i64;
+        let mut tx = self.db.begin().await?;
+
+        sqlx::query! {
+            "INSERT INTO index_runs \
+             (repo_ref, started_at, duration_ms, files_indexed, bytes) \
+             VALUES (?, ?, ?, ?, ?)",
+            repo_str,
+            run.started_at,
+            run.duration_ms,
+            run.files_indexed,
+            run.bytes,
+        }
+        .execute(&mut tx)
+        .await?;
+
+        sqlx::query! {
+            "DELETE FROM index_runs \
+             WHERE repo_ref = ? AND id NOT IN \
+             (SELECT id FROM index_runs WHERE repo_ref = ? ORDER BY id DESC LIMIT ?)",
+            repo_str,
+            repo_str,
+            history_len,
+        }
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// The last `limit` indexing runs of the repository, most recent first.
+    pub async fn index_history(&self, limit: usize) -> anyhow::Result<Vec<IndexRun>> {
+        let repo_str = self.reporef.to_string();
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        let runs = sqlx::query_as! {
+            IndexRun,
+            "SELECT started_at, duration_ms, files_indexed, bytes FROM index_runs \
+             WHERE repo_ref = ? \
+             ORDER BY id DESC \
+             LIMIT ?",
+            repo_str,
+            limit,
+        }
+        .fetch_all(self.db.as_ref())
+        .await?;
+
+        Ok(runs)
+    }
+
+    /// The cache key of the last file embedded by an interrupted semantic
+    /// backfill, if there is one.
+    pub(crate) async fn backfill_cursor(&self) -> anyhow::Result<Option<String>> {
+        let repo_str = self.reporef.to_string();
+        let row = sqlx::query! {
+            "SELECT last_file_hash FROM semantic_backfill \
+             WHERE repo_ref = ?",
+            repo_str,
+        }
+        .fetch_optional(self.db.as_ref())
+        .await?;
+
+        Ok(row.map(|row| row.last_file_hash))
+    }
+
+    /// Record that a semantic backfill has embedded every file up to
+    /// `file_hash`, in cache key order.
+    pub(crate) async fn save_backfill_cursor(&self, file_hash: &str) -> anyhow::Result<()> {
+        let repo_str = self.reporef.to_string();
+        sqlx::query! {
+            "INSERT OR REPLACE INTO semantic_backfill (repo_ref, last_file_hash) \
+             VALUES (?, ?)",
+            repo_str,
+            file_hash,
+        }
+        .execute(self.db.as_ref())
+        .await?;
+
+        Ok(())
+    }
+
+    /// Forget the progress of a semantic backfill, so the next one starts
+    /// from the first file.
+    pub(crate) async fn clear_backfill_cursor(&self) -> anyhow::Result<()> {
+        let mut tx = self.db.begin().await?;
+        self.delete_backfill_cursor(&mut tx).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Take the lease for indexing the repository, unless another
+    /// process holds it.
+    ///
+    /// Every process sharing the database should hold the lease while
+    /// writing to the index or the caches of the repository. The lease
+    /// is renewed in the background for as long as it's held.
+    pub(crate) async fn lease(&self) -> anyhow::Result<Option<IndexLease>> {
+        self.lease_as(&LEASE_HOLDER, LEASE_TTL).await
+    }
+
+    async fn lease_as(&self, holder: &str, ttl: Duration) -> anyhow::Result<Option<IndexLease>> {
+        let repo_str = self.reporef.to_string();
+        let now = unix_now();
+        let expires_at = now + ttl.as_secs() as i64;
+
+        // expired leases are taken over, others are left alone
+        let taken = sqlx::query! {
+            "INSERT INTO index_leases (repo_ref, holder, expires_at) \
+             VALUES (?, ?, ?) \
+             ON CONFLICT (repo_ref) DO UPDATE \
+             SET holder = excluded.holder, expires_at = excluded.expires_at \
+             WHERE index_leases.expires_at <= ?",
+            repo_str,
+            holder,
+            expires_at,
+            now,
+        }
+        .execute(self.db.as_ref())
+        .await?
+        .rows_affected()
+            == 1;
+
+        if !taken {
+            return Ok(None);
+        }
+
+        let renewal = tokio::spawn({
+            let db = self.db.clone();
+            let (repo_str, holder) = (repo_str.clone(), holder.to_owned());
+            async move {
+                let mut interval = tokio::time::interval((ttl / 3).max(Duration::from_secs(1)));
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    let expires_at = unix_now() + ttl.as_secs() as i64;
+                    let renewed = sqlx::query! {
+                        "UPDATE index_leases SET expires_at = ? \
+                         WHERE repo_ref = ? AND holder = ?",
+                        expires_at,
+                        repo_str,
+                        holder,
+                    }
+                    .execute(db.as_ref())
+                    .await;
+
+                    if let Err(err) = renewed {
+                        warn!(?err, repo_ref = %repo_str, "failed to renew index lease");
+                    }
+                }
+            }
+        });
+
+        Ok(Some(IndexLease {
+            db: self.db.clone(),
+            repo_ref: repo_str,
+            holder: holder.to_owned(),
+            renewal,
+            released: false,
+        }))
+    }
+
+    async fn delete_backfill_cursor(
+        &self,
+        tx: &mut sqlx::Transaction<'_, Sqlite>,
+    ) -> anyhow::Result<()> {
+        let repo_str = self.reporef.to_string();
+        sqlx::query! {
+            "DELETE FROM semantic_backfill \
+             WHERE repo_ref = ?",
+            repo_str
+        }
+        .execute(&mut *tx)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Make every chunk searchable on `from_branch` searchable on
+    /// `into_branch` as well, returning the number of updated chunks.
+    ///
+    /// This is meant for branch merges, where most files only gain a
+    /// new branch membership. Only the branch payloads are updated, so
+    /// nothing is re-chunked or embedded. Chunks written before the
+    /// branch list was cached are not considered, and will be picked
+    /// up by the next full index instead.
+    ///
+    /// As with [`ChunkCache::commit`], the SQLite changes are only
+    /// committed after all qdrant writes have completed.
+    pub async fn merge_branch(
+        &self,
+        qdrant: &QdrantClient,
+        ordering: Option<WriteOrdering>,
+        payload_schema: &PayloadSchema,
+        from_branch: &str,
+        into_branch: &str,
+    ) -> anyhow::Result<usize> {
+        let repo_str = self.reporef.to_string();
+        let rows = sqlx::query! {
+            "SELECT chunk_hash, branch_list FROM chunk_cache \
+             WHERE repo_ref = ?",
+            repo_str,
+        }
+        .fetch_all(self.db.as_ref())
+        .await?;
+
+        let updates = plan_branch_merge(
+            rows.into_iter()
+                .map(|row| (row.chunk_hash, row.branch_list)),
+            from_branch,
+            into_branch,
+        );
+
+        let mut tx = self.db.begin().await?;
+        let mut update_size = 0;
+        for (branches_list, points) in updates {
+            let branch_list = branches_list.join("\n");
+            let branches_hash = blake3::hash(branch_list.as_ref()).to_string();
+            update_size += points.len();
+
+            for p in &points {
+                sqlx::query! {
+                    "UPDATE chunk_cache SET branches = ?, branch_list = ? \
+                     WHERE chunk_hash = ?",
+                     branches_hash,
+                     branch_list,
+                     p
+                }
+                .execute(&mut tx)
+                .await?;
+            }
+
+            let id = points
+                .into_iter()
+                .map(PointId::from)
+                .collect::<Vec<_>>()
+                .into();
+            let payload = qdrant_client::client::Payload::new_from_hashmap(
+                semantic::branches_payload(payload_schema, &branches_list),
+            );
+
+            qdrant
+                .set_payload_blocking(semantic::COLLECTION_NAME, &id, payload, ordering.clone())
+                .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(update_size)
+    }
+
+    pub async fn chunks_for_file(
+        &self,
+        key: &'a str,
+        payload_schema: &'a PayloadSchema,
+    ) -> ChunkCache<'a> {
+        ChunkCache::for_file(self.db, self.reporef, key, payload_schema).await
+    }
+}
+
+/// The exclusive right to index a repository, among every process
+/// sharing the database.
+///
+/// Dropping the lease releases it in the background; call
+/// [`IndexLease::release`] to wait for it.
+pub(crate) struct IndexLease {
+    db: SqlDb,
+    repo_ref: String,
+    holder: String,
+    renewal: tokio::task::JoinHandle<()>,
+    released: bool,
+}
+
+impl IndexLease {
+    pub(crate) async fn release(mut self) -> anyhow::Result<()> {
+        self.released = true;
+        self.renewal.abort();
+        release_lease(&self.db, &self.repo_ref, &self.holder).await
+    }
+}
+
+impl Drop for IndexLease {
+    fn drop(&mut self) {
+        self.renewal.abort();
+        if self.released {
+            return;
+        }
+
+        let (db, repo_ref, holder) = (
+            self.db.clone(),
+            std::mem::take(&mut self.repo_ref),
+            std::mem::take(&mut self.holder),
+        );
+        if let Ok(rt) = tokio::runtime::Handle::try_current() {
+            rt.spawn(async move {
+                if let Err(err) = release_lease(&db, &repo_ref, &holder).await {
+                    warn!(?err, %repo_ref, "failed to release index lease");
+                }
+            });
+        }
+    }
+}
+
+async fn release_lease(db: &SqlDb, repo_ref: &str, holder: &str) -> anyhow::Result<()> {
+    sqlx::query! {
+        "DELETE FROM index_leases WHERE repo_ref = ? AND holder = ?",
+        repo_ref,
+        holder,
+    }
+    .execute(db.as_ref())
+    .await?;
+
+    Ok(())
+}
+
+fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Group the chunks that need `into_branch` added to their branch list
+/// by their new list of branches.
+///
+/// Takes `(chunk_hash, branch_list)` pairs, with `branch_list` in the
+/// newline-separated format of the `chunk_cache` table.
+fn plan_branch_merge(
+    rows: impl IntoIterator<Item = (String, String)>,
+    from_branch: &str,
+    into_branch: &str,
+) -> HashMap<Vec<String>, Vec<String>> {
+    let mut updates = HashMap::<_, Vec<_>>::new();
+    for (chunk_hash, branch_list) in rows {
+        if let Some(branches) = merged_branch_list(&branch_list, from_branch, into_branch) {
+            updates.entry(branches).or_default().push(chunk_hash);
+        }
+    }
+
+    updates
+}
+
+/// Add `into_branch` to a newline-separated list of branches that
+/// holds `from_branch`, or `None` if the list doesn't change.
+pub(crate) fn merged_branch_list(
+    branch_list: &str,
+    from_branch: &str,
+    into_branch: &str,
+) -> Option<Vec<String>> {
+    let mut branches = branch_list.lines().collect::<BTreeSet<_>>();
+    if !branches.contains(from_branch) || !branches.insert(into_branch) {
+        return None;
+    }
+
+    // branches are kept sorted, as the indexer produces them,
+    // so the next full index computes the same hash
+    Some(branches.into_iter().map(str::to_owned).collect())
+}
+
+/// Pair cached chunk hashes with the points retrieved from qdrant.
+fn match_stored_chunks(
+    chunk_hashes: Vec<String>,
+    points: impl IntoIterator<Item = Payload>,
+) -> Vec<StoredChunk> {
+    let mut points = points
+        .into_iter()
+        .filter_map(|p| Some((p.id.clone()?, p)))
+        .collect::<HashMap<_, _>>();
+
+    let mut chunks = chunk_hashes
+        .into_iter()
+        .map(|chunk_hash| match points.remove(&chunk_hash) {
+            Some(p) => StoredChunk::Embedded {
+                chunk_hash,
+                byte_range: p.start_byte..p.end_byte,
+                embedding: p.embedding.unwrap_or_default(),
+            },
+            None => StoredChunk::Missing { chunk_hash },
+        })
+        .collect::<Vec<_>>();
+
+    chunks.sort_by(|a, b| a.chunk_hash().cmp(b.chunk_hash()));
+    chunks
+}
+
+/// The phases of a [`ChunkCache::commit`], in the order they run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitPhase {
+    BranchUpdates,
+    Deletes,
+    Inserts,
+}
+
+/// Number of points affected by a [`ChunkCache::commit`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CommitStats {
+    pub new: usize,
+    pub updated: usize,
+    pub deleted: usize,
+}
+
+/// A chunk of a file in the SQLite cache, with what qdrant stores for it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoredChunk {
+    Embedded {
+        chunk_hash: String,
+        byte_range: Range<u64>,
+        embedding: Embedding,
+    },
+    /// The chunk is cached, but qdrant has no point for it
+    Missing { chunk_hash: String },
+}
+
+impl StoredChunk {
+    pub fn chunk_hash(&self) -> &str {
+        match self {
+            Self::Embedded { chunk_hash, .. } | Self::Missing { chunk_hash } => chunk_hash,
+        }
     }
 }
 
@@ -162,10 +950,12 @@ pub struct ChunkCache<'a> {
     sql: &'a SqlDb,
     reporef: &'a RepoRef,
     file_cache_key: &'a str,
+    payload_schema: &'a PayloadSchema,
+    near_duplicates: Option<&'a NearDuplicates>,
     cache: scc::HashMap<String, FreshValue<String>>,
     update: scc::HashMap<(Vec<String>, String), Vec<String>>,
     new: RwLock<Vec<PointStruct>>,
-    new_sql: RwLock<Vec<(String, String)>>,
+    new_sql: RwLock<Vec<(String, String, String)>>,
 }
 
 impl<'a> ChunkCache<'a> {
@@ -173,6 +963,7 @@ impl<'a> ChunkCache<'a> {
         sql: &'a SqlDb,
         reporef: &'a RepoRef,
         file_cache_key: &'a str,
+        payload_schema: &'a PayloadSchema,
     ) -> ChunkCache<'a> {
         let rows = sqlx::query! {
             "SELECT chunk_hash, branches FROM chunk_cache \
@@ -191,6 +982,8 @@ impl<'a> ChunkCache<'a> {
             sql,
             reporef,
             file_cache_key,
+            payload_schema,
+            near_duplicates: None,
             cache,
             update: Default::default(),
             new: Default::default(),
@@ -198,14 +991,22 @@ impl<'a> ChunkCache<'a> {
         }
     }
 
+    /// Give new chunks the embeddings of near-identical chunks found by `near_duplicates`,
+    /// instead of embedding each of them.
+    pub fn with_near_duplicates(mut self, near_duplicates: Option<&'a NearDuplicates>) -> Self {
+        self.near_duplicates = near_duplicates;
+        self
+    }
+
     pub fn update_or_embed(
         &self,
         data: &'a str,
-        embedder: impl FnOnce(&'a str) -> anyhow::Result<Embedding>,
+        embedder: &dyn Embedder,
         payload: Payload,
     ) -> anyhow::Result<()> {
         let id = self.cache_key(data);
-        let branches_hash = blake3::hash(payload.branches.join("\n").as_ref()).to_string();
+        let branch_list = payload.branches.join("\n");
+        let branches_hash = blake3::hash(branch_list.as_ref()).to_string();
 
         match self.cache.entry(id) {
             scc::hash_map::Entry::Occupied(mut existing) => {
@@ -223,15 +1024,20 @@ impl<'a> ChunkCache<'a> {
             scc::hash_map::Entry::Vacant(vacant) => {
                 let key = vacant.key();
                 trace!(?key, "inserting new");
-                self.new_sql
-                    .write()
-                    .unwrap()
-                    .push((vacant.key().to_owned(), branches_hash.clone()));
+                self.new_sql.write().unwrap().push((
+                    vacant.key().to_owned(),
+                    branches_hash.clone(),
+                    branch_list,
+                ));
 
+                let embedding = match self.near_duplicates {
+                    Some(near_duplicates) => near_duplicates.embed_with(data, embedder)?,
+                    None => embedder.embed(data)?,
+                };
                 self.new.write().unwrap().push(PointStruct {
                     id: Some(PointId::from(vacant.key().clone())),
-                    vectors: Some(embedder(data)?.into()),
-                    payload: payload.into_qdrant(),
+                    vectors: Some(embedding.into()),
+                    payload: payload.into_qdrant(self.payload_schema),
                 });
 
                 vacant.insert_entry(branches_hash.into());
@@ -252,17 +1058,47 @@ impl<'a> ChunkCache<'a> {
     ///
     /// Since qdrant changes are pipelined on their end, data written
     /// here is not necessarily available for querying when the
-    /// commit's completed.
-    pub async fn commit(self, qdrant: &QdrantClient) -> anyhow::Result<(usize, usize, usize)> {
+    /// commit's completed. Every write uses `ordering`, which decides
+    /// how many replicas have applied it before qdrant acknowledges it.
+    pub async fn commit(
+        self,
+        qdrant: &QdrantClient,
+        ordering: Option<WriteOrdering>,
+    ) -> anyhow::Result<CommitStats> {
+        self.commit_with_progress(qdrant, ordering, |_, _| {}).await
+    }
+
+    /// Same as [`ChunkCache::commit`], but calls `progress` with the
+    /// number of affected points as each phase completes.
+    ///
+    /// Note the SQLite changes are only committed at the very end, so a
+    /// completed phase can still be rolled back if a later one fails.
+    pub async fn commit_with_progress(
+        self,
+        qdrant: &QdrantClient,
+        ordering: Option<WriteOrdering>,
+        mut progress: impl FnMut(CommitPhase, usize) + Send,
+    ) -> anyhow::Result<CommitStats> {
         let mut tx = self.sql.begin().await?;
 
-        let update_size = self.commit_branch_updates(&mut tx, qdrant).await?;
-        let delete_size = self.commit_deletes(&mut tx, qdrant).await?;
-        let new_size = self.commit_inserts(&mut tx, qdrant).await?;
+        let updated = self
+            .commit_branch_updates(&mut tx, qdrant, &ordering)
+            .await?;
+        progress(CommitPhase::BranchUpdates, updated);
+
+        let deleted = self.commit_deletes(&mut tx, qdrant, &ordering).await?;
+        progress(CommitPhase::Deletes, deleted);
+
+        let new = self.commit_inserts(&mut tx, qdrant, &ordering).await?;
+        progress(CommitPhase::Inserts, new);
 
         tx.commit().await?;
 
-        Ok((new_size, update_size, delete_size))
+        Ok(CommitStats {
+            new,
+            updated,
+            deleted,
+        })
     }
 
     /// Insert new additions to both qdrant and sqlite.
@@ -274,17 +1110,18 @@ impl<'a> ChunkCache<'a> {
         &self,
         tx: &mut sqlx::Transaction<'_, Sqlite>,
         qdrant: &QdrantClient,
+        ordering: &Option<WriteOrdering>,
     ) -> Result<usize, anyhow::Error> {
         let new: Vec<_> = std::mem::take(self.new.write().unwrap().as_mut());
         let new_sql = std::mem::take(&mut *self.new_sql.write().unwrap());
         let new_size = new.len();
 
         let repo_str = self.reporef.to_string();
-        for (p, branches) in new_sql {
+        for (p, branches, branch_list) in new_sql {
             sqlx::query! {
-                "INSERT INTO chunk_cache (chunk_hash, file_hash, branches, repo_ref) \
-                 VALUES (?, ?, ?, ?)",
-                 p, self.file_cache_key, branches, repo_str
+                "INSERT INTO chunk_cache (chunk_hash, file_hash, branches, branch_list, repo_ref) \
+                 VALUES (?, ?, ?, ?, ?)",
+                 p, self.file_cache_key, branches, branch_list, repo_str
             }
             .execute(&mut *tx)
             .await?;
@@ -293,7 +1130,7 @@ impl<'a> ChunkCache<'a> {
         // qdrant doesn't like empty payloads.
         if !new.is_empty() {
             qdrant
-                .upsert_points_blocking(semantic::COLLECTION_NAME, new, None)
+                .upsert_points_blocking(semantic::COLLECTION_NAME, new, ordering.clone())
                 .await?;
         }
         Ok(new_size)
@@ -304,6 +1141,7 @@ impl<'a> ChunkCache<'a> {
         &self,
         tx: &mut sqlx::Transaction<'_, Sqlite>,
         qdrant: &QdrantClient,
+        ordering: &Option<WriteOrdering>,
     ) -> Result<usize, anyhow::Error> {
         let mut to_delete = vec![];
         self.cache
@@ -335,7 +1173,7 @@ impl<'a> ChunkCache<'a> {
                         .map(PointId::from)
                         .collect::<Vec<_>>()
                         .into(),
-                    None,
+                    ordering.clone(),
                 )
                 .await?;
         }
@@ -348,6 +1186,7 @@ impl<'a> ChunkCache<'a> {
         &self,
         tx: &mut sqlx::Transaction<'_, Sqlite>,
         qdrant: &QdrantClient,
+        ordering: &Option<WriteOrdering>,
     ) -> Result<usize, anyhow::Error> {
         let mut update_size = 0;
         let mut qdrant_updates = vec![];
@@ -355,14 +1194,16 @@ impl<'a> ChunkCache<'a> {
         let mut next = self.update.first_occupied_entry();
         while let Some(entry) = next {
             let (branches_list, branches_hash) = entry.key();
+            let branch_list = branches_list.join("\n");
             let points = entry.get();
             update_size += points.len();
 
             for p in entry.get() {
                 sqlx::query! {
-                    "UPDATE chunk_cache SET branches = ? \
+                    "UPDATE chunk_cache SET branches = ?, branch_list = ? \
                      WHERE chunk_hash = ?",
                      branches_hash,
+                     branch_list,
                      p
                 }
                 .execute(&mut *tx)
@@ -376,13 +1217,17 @@ impl<'a> ChunkCache<'a> {
                 .collect::<Vec<_>>()
                 .into();
 
+            // `set_payload` merges the given keys into the existing
+            // payload, so any other fields (including user-defined
+            // extras) are left untouched.
             let payload = qdrant_client::client::Payload::new_from_hashmap(
-                [("branches".to_string(), branches_list.to_owned().into())].into(),
+                semantic::branches_payload(self.payload_schema, branches_list),
             );
 
+            let ordering = ordering.clone();
             qdrant_updates.push(async move {
                 qdrant
-                    .set_payload_blocking(semantic::COLLECTION_NAME, &id, payload, None)
+                    .set_payload_blocking(semantic::COLLECTION_NAME, &id, payload, ordering)
                     .await
             });
             next = entry.next();
@@ -401,6 +1246,45 @@ impl<'a> ChunkCache<'a> {
         Ok(update_size)
     }
 
+    /// Retrieve the qdrant point of every cached chunk, ordered by chunk hash.
+    ///
+    /// Chunks with a row in SQLite but no point in qdrant are reported as
+    /// [`StoredChunk::Missing`]. Nothing is written to either database.
+    pub async fn stored_chunks(&self, qdrant: &QdrantClient) -> anyhow::Result<Vec<StoredChunk>> {
+        let mut chunk_hashes = vec![];
+        self.cache
+            .scan_async(|id, _| chunk_hashes.push(id.to_owned()))
+            .await;
+
+        if chunk_hashes.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let ids = chunk_hashes
+            .iter()
+            .cloned()
+            .map(PointId::from)
+            .collect::<Vec<_>>();
+        let points = qdrant
+            .get_points(
+                semantic::COLLECTION_NAME,
+                &ids,
+                Some(WithVectorsSelector {
+                    selector_options: Some(with_vectors_selector::SelectorOptions::Enable(true)),
+                }),
+                Some(WithPayloadSelector {
+                    selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
+                }),
+                None,
+            )
+            .await?
+            .result
+            .into_iter()
+            .map(|point| Payload::from_scroll(point, self.payload_schema));
+
+        Ok(match_stored_chunks(chunk_hashes, points))
+    }
+
     /// Return the cache key for the file that contains these chunks
     pub fn file_hash(&self) -> String {
         self.file_cache_key.to_string()
@@ -420,3 +1304,491 @@ impl<'a> ChunkCache<'a> {
         id
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::semantic::embedder::StubEmbedder;
+    use qdrant_client::prelude::QdrantClientConfig;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    async fn test_db() -> SqlDb {
+        // a single connection, as every in-memory connection gets its own database
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        Arc::new(pool)
+    }
+
+    #[tokio::test]
+    async fn commit_progress_in_phase_order() {
+        let db = test_db().await;
+        let payload_schema = PayloadSchema::default();
+        let reporef: RepoRef = "github.com/bloopai/bloop".parse().unwrap();
+
+        // nothing to write, so qdrant is never contacted
+        let config = QdrantClientConfig::from_url("http://127.0.0.1:6334");
+        let qdrant = QdrantClient::new(Some(config)).unwrap();
+
+        let mut phases = vec![];
+        let stats = FileCache::for_repo(&db, &reporef)
+            .chunks_for_file("file", &payload_schema)
+            .await
+            .commit_with_progress(&qdrant, None, |phase, _| phases.push(phase))
+            .await
+            .unwrap();
+
+        assert_eq!(stats, CommitStats::default());
+        assert_eq!(
+            phases,
+            [
+                CommitPhase::BranchUpdates,
+                CommitPhase::Deletes,
+                CommitPhase::Inserts
+            ]
+        );
+    }
+
+    fn snapshot(keys: &[&str]) -> FileCacheSnapshot {
+        let snapshot = scc::HashMap::default();
+        for key in keys {
+            _ = snapshot.insert(key.to_string(), FreshValue::from(RepoStats::default()));
+        }
+        snapshot.into()
+    }
+
+    async fn total_changes(db: &SqlDb) -> i64 {
+        sqlx::query_scalar("SELECT total_changes()")
+            .fetch_one(db.as_ref())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn persisting_unchanged_snapshot_leaves_rows_alone() {
+        let db = test_db().await;
+        let reporef: RepoRef = "github.com/bloopai/bloop".parse().unwrap();
+        let cache = FileCache::for_repo(&db, &reporef);
+
+        let stats = cache.persist(snapshot(&["a", "b", "c"])).await.unwrap();
+        assert_eq!(
+            stats,
+            PersistStats {
+                inserted: 3,
+                deleted: 0,
+                ignored: 0,
+            }
+        );
+        let before = total_changes(&db).await;
+
+        // an index with nothing to do persists the snapshot it retrieved
+        let stats = cache.persist(cache.retrieve().await).await.unwrap();
+        assert_eq!(stats, PersistStats::default());
+        assert_eq!(total_changes(&db).await, before);
+
+        let stats = cache.persist(snapshot(&["a", "c", "d"])).await.unwrap();
+        assert_eq!(
+            stats,
+            PersistStats {
+                inserted: 1,
+                deleted: 1,
+                ignored: 0,
+            }
+        );
+        assert_eq!(total_changes(&db).await, before + 2);
+
+        let mut keys = vec![];
+        cache.retrieve().await.scan(|k, _| keys.push(k.clone()));
+        keys.sort();
+        assert_eq!(keys, ["a", "c", "d"]);
+    }
+
+    #[tokio::test]
+    async fn persisting_leaves_the_rows_of_other_repos_alone() {
+        let db = test_db().await;
+        let first: RepoRef = "github.com/bloopai/bloop".parse().unwrap();
+        let second: RepoRef = "github.com/bloopai/other".parse().unwrap();
+        let first_cache = FileCache::for_repo(&db, &first);
+        let second_cache = FileCache::for_repo(&db, &second);
+
+        first_cache.persist(snapshot(&["a", "b"])).await.unwrap();
+
+        // `b` is already held by the first repository
+        let stats = second_cache.persist(snapshot(&["b", "c"])).await.unwrap();
+        assert_eq!(
+            stats,
+            PersistStats {
+                inserted: 1,
+                deleted: 0,
+                ignored: 1,
+            }
+        );
+
+        // dropping a key of the second repository doesn't touch the first
+        let mut keys = vec![];
+        second_cache
+            .retrieve()
+            .await
+            .scan(|k, _| keys.push(k.clone()));
+        assert_eq!(keys, ["c"]);
+        second_cache.persist(snapshot(&[])).await.unwrap();
+
+        let mut keys = vec![];
+        first_cache
+            .retrieve()
+            .await
+            .scan(|k, _| keys.push(k.clone()));
+        keys.sort();
+        assert_eq!(keys, ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn vacuum_reclaims_deleted_rows() {
+        let db = test_db().await;
+        let reporef: RepoRef = "github.com/bloopai/bloop".parse().unwrap();
+        let cache = FileCache::for_repo(&db, &reporef);
+
+        let keys = (0..2000)
+            .map(|i| format!("file-{i:064}"))
+            .collect::<Vec<_>>();
+        let keys = keys.iter().map(String::as_str).collect::<Vec<_>>();
+        cache.persist(snapshot(&keys)).await.unwrap();
+        cache.delete().await.unwrap();
+
+        assert!(cache.vacuum().await.unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn branches_for_file_on_two_branches() {
+        let db = test_db().await;
+
+        let reporef: RepoRef = "github.com/bloopai/bloop".parse().unwrap();
+        let rows = [
+            ("chunk-1", "file", "main\ndev"),
+            ("chunk-2", "file", "main"),
+            ("chunk-3", "other-file", "feature"),
+        ];
+
+        for (chunk_hash, file_hash, branch_list) in rows {
+            sqlx::query(
+                "INSERT INTO chunk_cache (chunk_hash, file_hash, branches, branch_list, repo_ref) \
+                 VALUES (?, ?, ?, ?, ?)",
+            )
+            .bind(chunk_hash)
+            .bind(file_hash)
+            .bind(blake3::hash(branch_list.as_bytes()).to_string())
+            .bind(branch_list)
+            .bind(reporef.to_string())
+            .execute(db.as_ref())
+            .await
+            .unwrap();
+        }
+
+        let branches = FileCache::for_repo(&db, &reporef)
+            .branches_for_file("file")
+            .await
+            .unwrap();
+
+        assert_eq!(branches, ["dev", "main"]);
+    }
+
+    #[tokio::test]
+    async fn consecutive_runs_append_history() {
+        let db = test_db().await;
+        let reporef: RepoRef = "github.com/bloopai/bloop".parse().unwrap();
+        let other: RepoRef = "github.com/bloopai/other".parse().unwrap();
+        let cache = FileCache::for_repo(&db, &reporef);
+
+        let run = |n: i64| IndexRun {
+            started_at: 1_000 + n,
+            duration_ms: 10 * n,
+            files_indexed: n,
+            bytes: 100 * n,
+        };
+
+        cache.record_index_run(&run(1)).await.unwrap();
+        cache.record_index_run(&run(2)).await.unwrap();
+        FileCache::for_repo(&db, &other)
+            .record_index_run(&run(3))
+            .await
+            .unwrap();
+
+        assert_eq!(cache.index_history(10).await.unwrap(), [run(2), run(1)]);
+        assert_eq!(cache.index_history(1).await.unwrap(), [run(2)]);
+
+        for n in 0..INDEX_HISTORY_LEN as i64 {
+            cache.record_index_run(&run(10 + n)).await.unwrap();
+        }
+
+        let history = cache.index_history(usize::MAX).await.unwrap();
+        assert_eq!(history.len(), INDEX_HISTORY_LEN);
+        assert_eq!(history.last(), Some(&run(10)));
+
+        // pruning is per repository
+        assert_eq!(
+            FileCache::for_repo(&db, &other)
+                .index_history(10)
+                .await
+                .unwrap(),
+            [run(3)]
+        );
+    }
+
+    #[test]
+    fn branch_merge_plan_adds_target_branch() {
+        let rows = [
+            ("feature-only", "feature"),
+            ("shared", "dev\nfeature"),
+            ("merged-already", "feature\nmain"),
+            ("unrelated", "dev"),
+            ("before-branch-list", ""),
+        ]
+        .map(|(hash, list)| (hash.to_owned(), list.to_owned()));
+
+        let plan = plan_branch_merge(rows, "feature", "main");
+
+        assert_eq!(
+            plan,
+            HashMap::from([
+                (
+                    vec!["feature".to_owned(), "main".to_owned()],
+                    vec!["feature-only".to_owned()]
+                ),
+                (
+                    vec!["dev".to_owned(), "feature".to_owned(), "main".to_owned()],
+                    vec!["shared".to_owned()]
+                ),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn branch_merge_matches_reindex_without_embedding() {
+        let db = test_db().await;
+        let payload_schema = PayloadSchema::default();
+        let reporef: RepoRef = "github.com/bloopai/bloop".parse().unwrap();
+        let file_cache = FileCache::for_repo(&db, &reporef);
+        let chunks = ["fn a() {}", "fn b() {}", "fn c() {}"];
+
+        // the chunks were embedded when the feature branch was indexed
+        let cache = file_cache.chunks_for_file("file", &payload_schema).await;
+        for chunk in chunks {
+            sqlx::query(
+                "INSERT INTO chunk_cache (chunk_hash, file_hash, branches, branch_list, repo_ref) \
+                 VALUES (?, ?, ?, ?, ?)",
+            )
+            .bind(cache.cache_key(chunk))
+            .bind("file")
+            .bind(blake3::hash(b"feature").to_string())
+            .bind("feature")
+            .bind(reporef.to_string())
+            .execute(db.as_ref())
+            .await
+            .unwrap();
+        }
+
+        // re-indexing after the merge re-chunks the file
+        let embedder = StubEmbedder::default();
+        let merged = vec!["feature".to_owned(), "main".to_owned()];
+        let cache = file_cache.chunks_for_file("file", &payload_schema).await;
+        for chunk in chunks {
+            let payload = Payload {
+                branches: merged.clone(),
+                ..Default::default()
+            };
+            cache.update_or_embed(chunk, &embedder, payload).unwrap();
+        }
+
+        let mut reindex = HashMap::new();
+        cache.update.scan(|(branches, _), points| {
+            let mut points = points.clone();
+            points.sort();
+            reindex.insert(branches.clone(), points);
+        });
+
+        // merging only reads the cache
+        let rows = sqlx::query_as::<_, (String, String)>(
+            "SELECT chunk_hash, branch_list FROM chunk_cache WHERE repo_ref = ?",
+        )
+        .bind(reporef.to_string())
+        .fetch_all(db.as_ref())
+        .await
+        .unwrap();
+        let mut merge = plan_branch_merge(rows, "feature", "main");
+        merge.values_mut().for_each(|points| points.sort());
+
+        assert_eq!(embedder.calls(), 0);
+        assert_eq!(merge, reindex);
+        assert_eq!(merge[&merged].len(), chunks.len());
+    }
+
+    #[tokio::test]
+    async fn new_chunks_are_embedded_once() {
+        let db = test_db().await;
+        let payload_schema = PayloadSchema::default();
+        let reporef: RepoRef = "github.com/bloopai/bloop".parse().unwrap();
+        let file_cache = FileCache::for_repo(&db, &reporef);
+        let chunks = ["fn a() {}", "fn b() {}", "fn a() {}"];
+
+        let embedder = StubEmbedder::default();
+        let cache = file_cache.chunks_for_file("file", &payload_schema).await;
+        for chunk in chunks {
+            cache
+                .update_or_embed(chunk, &embedder, Payload::default())
+                .unwrap();
+        }
+
+        // the repeated chunk is already in the cache
+        assert_eq!(embedder.calls(), 2);
+        let new = cache.new.read().unwrap();
+        assert_eq!(new.len(), 2);
+        assert_eq!(
+            new[0].vectors,
+            Some(embedder.embed(chunks[0]).unwrap().into())
+        );
+
+        // near-identical chunks share the embedding of the first
+        let near_duplicates = NearDuplicates::new(0.5);
+        let embedder = StubEmbedder::default();
+        let cache = file_cache
+            .chunks_for_file("other", &payload_schema)
+            .await
+            .with_near_duplicates(Some(&near_duplicates));
+        let chunk = |name| {
+            format!("let {name} = compute(first, second, third, fourth, fifth, sixth) * scale;")
+        };
+        for chunk in [chunk("total"), chunk("sum")] {
+            cache
+                .update_or_embed(&chunk, &embedder, Payload::default())
+                .unwrap();
+        }
+        assert_eq!(embedder.calls(), 1);
+        assert_eq!(cache.new.read().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn stored_chunks_report_missing_points() {
+        let db = test_db().await;
+        let payload_schema = PayloadSchema::default();
+        let reporef: RepoRef = "github.com/bloopai/bloop".parse().unwrap();
+        let cache = FileCache::for_repo(&db, &reporef)
+            .chunks_for_file("file", &payload_schema)
+            .await;
+
+        let (first, second) = (cache.cache_key("fn a() {}"), cache.cache_key("fn b() {}"));
+        for chunk_hash in [&first, &second] {
+            sqlx::query(
+                "INSERT INTO chunk_cache (chunk_hash, file_hash, branches, branch_list, repo_ref) \
+                 VALUES (?, ?, ?, ?, ?)",
+            )
+            .bind(chunk_hash)
+            .bind("file")
+            .bind(blake3::hash(b"main").to_string())
+            .bind("main")
+            .bind(reporef.to_string())
+            .execute(db.as_ref())
+            .await
+            .unwrap();
+        }
+
+        let cache = FileCache::for_repo(&db, &reporef)
+            .chunks_for_file("file", &payload_schema)
+            .await;
+        let mut chunk_hashes = vec![];
+        cache.cache.scan(|id, _| chunk_hashes.push(id.to_owned()));
+
+        // qdrant only returned the first chunk, and a point of another file
+        let points = [(&first, 0..9), (&"unrelated".to_owned(), 3..5)].map(|(id, range)| Payload {
+            id: Some(id.to_owned()),
+            start_byte: range.start,
+            end_byte: range.end,
+            embedding: Some(vec![0.5, 0.25]),
+            ..Default::default()
+        });
+
+        let mut expected = vec![
+            StoredChunk::Embedded {
+                chunk_hash: first,
+                byte_range: 0..9,
+                embedding: vec![0.5, 0.25],
+            },
+            StoredChunk::Missing { chunk_hash: second },
+        ];
+        expected.sort_by(|a, b| a.chunk_hash().cmp(b.chunk_hash()));
+
+        assert_eq!(match_stored_chunks(chunk_hashes, points), expected);
+    }
+
+    #[tokio::test]
+    async fn index_lease_excludes_other_processes() {
+        let db = test_db().await;
+        let reporef: RepoRef = "github.com/bloopai/bloop".parse().unwrap();
+        let other: RepoRef = "github.com/bloopai/other".parse().unwrap();
+        let cache = FileCache::for_repo(&db, &reporef);
+
+        let lease = cache.lease_as("a", LEASE_TTL).await.unwrap().unwrap();
+        assert!(cache.lease_as("b", LEASE_TTL).await.unwrap().is_none());
+
+        // leases are per repository
+        let other_cache = FileCache::for_repo(&db, &other);
+        assert!(other_cache
+            .lease_as("b", LEASE_TTL)
+            .await
+            .unwrap()
+            .is_some());
+
+        lease.release().await.unwrap();
+        assert!(cache.lease_as("b", LEASE_TTL).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn expired_index_lease_is_taken_over() {
+        let db = test_db().await;
+        let reporef: RepoRef = "github.com/bloopai/bloop".parse().unwrap();
+        let cache = FileCache::for_repo(&db, &reporef);
+
+        // the holder died before renewing its lease
+        let stale = cache.lease_as("a", Duration::ZERO).await.unwrap().unwrap();
+        let lease = cache.lease_as("b", LEASE_TTL).await.unwrap().unwrap();
+
+        // releasing the stale lease doesn't release the new one
+        stale.release().await.unwrap();
+        assert!(cache.lease_as("c", LEASE_TTL).await.unwrap().is_none());
+        drop(lease);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_processes_take_one_index_lease() {
+        let dir = tempdir::TempDir::new("lease-db").unwrap();
+        let url = format!("sqlite://{}/bleep.db?mode=rwc", dir.path().display());
+
+        // a pool for every process sharing the database
+        let mut pools = vec![];
+        for _ in 0..4 {
+            let pool = SqlitePoolOptions::new().connect(&url).await.unwrap();
+            sqlx::migrate!().run(&pool).await.unwrap();
+            pools.push(Arc::new(pool));
+        }
+
+        let attempts = pools.iter().enumerate().map(|(i, db)| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                let reporef: RepoRef = "github.com/bloopai/bloop".parse().unwrap();
+                FileCache::for_repo(&db, &reporef)
+                    .lease_as(&i.to_string(), LEASE_TTL)
+                    .await
+                    .unwrap()
+            })
+        });
+
+        let leases = futures::future::join_all(attempts)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        assert_eq!(leases.iter().flatten().count(), 1);
+    }
+}
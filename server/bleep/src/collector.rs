@@ -1,5 +1,11 @@
 mod bytes_filter;
+mod capped;
+mod either;
 mod frequency;
+mod score_floor;
 
 pub use bytes_filter::BytesFilterCollector;
-pub use frequency::FrequencyCollector;
+pub use capped::CappedDocSetCollector;
+pub use either::EitherCollector;
+pub use frequency::{FrequencyCollector, IdFrequencyCollector};
+pub use score_floor::{ScoreFloor, ScoreFloorCollector};
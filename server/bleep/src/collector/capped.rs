@@ -0,0 +1,63 @@
+use tantivy::{
+    collector::{Collector, SegmentCollector},
+    DocAddress, DocId, Score, SegmentOrdinal, SegmentReader,
+};
+
+/// Like tantivy's `DocSetCollector`, but keeps at most `limit` documents.
+///
+/// The documents kept are the first ones in address order, so the same index always yields the
+/// same set.
+pub struct CappedDocSetCollector(pub usize);
+
+impl Collector for CappedDocSetCollector {
+    type Fruit = Vec<DocAddress>;
+
+    type Child = CappedDocSetSegmentCollector;
+
+    fn for_segment(
+        &self,
+        segment_local_id: SegmentOrdinal,
+        _segment_reader: &SegmentReader,
+    ) -> tantivy::Result<CappedDocSetSegmentCollector> {
+        Ok(CappedDocSetSegmentCollector {
+            segment_ord: segment_local_id,
+            limit: self.0,
+            docs: vec![],
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        false
+    }
+
+    fn merge_fruits(&self, segments: Vec<Self::Fruit>) -> tantivy::Result<Self::Fruit> {
+        let mut docs = segments.into_iter().flatten().collect::<Vec<_>>();
+        docs.sort_unstable();
+        docs.truncate(self.0);
+        Ok(docs)
+    }
+}
+
+pub struct CappedDocSetSegmentCollector {
+    segment_ord: SegmentOrdinal,
+    limit: usize,
+    docs: Vec<DocId>,
+}
+
+impl SegmentCollector for CappedDocSetSegmentCollector {
+    type Fruit = Vec<DocAddress>;
+
+    fn collect(&mut self, doc: DocId, _score: Score) {
+        // documents of a segment are collected in increasing order
+        if self.docs.len() < self.limit {
+            self.docs.push(doc);
+        }
+    }
+
+    fn harvest(self) -> <Self as SegmentCollector>::Fruit {
+        self.docs
+            .into_iter()
+            .map(|doc_id| DocAddress::new(self.segment_ord, doc_id))
+            .collect()
+    }
+}
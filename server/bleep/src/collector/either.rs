@@ -0,0 +1,97 @@
+// picks one of two collectors with the same fruit at runtime
+
+use tantivy::collector::{Collector, SegmentCollector};
+use tantivy::{DocId, Score, SegmentReader};
+
+/// Either of two collectors that produce the same fruit, for searches that decide how to
+/// collect hits from their parameters.
+pub enum EitherCollector<A, B> {
+    Left(A),
+    Right(B),
+}
+
+impl<A, B> Collector for EitherCollector<A, B>
+where
+    A: Collector,
+    B: Collector<Fruit = A::Fruit>,
+{
+    type Fruit = A::Fruit;
+
+    type Child = EitherCollector<A::Child, B::Child>;
+
+    fn for_segment(
+        &self,
+        segment_local_id: u32,
+        segment_reader: &SegmentReader,
+    ) -> tantivy::Result<Self::Child> {
+        Ok(match self {
+            Self::Left(a) => {
+                EitherCollector::Left(a.for_segment(segment_local_id, segment_reader)?)
+            }
+            Self::Right(b) => {
+                EitherCollector::Right(b.for_segment(segment_local_id, segment_reader)?)
+            }
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        match self {
+            Self::Left(a) => a.requires_scoring(),
+            Self::Right(b) => b.requires_scoring(),
+        }
+    }
+
+    fn merge_fruits(
+        &self,
+        segment_fruits: Vec<<Self::Child as SegmentCollector>::Fruit>,
+    ) -> tantivy::Result<Self::Fruit> {
+        // segments are always collected by the same side as their parent
+        match self {
+            Self::Left(a) => a.merge_fruits(
+                segment_fruits
+                    .into_iter()
+                    .filter_map(|fruit| match fruit {
+                        EitherFruit::Left(fruit) => Some(fruit),
+                        EitherFruit::Right(_) => None,
+                    })
+                    .collect(),
+            ),
+            Self::Right(b) => b.merge_fruits(
+                segment_fruits
+                    .into_iter()
+                    .filter_map(|fruit| match fruit {
+                        EitherFruit::Left(_) => None,
+                        EitherFruit::Right(fruit) => Some(fruit),
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+pub enum EitherFruit<A, B> {
+    Left(A),
+    Right(B),
+}
+
+impl<A, B> SegmentCollector for EitherCollector<A, B>
+where
+    A: SegmentCollector,
+    B: SegmentCollector,
+{
+    type Fruit = EitherFruit<A::Fruit, B::Fruit>;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        match self {
+            Self::Left(a) => a.collect(doc, score),
+            Self::Right(b) => b.collect(doc, score),
+        }
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        match self {
+            Self::Left(a) => EitherFruit::Left(a.harvest()),
+            Self::Right(b) => EitherFruit::Right(b.harvest()),
+        }
+    }
+}
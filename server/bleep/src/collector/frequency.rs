@@ -1,8 +1,8 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
 use tantivy::{
     collector::{Collector, SegmentCollector},
-    fastfield::BytesFastFieldReader,
+    fastfield::{BytesFastFieldReader, Column},
     schema::Field,
     Score, SegmentReader,
 };
@@ -62,3 +62,55 @@ impl SegmentCollector for FrequencySegmentCollector {
         self.freqs
     }
 }
+
+/// Like [`FrequencyCollector`], for a `u64` fast field.
+pub struct IdFrequencyCollector(pub Field);
+
+impl Collector for IdFrequencyCollector {
+    type Fruit = HashMap<u64, usize>;
+
+    type Child = IdFrequencySegmentCollector;
+
+    fn for_segment(
+        &self,
+        _segment_local_id: u32,
+        segment_reader: &SegmentReader,
+    ) -> tantivy::Result<IdFrequencySegmentCollector> {
+        let reader = segment_reader.fast_fields().u64(self.0)?;
+        Ok(IdFrequencySegmentCollector {
+            reader,
+            freqs: HashMap::new(),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        false
+    }
+
+    fn merge_fruits(&self, segments: Vec<Self::Fruit>) -> tantivy::Result<Self::Fruit> {
+        let mut freqs = HashMap::new();
+        for segment in segments.into_iter() {
+            for (k, v) in segment {
+                *freqs.entry(k).or_default() += v;
+            }
+        }
+        Ok(freqs)
+    }
+}
+
+pub struct IdFrequencySegmentCollector {
+    reader: Arc<dyn Column<u64>>,
+    freqs: HashMap<u64, usize>,
+}
+
+impl SegmentCollector for IdFrequencySegmentCollector {
+    type Fruit = HashMap<u64, usize>;
+
+    fn collect(&mut self, doc: u32, _score: Score) {
+        *self.freqs.entry(self.reader.get_val(doc)).or_default() += 1;
+    }
+
+    fn harvest(self) -> <Self as SegmentCollector>::Fruit {
+        self.freqs
+    }
+}
@@ -0,0 +1,182 @@
+// drops top-k hits that score too low compared to a fixed or relative threshold
+
+use tantivy::collector::{Collector, SegmentCollector};
+use tantivy::{DocAddress, Score, SegmentReader};
+
+/// The minimum score a hit needs to be kept.
+///
+/// BM25 scores aren't normalized, so a fixed `min_score` depends on the
+/// query and the corpus; `top_fraction` is relative to the best hit instead.
+/// When both are set, a hit has to pass both.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ScoreFloor {
+    pub min_score: Option<Score>,
+    /// Fraction of the top hit's score, between 0 and 1
+    pub top_fraction: Option<Score>,
+}
+
+impl ScoreFloor {
+    pub fn is_set(&self) -> bool {
+        self.min_score.is_some() || self.top_fraction.is_some()
+    }
+
+    /// Drop the hits below the floor.
+    ///
+    /// `hits` must be in descending score order, as produced by `TopDocs`.
+    pub fn apply(&self, hits: &mut Vec<(Score, DocAddress)>) {
+        let Some(&(top, _)) = hits.first() else {
+            return;
+        };
+
+        let relative = self.top_fraction.map(|fraction| top * fraction);
+        let threshold = match (self.min_score, relative) {
+            (Some(a), Some(b)) => a.max(b),
+            (a, b) => a.or(b).unwrap_or(Score::MIN),
+        };
+
+        hits.retain(|(score, _)| *score >= threshold);
+    }
+}
+
+/// Apply a [`ScoreFloor`] to the hits of a top-k collector, then skip
+/// `offset` of them.
+///
+/// The offset has to be applied here rather than by `TopDocs`, as the
+/// top hit of a later page is not the top hit overall.
+pub struct ScoreFloorCollector<TCollector> {
+    floor: ScoreFloor,
+    offset: usize,
+    collector: TCollector,
+}
+
+impl<TCollector> ScoreFloorCollector<TCollector> {
+    pub fn new(floor: ScoreFloor, offset: usize, collector: TCollector) -> Self {
+        Self {
+            floor,
+            offset,
+            collector,
+        }
+    }
+}
+
+impl<TCollector, TFruit> Collector for ScoreFloorCollector<TCollector>
+where
+    TCollector: Collector<Fruit = (Vec<(Score, DocAddress)>, TFruit)>,
+    TFruit: tantivy::collector::Fruit,
+{
+    type Fruit = TCollector::Fruit;
+
+    type Child = TCollector::Child;
+
+    fn for_segment(
+        &self,
+        segment_local_id: u32,
+        segment_reader: &SegmentReader,
+    ) -> tantivy::Result<TCollector::Child> {
+        self.collector.for_segment(segment_local_id, segment_reader)
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.collector.requires_scoring()
+    }
+
+    fn merge_fruits(
+        &self,
+        segment_fruits: Vec<<TCollector::Child as SegmentCollector>::Fruit>,
+    ) -> tantivy::Result<TCollector::Fruit> {
+        let (mut hits, rest) = self.collector.merge_fruits(segment_fruits)?;
+        self.floor.apply(&mut hits);
+        hits.drain(..self.offset.min(hits.len()));
+        Ok((hits, rest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::{
+        collector::{Count, TopDocs},
+        doc,
+        query::TermQuery,
+        schema::{IndexRecordOption, Schema, TEXT},
+        Index, Term,
+    };
+
+    fn search(floor: ScoreFloor, offset: usize) -> (Vec<u32>, usize) {
+        let mut builder = Schema::builder();
+        let content = builder.add_text_field("content", TEXT);
+        let index = Index::create_in_ram(builder.build());
+
+        let mut writer = index.writer(15_000_000).unwrap();
+        for text in [
+            "needle needle needle",
+            "needle needle needle needle",
+            "needle in a very large haystack of hay and straw and more hay",
+            "needle lost among many many other unrelated words in this document",
+            "no match here",
+        ] {
+            writer.add_document(doc!(content => text)).unwrap();
+        }
+        writer.commit().unwrap();
+
+        let query = TermQuery::new(
+            Term::from_field_text(content, "needle"),
+            IndexRecordOption::WithFreqs,
+        );
+        let collector = ScoreFloorCollector::new(floor, offset, (TopDocs::with_limit(10), Count));
+        let (hits, count) = index
+            .reader()
+            .unwrap()
+            .searcher()
+            .search(&query, &collector)
+            .unwrap();
+
+        (
+            hits.into_iter().map(|(_, addr)| addr.doc_id).collect(),
+            count,
+        )
+    }
+
+    #[test]
+    fn unset_floor_keeps_everything() {
+        let (mut hits, count) = search(ScoreFloor::default(), 0);
+        hits.sort();
+        assert_eq!(hits, [0, 1, 2, 3]);
+        assert_eq!(count, 4);
+    }
+
+    #[test]
+    fn min_score_drops_weak_matches() {
+        let (all, _) = search(ScoreFloor::default(), 0);
+        assert_eq!(&all[..2], [1, 0]);
+
+        let floor = ScoreFloor {
+            min_score: Some(0.4),
+            ..Default::default()
+        };
+        let (hits, count) = search(floor, 0);
+        assert_eq!(hits, [1, 0]);
+
+        // only the hits are filtered, not the metadata
+        assert_eq!(count, 4);
+    }
+
+    #[test]
+    fn top_fraction_is_relative_to_best_hit() {
+        let floor = ScoreFloor {
+            top_fraction: Some(0.8),
+            ..Default::default()
+        };
+        assert_eq!(search(floor, 0).0, [1, 0]);
+
+        // the floor is computed before paging
+        assert_eq!(search(floor, 1).0, [0]);
+        assert_eq!(search(floor, 2).0, Vec::<u32>::new());
+
+        let floor = ScoreFloor {
+            top_fraction: Some(1.0),
+            ..Default::default()
+        };
+        assert_eq!(search(floor, 0).0, [1]);
+    }
+}
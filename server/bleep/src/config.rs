@@ -1,10 +1,24 @@
-use crate::{semantic::chunk::OverlapStrategy, state::StateSource};
+use crate::{
+    indexes::{CorpusMode, ImportPatterns, MissingRepoMode, DEFAULT_MAX_RESULT_LIMIT},
+    repo::{
+        iterator::{ConflictMode, LfsMode, SymlinkMode},
+        RepoRef,
+    },
+    semantic::{
+        chunk::{ChunkStrategy, OverlapStrategy},
+        PayloadSchema, WriteConsistency,
+    },
+    state::StateSource,
+};
 use anyhow::{Context, Result};
 use clap::Parser;
 
 use secrecy::{ExposeSecret, SecretString};
 use serde::{Deserialize, Serialize, Serializer};
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 #[derive(Serialize, Deserialize, Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -58,11 +72,41 @@ pub struct Configuration {
     /// Size of memory to use for repo indexes
     pub repo_buffer_size: usize,
 
+    #[clap(long)]
+    /// Commit the file index every time this many entries were walked
+    ///
+    /// Each commit bounds the memory held by the index writer and the work
+    /// lost if indexing is interrupted, but has to wait for every file in
+    /// flight and rewrites the file cache, so low values slow indexing
+    /// down. If unset, a repository is only committed once it's fully
+    /// indexed.
+    pub commit_interval: Option<usize>,
+
+    #[clap(long)]
+    /// Keep the content of the files being indexed, and of the documents written since the
+    /// last commit, within this many bytes
+    ///
+    /// Files wait for others to finish before they're read while the budget is used up, and
+    /// the file index is committed as soon as the written documents exceed it, whatever the
+    /// commit interval. The writer's own buffer is set by `buffer-size`.
+    pub memory_budget_bytes: Option<u64>,
+
+    #[clap(long, default_value_t = 0)]
+    #[serde(default)]
+    /// Wait this many milliseconds for another process to stop writing to the index before
+    /// giving up on indexing. By default, indexing fails right away
+    pub writer_lock_timeout_ms: u64,
+
     #[clap(short, long, default_value_t = default_parallelism())]
     #[serde(default = "default_parallelism")]
     /// Maximum number of parallel background threads
     pub max_threads: usize,
 
+    #[clap(long, default_value_t = default_max_result_limit())]
+    #[serde(default = "default_max_result_limit")]
+    /// Maximum number of results any search returns at once, whatever the request asks for
+    pub max_result_limit: usize,
+
     #[clap(long, default_value_t = default_host())]
     #[serde(default = "default_host")]
     /// Bind the webserver to `<port>`
@@ -73,6 +117,132 @@ pub struct Configuration {
     /// Bind the webserver to `<host>`
     pub port: u16,
 
+    //
+    // Indexing options
+    //
+    #[clap(long, default_value_t = false)]
+    #[serde(default)]
+    /// Rewrite CRLF line endings to LF before indexing file contents
+    pub normalize_line_endings: bool,
+
+    #[clap(long, value_delimiter = ',', default_values_t = default_generated_file_globs())]
+    #[serde(default = "default_generated_file_globs")]
+    /// Path globs of generated files, which are indexed without extracting symbols
+    pub generated_file_globs: Vec<String>,
+
+    #[clap(long, value_delimiter = ',', default_values_t = default_generated_file_markers())]
+    #[serde(default = "default_generated_file_markers")]
+    /// Markers in the header of a file that flag it as generated
+    pub generated_file_markers: Vec<String>,
+
+    #[clap(long, value_delimiter = ',', default_values_t = default_vendored_globs())]
+    #[serde(default = "default_vendored_globs")]
+    /// Path globs of vendored files, which are left out of content search unless asked for
+    pub vendored_globs: Vec<String>,
+
+    #[clap(long, default_value_t = default_slow_file_ms())]
+    #[serde(default = "default_slow_file_ms")]
+    /// Warn about files that take longer than this many milliseconds to index
+    pub slow_file_ms: u64,
+
+    #[clap(long, default_value_t = 0)]
+    #[serde(default)]
+    /// Index files smaller than this many bytes by path only, leaving their content out of
+    /// content search
+    pub min_file_size_bytes: u64,
+
+    #[clap(long, default_value_t = default_max_file_size_bytes())]
+    #[serde(default = "default_max_file_size_bytes")]
+    /// Skip files larger than this many bytes without reading them, even when large files are
+    /// split into shards
+    ///
+    /// Without `shard-lines`, files over 600 KB are skipped as too large anyway, so this only
+    /// lowers that limit. With it, files between the two limits are split into shards, and
+    /// larger files are still skipped.
+    pub max_file_size_bytes: u64,
+
+    #[clap(long)]
+    /// Split files over the size limits into documents of this many lines, instead of
+    /// skipping them
+    ///
+    /// Shards are searched on their own, with lines counted from the start of the shard, and
+    /// put back together when a file is looked up by path. Their symbols and embeddings are
+    /// left out. Shards have at most 5000 lines.
+    pub shard_lines: Option<usize>,
+
+    #[clap(long, value_enum, default_value_t = LfsMode::default())]
+    #[serde(default)]
+    /// How to index Git LFS pointer files
+    pub lfs_mode: LfsMode,
+
+    #[clap(long, value_enum, default_value_t = ConflictMode::default())]
+    #[serde(default)]
+    /// How to index files with unresolved merge conflict markers
+    pub conflict_mode: ConflictMode,
+
+    #[clap(long, value_enum, default_value_t = SymlinkMode::default())]
+    #[serde(default)]
+    /// How to index symbolic links to files, in repositories walked on disk
+    pub symlink_mode: SymlinkMode,
+
+    #[clap(long, value_enum, default_value_t = MissingRepoMode::default())]
+    #[serde(default)]
+    /// What to do with the index of a repository whose directory is missing or empty
+    pub on_missing_repo: MissingRepoMode,
+
+    #[clap(long, value_delimiter = ',')]
+    #[serde(default)]
+    /// Directories that repositories have to be in to be indexed
+    ///
+    /// Repositories anywhere can be indexed when this is empty.
+    pub allowed_repo_roots: Vec<PathBuf>,
+
+    #[clap(long, value_delimiter = ',')]
+    #[serde(default)]
+    /// Repositories, by repo ref, that are only indexed for lexical search
+    ///
+    /// Their files are never embedded, even if the repository itself doesn't opt out.
+    pub lexical_only_repos: Vec<String>,
+
+    #[clap(long, default_value_t = false)]
+    #[serde(default)]
+    /// Store the last commit that changed each line of a file, following the history of `HEAD`
+    ///
+    /// This walks the history of every file that is (re-)indexed, so it slows indexing down.
+    pub compute_line_blame: bool,
+
+    #[clap(skip)]
+    #[serde(default)]
+    /// Languages of files by extension (`mylang`) or path glob (`**/*.tpl`), which win over
+    /// language detection, only set in the config file
+    pub language_overrides: HashMap<String, String>,
+
+    #[clap(skip)]
+    #[serde(default)]
+    /// Patterns of import statements by language, used to find the files that import a path,
+    /// only set in the config file
+    pub import_patterns: ImportPatterns,
+
+    #[clap(long, value_enum, default_value_t = CorpusMode::default())]
+    #[serde(default)]
+    /// Tune the file index for searching source code or prose. Changing this re-indexes
+    /// everything
+    pub corpus_mode: CorpusMode,
+
+    #[clap(long, default_value_t = default_min_ngram_len())]
+    #[serde(default = "default_min_ngram_len")]
+    /// Leave n-grams shorter than this many characters, between 1 and 3, out of the content of
+    /// source code. Higher values shrink the index, but content searches for shorter strings
+    /// match nothing. Changing this re-indexes everything
+    pub min_ngram_len: usize,
+
+    #[clap(long, default_value_t = false)]
+    #[serde(default)]
+    /// Also index the content of source code as stemmed words, so content searches written in
+    /// natural language match as well as identifiers do. This grows the index, and has no
+    /// effect in the prose corpus mode. Changing this re-indexes everything
+    pub index_content_prose: bool,
+
     //
     // External dependencies
     //
@@ -112,6 +282,12 @@ pub struct Configuration {
     /// URL for the qdrant server
     pub qdrant_url: Option<String>,
 
+    #[clap(long, value_enum, default_value_t = WriteConsistency::default())]
+    #[serde(default)]
+    /// How qdrant orders writes across replicas. Stronger ordering survives node failures, at
+    /// the cost of write latency
+    pub qdrant_write_consistency: WriteConsistency,
+
     #[clap(long, default_value_os_t = default_model_dir())]
     #[serde(default = "default_model_dir")]
     /// Path to the embedding model directory
@@ -126,6 +302,25 @@ pub struct Configuration {
     /// Chunking strategy
     pub overlap: Option<OverlapStrategy>,
 
+    #[clap(long, value_enum, default_value_t = ChunkStrategy::default())]
+    #[serde(default)]
+    /// How files are split into chunks for the semantic index
+    pub chunk_strategy: ChunkStrategy,
+
+    #[clap(long)]
+    /// Give chunks at least this similar to a chunk embedded earlier in the same run its
+    /// embedding, instead of embedding them
+    ///
+    /// Similarity is the estimated Jaccard similarity of the word shingles of chunks, between
+    /// 0 and 1. Boilerplate that only differs by a few names is usually above 0.6. If unset,
+    /// every new chunk is embedded.
+    pub near_duplicate_threshold: Option<f32>,
+
+    #[clap(skip)]
+    #[serde(default)]
+    /// Names of the qdrant payload fields, only set in the config file
+    pub payload_schema: PayloadSchema,
+
     //
     // Installation-specific values
     //
@@ -192,6 +387,12 @@ impl Configuration {
         self.index_dir.join(name)
     }
 
+    /// Whether the configuration lets the files of a repository be embedded.
+    pub fn semantic_enabled(&self, reporef: &RepoRef) -> bool {
+        let reporef = reporef.to_string();
+        !self.lexical_only_repos.iter().any(|r| *r == reporef)
+    }
+
     pub fn github_client_id_and_secret(&self) -> Option<(&str, &str)> {
         let id = self.github_client_id.as_ref()?.expose_secret();
         let secret = self.github_client_secret.as_ref()?.expose_secret();
@@ -204,8 +405,8 @@ impl Configuration {
             .config_file
             .as_ref()
             .context("no config file specified")
-            .and_then(Self::read) else
-        {
+            .and_then(Self::read)
+        else {
             return Ok(cli);
         };
 
@@ -243,12 +444,112 @@ impl Configuration {
                 default_repo_buffer_size()
             ),
 
+            commit_interval: b.commit_interval.or(a.commit_interval),
+
+            memory_budget_bytes: b.memory_budget_bytes.or(a.memory_budget_bytes),
+
+            writer_lock_timeout_ms: right_if_default!(
+                b.writer_lock_timeout_ms,
+                a.writer_lock_timeout_ms,
+                0
+            ),
+
             max_threads: right_if_default!(b.max_threads, a.max_threads, default_parallelism()),
 
+            max_result_limit: right_if_default!(
+                b.max_result_limit,
+                a.max_result_limit,
+                default_max_result_limit()
+            ),
+
             host: right_if_default!(b.host, a.host, default_host()),
 
             port: right_if_default!(b.port, a.port, default_port()),
 
+            normalize_line_endings: b.normalize_line_endings | a.normalize_line_endings,
+
+            generated_file_globs: right_if_default!(
+                b.generated_file_globs,
+                a.generated_file_globs,
+                default_generated_file_globs()
+            ),
+
+            generated_file_markers: right_if_default!(
+                b.generated_file_markers,
+                a.generated_file_markers,
+                default_generated_file_markers()
+            ),
+
+            vendored_globs: right_if_default!(
+                b.vendored_globs,
+                a.vendored_globs,
+                default_vendored_globs()
+            ),
+
+            slow_file_ms: right_if_default!(b.slow_file_ms, a.slow_file_ms, default_slow_file_ms()),
+
+            min_file_size_bytes: right_if_default!(b.min_file_size_bytes, a.min_file_size_bytes, 0),
+
+            max_file_size_bytes: right_if_default!(
+                b.max_file_size_bytes,
+                a.max_file_size_bytes,
+                default_max_file_size_bytes()
+            ),
+
+            shard_lines: b.shard_lines.or(a.shard_lines),
+
+            lfs_mode: right_if_default!(b.lfs_mode, a.lfs_mode, LfsMode::default()),
+
+            conflict_mode: right_if_default!(
+                b.conflict_mode,
+                a.conflict_mode,
+                ConflictMode::default()
+            ),
+
+            symlink_mode: right_if_default!(b.symlink_mode, a.symlink_mode, SymlinkMode::default()),
+
+            on_missing_repo: right_if_default!(
+                b.on_missing_repo,
+                a.on_missing_repo,
+                MissingRepoMode::default()
+            ),
+
+            allowed_repo_roots: right_if_default!(
+                b.allowed_repo_roots,
+                a.allowed_repo_roots,
+                vec![]
+            ),
+
+            lexical_only_repos: right_if_default!(
+                b.lexical_only_repos,
+                a.lexical_only_repos,
+                vec![]
+            ),
+
+            compute_line_blame: b.compute_line_blame | a.compute_line_blame,
+
+            language_overrides: right_if_default!(
+                b.language_overrides,
+                a.language_overrides,
+                HashMap::new()
+            ),
+
+            import_patterns: right_if_default!(
+                b.import_patterns,
+                a.import_patterns,
+                ImportPatterns::default()
+            ),
+
+            corpus_mode: right_if_default!(b.corpus_mode, a.corpus_mode, CorpusMode::default()),
+
+            min_ngram_len: right_if_default!(
+                b.min_ngram_len,
+                a.min_ngram_len,
+                default_min_ngram_len()
+            ),
+
+            index_content_prose: b.index_content_prose | a.index_content_prose,
+
             model_dir: right_if_default!(b.model_dir, a.model_dir, default_model_dir()),
 
             max_chunk_tokens: right_if_default!(
@@ -259,10 +560,30 @@ impl Configuration {
 
             overlap: b.overlap.or(a.overlap),
 
+            chunk_strategy: right_if_default!(
+                b.chunk_strategy,
+                a.chunk_strategy,
+                ChunkStrategy::default()
+            ),
+
+            near_duplicate_threshold: b.near_duplicate_threshold.or(a.near_duplicate_threshold),
+
+            payload_schema: right_if_default!(
+                b.payload_schema,
+                a.payload_schema,
+                PayloadSchema::default()
+            ),
+
             frontend_dist: b.frontend_dist.or(a.frontend_dist),
 
             qdrant_url: b.qdrant_url.or(a.qdrant_url),
 
+            qdrant_write_consistency: right_if_default!(
+                b.qdrant_write_consistency,
+                a.qdrant_write_consistency,
+                WriteConsistency::default()
+            ),
+
             answer_api_url: right_if_default!(
                 b.answer_api_url,
                 a.answer_api_url,
@@ -355,6 +676,51 @@ fn default_host() -> String {
     String::from("127.0.0.1")
 }
 
+fn default_generated_file_globs() -> Vec<String> {
+    [
+        "*.pb.go",
+        "*.pb.cc",
+        "*.pb.h",
+        "*_pb2.py",
+        "*_generated.*",
+        "*.min.js",
+        "*.bundle.js",
+    ]
+    .map(String::from)
+    .into()
+}
+
+fn default_generated_file_markers() -> Vec<String> {
+    ["Code generated", "@generated"].map(String::from).into()
+}
+
+fn default_vendored_globs() -> Vec<String> {
+    [
+        "**/vendor/**",
+        "**/third_party/**",
+        "**/node_modules/**",
+        "**/bower_components/**",
+    ]
+    .map(String::from)
+    .into()
+}
+
+fn default_slow_file_ms() -> u64 {
+    1000
+}
+
+fn default_max_file_size_bytes() -> u64 {
+    1024 * 1024
+}
+
+fn default_min_ngram_len() -> usize {
+    1
+}
+
+fn default_max_result_limit() -> usize {
+    DEFAULT_MAX_RESULT_LIMIT
+}
+
 fn default_answer_api_url() -> String {
     String::from("http://127.0.0.1:7879")
 }
@@ -37,8 +37,49 @@ async fn connect(data_dir: &str) -> Result<SqlitePool> {
     Ok(pool)
 }
 
+/// Open the database of `config` without ever writing to it.
+///
+/// This is meant for replicas sharing the database of a primary that
+/// indexes repositories. Migrations are left to the primary, and any
+/// write to the caches fails, so replicas can't take the lease for
+/// indexing a repository either.
+pub async fn init_read_only(config: &Configuration) -> Result<SqlitePool> {
+    connect_read_only(&config.index_dir.to_string_lossy()).await
+}
+
+async fn connect_read_only(data_dir: &str) -> Result<SqlitePool> {
+    let url = format!("sqlite://{data_dir}/bleep.db?mode=ro");
+    debug!("loading read-only db from {url}");
+    Ok(SqlitePool::connect(&url).await?)
+}
+
 fn reset(data_dir: &str) -> Result<()> {
     let db_path = Path::new(data_dir).join("bleep.db");
     let bk_path = db_path.with_extension("db.bk");
     std::fs::rename(db_path, bk_path).context("failed to backup old database")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cache::FileCache, repo::RepoRef};
+
+    #[tokio::test]
+    async fn read_only_db_refuses_cache_writes() {
+        let dir = tempdir::TempDir::new("read-only-db").unwrap();
+        let data_dir = dir.path().to_string_lossy();
+        let reporef: RepoRef = "github.com/bloopai/bloop".parse().unwrap();
+
+        let primary = Arc::new(connect(&data_dir).await.unwrap());
+        FileCache::for_repo(&primary, &reporef)
+            .save_backfill_cursor("a")
+            .await
+            .unwrap();
+
+        let replica = Arc::new(connect_read_only(&data_dir).await.unwrap());
+        let cache = FileCache::for_repo(&replica, &reporef);
+        assert_eq!(cache.backfill_cursor().await.unwrap().as_deref(), Some("a"));
+        assert!(cache.save_backfill_cursor("b").await.is_err());
+        assert!(cache.lease().await.is_err());
+    }
+}
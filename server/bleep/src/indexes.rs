@@ -1,33 +1,45 @@
-use std::{fs, ops::Deref, path::Path, sync::Arc};
+use std::{fs, ops::Deref, path::Path, sync::Arc, time::Duration};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
 use smallvec::SmallVec;
 use tantivy::{
     collector::{Collector, MultiFruit},
+    directory::error::LockError,
     schema::Schema,
-    tokenizer::NgramTokenizer,
-    DocAddress, Document, IndexReader, IndexWriter, Score,
+    DocAddress, Document, IndexReader, IndexWriter, ReloadPolicy, Score, TantivyError,
 };
 use tokio::sync::RwLock;
 
+mod analyzer;
+pub mod blame;
 pub mod file;
+mod imports;
+mod langs;
+pub mod query;
 pub mod reader;
 pub mod repo;
 mod schema;
 
-pub use file::File;
+pub use analyzer::CorpusMode;
+pub use file::{File, MissingRepoMode};
+pub use imports::{ImportPatterns, ImportTarget};
+pub use langs::LangDictionary;
 pub use repo::Repo;
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::{
     background::{SyncHandle, SyncPipes},
     cache::FileCache,
+    collector::CappedDocSetCollector,
     db::SqlDb,
     query::parser::Query,
-    repo::{RepoError, RepoMetadata, RepoRef, Repository},
+    repo::{
+        iterator::{SkippedEntries, WalkBreakdown},
+        RepoError, RepoMetadata, RepoRef, Repository,
+    },
     semantic::Semantic,
-    state::RepositoryPool,
+    state::{IndexVersion, RepositoryPool},
     Configuration,
 };
 
@@ -63,21 +75,40 @@ impl<'a> GlobalWriteHandle<'a> {
         Ok(())
     }
 
+    /// Index `repo` into every index.
+    ///
+    /// Returns the metadata of the repository, the number of indexable files, and the number of
+    /// files that failed to embed, or `None` if the repository is missing and its documents were
+    /// kept.
     pub(crate) async fn index(
         &self,
         sync_handle: &SyncHandle,
         repo: &Repository,
-    ) -> Result<Arc<RepoMetadata>, RepoError> {
+    ) -> Result<Option<(Arc<RepoMetadata>, u64, u64)>, RepoError> {
         let metadata = repo.get_repo_metadata().await;
 
-        futures::future::join_all(self.handles.iter().map(|handle| {
-            handle.index(&sync_handle.reporef, repo, &metadata, sync_handle.pipes())
+        let outcomes = futures::future::join_all(self.handles.iter().map(|handle| {
+            handle.index(
+                &sync_handle.reporef,
+                repo,
+                &metadata,
+                sync_handle.pipes(),
+                false,
+                None,
+            )
         }))
         .await
         .into_iter()
         .collect::<Result<Vec<_>, _>>()?;
 
-        Ok(metadata)
+        debug!(?outcomes, "repository indexed");
+        if outcomes.iter().any(|o| o.kept_missing) {
+            return Ok(None);
+        }
+
+        let indexable_files = outcomes.iter().map(|o| o.indexable_files).sum();
+        let semantic_failures = outcomes.iter().map(|o| o.semantic_failures).sum();
+        Ok(Some((metadata, indexable_files, semantic_failures)))
     }
 }
 
@@ -94,24 +125,47 @@ impl Indexes {
         sql: SqlDb,
         semantic: Option<Semantic>,
     ) -> Result<Self> {
-        if config.source.index_version_mismatch() {
+        let version = IndexVersion {
+            lexical: config
+                .corpus_mode
+                .index_version(config.min_ngram_len, config.index_content_prose),
+            semantic: config.payload_schema.index_version(),
+        };
+        let mismatch = config.source.index_version_mismatch(&version);
+        if mismatch.lexical || mismatch.semantic {
             // we don't support old schemas, and tantivy will hard
             // error if we try to open a db with a different schema.
             std::fs::remove_dir_all(config.index_path("repo"))?;
             std::fs::remove_dir_all(config.index_path("content"))?;
 
             let mut refs = vec![];
-            // knocking out our current file caches will force re-indexing qdrant
             repo_pool.for_each(|reporef, repo| {
                 refs.push(reporef.to_owned());
                 repo.last_index_unix_secs = 0;
             });
 
             for reporef in refs {
-                FileCache::for_repo(&sql, &reporef).delete().await?;
+                let file_cache = FileCache::for_repo(&sql, &reporef);
+                if mismatch.semantic {
+                    // knocking out our current chunk caches will force re-indexing qdrant
+                    file_cache.delete().await?;
+                } else {
+                    // chunks are keyed by the semantic version only, so they're reused
+                    file_cache.delete_files_only().await?;
+                }
             }
         }
-        config.source.save_index_version()?;
+        config.source.save_index_version(&version)?;
+
+        let file = Indexer::create(
+            File::new(config.clone(), sql, semantic),
+            config.index_path("content").as_ref(),
+            config.buffer_size,
+            config.max_threads,
+        )?
+        .with_max_result_limit(config.max_result_limit);
+        file.source.langs.load().await?;
+        file.reconcile_file_caches().await?;
 
         Ok(Self {
             repo: Indexer::create(
@@ -119,46 +173,249 @@ impl Indexes {
                 config.index_path("repo").as_ref(),
                 config.repo_buffer_size,
                 config.max_threads,
-            )?,
-            file: Indexer::create(
-                File::new(sql, semantic),
-                config.index_path("content").as_ref(),
-                config.buffer_size,
-                config.max_threads,
-            )?,
+            )?
+            .with_max_result_limit(config.max_result_limit),
+            file,
             write_mutex: Default::default(),
         })
     }
 
+    /// Acquire the writers of all indexes.
+    ///
+    /// Only one set of writers exists at any time, so every index,
+    /// delete, and commit cycle runs to completion before the next one
+    /// starts. Tantivy would refuse to open a second writer on the same
+    /// index anyway, so this only turns that error into a wait.
     pub async fn writers(&self) -> Result<GlobalWriteHandle<'_>> {
         let id: u64 = rand::random();
         debug!(id, "waiting for other writers to finish");
         let _write_lock = self.write_mutex.lock().await;
         debug!(id, "lock acquired");
 
+        let timeout = self.writer_lock_timeout();
         Ok(GlobalWriteHandle {
-            handles: vec![self.repo.write_handle()?, self.file.write_handle()?],
+            handles: vec![
+                self.repo.write_handle_within(timeout).await?,
+                self.file.write_handle_within(timeout).await?,
+            ],
             _write_lock,
         })
     }
+
+    /// How long to wait for another process to let go of the writer of an index.
+    fn writer_lock_timeout(&self) -> Duration {
+        Duration::from_millis(self.file.source.config.writer_lock_timeout_ms)
+    }
+
+    /// Delete every repository of the file index that matches `pred`, waiting for other writers
+    /// to finish first.
+    ///
+    /// See [`Indexer::delete_repos_where`] for what is deleted.
+    pub async fn delete_repos_where(
+        &self,
+        pred: impl Fn(&RepoRef) -> bool,
+    ) -> Result<Vec<RepoRef>> {
+        let _write_lock = self.write_mutex.lock().await;
+        self.file.delete_repos_where(pred).await
+    }
+
+    /// Snapshot the file index and the database to `dest`, waiting for other writers to finish
+    /// first.
+    ///
+    /// See [`Indexer::snapshot`] for what the bundle holds.
+    pub async fn snapshot(&self, dest: &Path, qdrant: bool) -> Result<file::SnapshotManifest> {
+        let _write_lock = self.write_mutex.lock().await;
+        self.file.snapshot(dest, qdrant).await
+    }
+
+    /// Extract the symbols of every file of a repository again, from the content stored in the
+    /// file index.
+    ///
+    /// This is much cheaper than re-indexing the repository, but only picks up changes to symbol
+    /// extraction. Returns the number of files updated.
+    pub async fn reindex_symbols(&self, reporef: &RepoRef, repo: &Repository) -> Result<usize> {
+        let _write_lock = self.write_mutex.lock().await;
+        let metadata = repo.get_repo_metadata().await;
+
+        let mut handle = self
+            .file
+            .write_handle_within(self.writer_lock_timeout())
+            .await?;
+        let searcher = self.file.reader.read().await.searcher();
+        let rewritten = self.file.source.reindex_symbols(
+            &searcher,
+            &handle.writer.read().unwrap(),
+            repo,
+            reporef,
+            &metadata,
+        )?;
+        handle.commit().await?;
+
+        Ok(rewritten)
+    }
+
+    /// Make the files of `reporef` that are on `from_branch` searchable on `into_branch` as
+    /// well, after a merge, without reading or embedding them again.
+    ///
+    /// Both the file documents and the embedded chunks are updated, see
+    /// [`Semantic::index_branch_merge`]. Returns the number of documents rewritten.
+    pub async fn merge_branch(
+        &self,
+        reporef: &RepoRef,
+        from_branch: &str,
+        into_branch: &str,
+    ) -> Result<usize> {
+        let _write_lock = self.write_mutex.lock().await;
+
+        let mut handle = self
+            .file
+            .write_handle_within(self.writer_lock_timeout())
+            .await?;
+        let searcher = self.file.reader.read().await.searcher();
+        let rewritten = self.file.source.merge_branch(
+            &searcher,
+            &handle.writer.read().unwrap(),
+            reporef,
+            from_branch,
+            into_branch,
+        )?;
+        handle.commit().await?;
+
+        if let Some(semantic) = &self.file.source.semantic {
+            semantic
+                .index_branch_merge(&self.file.source.sql, reporef, from_branch, into_branch)
+                .await?;
+        }
+
+        Ok(rewritten)
+    }
+}
+
+/// An `IndexWriter` that can be committed in the middle of an indexing run.
+///
+/// Documents are added while holding the lock for reading, so a commit,
+/// which holds it for writing, waits for the documents in flight.
+pub type SharedWriter = std::sync::RwLock<IndexWriter>;
+
+/// A summary of a single `index_repository` run.
+#[derive(Debug, Default)]
+pub struct IndexOutcome {
+    /// Entries dropped by the walker before they could be indexed.
+    pub skipped: SkippedEntries,
+    /// What the run would have indexed, set for dry runs only.
+    pub breakdown: Option<WalkBreakdown>,
+    /// The files that took the longest to index, slowest first.
+    pub slowest: Vec<SlowFile>,
+    /// Files of the repository in the index after the run, whether this run wrote them or they
+    /// were already up to date.
+    pub indexable_files: u64,
+    /// The files this run added to the index, replaced, or removed from it.
+    pub changes: ChangeSet,
+    /// Files this run failed to embed into the semantic index.
+    pub semantic_failures: u64,
+    /// The most bytes of file content the run held at once, in flight or written since the
+    /// last commit, for runs with a memory budget.
+    pub peak_memory_bytes: u64,
+    /// Whether the repository was missing or empty, and its documents were kept as they were
+    /// rather than indexed.
+    pub kept_missing: bool,
+}
+
+/// Files changed by an indexing run, by relative path.
+///
+/// A file is modified when its document was rewritten, which also happens when only its branches
+/// or attributes changed. Each list is sorted, and directories aren't included.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ChangeSet {
+    pub added: Vec<String>,
+    pub modified: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+/// Time spent indexing a single file, by phase.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FileTimings {
+    /// Hashing, the cache lookup, and language detection
+    pub read: Duration,
+    pub symbols: Duration,
+    /// Embedding, and writing the document
+    pub write: Duration,
+}
+
+impl FileTimings {
+    pub fn total(&self) -> Duration {
+        self.read + self.symbols + self.write
+    }
+}
+
+/// A file reported in [`IndexOutcome::slowest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowFile {
+    pub relative_path: String,
+    pub timings: FileTimings,
 }
 
 #[async_trait]
 pub trait Indexable: Send + Sync {
     /// This is where files are scanned and indexed.
+    ///
+    /// With `dry_run`, files are scanned and classified, but nothing is
+    /// written to the index, the file cache, or the semantic index.
+    ///
+    /// With a `target_ref`, files are read from the tree of that git
+    /// reference instead of the branches selected by the repository's
+    /// branch filter, and the working tree is left alone.
+    #[allow(clippy::too_many_arguments)]
     async fn index_repository(
         &self,
         reporef: &RepoRef,
         repo: &Repository,
         metadata: &RepoMetadata,
-        writer: &IndexWriter,
+        writer: &SharedWriter,
         pipes: &SyncPipes,
-    ) -> Result<()>;
+        dry_run: bool,
+        target_ref: Option<&str>,
+    ) -> Result<IndexOutcome>;
 
+    /// Delete all documents of `repo`.
+    ///
+    /// Operations on a writer are ordered, so this removes every document
+    /// of the repo that was added to `writer` before the call, regardless
+    /// of what else the writer is doing concurrently. Documents added
+    /// after it are kept, which is what allows re-indexing a repo within
+    /// a single commit. The deletion becomes visible on the next commit.
     fn delete_by_repo(&self, writer: &IndexWriter, repo: &Repository);
 
     /// Return the tantivy `Schema` of the current index
     fn schema(&self) -> Schema;
+
+    /// Pick up state kept outside of the index that documents refer to, which another process
+    /// may have written to.
+    ///
+    /// This runs after the reader of the index is reloaded.
+    async fn reload(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// The repository being indexed by whoever holds the writer of this index, if it can be
+    /// told.
+    ///
+    /// This is only asked for once a writer couldn't be opened, to report in [`IndexBusy`].
+    async fn writer_holder(&self) -> Option<RepoRef> {
+        None
+    }
+
+    /// Persist the state kept outside of the index that matches the documents just committed.
+    ///
+    /// This runs after every successful commit of a writer, so that state never claims
+    /// documents the index doesn't have.
+    async fn after_commit(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Forget the state waiting for [`Indexable::after_commit`], as its documents were rolled
+    /// back or dropped along with the writer.
+    fn discard_uncommitted(&self) {}
 }
 
 #[async_trait]
@@ -187,7 +444,7 @@ pub struct IndexWriteHandle<'a> {
     source: &'a dyn Indexable,
     index: &'a tantivy::Index,
     reader: &'a RwLock<IndexReader>,
-    writer: IndexWriter,
+    writer: SharedWriter,
 }
 
 impl<'a> IndexWriteHandle<'a> {
@@ -197,7 +454,8 @@ impl<'a> IndexWriteHandle<'a> {
     }
 
     pub fn delete(&self, repo: &Repository) {
-        self.source.delete_by_repo(&self.writer, repo)
+        self.source
+            .delete_by_repo(&self.writer.read().unwrap(), repo)
     }
 
     pub async fn index(
@@ -206,25 +464,65 @@ impl<'a> IndexWriteHandle<'a> {
         repo: &Repository,
         metadata: &RepoMetadata,
         progress: &SyncPipes,
-    ) -> Result<()> {
+        dry_run: bool,
+        target_ref: Option<&str>,
+    ) -> Result<IndexOutcome> {
         self.source
-            .index_repository(reporef, repo, metadata, &self.writer, progress)
+            .index_repository(
+                reporef,
+                repo,
+                metadata,
+                &self.writer,
+                progress,
+                dry_run,
+                target_ref,
+            )
             .await
     }
 
     pub async fn commit(&mut self) -> Result<()> {
-        self.writer.commit()?;
+        self.writer.get_mut().unwrap().commit()?;
+        self.source.after_commit().await?;
         self.refresh_reader().await?;
 
         Ok(())
     }
 
     pub fn rollback(&mut self) -> Result<()> {
-        self.writer.rollback()?;
+        self.writer.get_mut().unwrap().rollback()?;
+        self.source.discard_uncommitted();
         Ok(())
     }
 }
 
+impl Drop for IndexWriteHandle<'_> {
+    fn drop(&mut self) {
+        // whatever wasn't committed by now never will be
+        self.source.discard_uncommitted();
+    }
+}
+
+/// Returned when trying to write to an index that was opened with
+/// [`Indexer::open_read_only`].
+#[derive(thiserror::Error, Debug)]
+#[error("index is read-only")]
+pub struct ReadOnly;
+
+/// Returned when the writer of an index is held by someone else, which is usually another
+/// process indexing into the same directory.
+#[derive(thiserror::Error, Debug, Default)]
+#[error("index is busy{}", .holder.as_ref().map(|r| format!(", indexing {r}")).unwrap_or_default())]
+pub struct IndexBusy {
+    /// The repository that the holder of the writer is indexing, if it can be told
+    pub holder: Option<RepoRef>,
+}
+
+/// Maximum number of results of a search, for indexers that aren't given one.
+pub const DEFAULT_MAX_RESULT_LIMIT: usize = 1000;
+
+/// How often to try opening a writer again while waiting for it.
+const WRITER_LOCK_RETRY: Duration = Duration::from_millis(50);
+
 /// A wrapper around `tantivy::IndexReader`.
 ///
 /// This contains the schema, and also additional fields used to enable re-indexing.
@@ -234,20 +532,55 @@ pub struct Indexer<T> {
     pub reader: RwLock<IndexReader>,
     pub reindex_buffer_size: usize,
     pub reindex_threads: usize,
+    pub read_only: bool,
+    pub max_result_limit: usize,
 }
 
 impl<T: Indexable> Indexer<T> {
     fn write_handle(&self) -> Result<IndexWriteHandle<'_>> {
+        if self.read_only {
+            return Err(ReadOnly.into());
+        }
+
+        let writer = match self
+            .index
+            .writer_with_num_threads(self.reindex_threads, self.reindex_buffer_size)
+        {
+            Err(TantivyError::LockFailure(LockError::LockBusy, _)) => {
+                return Err(IndexBusy::default().into())
+            }
+            writer => writer?,
+        };
+
         Ok(IndexWriteHandle {
             source: &self.source,
             index: &self.index,
             reader: &self.reader,
-            writer: self
-                .index
-                .writer_with_num_threads(self.reindex_threads, self.reindex_buffer_size)?,
+            writer: writer.into(),
         })
     }
 
+    /// Like [`Indexer::write_handle`], but waiting up to `timeout` for the writer to be let go
+    /// of while it's busy.
+    ///
+    /// Fails with an [`IndexBusy`] naming the repository of the holder once the time is up,
+    /// which is right away for a zero `timeout`.
+    async fn write_handle_within(&self, timeout: Duration) -> Result<IndexWriteHandle<'_>> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            match self.write_handle() {
+                Err(err) if err.is::<IndexBusy>() => {}
+                handle => return handle,
+            }
+
+            if tokio::time::Instant::now() >= deadline {
+                let holder = self.source.writer_holder().await;
+                return Err(IndexBusy { holder }.into());
+            }
+            tokio::time::sleep(WRITER_LOCK_RETRY).await;
+        }
+    }
+
     fn init_index(schema: Schema, path: &Path, threads: usize) -> Result<tantivy::Index> {
         fs::create_dir_all(path).context("failed to create index dir")?;
 
@@ -256,9 +589,7 @@ impl<T: Indexable> Indexer<T> {
 
         index.set_default_multithread_executor()?;
         index.set_multithread_executor(threads)?;
-        index
-            .tokenizers()
-            .register("default", NgramTokenizer::new(1, 3, false));
+        register_tokenizers(&index);
 
         Ok(index)
     }
@@ -273,17 +604,140 @@ impl<T: Indexable> Indexer<T> {
             source,
             reindex_threads: threads,
             reindex_buffer_size: buffer_size,
+            read_only: false,
+            max_result_limit: DEFAULT_MAX_RESULT_LIMIT,
         };
 
         Ok(instance)
     }
 
+    /// Open an existing index at the specified path without ever allocating a writer.
+    ///
+    /// This is meant for replicas serving an index that a primary writes to over
+    /// shared storage. The storage must provide atomic renames, as tantivy
+    /// publishes commits by atomically replacing `meta.json`, and only a single
+    /// process may ever hold a writer for the index.
+    ///
+    /// Commits of the primary become visible after calling [`Indexer::reload`].
+    /// Any attempt to write through this instance fails with [`ReadOnly`].
+    pub fn open_read_only(source: T, path: &Path, threads: usize) -> Result<Self> {
+        let mut index = tantivy::Index::open(tantivy::directory::MmapDirectory::open(path)?)?;
+        if index.schema() != source.schema() {
+            bail!("index at {path:?} has a different schema");
+        }
+
+        index.set_multithread_executor(threads)?;
+        register_tokenizers(&index);
+
+        // file change notifications are unreliable on network filesystems,
+        // so replicas poll `reload` instead
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()?
+            .into();
+
+        Ok(Self {
+            reader,
+            index,
+            source,
+            reindex_threads: threads,
+            reindex_buffer_size: 0,
+            read_only: true,
+            max_result_limit: DEFAULT_MAX_RESULT_LIMIT,
+        })
+    }
+
+    /// Cap the number of results of every search, see [`Indexer::clamp_limit`].
+    pub fn with_max_result_limit(mut self, max_result_limit: usize) -> Self {
+        self.max_result_limit = max_result_limit;
+        self
+    }
+
+    /// Clamp a caller-supplied number of results to the maximum of this index.
+    ///
+    /// Every search entry point that takes a limit from its caller goes through this, so no
+    /// request can make a collector hold more than [`Indexer::max_result_limit`] documents.
+    pub fn clamp_limit(&self, limit: usize) -> usize {
+        if limit > self.max_result_limit {
+            warn!(
+                limit,
+                max = self.max_result_limit,
+                "clamping the number of requested results"
+            );
+        }
+
+        limit.min(self.max_result_limit)
+    }
+
+    /// Collect the documents matching `query` that a lookup returns, in address order.
+    ///
+    /// Lookups that don't rank their matches collect them through this rather than tantivy's
+    /// `DocSetCollector`, so they are bounded by [`Indexer::max_result_limit`] like searches are.
+    pub fn collect_capped(
+        &self,
+        searcher: &tantivy::Searcher,
+        query: &dyn tantivy::query::Query,
+    ) -> tantivy::Result<Vec<DocAddress>> {
+        let addrs = searcher.search(query, &CappedDocSetCollector(self.max_result_limit))?;
+        if addrs.len() == self.max_result_limit {
+            warn!(
+                max = self.max_result_limit,
+                "lookup matched too many documents, results were truncated"
+            );
+        }
+
+        Ok(addrs)
+    }
+
+    /// Pick up the latest commit to the index.
+    pub async fn reload(&self) -> Result<()> {
+        self.reader.read().await.reload()?;
+        self.source.reload().await
+    }
+
+    /// An opaque token for the state of the index that searches currently see.
+    ///
+    /// The token changes whenever the reader moves to a commit that added, merged or deleted
+    /// documents, so clients can key cached results on it. It is derived from the segments
+    /// being searched, and stays the same across reloads that found nothing new.
+    pub async fn searcher_generation(&self) -> u64 {
+        use std::hash::{Hash, Hasher};
+
+        let searcher = self.reader.read().await.searcher();
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        for segment in searcher.segment_readers() {
+            segment.segment_id().hash(&mut hasher);
+            segment.delete_opstamp().hash(&mut hasher);
+        }
+
+        hasher.finish()
+    }
+
     pub async fn query<'a, R, I, C>(
         &'a self,
         queries: I,
         doc_reader: &'a R,
         collector: C,
     ) -> Result<SearchResults<'_, R::Document>>
+    where
+        I: Iterator<Item = &'a Query<'a>> + Send,
+        C: Collector<Fruit = (Vec<(Score, DocAddress)>, MultiFruit)>,
+        R: DocumentRead<Schema = T>,
+    {
+        self.query_within(queries, doc_reader, None, collector)
+            .await
+    }
+
+    /// Like [`Indexer::query`], but only matching the documents that `scope` matches too, if
+    /// one is given.
+    pub async fn query_within<'a, R, I, C>(
+        &'a self,
+        queries: I,
+        doc_reader: &'a R,
+        scope: Option<Box<dyn tantivy::query::Query>>,
+        collector: C,
+    ) -> Result<SearchResults<'_, R::Document>>
     where
         I: Iterator<Item = &'a Query<'a>> + Send,
         C: Collector<Fruit = (Vec<(Score, DocAddress)>, MultiFruit)>,
@@ -293,8 +747,14 @@ impl<T: Indexable> Indexer<T> {
         let queries = queries
             .filter(|q| doc_reader.query_matches(q))
             .collect::<SmallVec<[_; 2]>>();
-        let compiled_query =
+        let mut compiled_query =
             doc_reader.compile(&self.source, queries.iter().copied(), &self.index)?;
+        if let Some(scope) = scope {
+            compiled_query = Box::new(tantivy::query::BooleanQuery::intersection(vec![
+                compiled_query,
+                scope,
+            ]));
+        }
 
         let (top_k, metadata) = searcher
             .search(&compiled_query, &collector)
@@ -312,7 +772,446 @@ impl<T: Indexable> Indexer<T> {
     }
 }
 
+/// Register the tokenizers referenced by our schemas.
+///
+/// Text fields are broken up into n-grams of at most 3 characters, rather
+/// than the words of tantivy's usual default tokenizer, which drops words
+/// longer than 40 bytes. No token length limit is needed as a result, and
+/// identifiers or strings of any length remain searchable in full.
+///
+/// Each n-gram is positioned at its byte offset, which phrase queries rely on.
+/// The content of source code may leave out the shortest n-grams, depending on
+/// the configuration; other fields always keep them.
+fn register_tokenizers(index: &tantivy::Index) {
+    let tokenizers = index.tokenizers();
+    tokenizers.register("default", analyzer::ngrams(1));
+    tokenizers.register("ngrams_min2", analyzer::ngrams(2));
+    tokenizers.register("ngrams_min3", analyzer::ngrams(3));
+    tokenizers.register("prose", analyzer::prose());
+}
+
 pub struct SearchResults<'a, T> {
     pub docs: Box<dyn Iterator<Item = T> + Sync + Send + 'a>,
     pub metadata: MultiFruit,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn long_identifiers_are_searchable_in_full() {
+        use crate::query::{
+            compiler::Compiler,
+            parser::{Literal, Target},
+        };
+        use tantivy::{
+            collector::Count,
+            schema::{TextFieldIndexing, TextOptions},
+        };
+
+        let mut builder = Schema::builder();
+        let content = builder.add_text_field(
+            "content",
+            TextOptions::default().set_indexing_options(
+                TextFieldIndexing::default()
+                    .set_tokenizer("default")
+                    .set_index_option(tantivy::schema::IndexRecordOption::WithFreqsAndPositions),
+            ),
+        );
+        let index = tantivy::Index::create_in_ram(builder.build());
+        register_tokenizers(&index);
+
+        let identifier = "a_really_long_identifier_that_goes_well_past_forty_bytes_xyz";
+        assert_eq!(identifier.len(), 60);
+
+        let mut writer = index.writer(15_000_000).unwrap();
+        writer
+            .add_document(tantivy::doc!(content => format!("let {identifier} = 1;")))
+            .unwrap();
+        writer
+            .add_document(tantivy::doc!(content => &identifier[..40]))
+            .unwrap();
+        writer.commit().unwrap();
+
+        let query = Query {
+            target: Some(Target::Content(Literal::Plain(identifier.into()))),
+            ..Default::default()
+        };
+        let compiled = Compiler::new()
+            .literal(content, |q| {
+                q.target.as_ref().and_then(Target::content).cloned()
+            })
+            .compile([query].iter(), &index)
+            .unwrap();
+
+        let searcher = index.reader().unwrap().searcher();
+        assert_eq!(searcher.search(&compiled, &Count).unwrap(), 1);
+    }
+
+    #[test]
+    fn adjacent_words_outrank_scattered_ones() {
+        use crate::query::{
+            compiler::Compiler,
+            parser::{Literal, Target},
+        };
+        use tantivy::{
+            collector::TopDocs,
+            schema::{TextFieldIndexing, TextOptions},
+        };
+
+        let mut builder = Schema::builder();
+        let content = builder.add_text_field(
+            "content",
+            TextOptions::default().set_indexing_options(
+                TextFieldIndexing::default()
+                    .set_tokenizer("default")
+                    .set_index_option(tantivy::schema::IndexRecordOption::WithFreqsAndPositions),
+            ),
+        );
+        let index = tantivy::Index::create_in_ram(builder.build());
+        register_tokenizers(&index);
+
+        // every n-gram of the query is in both files, but only the first has the words together
+        let scattered = "let u = get(); set user pref = user.profile;\n".repeat(3);
+        let adjacent = "// get user profile\nfn load(id: i64) -> data { todo!() }";
+
+        let mut writer = index.writer(15_000_000).unwrap();
+        writer
+            .add_document(tantivy::doc!(content => scattered))
+            .unwrap();
+        writer
+            .add_document(tantivy::doc!(content => adjacent))
+            .unwrap();
+        writer.commit().unwrap();
+
+        let query = Query {
+            target: Some(Target::Content(Literal::Plain("get user profile".into()))),
+            ..Default::default()
+        };
+        let ranking = |compiler: Compiler| {
+            let compiled = compiler
+                .literal(content, |q| {
+                    q.target.as_ref().and_then(Target::content).cloned()
+                })
+                .compile([query.clone()].iter(), &index)
+                .unwrap();
+
+            index
+                .reader()
+                .unwrap()
+                .searcher()
+                .search(&compiled, &TopDocs::with_limit(10))
+                .unwrap()
+                .into_iter()
+                .map(|(_, addr)| addr.doc_id)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(ranking(Compiler::new()), [0, 1]);
+        assert_eq!(ranking(Compiler::new().phrase(&[content], 0)), [1, 0]);
+    }
+
+    #[test]
+    fn short_ngrams_are_left_out_of_content() {
+        use crate::query::{
+            compiler::Compiler,
+            parser::{Literal, Target},
+        };
+        use tantivy::{
+            collector::Count,
+            query::TermQuery,
+            schema::{IndexRecordOption, TextFieldIndexing, TextOptions},
+            Term,
+        };
+
+        let mut builder = Schema::builder();
+        let content = builder.add_text_field(
+            "content",
+            TextOptions::default().set_indexing_options(
+                TextFieldIndexing::default()
+                    .set_tokenizer(CorpusMode::Code.content_tokenizer(2))
+                    .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+            ),
+        );
+        let index = tantivy::Index::create_in_ram(builder.build());
+        register_tokenizers(&index);
+
+        let mut writer = index.writer(15_000_000).unwrap();
+        writer
+            .add_document(tantivy::doc!(content => "x = yz;"))
+            .unwrap();
+        writer.commit().unwrap();
+
+        let searcher = index.reader().unwrap().searcher();
+        let count = |query: &dyn tantivy::query::Query| searcher.search(query, &Count).unwrap();
+        let term = |text| {
+            TermQuery::new(
+                Term::from_field_text(content, text),
+                IndexRecordOption::Basic,
+            )
+        };
+        assert_eq!(count(&term("x")), 0);
+        assert_eq!(count(&term("yz")), 1);
+
+        // regexes still find the file, as long as a fragment is long enough
+        let query = Query {
+            target: Some(Target::Content(Literal::Regex("x.*yz".into()))),
+            ..Default::default()
+        };
+        let compiled = Compiler::new()
+            .min_ngram_len(&[content], 2)
+            .literal(content, |q| {
+                q.target.as_ref().and_then(Target::content).cloned()
+            })
+            .compile([query].iter(), &index)
+            .unwrap();
+        assert_eq!(count(compiled.as_ref()), 1);
+    }
+
+    #[tokio::test]
+    async fn repo_delete_during_concurrent_indexing() {
+        use crate::repo::{RepoRemote, SyncStatus};
+
+        fn repository(reporef: &RepoRef) -> Repository {
+            Repository {
+                disk_path: reporef.local_path().unwrap(),
+                remote: RepoRemote::from(reporef),
+                sync_status: SyncStatus::Queued,
+                last_commit_unix_secs: 0,
+                last_index_unix_secs: 0,
+                most_common_lang: None,
+                branch_filter: None,
+                semantic: true,
+                semantic_status: Default::default(),
+                path_root: None,
+            }
+        }
+
+        const DOCS: usize = 2000;
+        let dir = TempDir::new("concurrent-delete").unwrap();
+        let indexer = Indexer::create(Repo::new(), dir.path(), 15_000_000, 2).unwrap();
+        let deleted: RepoRef = "local//tmp/deleted".parse().unwrap();
+        let indexed: RepoRef = "local//tmp/indexed".parse().unwrap();
+
+        let mut handle = indexer.write_handle().unwrap();
+        let add = |reporef: &RepoRef| {
+            handle
+                .writer
+                .read()
+                .unwrap()
+                .add_document(tantivy::doc!(
+                    indexer.source.disk_path => reporef.local_path().unwrap().to_string_lossy().as_ref(),
+                    indexer.source.repo_ref => reporef.to_string(),
+                ))
+                .unwrap();
+        };
+
+        std::thread::scope(|s| {
+            s.spawn(|| (0..DOCS).for_each(|_| add(&indexed)));
+            s.spawn(|| {
+                for _ in 0..DOCS / 100 {
+                    (0..100).for_each(|_| add(&deleted));
+                    handle.delete(&repository(&deleted));
+                }
+            });
+        });
+
+        handle.commit().await.unwrap();
+
+        let searcher = indexer.reader.read().await.searcher();
+        let count = |reporef: &RepoRef| {
+            let term = Term::from_field_text(
+                indexer.source.disk_path,
+                &reporef.local_path().unwrap().to_string_lossy(),
+            );
+            let query =
+                tantivy::query::TermQuery::new(term, tantivy::schema::IndexRecordOption::Basic);
+            searcher.search(&query, &tantivy::collector::Count).unwrap()
+        };
+
+        assert_eq!(count(&deleted), 0);
+        assert_eq!(count(&indexed), DOCS);
+    }
+
+    #[test]
+    fn limits_are_clamped_by_default() {
+        let dir = TempDir::new("default-limit").unwrap();
+        let indexer = Indexer::create(Repo::new(), dir.path(), 15_000_000, 1).unwrap();
+        assert_eq!(indexer.clamp_limit(usize::MAX), DEFAULT_MAX_RESULT_LIMIT);
+        assert_eq!(indexer.clamp_limit(10), 10);
+    }
+
+    #[tokio::test]
+    async fn read_only_replica_sees_primary_commits() {
+        let dir = TempDir::new("read-only-index").unwrap();
+        let primary = Indexer::create(Repo::new(), dir.path(), 15_000_000, 1).unwrap();
+        let replica = Indexer::open_read_only(Repo::new(), dir.path(), 1).unwrap();
+
+        let err = replica.write_handle().err().unwrap();
+        assert!(err.downcast_ref::<ReadOnly>().is_some());
+
+        let mut handle = primary.write_handle().unwrap();
+        handle
+            .writer
+            .read()
+            .unwrap()
+            .add_document(tantivy::doc!(primary.source.name => "repo"))
+            .unwrap();
+        handle.commit().await.unwrap();
+
+        let num_docs = || async { replica.reader.read().await.searcher().num_docs() };
+        assert_eq!(num_docs().await, 0);
+
+        replica.reload().await.unwrap();
+        assert_eq!(num_docs().await, 1);
+    }
+
+    #[tokio::test]
+    async fn searcher_generation_changes_on_reload() {
+        let dir = TempDir::new("searcher-generation").unwrap();
+        let primary = Indexer::create(Repo::new(), dir.path(), 15_000_000, 1).unwrap();
+        let replica = Indexer::open_read_only(Repo::new(), dir.path(), 1).unwrap();
+
+        let empty = replica.searcher_generation().await;
+        replica.reload().await.unwrap();
+        assert_eq!(replica.searcher_generation().await, empty);
+
+        let mut handle = primary.write_handle().unwrap();
+        handle
+            .writer
+            .read()
+            .unwrap()
+            .add_document(tantivy::doc!(primary.source.name => "repo"))
+            .unwrap();
+        handle.commit().await.unwrap();
+
+        // not visible until the replica reloads
+        assert_eq!(replica.searcher_generation().await, empty);
+        replica.reload().await.unwrap();
+
+        let indexed = replica.searcher_generation().await;
+        assert_ne!(indexed, empty);
+        assert_eq!(primary.searcher_generation().await, indexed);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn lexical_version_bump_keeps_chunks() {
+        use crate::{
+            repo::{RepoRemote, SyncStatus},
+            state::VersionMismatch,
+        };
+        use clap::Parser;
+        use sqlx::sqlite::SqlitePoolOptions;
+
+        let dir = TempDir::new("index-versions").unwrap();
+        let mut config =
+            Configuration::parse_from(["bleep", "--index-dir", dir.path().to_str().unwrap()]);
+        config.source.set_default_dir(dir.path());
+        let config = Arc::new(config);
+
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        let sql = Arc::new(pool);
+
+        let reporef = RepoRef::from(&dir.path().join("repo"));
+        let repo_pool = RepositoryPool::default();
+        let repository = || Repository {
+            disk_path: dir.path().join("repo"),
+            remote: RepoRemote::from(&reporef),
+            sync_status: SyncStatus::Done,
+            last_commit_unix_secs: 0,
+            last_index_unix_secs: 100,
+            most_common_lang: None,
+            branch_filter: None,
+            semantic: true,
+            semantic_status: Default::default(),
+            path_root: None,
+        };
+
+        let rows = |table: &'static str| {
+            let sql = sql.clone();
+            async move {
+                sqlx::query_scalar::<_, i64>(&format!("SELECT COUNT(*) FROM {table}"))
+                    .fetch_one(sql.as_ref())
+                    .await
+                    .unwrap()
+            }
+        };
+        let reset = |version: IndexVersion| {
+            let (sql, repo_pool, config) = (sql.clone(), repo_pool.clone(), config.clone());
+            let (reporef, repository) = (reporef.clone(), repository());
+            async move {
+                for (query, hash) in [
+                    (
+                        "INSERT INTO file_cache (cache_hash, repo_ref) VALUES (?, ?)",
+                        "file",
+                    ),
+                    (
+                        "INSERT INTO chunk_cache (chunk_hash, file_hash, branches, repo_ref) \
+                         VALUES ('chunk', ?, 'HEAD', ?)",
+                        "file",
+                    ),
+                ] {
+                    sqlx::query(query)
+                        .bind(hash)
+                        .bind(reporef.to_string())
+                        .execute(sql.as_ref())
+                        .await
+                        .unwrap();
+                }
+
+                config.source.save_index_version(&version).unwrap();
+                _ = repo_pool.remove(&reporef);
+                _ = repo_pool.insert(reporef.clone(), repository);
+                Indexes::new(repo_pool.clone(), config, sql, None)
+                    .await
+                    .unwrap();
+                repo_pool.read(&reporef, |_, repo| repo.last_index_unix_secs)
+            }
+        };
+
+        // create the index directories
+        Indexes::new(repo_pool.clone(), config.clone(), sql.clone(), None)
+            .await
+            .unwrap();
+        let current = IndexVersion {
+            lexical: config
+                .corpus_mode
+                .index_version(config.min_ngram_len, config.index_content_prose),
+            semantic: config.payload_schema.index_version(),
+        };
+
+        let last_index = reset(IndexVersion {
+            lexical: "old".into(),
+            ..current.clone()
+        })
+        .await;
+        assert_eq!(last_index, Some(0));
+        assert_eq!(rows("file_cache").await, 0);
+        assert_eq!(rows("chunk_cache").await, 1);
+        assert_eq!(
+            config.source.index_version_mismatch(&current),
+            VersionMismatch::default()
+        );
+
+        let last_index = reset(IndexVersion {
+            semantic: "old".into(),
+            ..current.clone()
+        })
+        .await;
+        assert_eq!(last_index, Some(0));
+        assert_eq!(rows("file_cache").await, 0);
+        assert_eq!(rows("chunk_cache").await, 0);
+
+        // nothing changed
+        let last_index = reset(current).await;
+        assert_eq!(last_index, Some(100));
+        assert_eq!(rows("file_cache").await, 1);
+    }
+}
@@ -0,0 +1,202 @@
+//! The tokenizer of the text fields of our schemas.
+//!
+//! Changes to tokenization change what is stored in the index, so this file is part of the
+//! schema version.
+
+use serde::{Deserialize, Serialize};
+use tantivy::tokenizer::{
+    BoxTokenStream, Language, LowerCaser, NgramTokenizer, RemoveLongFilter, SimpleTokenizer,
+    Stemmer, StopWordFilter, TextAnalyzer, Token, TokenFilter, TokenStream,
+};
+
+/// Words longer than this many bytes are left out of prose.
+const MAX_WORD_LEN: usize = 40;
+
+/// Words too common in English prose to be worth searching for.
+const STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it",
+    "no", "not", "of", "on", "or", "such", "that", "the", "their", "then", "there", "these",
+    "they", "this", "to", "was", "will", "with",
+];
+
+/// What kind of text the file index is tuned to search.
+///
+/// The mode decides how `content` is tokenized, so switching it means re-indexing.
+#[derive(Serialize, Deserialize, clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CorpusMode {
+    /// Source code, searched by n-grams, with symbols
+    #[default]
+    Code,
+    /// Natural language documents, searched by stemmed words, without stop words or symbols
+    Prose,
+}
+
+impl CorpusMode {
+    /// The name of the tokenizer of the `content` field.
+    ///
+    /// Source code leaves out n-grams shorter than `min_ngram_len`, which is clamped between 1
+    /// and 3. Prose is tokenized into words regardless.
+    pub(super) fn content_tokenizer(self, min_ngram_len: usize) -> &'static str {
+        match (self, min_ngram_len) {
+            (Self::Code, 0 | 1) => "default",
+            (Self::Code, 2) => "ngrams_min2",
+            (Self::Code, _) => "ngrams_min3",
+            (Self::Prose, _) => "prose",
+        }
+    }
+
+    /// Whether the content of source code is also indexed as prose, if `content_prose` is
+    /// configured.
+    ///
+    /// Prose is already tokenized into words, so it's only ever indexed once.
+    pub(super) fn indexes_content_prose(self, content_prose: bool) -> bool {
+        content_prose && self == Self::Code
+    }
+
+    /// The version of the tantivy schema, which includes the tokenizer of `content`, and
+    /// whether it's also indexed as prose.
+    pub(super) fn index_version(self, min_ngram_len: usize, content_prose: bool) -> String {
+        let mut version = match self.content_tokenizer(min_ngram_len) {
+            "default" => crate::state::LEXICAL_VERSION.to_owned(),
+            tokenizer => format!("{}-{tokenizer}", crate::state::LEXICAL_VERSION),
+        };
+        if self.indexes_content_prose(content_prose) {
+            version.push_str("-content_prose");
+        }
+        version
+    }
+}
+
+/// Break text up into lowercase English word stems, leaving out stop words.
+pub(super) fn prose() -> TextAnalyzer {
+    TextAnalyzer::from(SimpleTokenizer)
+        .filter(RemoveLongFilter::limit(MAX_WORD_LEN))
+        .filter(LowerCaser)
+        .filter(StopWordFilter::remove(
+            STOP_WORDS.iter().map(|w| w.to_string()).collect(),
+        ))
+        .filter(Stemmer::new(Language::English))
+}
+
+/// Break text up into n-grams of `min_len` to 3 characters, positioned at their byte offset.
+///
+/// Shorter n-grams have the longest posting lists, so leaving them out shrinks the index, at
+/// the cost of not finding text shorter than `min_len`.
+pub(super) fn ngrams(min_len: usize) -> TextAnalyzer {
+    TextAnalyzer::from(NgramTokenizer::new(min_len.clamp(1, 3), 3, false)).filter(OffsetPositions)
+}
+
+/// Set the position of every token to its byte offset in the text.
+///
+/// The n-gram tokenizer puts every token at position 0. With positions, a phrase query over
+/// n-grams only matches text where they are as far apart as in the query.
+#[derive(Clone)]
+struct OffsetPositions;
+
+impl TokenFilter for OffsetPositions {
+    fn transform<'a>(&self, token_stream: BoxTokenStream<'a>) -> BoxTokenStream<'a> {
+        BoxTokenStream::from(OffsetPositionsStream { tail: token_stream })
+    }
+}
+
+struct OffsetPositionsStream<'a> {
+    tail: BoxTokenStream<'a>,
+}
+
+impl TokenStream for OffsetPositionsStream<'_> {
+    fn advance(&mut self) -> bool {
+        if !self.tail.advance() {
+            return false;
+        }
+
+        let token = self.tail.token_mut();
+        token.position = token.offset_from;
+        true
+    }
+
+    fn token(&self) -> &Token {
+        self.tail.token()
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        self.tail.token_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(analyzer: TextAnalyzer, text: &str) -> Vec<(String, usize)> {
+        let mut tokens = vec![];
+        analyzer.token_stream(text).process(&mut |token| {
+            tokens.push((token.text.clone(), token.position));
+        });
+        tokens
+    }
+
+    #[test]
+    fn prose_and_code_tokenize_markdown_differently() {
+        let markdown = "## Running the Servers\n\nEach server runs `cargo run`.\n";
+
+        let prose = tokens(prose(), markdown);
+        assert_eq!(
+            prose,
+            [
+                ("run".to_owned(), 0),
+                ("server".to_owned(), 2),
+                ("each".to_owned(), 3),
+                ("server".to_owned(), 4),
+                ("run".to_owned(), 5),
+                ("cargo".to_owned(), 6),
+                ("run".to_owned(), 7),
+            ]
+        );
+
+        // n-grams keep the case, the markup and the stop words, but not the words
+        let code = tokens(ngrams(1), markdown);
+        let texts = code.iter().map(|(t, _)| t.as_str()).collect::<Vec<_>>();
+        assert!(texts.contains(&"Run"));
+        assert!(texts.contains(&"the"));
+        assert!(texts.contains(&"## "));
+        assert!(!texts.contains(&"server"));
+        assert!(code.iter().all(|(t, _)| t.chars().count() <= 3));
+    }
+
+    #[test]
+    fn tokens_are_positioned_at_their_offset() {
+        let mut tokens = vec![];
+        ngrams(1).token_stream("a㐀b").process(&mut |token| {
+            tokens.push((token.text.clone(), token.position));
+        });
+
+        assert_eq!(
+            tokens,
+            [
+                ("a".to_owned(), 0),
+                ("a㐀".to_owned(), 0),
+                ("a㐀b".to_owned(), 0),
+                ("㐀".to_owned(), 1),
+                ("㐀b".to_owned(), 1),
+                ("b".to_owned(), 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn short_ngrams_can_be_left_out() {
+        let texts = |min_len| {
+            tokens(ngrams(min_len), "x = yz;")
+                .into_iter()
+                .map(|(t, _)| t)
+                .collect::<Vec<_>>()
+        };
+
+        assert!(texts(1).contains(&"x".to_owned()));
+        assert!(!texts(2).contains(&"x".to_owned()));
+        assert!(texts(2).contains(&"yz".to_owned()));
+        assert!(texts(2).iter().all(|t| t.chars().count() >= 2));
+        assert!(texts(3).iter().all(|t| t.chars().count() == 3));
+    }
+}
@@ -0,0 +1,328 @@
+//! A lightweight blame: the last commit that changed each line of a file.
+//!
+//! Only the first-parent history of `HEAD` is followed, and lines are matched between versions
+//! of a file by a plain line diff, so moved or copied code is attributed to the commit that
+//! moved it.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+/// Commits further back than this aren't looked at. Lines still unattributed by then are blamed
+/// on the oldest commit that was, like the boundary commits of `git blame`.
+const MAX_BLAME_DEPTH: usize = 1000;
+
+/// Versions of a file with more unmatched lines than this, multiplied, aren't diffed. Their
+/// changed regions are attributed wholesale instead.
+const MAX_DIFF_CELLS: usize = 1 << 21;
+
+/// A commit that last changed some lines of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlameCommit {
+    pub oid: gix::ObjectId,
+    pub unix_seconds: u64,
+}
+
+/// The commit that last changed each line of a file.
+///
+/// Lines are stored as runs of consecutive lines that share a commit, which keeps the encoded
+/// form small for files written in a handful of commits.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LineBlame {
+    pub commits: Vec<BlameCommit>,
+    /// The number of lines of each run, and the index of their commit in `commits`
+    runs: Vec<(usize, usize)>,
+}
+
+impl LineBlame {
+    /// Blame the lines of the file at `relative_path` as of `HEAD`.
+    ///
+    /// Returns `None` if the file isn't part of `HEAD`.
+    pub fn compute(git: &gix::Repository, relative_path: &Path) -> Result<Option<Self>> {
+        let mut commit = git.head_commit()?;
+        let Some(mut blob) = blob_at(&commit, relative_path)? else {
+            return Ok(None);
+        };
+
+        let mut lines = read_lines(git, blob)?;
+        let mut owner = vec![None; lines.len()];
+        // the line of the blamed version each line of the current version stands for
+        let mut origin = (0..lines.len()).map(Some).collect::<Vec<_>>();
+
+        let mut commits = vec![];
+        for depth in 0.. {
+            let parent = match commit.parent_ids().next() {
+                Some(id) if depth + 1 < MAX_BLAME_DEPTH => Some(id.object()?.try_into_commit()?),
+                _ => None,
+            };
+            let parent_blob = match &parent {
+                Some(parent) => blob_at(parent, relative_path)?,
+                None => None,
+            };
+
+            if parent_blob != Some(blob) {
+                let parent_lines = match parent_blob {
+                    Some(id) => read_lines(git, id)?,
+                    None => vec![],
+                };
+
+                let matches = matching_lines(&parent_lines, &lines);
+                let mut parent_origin = vec![None; parent_lines.len()];
+                let mut blamed = false;
+                for (line, matched) in origin.iter().zip(matches) {
+                    match (line, matched) {
+                        (Some(line), Some(parent_line)) => parent_origin[parent_line] = Some(*line),
+                        (Some(line), None) => {
+                            owner[*line] = Some(commits.len());
+                            blamed = true;
+                        }
+                        (None, _) => {}
+                    }
+                }
+
+                if blamed {
+                    commits.push(BlameCommit {
+                        oid: commit.id,
+                        unix_seconds: commit.time()?.seconds,
+                    });
+                }
+
+                lines = parent_lines;
+                origin = parent_origin;
+            }
+
+            match (parent, parent_blob) {
+                (Some(parent), Some(parent_blob)) if origin.iter().any(Option::is_some) => {
+                    commit = parent;
+                    blob = parent_blob;
+                }
+                _ => break,
+            }
+        }
+
+        let mut runs: Vec<(usize, usize)> = vec![];
+        for owner in owner {
+            // every line is blamed by the time the walk stops
+            let owner = owner.unwrap_or_default();
+            match runs.last_mut() {
+                Some((len, last)) if *last == owner => *len += 1,
+                _ => runs.push((1, owner)),
+            }
+        }
+
+        Ok(Some(Self { commits, runs }))
+    }
+
+    /// The commit that last changed the 0-based `line`.
+    pub fn commit_for_line(&self, line: usize) -> Option<&BlameCommit> {
+        let mut start = 0;
+        for &(len, commit) in &self.runs {
+            if line < start + len {
+                return self.commits.get(commit);
+            }
+            start += len;
+        }
+
+        None
+    }
+
+    /// The commit of every line, in order.
+    pub fn lines(&self) -> impl Iterator<Item = &BlameCommit> + '_ {
+        self.runs
+            .iter()
+            .flat_map(|&(len, commit)| std::iter::repeat(&self.commits[commit]).take(len))
+    }
+
+    /// Encode as the commits, each an oid and a timestamp, followed by the runs of lines, each
+    /// a length and a commit index. Numbers are LEB128 varints.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        write_varint(&mut bytes, self.commits.len() as u64);
+        for commit in &self.commits {
+            bytes.extend_from_slice(commit.oid.as_bytes());
+            write_varint(&mut bytes, commit.unix_seconds);
+        }
+
+        write_varint(&mut bytes, self.runs.len() as u64);
+        for &(len, commit) in &self.runs {
+            write_varint(&mut bytes, len as u64);
+            write_varint(&mut bytes, commit as u64);
+        }
+
+        bytes
+    }
+
+    /// Decode the output of [`LineBlame::encode`].
+    ///
+    /// Returns `None` for empty or malformed input, such as files indexed without blame.
+    pub fn decode(mut bytes: &[u8]) -> Option<Self> {
+        let bytes = &mut bytes;
+
+        let commit_count = read_varint(bytes)? as usize;
+        let mut commits = Vec::with_capacity(commit_count.min(bytes.len()));
+        for _ in 0..commit_count {
+            let oid = <[u8; 20]>::try_from(bytes.get(..20)?).ok()?;
+            *bytes = &bytes[20..];
+            commits.push(BlameCommit {
+                oid: gix::ObjectId::from(oid),
+                unix_seconds: read_varint(bytes)?,
+            });
+        }
+
+        let run_count = read_varint(bytes)? as usize;
+        let mut runs = Vec::with_capacity(run_count.min(bytes.len()));
+        for _ in 0..run_count {
+            let len = read_varint(bytes)? as usize;
+            let commit = read_varint(bytes)? as usize;
+            if commit >= commits.len() {
+                return None;
+            }
+            runs.push((len, commit));
+        }
+
+        bytes.is_empty().then_some(Self { commits, runs })
+    }
+}
+
+fn blob_at(commit: &gix::Commit<'_>, relative_path: &Path) -> Result<Option<gix::ObjectId>> {
+    Ok(commit
+        .tree()?
+        .peel_to_entry_by_path(relative_path)?
+        .filter(|entry| entry.mode().is_blob())
+        .map(|entry| entry.object_id()))
+}
+
+fn read_lines(git: &gix::Repository, blob: gix::ObjectId) -> Result<Vec<String>> {
+    let object = git.find_object(blob)?;
+    Ok(String::from_utf8_lossy(&object.data)
+        .lines()
+        .map(ToOwned::to_owned)
+        .collect())
+}
+
+/// For every line of `new`, find the line of `old` it is unchanged from, if any.
+fn matching_lines(old: &[String], new: &[String]) -> Vec<Option<usize>> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut matches = vec![None; new.len()];
+    for (i, matched) in matches.iter_mut().enumerate().take(prefix) {
+        *matched = Some(i);
+    }
+    for i in 0..suffix {
+        matches[new.len() - 1 - i] = Some(old.len() - 1 - i);
+    }
+
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+    if old_mid.is_empty() || new_mid.is_empty() {
+        return matches;
+    }
+
+    if (old_mid.len() + 1) * (new_mid.len() + 1) > MAX_DIFF_CELLS {
+        return matches;
+    }
+
+    // longest common subsequence of the changed region, from the end
+    let width = new_mid.len() + 1;
+    let mut lcs = vec![0u32; (old_mid.len() + 1) * width];
+    for i in (0..old_mid.len()).rev() {
+        for j in (0..new_mid.len()).rev() {
+            lcs[i * width + j] = if old_mid[i] == new_mid[j] {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < old_mid.len() && j < new_mid.len() {
+        if old_mid[i] == new_mid[j] {
+            matches[prefix + j] = Some(prefix + i);
+            i += 1;
+            j += 1;
+        } else if lcs[(i + 1) * width + j] >= lcs[i * width + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+
+    matches
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte < 0x80 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(ToOwned::to_owned).collect()
+    }
+
+    #[test]
+    fn match_unchanged_lines() {
+        let old = lines("a\nb\nc\nd\n");
+        let new = lines("a\nx\nc\ny\nd\n");
+        assert_eq!(
+            matching_lines(&old, &new),
+            [Some(0), None, Some(2), None, Some(3)]
+        );
+
+        assert_eq!(matching_lines(&[], &new), [None; 5]);
+        assert_eq!(
+            matching_lines(&new, &new),
+            (0..5).map(Some).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let commit = |byte, unix_seconds| BlameCommit {
+            oid: gix::ObjectId::from([byte; 20]),
+            unix_seconds,
+        };
+        let blame = LineBlame {
+            commits: vec![commit(1, 1_700_000_000), commit(2, 1_600_000_000)],
+            runs: vec![(2, 1), (300, 0), (1, 1)],
+        };
+
+        let encoded = blame.encode();
+        assert_eq!(LineBlame::decode(&encoded), Some(blame.clone()));
+        assert_eq!(blame.lines().count(), 303);
+        assert_eq!(blame.commit_for_line(1), Some(&blame.commits[1]));
+        assert_eq!(blame.commit_for_line(2), Some(&blame.commits[0]));
+        assert_eq!(blame.commit_for_line(302), Some(&blame.commits[1]));
+        assert_eq!(blame.commit_for_line(303), None);
+
+        assert_eq!(LineBlame::decode(&[]), None);
+        assert_eq!(LineBlame::decode(&encoded[..encoded.len() - 1]), None);
+    }
+}
@@ -1,5 +1,8 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    io::{BufRead, Write},
+    ops::Range,
+    panic::AssertUnwindSafe,
     path::{Path, PathBuf},
     sync::{
         atomic::{AtomicU64, Ordering},
@@ -7,49 +10,398 @@ use std::{
     },
 };
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
+use ignore::overrides::{Override, OverrideBuilder};
 use rayon::prelude::*;
 use scc::hash_map::Entry;
+use serde::{Deserialize, Serialize};
 use tantivy::{
-    collector::TopDocs,
+    collector::{DocSetCollector, TopDocs},
     doc,
-    query::{BooleanQuery, Query, QueryParser, TermQuery},
-    schema::{IndexRecordOption, Schema, Term},
-    IndexWriter,
+    fastfield::Column,
+    query::{AllQuery, BooleanQuery, Explanation, Query, RangeQuery, TermQuery},
+    schema::{Field, IndexRecordOption, Schema, Term},
+    tokenizer::TokenStream,
+    DocAddress, DocSet, Document, IndexReader, IndexWriter, ReloadPolicy, SegmentReader,
+    TantivyError, TERMINATED,
 };
 use tokenizers as _;
 use tokio::runtime::Handle;
-use tracing::{info, trace, warn};
+use tracing::{error, info, trace, warn};
 
 pub use super::schema::File;
 
-#[cfg(feature = "debug")]
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use super::{
-    reader::{ContentDocument, ContentReader, FileDocument, FileReader},
-    DocumentRead, Indexable, Indexer,
+    blame::LineBlame,
+    query::FileQueryBuilder,
+    reader::{ContentDocument, ContentReader, FileDocument, FileReader, OutlineItem, ReadOptions},
+    ChangeSet, CorpusMode, DocumentRead, FileTimings, ImportTarget, IndexOutcome, Indexable,
+    Indexer, LangDictionary, SharedWriter, SlowFile,
 };
 use crate::{
     background::SyncPipes,
-    cache::{FileCache, FileCacheSnapshot},
+    cache::{merged_branch_list, FileCache, FileCacheSnapshot, IndexRun, RepoStats},
     intelligence::TreeSitterFile,
-    query::compiler::{case_permutations, trigrams},
-    repo::{iterator::*, RepoMetadata, RepoRef, Repository},
+    query::{
+        compiler::{case_permutations, trigrams},
+        execute::target_regexes,
+        languages::parse_alias,
+        parser,
+    },
+    repo::{iterator::*, RepoMetadata, RepoRef, Repository, SemanticStatus},
+    semantic::{chunk::ChunkStrategy, minhash::NearDuplicates, Semantic},
+    state::IndexVersion,
     symbol::SymbolLocations,
+    text_range::{LineIndex, TextRange},
 };
 
+/// The number of hits fetched by lookups that expect a single document.
+///
+/// The index can transiently contain more than one document for the same
+/// path while it's being re-indexed, so we fetch a few and pick one
+/// instead of failing the request.
+const TOP_HIT_DUPLICATE_HEADROOM: usize = 4;
+
+/// The most names [`Indexer::by_symbols`] looks up in a single query.
+pub const MAX_SYMBOL_NAMES: usize = 64;
+
+/// The most paths [`Indexer::by_paths`] fetches in a single query.
+pub const MAX_PATHS: usize = 64;
+
+/// A file returned by [`Indexer::by_symbols`].
+#[derive(Debug)]
+pub struct SymbolMatch {
+    pub doc: ContentDocument,
+    /// The names that are symbols of the file, in the order they were given
+    pub names: Vec<String>,
+}
+
+/// A symbol returned by [`Indexer::find_symbols`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FoundSymbol {
+    pub relative_path: String,
+    pub lang: Option<String>,
+    pub symbol: OutlineItem,
+}
+
+/// An occurrence of a symbol returned by [`Indexer::by_symbol`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolHit {
+    pub relative_path: String,
+    pub range: TextRange,
+    pub kind: String,
+}
+
+/// A file returned by [`Indexer::find_references`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileReference {
+    pub relative_path: String,
+    pub lang: Option<String>,
+    /// Lines of the statements importing the target, zero-based and end-exclusive
+    pub lines: Vec<Range<usize>>,
+}
+
+/// The bundle written by [`Indexer::snapshot`], as described by its `snapshot.json`.
+///
+/// Next to the manifest, `content/` holds the files of the committed tantivy index, and
+/// `bleep.db` a copy of the SQLite database.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotManifest {
+    /// The versions the index was written with, which the restoring build must match
+    pub version: IndexVersion,
+    /// When the snapshot was taken, in unix seconds
+    pub created_unix_secs: u64,
+    /// The files of the tantivy index, relative to `content/`
+    pub index_files: Vec<PathBuf>,
+    /// The snapshot of the qdrant collection taken along with this one, if any
+    pub qdrant_snapshot: Option<String>,
+}
+
+const SNAPSHOT_MANIFEST: &str = "snapshot.json";
+const SNAPSHOT_INDEX_DIR: &str = "content";
+const SNAPSHOT_DB: &str = "bleep.db";
+
+/// How a file scores for a query, as returned by [`Indexer::by_repo_explain`].
+#[derive(serde::Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum QueryExplanation {
+    /// The file matches, with the breakdown of its score
+    Matched { explanation: Explanation },
+    /// The file doesn't match, with the reasons we could find
+    Excluded { reasons: Vec<String> },
+}
+
+/// What to do when indexing a repository whose directory is missing or empty.
+///
+/// Indexing such a repository finds no files, so its documents are all swept as stale. That's
+/// rarely what's wanted when the directory is only missing for a while, as on a drive that
+/// isn't mounted, which is what [`MissingRepoMode::SkipAndKeep`] is for.
+#[derive(Serialize, Deserialize, clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingRepoMode {
+    /// Index the repository anyway, which removes all of its documents
+    #[default]
+    DeleteIndex,
+    /// Leave the documents of the repository as they are
+    SkipAndKeep,
+    /// Fail the indexing run
+    Error,
+}
+
+/// Errors of lookups that expect a single file.
+#[derive(thiserror::Error, Debug)]
+pub enum FileIndexError {
+    #[error("file not found")]
+    NotFound,
+    #[error("path matches {count} different files")]
+    Ambiguous { count: usize },
+    #[error("failed to read document: {0}")]
+    Corrupt(#[source] tantivy::TantivyError),
+    #[error("failed to build query: {0}")]
+    QueryParse(#[source] tantivy::TantivyError),
+    #[error("failed to search index for `{path}`: {source}")]
+    Search {
+        path: String,
+        #[source]
+        source: tantivy::TantivyError,
+    },
+    #[error("byte range {start}..{end} is out of bounds for a file of {len} bytes")]
+    BadRange {
+        start: usize,
+        end: usize,
+        len: usize,
+    },
+}
+
+/// A byte range of the stored content of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileBytes {
+    pub bytes: Vec<u8>,
+    /// Length of the whole content, in bytes
+    pub total_len: usize,
+}
+
+/// A document returned by [`Indexer::by_path_all_versions`].
+#[derive(Debug)]
+pub struct DocumentVersion {
+    pub doc: ContentDocument,
+    pub last_commit_unix_seconds: u64,
+    /// Where the document is stored in the searcher it was read from
+    pub addr: DocAddress,
+}
+
+/// Which page of a list of documents to return.
+#[derive(Debug, Clone, Copy)]
+pub struct Pagination {
+    pub limit: usize,
+    /// Number of documents to skip before the page
+    pub offset: usize,
+}
+
+/// A page of the documents of a repository, returned by [`Indexer::by_repo`].
+#[derive(Debug)]
+pub struct RepoPage {
+    pub docs: Vec<ContentDocument>,
+    /// Number of documents that match, over every page
+    pub total: usize,
+}
+
+/// How the documents of a repository differ from its files, as found by
+/// [`Indexer::verify_repo`].
+///
+/// Paths are the relative paths of the documents, sorted.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Files that would be indexed, but have no document
+    pub missing: Vec<String>,
+    /// Files whose document was indexed from other content, or with other options
+    pub stale: Vec<String>,
+    /// Documents of files that are gone, or that are no longer indexed
+    pub orphaned: Vec<String>,
+}
+
+impl VerifyReport {
+    /// Whether the index matches the files.
+    pub fn is_consistent(&self) -> bool {
+        self.missing.is_empty() && self.stale.is_empty() && self.orphaned.is_empty()
+    }
+}
+
+/// Running totals of the files written during an indexing run.
+#[derive(Default)]
+struct RunTotals {
+    files: AtomicU64,
+    bytes: AtomicU64,
+    /// Files written, plus the ones that were already up to date
+    indexable_files: AtomicU64,
+    /// The slowest files so far, slowest first
+    slowest: std::sync::Mutex<Vec<SlowFile>>,
+    /// Relative paths of the files written
+    written: std::sync::Mutex<Vec<String>>,
+    /// Files that failed to embed into the semantic index
+    semantic_failures: AtomicU64,
+}
+
+/// The bytes of file content an indexing run holds, against `--memory-budget-bytes`.
+///
+/// Files count against the budget from before the walker reads them until the next commit, and
+/// a file is only read while it fits, or when nothing else is in flight. Only commits free what
+/// the written documents hold, so a spent budget lets one file through at a time until the
+/// batch is over and committed.
+struct MemoryBudget {
+    limit: u64,
+    in_flight: std::sync::Mutex<u64>,
+    released: std::sync::Condvar,
+    uncommitted: AtomicU64,
+    peak: AtomicU64,
+}
+
+impl MemoryBudget {
+    fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            in_flight: Default::default(),
+            released: Default::default(),
+            uncommitted: Default::default(),
+            peak: Default::default(),
+        }
+    }
+
+    /// Entries in each batch, so that a batch of the largest files still fits in the budget.
+    fn batch_size(&self) -> usize {
+        (self.limit / MAX_FILE_LEN).max(1) as usize
+    }
+
+    /// Wait until `bytes` more fit in the budget, or nothing else is in flight.
+    fn acquire(&self, bytes: u64) -> Reservation<'_> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        loop {
+            let held = *in_flight + self.uncommitted.load(Ordering::Relaxed);
+            if bytes == 0 || *in_flight == 0 || held + bytes <= self.limit {
+                self.peak.fetch_max(held + bytes, Ordering::Relaxed);
+                *in_flight += bytes;
+                return Reservation {
+                    budget: self,
+                    bytes,
+                    written: false,
+                };
+            }
+            in_flight = self.released.wait(in_flight).unwrap();
+        }
+    }
+
+    fn is_spent(&self) -> bool {
+        self.uncommitted.load(Ordering::Relaxed) >= self.limit
+    }
+
+    fn committed(&self) {
+        self.uncommitted.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Bytes in flight from [`MemoryBudget::acquire`], which are freed when dropped.
+struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: u64,
+    written: bool,
+}
+
+impl Reservation<'_> {
+    /// The file was written, so its bytes are held until the next commit.
+    fn written(mut self) {
+        self.written = true;
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.budget.in_flight.lock().unwrap();
+        *in_flight -= self.bytes;
+        if self.written {
+            self.budget
+                .uncommitted
+                .fetch_add(self.bytes, Ordering::Relaxed);
+        }
+        self.budget.released.notify_all();
+    }
+}
+
+/// Number of the slowest files of a run reported in its [`IndexOutcome`].
+const SLOWEST_FILES: usize = 10;
+
+impl RunTotals {
+    fn record_timings(&self, relative_path: &Path, timings: FileTimings) {
+        let mut slowest = self.slowest.lock().unwrap();
+        if slowest.len() == SLOWEST_FILES
+            && slowest.last().map(|f| f.timings.total()) >= Some(timings.total())
+        {
+            return;
+        }
+
+        let pos = slowest.partition_point(|f| f.timings.total() >= timings.total());
+        slowest.insert(
+            pos,
+            SlowFile {
+                relative_path: relative_path.to_string_lossy().into_owned(),
+                timings,
+            },
+        );
+        slowest.truncate(SLOWEST_FILES);
+    }
+}
+
 struct Workload<'a> {
     repo_disk_path: &'a Path,
+    /// The directory indexed paths are relative to, relative to `repo_disk_path`
+    path_root: Option<&'a Path>,
     repo_ref: String,
     repo_name: &'a str,
     repo_metadata: &'a RepoMetadata,
     file_cache: &'a FileCache<'a>,
     cache_snapshot: &'a FileCacheSnapshot,
+    totals: &'a RunTotals,
+    blame_repo: Option<&'a gix::ThreadSafeRepository>,
+    /// Whether the repository is embedded into the semantic index
+    semantic: bool,
+    near_duplicates: Option<&'a NearDuplicates>,
     dir_entry: RepoDirEntry,
 }
 
+/// An entry as it's indexed, and the keys it's cached under.
+struct PreparedEntry {
+    dir_entry: RepoDirEntry,
+    is_lfs_pointer: bool,
+    has_bom: bool,
+    has_conflicts: bool,
+    language: Option<String>,
+    is_generated: bool,
+    is_small: bool,
+    /// Whether the file is over the size limits, and indexed in shards
+    is_sharded: bool,
+    /// Owners of the file in CODEOWNERS
+    owners: Vec<String>,
+    /// Tags of the file in `.bloop/tags.toml`
+    tags: Vec<String>,
+    semantic_hash: String,
+    tantivy_hash: String,
+}
+
+/// The path of a document for the file at `repo_path` in the repository, or `None` if it's
+/// outside of `path_root`.
+fn root_relative(repo_path: &Path, path_root: Option<&Path>) -> Option<PathBuf> {
+    match path_root {
+        None => Some(repo_path.to_owned()),
+        Some(root) => repo_path
+            .strip_prefix(root)
+            .ok()
+            .filter(|path| !path.as_os_str().is_empty())
+            .map(ToOwned::to_owned),
+    }
+}
+
 #[async_trait]
 impl Indexable for File {
     async fn index_repository(
@@ -57,74 +409,262 @@ impl Indexable for File {
         reporef: &RepoRef,
         repo: &Repository,
         repo_metadata: &RepoMetadata,
-        writer: &IndexWriter,
+        writer: &SharedWriter,
         pipes: &SyncPipes,
-    ) -> Result<()> {
+        dry_run: bool,
+        target_ref: Option<&str>,
+    ) -> Result<IndexOutcome> {
+        if !self.config.allowed_repo_roots.is_empty() {
+            repo.check_allowed(&self.config.allowed_repo_roots)?;
+        }
+
         let file_cache = Arc::new(FileCache::for_repo(&self.sql, reporef));
         let cache_snapshot = file_cache.retrieve().await;
         let repo_name = reporef.indexed_name();
         let processed = &AtomicU64::new(0);
+        let totals = &RunTotals::default();
+        let semantic = self.embeds(reporef, repo);
+
+        // blame follows the history of `HEAD`, so repositories without commits have none
+        let blame_repo =
+            if self.config.compute_line_blame && repo_metadata.last_commit_unix_secs.is_some() {
+                gix::open(&repo.disk_path)
+                    .map_err(|err| warn!(?err, "failed to open git repo for line blame"))
+                    .ok()
+                    .map(gix::Repository::into_sync)
+            } else {
+                None
+            };
+        let blame_repo = blame_repo.as_ref();
+        let path_root = repo.resolve_path_root()?;
+        let path_root = path_root.as_deref();
+        let repo_str = reporef.to_string();
+        let admit = |dir_entry| {
+            self.admit_entry(
+                &repo_str,
+                &repo.disk_path,
+                path_root,
+                repo_metadata,
+                semantic,
+                blame_repo.is_some(),
+                dir_entry,
+            )
+        };
+        let near_duplicates = self
+            .config
+            .near_duplicate_threshold
+            .filter(|_| self.semantic.is_some() && semantic)
+            .map(NearDuplicates::new);
+        let near_duplicates = near_duplicates.as_ref();
+        let budget = self.config.memory_budget_bytes.map(MemoryBudget::new);
+        let budget = &budget;
 
         let file_worker = |count: usize| {
             let cache_snapshot = cache_snapshot.clone();
             let file_cache = file_cache.clone();
-            move |dir_entry: RepoDirEntry| {
+            move |dir_entry: RepoDirEntry, reserved: Option<Reservation<'_>>| {
                 let completed = processed.fetch_add(1, Ordering::Relaxed);
                 pipes.index_percent(((completed as f32 / count as f32) * 100f32) as u8);
 
                 let entry_disk_path = dir_entry.path().unwrap_or_default().to_owned();
                 let workload = Workload {
                     repo_disk_path: &repo.disk_path,
+                    path_root,
                     repo_ref: reporef.to_string(),
                     repo_name: &repo_name,
                     file_cache: &file_cache,
                     cache_snapshot: &cache_snapshot,
                     repo_metadata,
+                    totals,
+                    blame_repo,
+                    semantic,
+                    near_duplicates,
                     dir_entry,
                 };
 
                 trace!(entry_disk_path, "queueing entry");
-                if let Err(err) = self.worker(workload, writer) {
-                    warn!(%err, entry_disk_path, "indexing failed; skipping");
+                let written = match self.worker(workload, &writer.read().unwrap()) {
+                    Ok(written) => written,
+                    Err(err) => {
+                        warn!(%err, entry_disk_path, "indexing failed; skipping");
+                        false
+                    }
+                };
+
+                // entries that weren't written free their bytes right away, as nothing has to
+                // be committed for them
+                if let Some(reserved) = reserved.filter(|_| written) {
+                    reserved.written();
                 }
             }
         };
+        let reserve = |bytes| budget.as_ref().map(|budget| budget.acquire(bytes));
+
+        // without an interval, the whole repository is a single batch, and with a memory
+        // budget, batches are small enough to commit before going far over it
+        let commit_interval = match self.config.commit_interval {
+            Some(interval) => interval.max(1),
+            None => usize::MAX,
+        };
+        let batch_size = match budget {
+            Some(budget) => commit_interval.min(budget.batch_size()),
+            None => commit_interval,
+        };
+        let commit_due = |since_commit: usize| {
+            since_commit >= commit_interval || budget.as_ref().map_or(false, MemoryBudget::is_spent)
+        };
 
+        let started_at = std::time::SystemTime::now();
         let start = std::time::Instant::now();
+        let mut outcome = IndexOutcome::default();
+
+        if target_ref.is_some() && repo_metadata.last_commit_unix_secs.is_none() {
+            bail!("can't index a git reference of a repository without commits");
+        }
+
+        let is_missing = std::fs::read_dir(&repo.disk_path)
+            .map(|mut entries| entries.next().is_none())
+            .unwrap_or(true);
+        if is_missing {
+            match self.config.on_missing_repo {
+                MissingRepoMode::DeleteIndex => {
+                    warn!(?repo.disk_path, "repository is missing or empty; removing its files")
+                }
+                MissingRepoMode::SkipAndKeep => {
+                    warn!(?repo.disk_path, "repository is missing or empty; keeping its index");
+                    outcome.kept_missing = true;
+                    return Ok(outcome);
+                }
+                MissingRepoMode::Error => {
+                    bail!("repository at {:?} is missing or empty", repo.disk_path)
+                }
+            }
+        }
 
         // If we could determine the time of the last commit, proceed
         // with a Git Walker, otherwise use a FS walker
         if repo_metadata.last_commit_unix_secs.is_some() {
-            let walker = GitWalker::open_repository(
-                reporef,
-                &repo.disk_path,
-                repo.branch_filter.as_ref().map(Into::into),
-            )?;
+            let walker = match target_ref {
+                Some(target_ref) => GitWalker::open_ref(reporef, &repo.disk_path, target_ref)?,
+                None => GitWalker::open_repository(
+                    reporef,
+                    &repo.disk_path,
+                    repo.branch_filter.as_ref().map(Into::into),
+                )?,
+            }
+            .index_large_files(self.lines_per_shard().is_some())
+            .max_file_size(self.config.max_file_size_bytes);
+            // objects are read as they're walked, so the counts are only known afterwards
+            let skipped = walker.skipped();
+            if dry_run {
+                outcome.breakdown = Some(walker.dry_run(pipes, admit));
+                outcome.skipped = skipped.entries();
+                return Ok(outcome);
+            }
+
             let count = walker.len();
-            walker.for_each(pipes, file_worker(count));
+            let batches = walker.into_batches(batch_size);
+            let last = batches.len().saturating_sub(1);
+            let mut since_commit = 0;
+            for (i, batch) in batches.into_iter().enumerate() {
+                since_commit += batch.len();
+                batch.for_each_reserved(pipes, reserve, file_worker(count));
+                if i < last && !pipes.is_cancelled() && commit_due(since_commit) {
+                    commit_batch(writer, &self.langs, &file_cache, &cache_snapshot).await?;
+                    since_commit = 0;
+                    if let Some(budget) = budget {
+                        budget.committed();
+                    }
+                }
+            }
+            outcome.skipped = skipped.entries();
         } else {
-            let walker = FileWalker::index_directory(&repo.disk_path);
+            let walker = FileWalker::index_directory(&repo.disk_path, self.config.symlink_mode)
+                .index_large_files(self.lines_per_shard().is_some())
+                .max_file_size(self.config.max_file_size_bytes);
+            outcome.skipped = walker.skipped();
+            if dry_run {
+                outcome.breakdown = Some(walker.dry_run(pipes, admit));
+                return Ok(outcome);
+            }
+
             let count = walker.len();
-            walker.for_each(pipes, file_worker(count));
+            let batches = walker.into_batches(batch_size);
+            let last = batches.len().saturating_sub(1);
+            let mut since_commit = 0;
+            for (i, batch) in batches.into_iter().enumerate() {
+                since_commit += batch.len();
+                batch.for_each_reserved(pipes, reserve, file_worker(count));
+                if i < last && !pipes.is_cancelled() && commit_due(since_commit) {
+                    commit_batch(writer, &self.langs, &file_cache, &cache_snapshot).await?;
+                    since_commit = 0;
+                    if let Some(budget) = budget {
+                        budget.committed();
+                    }
+                }
+            }
         };
 
         if pipes.is_cancelled() {
             bail!("cancelled");
         }
 
-        info!(?repo.disk_path, "repo file indexing finished, took {:?}", start.elapsed());
+        let elapsed = start.elapsed();
+        info!(?repo.disk_path, "repo file indexing finished, took {:?}", elapsed);
+
+        outcome.slowest = std::mem::take(&mut *totals.slowest.lock().unwrap());
+        outcome.indexable_files = totals.indexable_files.load(Ordering::Relaxed);
+        outcome.semantic_failures = totals.semantic_failures.load(Ordering::Relaxed);
+        if let Some(budget) = budget {
+            outcome.peak_memory_bytes = budget.peak.load(Ordering::Relaxed);
+            info!(
+                ?repo.disk_path,
+                peak = outcome.peak_memory_bytes,
+                limit = budget.limit,
+                "memory held by indexing"
+            );
+        }
+        if let Some(near_duplicates) = near_duplicates {
+            info!(
+                ?repo.disk_path,
+                reused = near_duplicates.reused(),
+                "reused embeddings of near-duplicate chunks"
+            );
+        }
+        if outcome.indexable_files == 0 {
+            info!(?repo.disk_path, "no indexable files found");
+        }
 
         // files that are no longer tracked by the git index are to be removed
         // from the tantivy & qdrant indices
         let mut qdrant_remove_list = vec![];
-        cache_snapshot.retain(|k, v| {
-            if !v.fresh {
-                writer.delete_term(Term::from_field_text(self.unique_hash, k));
-                qdrant_remove_list.push(k.to_string());
+        {
+            let writer = writer.read().unwrap();
+
+            // the documents of stale entries are still there until the deletes are committed
+            let reader: IndexReader = writer
+                .index()
+                .reader_builder()
+                .reload_policy(ReloadPolicy::Manual)
+                .try_into()?;
+            let searcher = reader.searcher();
+            let written = std::mem::take(&mut *totals.written.lock().unwrap());
+
+            // a run for a single reference only walks that reference, so what the other
+            // branches hold isn't stale
+            if target_ref.is_none() {
+                cache_snapshot.retain(|k, v| {
+                    if !v.fresh {
+                        writer.delete_term(Term::from_field_text(self.unique_hash, k));
+                        qdrant_remove_list.push(k.to_string());
+                    }
+
+                    v.fresh
+                });
             }
 
-            v.fresh
-        });
+            outcome.changes = self.change_set(&searcher, &qdrant_remove_list, written)?;
+        }
 
         // batch-delete points from qdrant index
         if !qdrant_remove_list.is_empty() {
@@ -139,9 +679,39 @@ impl Indexable for File {
             }
         }
 
+        // the points of a repository that was just opted out of semantic search are removed, and
+        // so are its chunk caches, which would otherwise skip embedding when it's opted back in
+        if !semantic && repo.semantic_status != SemanticStatus::Disabled {
+            if let Some(semantic) = &self.semantic {
+                semantic
+                    .delete_points_for_hash(&reporef.to_string(), std::iter::empty())
+                    .await;
+                file_cache.delete_chunks_only().await?;
+            }
+        }
+
         pipes.index_percent(100);
-        file_cache.persist(cache_snapshot).await?;
-        Ok(())
+        // ids of new languages are written before the documents using them are committed
+        self.langs.persist().await?;
+        let run = IndexRun {
+            started_at: started_at
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or_default(),
+            duration_ms: elapsed.as_millis() as i64,
+            files_indexed: totals.files.load(Ordering::Relaxed) as i64,
+            bytes: totals.bytes.load(Ordering::Relaxed) as i64,
+        };
+
+        // the cache and the run are persisted once the documents are committed, see
+        // `after_commit`
+        file_cache.begin_commit().await?;
+        self.uncommitted_caches
+            .lock()
+            .unwrap()
+            .push((reporef.clone(), cache_snapshot, run));
+
+        Ok(outcome)
     }
 
     fn delete_by_repo(&self, writer: &IndexWriter, repo: &Repository) {
@@ -154,6 +724,72 @@ impl Indexable for File {
     fn schema(&self) -> Schema {
         self.schema.clone()
     }
+
+    async fn reload(&self) -> Result<()> {
+        self.langs.load().await
+    }
+
+    async fn writer_holder(&self) -> Option<RepoRef> {
+        // whoever writes to the index holds the lease of the repository it's indexing
+        FileCache::leased_repos(&self.sql)
+            .await
+            .ok()?
+            .into_iter()
+            .next()
+    }
+
+    async fn after_commit(&self) -> Result<()> {
+        let committed = std::mem::take(&mut *self.uncommitted_caches.lock().unwrap());
+        for (reporef, cache_snapshot, run) in committed {
+            let file_cache = FileCache::for_repo(&self.sql, &reporef);
+            file_cache.persist(cache_snapshot).await?;
+
+            if let Err(err) = file_cache.record_index_run(&run).await {
+                warn!(%err, "failed to record indexing run");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn discard_uncommitted(&self) {
+        self.uncommitted_caches.lock().unwrap().clear();
+    }
+}
+
+/// Explain how a document scores for `query`, or return `None` if it doesn't match it.
+fn explain(
+    query: &dyn tantivy::query::Query,
+    searcher: &tantivy::Searcher,
+    addr: DocAddress,
+) -> Result<Option<Explanation>> {
+    match query.explain(searcher, addr) {
+        Ok(explanation) => Ok(Some(explanation)),
+        // this is how tantivy reports documents that don't match
+        Err(TantivyError::InvalidArgument(_)) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Commit the documents written so far, along with the matching state of
+/// the file cache, so an interrupted run doesn't have to start over.
+///
+/// This must only be called between batches, once every worker is done:
+/// files are entered into the cache before their document is written.
+async fn commit_batch(
+    writer: &SharedWriter,
+    langs: &LangDictionary,
+    file_cache: &FileCache<'_>,
+    cache_snapshot: &FileCacheSnapshot,
+) -> Result<()> {
+    // the documents refer to languages by id, which must outlive them
+    langs.persist().await?;
+    file_cache.begin_commit().await?;
+    writer.write().unwrap().commit()?;
+    let persisted = file_cache.persist(cache_snapshot.clone()).await?;
+    trace!(?persisted, "committed batch");
+
+    Ok(())
 }
 
 impl Indexer<File> {
@@ -169,64 +805,48 @@ impl Indexer<File> {
         query_str: &str,
         branch: Option<&str>,
         limit: usize,
-    ) -> impl Iterator<Item = FileDocument> + '_ {
+    ) -> Result<impl Iterator<Item = FileDocument> + '_> {
+        let limit = self.clamp_limit(limit);
+
         // lifted from query::compiler
         let reader = self.reader.read().await;
         let searcher = reader.searcher();
-        let collector = TopDocs::with_limit(100);
+        let collector = TopDocs::with_limit(limit.max(1));
         let file_source = &self.source;
 
         // hits is a mapping between a document address and the number of trigrams in it that
         // matched the query
-        let repo_ref_term = Term::from_field_text(self.source.repo_ref, &repo_ref.to_string());
-        let branch_term = branch
-            .map(|b| {
-                trigrams(b)
-                    .map(|token| Term::from_field_text(self.source.branches, token.as_str()))
-                    .map(|term| TermQuery::new(term, IndexRecordOption::Basic))
-                    .map(Box::new)
-                    .map(|q| q as Box<dyn Query>)
-                    .collect::<Vec<_>>()
-            })
-            .map(BooleanQuery::intersection);
-        let mut hits = trigrams(query_str)
-            .flat_map(|s| case_permutations(s.as_str()))
-            .map(|token| Term::from_field_text(self.source.relative_path, token.as_str()))
-            .map(|term| {
-                let mut query: Vec<Box<dyn Query>> = vec![
-                    Box::new(TermQuery::new(term, IndexRecordOption::Basic)),
-                    Box::new(TermQuery::new(
-                        repo_ref_term.clone(),
-                        IndexRecordOption::Basic,
-                    )),
-                ];
-
-                if let Some(b) = branch_term.as_ref() {
-                    query.push(Box::new(b.clone()));
-                };
+        let filter = FileQueryBuilder::new(file_source, searcher.index())
+            .context("failed to build file query")?
+            .repo(repo_ref)
+            .branch(branch);
 
-                BooleanQuery::intersection(query)
-            })
-            .flat_map(|query| {
-                searcher
-                    .search(&query, &collector)
-                    .expect("failed to search index")
-                    .into_iter()
-                    .map(move |(_, addr)| addr)
-            })
-            .fold(HashMap::new(), |mut map: HashMap<_, usize>, hit| {
-                *map.entry(hit).or_insert(0) += 1;
-                map
-            })
+        let mut counts: HashMap<_, usize> = HashMap::new();
+        for token in trigrams(query_str).flat_map(|s| case_permutations(s.as_str())) {
+            let term = Term::from_field_text(self.source.relative_path, token.as_str());
+            let query = filter
+                .clone()
+                .clause(Box::new(TermQuery::new(term, IndexRecordOption::Basic)))
+                .build();
+
+            for (_, addr) in searcher
+                .search(&query, &collector)
+                .context("failed to search index")?
+            {
+                *counts.entry(addr).or_insert(0) += 1;
+            }
+        }
+
+        let mut hits = counts
             .into_iter()
-            .map(move |(addr, count)| {
+            .map(|(addr, count)| {
                 let retrieved_doc = searcher
                     .doc(addr)
-                    .expect("failed to get document by address");
+                    .context("failed to get document by address")?;
                 let doc = FileReader.read_document(file_source, retrieved_doc);
-                (doc, count)
+                Ok((doc, count))
             })
-            .collect::<Vec<_>>();
+            .collect::<Result<Vec<_>>>()?;
 
         // order hits in
         // - decsending order of number of matched trigrams
@@ -259,7 +879,8 @@ impl Indexer<File> {
 
         // if the regex filter fails to build for some reason, the filter defaults to returning
         // false and zero results are produced
-        hits.into_iter()
+        Ok(hits
+            .into_iter()
             .map(|(doc, _)| doc)
             .filter(move |doc| {
                 regex_filter
@@ -268,512 +889,6705 @@ impl Indexer<File> {
                     .unwrap_or_default()
             })
             .filter(|doc| !doc.relative_path.ends_with('/')) // omit directories
-            .take(limit)
+            .take(limit))
     }
 
-    pub async fn by_path(
-        &self,
-        repo_ref: &RepoRef,
-        relative_path: &str,
-        branch: Option<&str>,
-    ) -> Result<Option<ContentDocument>> {
-        let reader = self.reader.read().await;
-        let searcher = reader.searcher();
+    /// Make the file caches of repositories whose commit was interrupted match the index.
+    ///
+    /// The index is taken as the truth: the cache of each of these repositories is replaced
+    /// with the keys of its documents, so the next run re-indexes the files that didn't make it
+    /// to the index, and deletes the documents of files that are gone. Repositories leased by
+    /// another process are left to it. The stats of the files only the index has are taken from
+    /// their stored content, which is empty for files indexed by path only.
+    ///
+    /// Returns the repositories that were reconciled.
+    pub async fn reconcile_file_caches(&self) -> Result<Vec<RepoRef>> {
+        let sql = &self.source.sql;
+        let interrupted = FileCache::interrupted_commits(sql).await?;
+        if interrupted.is_empty() {
+            return Ok(vec![]);
+        }
 
-        let file_index = searcher.index();
+        let searcher = self.reader.read().await.searcher();
+        let mut reconciled = vec![];
+        for reporef in interrupted {
+            let file_cache = FileCache::for_repo(sql, &reporef);
+            let Some(lease) = file_cache.lease().await? else {
+                continue;
+            };
 
-        // query the `relative_path` field of the `File` index, using tantivy's query language
-        //
-        // XXX: can we use the bloop query language here instead?
-        let query_parser = QueryParser::for_index(
-            file_index,
-            vec![self.source.repo_ref, self.source.relative_path],
-        );
+            let query = TermQuery::new(
+                Term::from_field_text(self.repo_ref, &reporef.to_string()),
+                IndexRecordOption::Basic,
+            );
+            let cache_snapshot = FileCacheSnapshot::default();
+            for addr in searcher.search(&query, &DocSetCollector)? {
+                let doc = searcher.doc(addr)?;
+                let Some(key) = doc.get_first(self.unique_hash).and_then(|v| v.as_text()) else {
+                    continue;
+                };
+                let is_dir = doc
+                    .get_first(self.is_directory)
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or_default();
+                let stats = match doc.get_first(self.content).and_then(|v| v.as_text()) {
+                    Some(content) if !is_dir => RepoStats::file(content),
+                    _ => RepoStats::default(),
+                };
+                _ = cache_snapshot.insert(key.to_owned(), stats.into());
+            }
+
+            let stats = file_cache.persist(cache_snapshot).await?;
+            warn!(%reporef, ?stats, "reconciled file cache after an interrupted commit");
+            lease.release().await?;
+            reconciled.push(reporef);
+        }
+
+        Ok(reconciled)
+    }
 
-        let mut query_string =
-            format!(r#"repo_ref:"{repo_ref}" AND relative_path:"{relative_path}""#);
+    /// Write a copy of the committed file index and the SQLite database to `dest`, which must
+    /// not exist yet.
+    ///
+    /// The copy holds the writer lock of the index throughout, so no commit can land halfway
+    /// through, and the bundle matches a single commit. This fails if a writer is already open,
+    /// so see [`Indexes::snapshot`] to wait for other writers instead. With `qdrant`, qdrant
+    /// also takes a snapshot of the semantic collection, which is named in the manifest.
+    ///
+    /// [`Indexes::snapshot`]: super::Indexes::snapshot
+    pub async fn snapshot(&self, dest: &Path, qdrant: bool) -> Result<SnapshotManifest> {
+        use tantivy::directory::Directory;
+
+        if dest.exists() {
+            bail!("snapshot destination {dest:?} already exists");
+        }
+        let semantic = match (qdrant, &self.source.semantic) {
+            (true, None) => bail!("there is no semantic index to snapshot"),
+            (true, semantic) => semantic.as_ref(),
+            (false, _) => None,
+        };
+
+        // tantivy's lock keeps writers of any process out until this is dropped
+        let _writer = self.write_handle()?;
+        let metas = self.index.load_metas()?;
+        let directory = self.index.directory();
 
-        if let Some(b) = branch {
-            query_string += &format!(r#" AND branches:"{b}""#);
+        let content = dest.join(SNAPSHOT_INDEX_DIR);
+        std::fs::create_dir_all(&content)?;
+
+        // segments list the files of components they may not have, such as deletes
+        let mut index_files = vec![];
+        for path in metas
+            .segments
+            .iter()
+            .flat_map(|segment| segment.list_files())
+        {
+            if directory.exists(&path)? {
+                let data = directory.open_read(&path)?.read_bytes()?;
+                std::fs::write(content.join(&path), data.as_slice())?;
+                index_files.push(path);
+            }
         }
+        index_files.sort();
+
+        // the files tantivy may garbage collect once they're no longer used, as it would have
+        // recorded them itself
+        std::fs::write(
+            content.join(".managed.json"),
+            serde_json::to_vec(&index_files)?,
+        )?;
+        std::fs::write(
+            content.join("meta.json"),
+            directory.atomic_read(Path::new("meta.json"))?,
+        )?;
+
+        sqlx::query("VACUUM INTO ?")
+            .bind(dest.join(SNAPSHOT_DB).to_string_lossy().into_owned())
+            .execute(self.source.sql.as_ref())
+            .await?;
 
-        let query = query_parser
-            .parse_query(&query_string)
-            .expect("failed to parse tantivy query");
+        let qdrant_snapshot = match semantic {
+            Some(semantic) => Some(semantic.create_snapshot().await?),
+            None => None,
+        };
+
+        let config = &self.source.config;
+        let manifest = SnapshotManifest {
+            version: IndexVersion {
+                lexical: config
+                    .corpus_mode
+                    .index_version(config.min_ngram_len, config.index_content_prose),
+                semantic: config.payload_schema.index_version(),
+            },
+            created_unix_secs: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs(),
+            index_files,
+            qdrant_snapshot,
+        };
+        std::fs::write(
+            dest.join(SNAPSHOT_MANIFEST),
+            serde_json::to_vec_pretty(&manifest)?,
+        )?;
 
-        self.top_hit(query, searcher).await
+        info!(
+            ?dest,
+            files = manifest.index_files.len(),
+            "wrote index snapshot"
+        );
+        Ok(manifest)
     }
 
-    async fn top_hit(
-        &self,
-        query: Box<dyn Query>,
-        searcher: tantivy::Searcher,
-    ) -> Result<Option<ContentDocument>> {
-        let file_source = &self.source;
+    /// Swap a bundle written by [`Indexer::snapshot`] in for the file index and the database
+    /// of the data directory of `config`.
+    ///
+    /// This has to run before the index or the database are opened, as it replaces their files.
+    /// Bundles written with other index versions than this build's are rejected. The bundle is
+    /// staged in a temporary directory next to the current files, which are only replaced once
+    /// the copy is complete, and the bundle itself is left as it was. A restore that failed is
+    /// cleaned up by the next one. Qdrant snapshots are restored through qdrant.
+    pub fn restore(bundle: &Path, config: &crate::Configuration) -> Result<SnapshotManifest> {
+        let manifest_path = bundle.join(SNAPSHOT_MANIFEST);
+        let manifest: SnapshotManifest = serde_json::from_reader(
+            std::fs::File::open(&manifest_path)
+                .with_context(|| format!("failed to open {manifest_path:?}"))?,
+        )?;
+
+        let version = IndexVersion {
+            lexical: config
+                .corpus_mode
+                .index_version(config.min_ngram_len, config.index_content_prose),
+            semantic: config.payload_schema.index_version(),
+        };
+        if manifest.version != version {
+            bail!(
+                "snapshot was written with index version {:?}, but this build uses {version:?}",
+                manifest.version
+            );
+        }
 
-        let collector = TopDocs::with_limit(1);
-        let search_results = searcher
-            .search(&query, &collector)
-            .expect("failed to search index");
+        let index_dir = &config.index_dir;
+        let staging = index_dir.join("restore.tmp");
+        if staging.exists() {
+            std::fs::remove_dir_all(&staging)?;
+        }
 
-        match search_results.as_slice() {
-            // no paths matched, the input path was not well formed
-            [] => Ok(None),
+        let staged = staging.join(SNAPSHOT_INDEX_DIR);
+        let staged_db = staging.join(SNAPSHOT_DB);
+        std::fs::create_dir_all(&staged)?;
+        for entry in std::fs::read_dir(bundle.join(SNAPSHOT_INDEX_DIR))? {
+            let entry = entry?;
+            std::fs::copy(entry.path(), staged.join(entry.file_name()))?;
+        }
+        std::fs::copy(bundle.join(SNAPSHOT_DB), &staged_db)?;
 
-            // exactly one path, good
-            [(_, doc_addr)] => {
-                let retrieved_doc = searcher
-                    .doc(*doc_addr)
-                    .expect("failed to get document by address");
-                Ok(Some(
-                    ContentReader.read_document(file_source, retrieved_doc),
-                ))
-            }
+        let content = index_dir.join("content");
+        if content.exists() {
+            std::fs::rename(&content, staging.join("replaced"))?;
+        }
+        std::fs::rename(&staged, &content)?;
 
-            // more than one path matched, this can occur when top docs is no
-            // longer limited to 1 and the index contains dupes
-            _ => {
-                warn!("TopDocs is not limited to 1 and index contains duplicates");
-                Err(anyhow::Error::msg("multiple paths returned"))
+        // the write-ahead log of the replaced database must not be applied to the restored one
+        for stale in ["bleep.db-wal", "bleep.db-shm"] {
+            match std::fs::remove_file(index_dir.join(stale)) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
             }
         }
+        std::fs::rename(&staged_db, index_dir.join("bleep.db"))?;
+
+        // otherwise the restored index is thrown away as outdated on startup
+        config.source.save_index_version(&version)?;
+        std::fs::remove_dir_all(&staging)?;
+
+        info!(?bundle, ?index_dir, "restored index snapshot");
+        Ok(manifest)
     }
 
-    // Produce all files in a repo
-    //
-    // TODO: Look at this again when:
-    //  - directory retrieval is ready
-    //  - unified referencing is ready
-    pub async fn by_repo<S: AsRef<str>>(
+    /// Check that the index is on disk and can be searched.
+    ///
+    /// This reads the index metadata and runs a query for a single document, so it's cheap enough
+    /// for frequent readiness probes.
+    pub async fn healthcheck(&self) -> Result<(), FileIndexError> {
+        self.index.load_metas().map_err(FileIndexError::Corrupt)?;
+        self.reader
+            .read()
+            .await
+            .searcher()
+            .search(&AllQuery, &TopDocs::with_limit(1))
+            .map_err(FileIndexError::Corrupt)?;
+
+        Ok(())
+    }
+
+    /// Look up the document of a file.
+    ///
+    /// The path may be a part of the stored path, as long as a single file
+    /// matches it. An exact match always wins over longer paths.
+    pub async fn by_path(
         &self,
         repo_ref: &RepoRef,
-        langs: impl Iterator<Item = S>,
+        relative_path: &str,
         branch: Option<&str>,
-    ) -> Vec<ContentDocument> {
+    ) -> Result<ContentDocument, FileIndexError> {
         let reader = self.reader.read().await;
         let searcher = reader.searcher();
 
-        let mut query = vec![];
+        let filter = FileQueryBuilder::new(&self.source, searcher.index())
+            .map_err(FileIndexError::QueryParse)?
+            .repo(repo_ref)
+            .branch(branch);
 
-        // repo query
-        query.push(Box::new(TermQuery::new(
-            Term::from_field_text(self.source.repo_ref, &repo_ref.to_string()),
-            IndexRecordOption::Basic,
-        )) as Box<dyn Query>);
-
-        let branch_term = branch
-            .map(|b| {
-                trigrams(b)
-                    .map(|token| Term::from_field_text(self.source.branches, token.as_str()))
-                    .map(|term| TermQuery::new(term, IndexRecordOption::Basic))
-                    .map(Box::new)
-                    .map(|q| q as Box<dyn Query>)
-                    .collect::<Vec<_>>()
-            })
-            .map(BooleanQuery::intersection);
-        if let Some(b) = branch_term {
-            query.push(Box::new(b) as Box<dyn Query>);
-        };
-
-        query.push({
-            let queries = langs
-                .map(|lang| {
-                    Box::new(TermQuery::new(
-                        Term::from_field_bytes(
-                            self.source.lang,
-                            lang.as_ref().to_ascii_lowercase().as_bytes(),
-                        ),
-                        IndexRecordOption::Basic,
-                    )) as Box<dyn Query>
-                })
-                .collect::<Vec<_>>();
-            Box::new(BooleanQuery::union(queries))
-        });
+        self.top_hit(relative_path, filter, searcher).await
+    }
 
-        let query = BooleanQuery::intersection(query);
-        let collector = TopDocs::with_limit(500);
-        searcher
-            .search(&query, &collector)
-            .expect("failed to search index")
-            .into_par_iter()
-            .map(|(_, doc_addr)| {
-                let retrieved_doc = searcher
-                    .doc(doc_addr)
-                    .expect("failed to get document by address");
-                ContentReader.read_document(&self.source, retrieved_doc)
-            })
-            .collect()
+    /// The size of the files of a repository, as of its last committed index.
+    ///
+    /// This reads the sums kept with the file cache, see [`RepoStats`], so it takes the same time
+    /// however large the repository is.
+    pub async fn repo_stats(&self, repo_ref: &RepoRef) -> Result<RepoStats> {
+        FileCache::for_repo(&self.source.sql, repo_ref)
+            .repo_stats()
+            .await
     }
-}
 
-impl File {
-    #[tracing::instrument(fields(repo=%workload.repo_ref, entry_disk_path=?workload.dir_entry.path()), skip_all)]
-    fn worker(&self, workload: Workload<'_>, writer: &IndexWriter) -> Result<()> {
-        let Workload {
-            repo_ref,
-            repo_disk_path,
-            repo_name,
-            repo_metadata,
-            file_cache,
-            cache_snapshot,
+    /// Whether `content` is what's indexed for the file at `path` in `repo`, as of the last
+    /// committed index.
+    ///
+    /// The file is hashed like indexing would, and the hash looked up in the file cache, so this
+    /// reads neither the file nor the semantic index. File watchers can use it to skip saves
+    /// that didn't change anything. `path` is relative to the repository, or a path on disk
+    /// inside it. The file is taken to be on `HEAD` alone, as files walked on disk are.
+    pub async fn is_fresh(
+        &self,
+        reporef: &RepoRef,
+        repo: &Repository,
+        path: &Path,
+        content: &str,
+    ) -> Result<bool> {
+        let repo_path = path.strip_prefix(&repo.disk_path).unwrap_or(path);
+        let path_root = repo.resolve_path_root()?;
+        let Some(relative_path) = root_relative(repo_path, path_root.as_deref()) else {
+            return Ok(false);
+        };
+
+        let metadata = repo.get_repo_metadata().await;
+        let dir_entry = RepoDirEntry::File(file_entry(
+            repo.disk_path.join(repo_path).to_string_lossy().to_string(),
+            content.as_bytes().to_vec(),
+            vec!["HEAD".into()],
+        ));
+        let Some(entry) = self.source.prepare_entry(
+            &reporef.to_string(),
+            &repo.disk_path,
+            &metadata,
+            repo_path,
+            &relative_path,
+            self.source.embeds(reporef, repo),
+            self.source.config.compute_line_blame && metadata.last_commit_unix_secs.is_some(),
             dir_entry,
-        } = workload;
+        ) else {
+            // a skipped file may still have an older document to drop
+            return Ok(false);
+        };
 
-        #[cfg(feature = "debug")]
-        let start = Instant::now();
-        trace!("processing file");
+        FileCache::for_repo(&self.source.sql, reporef)
+            .is_fresh(&entry.tantivy_hash)
+            .await
+    }
 
-        let relative_path = {
-            let entry_srcpath = PathBuf::from(dir_entry.path().ok_or(anyhow::anyhow!(
-                "dir entry is not a valid file or directory"
-            ))?);
-            entry_srcpath
-                .strip_prefix(repo_disk_path)
+    /// Check the committed documents of `repo` against the files that indexing it would write.
+    ///
+    /// Files are walked the way [`Indexable::index_repository`] walks them, so ignore rules and
+    /// branch filters apply, and hashed by [`File::prepare_entry`], so a file is stale exactly
+    /// when the next run would write it again. Nothing is written, and directories aren't
+    /// checked.
+    pub async fn verify_repo(&self, reporef: &RepoRef, repo: &Repository) -> Result<VerifyReport> {
+        let metadata = repo.get_repo_metadata().await;
+        let path_root = repo.resolve_path_root()?;
+        let line_blame =
+            self.source.config.compute_line_blame && metadata.last_commit_unix_secs.is_some();
+        let repo_ref = reporef.to_string();
+        let semantic = self.source.embeds(reporef, repo);
+
+        // hashes of the files that would be indexed, by relative path
+        let on_disk = std::sync::Mutex::new(HashMap::new());
+        let hash_entry = |dir_entry: RepoDirEntry| {
+            let (RepoDirEntry::File(_), Some(path)) = (&dir_entry, dir_entry.path()) else {
+                return;
+            };
+            let path = PathBuf::from(path);
+            let repo_path = path
+                .strip_prefix(&repo.disk_path)
                 .map(ToOwned::to_owned)
-                .unwrap_or(entry_srcpath)
-        };
-        let entry_pathbuf = repo_disk_path.join(&relative_path);
+                .unwrap_or(path);
+            let Some(relative_path) = root_relative(&repo_path, path_root.as_deref()) else {
+                return;
+            };
 
-        let semantic_hash = {
-            let mut hash = blake3::Hasher::new();
-            hash.update(crate::state::SCHEMA_VERSION.as_bytes());
-            hash.update(relative_path.to_string_lossy().as_ref().as_ref());
-            hash.update(repo_ref.as_bytes());
-            hash.update(dir_entry.buffer().unwrap_or_default().as_bytes());
-            hash.finalize().to_hex().to_string()
+            let Some(entry) = self.source.prepare_entry(
+                &repo_ref,
+                &repo.disk_path,
+                &metadata,
+                &repo_path,
+                &relative_path,
+                semantic,
+                line_blame,
+                dir_entry,
+            ) else {
+                return;
+            };
+
+            let relative_path = relative_path.to_string_lossy().into_owned();
+            #[cfg(windows)]
+            let relative_path = relative_path.replace('\\', "/");
+            on_disk
+                .lock()
+                .unwrap()
+                .insert(relative_path, entry.tantivy_hash);
         };
 
-        let tantivy_hash = {
-            let branch_list = dir_entry.branches().unwrap_or_default();
-            let mut hash = blake3::Hasher::new();
-            hash.update(semantic_hash.as_ref());
-            hash.update(branch_list.join("\n").as_bytes());
-            hash.finalize().to_hex().to_string()
+        let (progress, _) = tokio::sync::broadcast::channel(1);
+        let pipes = SyncPipes::new(reporef.clone(), None, progress);
+        let large_files = self.source.lines_per_shard().is_some();
+        if metadata.last_commit_unix_secs.is_some() {
+            GitWalker::open_repository(
+                reporef,
+                &repo.disk_path,
+                repo.branch_filter.as_ref().map(Into::into),
+            )?
+            .index_large_files(large_files)
+            .max_file_size(self.source.config.max_file_size_bytes)
+            .for_each(&pipes, hash_entry);
+        } else {
+            FileWalker::index_directory(&repo.disk_path, self.source.config.symlink_mode)
+                .index_large_files(large_files)
+                .max_file_size(self.source.config.max_file_size_bytes)
+                .for_each(&pipes, hash_entry);
+        }
+        let on_disk = on_disk.into_inner().unwrap();
+
+        let reader = self.reader.read().await;
+        let searcher = reader.searcher();
+        let query = FileQueryBuilder::new(&self.source, searcher.index())?
+            .repo(reporef)
+            .build();
+
+        // a file that is being re-indexed can have more than one document
+        let mut indexed = HashMap::<String, Vec<String>>::new();
+        for addr in searcher.search(&query, &DocSetCollector)? {
+            let doc = searcher.doc(addr)?;
+            let text = |field| {
+                doc.get_first(field)
+                    .and_then(|value| value.as_text())
+                    .unwrap_or_default()
+                    .to_owned()
+            };
+
+            let path = text(self.source.relative_path);
+            if !path.is_empty() && !path.ends_with('/') {
+                indexed
+                    .entry(path)
+                    .or_default()
+                    .push(text(self.source.unique_hash));
+            }
+        }
+
+        let mut report = VerifyReport::default();
+        for (path, hash) in &on_disk {
+            match indexed.get(path) {
+                None => report.missing.push(path.clone()),
+                Some(hashes) if !hashes.contains(hash) => report.stale.push(path.clone()),
+                Some(_) => {}
+            }
+        }
+        report.orphaned = indexed
+            .into_keys()
+            .filter(|path| !on_disk.contains_key(path))
+            .collect();
+
+        report.missing.sort();
+        report.stale.sort();
+        report.orphaned.sort();
+        Ok(report)
+    }
+
+    /// Fetch the documents of several files of a repository by their exact paths, for callers
+    /// that would otherwise call [`Indexer::by_path`] for each of them.
+    ///
+    /// The documents are found by a single search. Paths that aren't indexed are left out of
+    /// the map, and like `by_path`, the freshest document of a path is picked and the shards of
+    /// a large file are put back together. At most [`MAX_PATHS`] paths can be fetched at once.
+    pub async fn by_paths(
+        &self,
+        repo_ref: &RepoRef,
+        relative_paths: &[&str],
+    ) -> Result<HashMap<String, ContentDocument>> {
+        if relative_paths.len() > MAX_PATHS {
+            bail!(
+                "can't fetch more than {MAX_PATHS} paths at once, got {}",
+                relative_paths.len()
+            );
+        }
+
+        let reader = self.reader.read().await;
+        let searcher = reader.searcher();
+
+        let query = FileQueryBuilder::new(&self.source, searcher.index())?
+            .repo(repo_ref)
+            .paths_any(relative_paths)
+            .build();
+
+        // a file that is being re-indexed can have more than one document
+        let mut versions = HashMap::<String, Vec<_>>::new();
+        for addr in self.collect_capped(&searcher, &query)? {
+            let last_commit = searcher
+                .segment_reader(addr.segment_ord)
+                .fast_fields()
+                .u64(self.source.last_commit_unix_seconds)?
+                .get_val(addr.doc_id);
+            let doc = searcher.doc(addr)?;
+            let unique_hash = doc
+                .get_first(self.source.unique_hash)
+                .and_then(|value| value.as_text())
+                .unwrap_or_default()
+                .to_owned();
+            let doc = ContentReader.read_document(&self.source, doc);
+            versions
+                .entry(doc.relative_path.clone())
+                .or_default()
+                .push((last_commit, (unique_hash, doc)));
+        }
+
+        let mut docs = HashMap::new();
+        for (path, versions) in versions {
+            let Some((unique_hash, doc)) = pick_freshest(versions) else {
+                continue;
+            };
+            let doc = match doc.shard_index {
+                Some(_) => self.reassemble_shards(&searcher, &unique_hash)?,
+                None => doc,
+            };
+            docs.insert(path, doc);
+        }
+
+        Ok(docs)
+    }
+
+    /// List every document stored for exactly `relative_path`, freshest first.
+    ///
+    /// There is normally a single one, but a file that is being re-indexed can have more until
+    /// the older documents are deleted. [`Indexer::by_path`] picks the freshest of them, and this
+    /// is for callers that want to look at the duplicates, or pick one another way. Documents
+    /// with the same commit time keep the order they are stored in.
+    pub async fn by_path_all_versions(
+        &self,
+        repo_ref: &RepoRef,
+        relative_path: &str,
+    ) -> Result<Vec<DocumentVersion>> {
+        let reader = self.reader.read().await;
+        let searcher = reader.searcher();
+
+        let query = FileQueryBuilder::new(&self.source, searcher.index())?
+            .repo(repo_ref)
+            .exact_path(relative_path)
+            .build();
+
+        let mut versions = self
+            .collect_capped(&searcher, &query)?
+            .into_iter()
+            .map(|addr| {
+                let last_commit_unix_seconds = searcher
+                    .segment_reader(addr.segment_ord)
+                    .fast_fields()
+                    .u64(self.source.last_commit_unix_seconds)?
+                    .get_val(addr.doc_id);
+                let doc = ContentReader.read_document(&self.source, searcher.doc(addr)?);
+
+                Ok(DocumentVersion {
+                    doc,
+                    last_commit_unix_seconds,
+                    addr,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        versions.sort_by_key(|version| std::cmp::Reverse(version.last_commit_unix_seconds));
+
+        Ok(versions)
+    }
+
+    /// Read a byte range of the stored content of a file, looked up like [`Indexer::by_path`].
+    ///
+    /// Ranges that end past the content are clamped to its length, so `0..usize::MAX` reads the
+    /// whole file. A range that starts past the content, or ends before it starts, is an error.
+    /// The range doesn't have to fall on character boundaries.
+    pub async fn file_body_bytes(
+        &self,
+        repo_ref: &RepoRef,
+        relative_path: &str,
+        branch: Option<&str>,
+        range: Range<usize>,
+    ) -> Result<FileBytes, FileIndexError> {
+        let doc = self.by_path(repo_ref, relative_path, branch).await?;
+        let content = doc.content.as_bytes();
+        let total_len = content.len();
+
+        if range.start > range.end || range.start > total_len {
+            return Err(FileIndexError::BadRange {
+                start: range.start,
+                end: range.end,
+                len: total_len,
+            });
+        }
+
+        Ok(FileBytes {
+            bytes: content[range.start..range.end.min(total_len)].to_vec(),
+            total_len,
+        })
+    }
+
+    /// Look up the files of a repository that have a symbol named after any of `names`.
+    ///
+    /// This runs a single query for all the names, instead of a search for each of them. Every
+    /// file is returned with the names it matched, which are checked against its symbols, so
+    /// symbols that merely contain a name don't count.
+    ///
+    /// At most [`MAX_SYMBOL_NAMES`] names can be looked up at once.
+    pub async fn by_symbols(
+        &self,
+        repo_ref: &RepoRef,
+        names: &[&str],
+        limit: usize,
+    ) -> Result<Vec<SymbolMatch>> {
+        if names.len() > MAX_SYMBOL_NAMES {
+            bail!(
+                "can't look up more than {MAX_SYMBOL_NAMES} symbols at once, got {}",
+                names.len()
+            );
+        }
+
+        let limit = self.clamp_limit(limit);
+        if limit == 0 {
+            return Ok(vec![]);
+        }
+
+        let reader = self.reader.read().await;
+        let searcher = reader.searcher();
+
+        let query = FileQueryBuilder::new(&self.source, searcher.index())?
+            .repo(repo_ref)
+            .symbols_any(names)
+            .build();
+
+        // candidates that only contain a name are dropped, so keep going until we have enough
+        let mut matches = vec![];
+        let mut offset = 0;
+        while matches.len() < limit {
+            let page = searcher.search(&query, &TopDocs::with_limit(limit).and_offset(offset))?;
+            offset += page.len();
+
+            for (_, addr) in &page {
+                let doc = ContentReader.read_document(&self.source, searcher.doc(*addr)?);
+                let symbols = doc
+                    .symbol_locations
+                    .list()
+                    .into_iter()
+                    .filter_map(|sym| doc.content.get(sym.range.start.byte..sym.range.end.byte))
+                    .collect::<HashSet<_>>();
+
+                let matched = names
+                    .iter()
+                    .filter(|name| symbols.contains(**name))
+                    .map(|name| name.to_string())
+                    .collect::<Vec<_>>();
+
+                if !matched.is_empty() && matches.len() < limit {
+                    matches.push(SymbolMatch {
+                        doc,
+                        names: matched,
+                    });
+                }
+            }
+
+            if page.len() < limit {
+                break;
+            }
+        }
+
+        Ok(matches)
+    }
+
+    /// Find every occurrence of a symbol named `symbol` in a repository, for going to its
+    /// definition without parsing any files.
+    ///
+    /// Names have to match exactly. Returns at most `limit` occurrences, grouped by file, in the
+    /// order of their position in the file.
+    pub async fn by_symbol(
+        &self,
+        repo_ref: &RepoRef,
+        symbol: &str,
+        limit: usize,
+    ) -> Result<Vec<SymbolHit>> {
+        let limit = self.clamp_limit(limit);
+        if limit == 0 {
+            return Ok(vec![]);
+        }
+
+        let reader = self.reader.read().await;
+        let searcher = reader.searcher();
+
+        let query = FileQueryBuilder::new(&self.source, searcher.index())?
+            .repo(repo_ref)
+            .symbols_any([symbol])
+            .build();
+
+        // candidates that only contain the name are dropped, so keep going until we have enough
+        let mut hits = vec![];
+        let mut offset = 0;
+        while hits.len() < limit {
+            let page = searcher.search(&query, &TopDocs::with_limit(limit).and_offset(offset))?;
+            offset += page.len();
+
+            for (_, addr) in &page {
+                let doc = ContentReader.read_document(&self.source, searcher.doc(*addr)?);
+                let mut symbols = doc.symbol_locations.list();
+                symbols.sort_by_key(|sym| sym.range);
+
+                let occurrences = symbols
+                    .into_iter()
+                    .filter(|sym| {
+                        doc.content.get(sym.range.start.byte..sym.range.end.byte) == Some(symbol)
+                    })
+                    .map(|sym| SymbolHit {
+                        relative_path: doc.relative_path.clone(),
+                        range: sym.range,
+                        kind: sym.kind,
+                    });
+
+                hits.extend(occurrences.take(limit - hits.len()));
+            }
+
+            if page.len() < limit {
+                break;
+            }
+        }
+
+        Ok(hits)
+    }
+
+    /// Find the symbols of a repository by name, kind, and language of their file.
+    ///
+    /// Every filter is optional, and they all have to match. Names and kinds have to match
+    /// exactly, with kinds compared regardless of case, as in `function` or `struct`. Languages
+    /// may be given by alias.
+    ///
+    /// Returns at most `limit` symbols, in the order of their files in the index, then of their
+    /// position in the file.
+    pub async fn find_symbols(
+        &self,
+        repo_ref: &RepoRef,
+        name: Option<&str>,
+        kind: Option<&str>,
+        lang: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<FoundSymbol>> {
+        let limit = self.clamp_limit(limit);
+        if limit == 0 {
+            return Ok(vec![]);
+        }
+
+        let reader = self.reader.read().await;
+        let searcher = reader.searcher();
+
+        let mut builder = FileQueryBuilder::new(&self.source, searcher.index())?
+            .repo(repo_ref)
+            .clause(Box::new(RangeQuery::new_u64(
+                self.source.symbol_count,
+                1..u64::MAX,
+            )));
+        if let Some(name) = name {
+            builder = builder.symbols_any([name]);
+        }
+        if let Some(lang) = lang {
+            builder = builder.langs([lang]);
+        }
+        let query = builder.build();
+
+        let mut found = vec![];
+        let mut offset = 0;
+        while found.len() < limit {
+            let page = searcher.search(&query, &TopDocs::with_limit(limit).and_offset(offset))?;
+            offset += page.len();
+
+            for (_, addr) in &page {
+                let doc = ContentReader.read_document(&self.source, searcher.doc(*addr)?);
+                let symbols = doc
+                    .outline()
+                    .into_iter()
+                    .filter(|sym| name.map_or(true, |name| sym.text == name))
+                    .filter(|sym| kind.map_or(true, |kind| sym.kind.eq_ignore_ascii_case(kind)))
+                    .map(|symbol| FoundSymbol {
+                        relative_path: doc.relative_path.clone(),
+                        lang: doc.lang.clone(),
+                        symbol,
+                    });
+
+                found.extend(symbols.take(limit - found.len()));
+            }
+
+            if page.len() < limit {
+                break;
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Find the files of a repository that import `target`, a path or a module name.
+    ///
+    /// This is a heuristic rather than name resolution: import statements are found with the
+    /// [`ImportPatterns`](super::ImportPatterns) of each language, and the module they import is compared with the
+    /// target by name, ignoring relative prefixes. Files of languages without patterns never
+    /// match. If `lang` is given, only files of that language are searched.
+    ///
+    /// Returns at most `limit` files, sorted by path, with the lines of their matching
+    /// statements. The shards of a large file are searched as one file.
+    pub async fn find_references(
+        &self,
+        repo_ref: &RepoRef,
+        target: &str,
+        lang: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<FileReference>> {
+        let limit = self.clamp_limit(limit);
+        let target = ImportTarget::new(target);
+        let Some(name) = target.name() else {
+            return Ok(vec![]);
         };
 
-        let last_commit = repo_metadata.last_commit_unix_secs.unwrap_or(0);
+        let reader = self.reader.read().await;
+        let searcher = reader.searcher();
 
-        match dir_entry {
-            _ if is_cache_fresh(cache_snapshot, &tantivy_hash, &entry_pathbuf) => {
-                info!("fresh; skipping");
-                return Ok(());
+        // only files that contain the name can import the target
+        let mut name_terms = vec![];
+        searcher
+            .index()
+            .tokenizer_for_field(self.content)?
+            .token_stream(name)
+            .process(&mut |token| {
+                name_terms.push(Box::new(TermQuery::new(
+                    Term::from_field_text(self.content, &token.text),
+                    IndexRecordOption::Basic,
+                )) as Box<dyn Query>)
+            });
+
+        let mut builder = FileQueryBuilder::new(&self.source, searcher.index())?.repo(repo_ref);
+        if !name_terms.is_empty() {
+            builder = builder.clause(Box::new(BooleanQuery::intersection(name_terms)));
+        }
+        if let Some(lang) = lang {
+            builder = builder.langs([lang]);
+        }
+        let query = builder.build();
+
+        let paths = searcher
+            .segment_readers()
+            .iter()
+            .map(|segment_reader| {
+                segment_reader
+                    .fast_fields()
+                    .bytes(self.source.raw_relative_path)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // candidates are read by path, so that the shards of a file are read together
+        let mut addrs = self
+            .collect_capped(&searcher, &query)?
+            .into_iter()
+            .map(|addr| {
+                let path = paths[addr.segment_ord as usize].get_bytes(addr.doc_id);
+                (path, addr)
+            })
+            .collect::<Vec<_>>();
+        addrs.sort_unstable();
+
+        let mut patterns = HashMap::new();
+        let mut references = BTreeMap::<String, FileReference>::new();
+        for (_, addr) in addrs {
+            let doc = ContentReader.read_document(&self.source, searcher.doc(addr)?);
+            if references.len() == limit && !references.contains_key(&doc.relative_path) {
+                break;
             }
-            RepoDirEntry::Dir(dir) => {
-                trace!("writing dir document");
-                let doc = dir.build_document(
-                    self,
-                    repo_name,
-                    relative_path.as_path(),
-                    repo_disk_path,
-                    repo_ref.as_str(),
-                    last_commit,
-                    tantivy_hash,
-                );
-                writer.add_document(doc)?;
-                trace!("dir document written");
+
+            let Some(doc_lang) = doc.lang.clone() else {
+                continue;
+            };
+
+            if !patterns.contains_key(&doc_lang) {
+                let compiled = self.source.config.import_patterns.compile(&doc_lang)?;
+                patterns.insert(doc_lang.clone(), compiled);
             }
-            RepoDirEntry::File(file) => {
-                trace!("writing file document");
-                let doc = file
-                    .build_document(
-                        self,
-                        repo_name,
-                        relative_path.as_path(),
-                        repo_disk_path,
-                        semantic_hash,
-                        tantivy_hash,
-                        entry_pathbuf.as_path(),
-                        repo_ref.as_str(),
-                        last_commit,
-                        repo_metadata,
-                        file_cache,
-                    )
-                    .ok_or(anyhow::anyhow!("failed to build document"))?;
-                writer.add_document(doc)?;
 
-                trace!("file document written");
+            // lines of shards are counted from the start of the whole file
+            let first_line = doc.shard_start.map_or(0, |start| start.line);
+            let content = &doc.content;
+            let lines = target
+                .find_imports(content, &patterns[&doc_lang])
+                .into_iter()
+                .map(|range| {
+                    let start = first_line + content[..range.start].matches('\n').count();
+                    start..start + content[range].trim_end().matches('\n').count() + 1
+                })
+                .collect::<Vec<_>>();
+
+            if !lines.is_empty() {
+                references
+                    .entry(doc.relative_path.clone())
+                    .or_insert(FileReference {
+                        relative_path: doc.relative_path,
+                        lang: doc.lang,
+                        lines: vec![],
+                    })
+                    .lines
+                    .extend(lines);
             }
-            RepoDirEntry::Other => anyhow::bail!("dir entry was neither a file nor a directory"),
         }
 
-        #[cfg(feature = "debug")]
-        {
-            let elapsed = start.elapsed();
-            let time: u64 = elapsed
-                .as_millis()
-                .try_into()
-                .expect("nobody waits this long");
-            self.histogram.write().unwrap().increment(time, 1).unwrap();
+        // the duplicates of a file that is being re-indexed find the same lines
+        Ok(references
+            .into_values()
+            .map(|mut reference| {
+                reference
+                    .lines
+                    .sort_by_key(|lines| (lines.start, lines.end));
+                reference.lines.dedup();
+                reference
+            })
+            .collect())
+    }
 
-            if time
-                > self
-                    .histogram
-                    .read()
-                    .unwrap()
-                    .percentile(99.9)
-                    .unwrap()
-                    .low()
-            {
-                // default console formatter is different when we're debugging. need to print more info here.
-                warn!(?relative_path, ?elapsed, "file took too long to process")
+    /// Estimate how many files of a repository match `query`, without running it.
+    ///
+    /// This only looks up how many documents contain each term of the query, so it's an upper
+    /// bound rather than an exact count: a file matching every trigram of a string doesn't
+    /// necessarily contain the string. Each query of an `or` is bounded by its rarest term, and
+    /// the bounds are added up. Regexes and branches aren't taken into account.
+    pub async fn estimate_hits(&self, repo_ref: &RepoRef, query: &str) -> Result<u64> {
+        let queries = parser::parse(query)?;
+        let reader = self.reader.read().await;
+        let searcher = reader.searcher();
+        let index = searcher.index();
+
+        let repo_hits =
+            searcher.doc_freq(&Term::from_field_text(self.repo_ref, &repo_ref.to_string()))?;
+
+        let mut estimate = 0u64;
+        for query in &queries {
+            let mut literals = vec![];
+            match &query.target {
+                Some(parser::Target::Content(literal)) => literals.push((self.content, literal)),
+                Some(parser::Target::Symbol(literal)) => literals.push((self.symbols, literal)),
+                None => {}
+            }
+            if let Some(path) = &query.path {
+                literals.push((self.relative_path, path));
             }
+
+            let mut hits = repo_hits;
+            if let Some(lang) = &query.lang {
+                // languages that were never interned have no documents
+                hits = match self.langs.id(&parse_alias(lang.clone())) {
+                    Some(id) => hits.min(searcher.doc_freq(&Term::from_field_u64(self.lang, id))?),
+                    None => 0,
+                };
+            }
+
+            for (field, literal) in literals {
+                let Some(text) = literal.as_plain() else {
+                    continue;
+                };
+
+                let mut tokens = vec![];
+                index
+                    .tokenizer_for_field(field)?
+                    .token_stream(&text)
+                    .process(&mut |token| tokens.push(token.text.clone()));
+
+                for token in tokens {
+                    // a file may contain any case of the token
+                    let mut token_hits = 0;
+                    if query.is_case_sensitive() {
+                        token_hits = searcher.doc_freq(&Term::from_field_text(field, &token))?;
+                    } else {
+                        for token in case_permutations(&token) {
+                            token_hits +=
+                                searcher.doc_freq(&Term::from_field_text(field, &token))?;
+                        }
+                    }
+                    hits = hits.min(token_hits);
+                }
+            }
+
+            estimate = estimate.saturating_add(hits);
         }
 
-        Ok(())
+        Ok(estimate.min(repo_hits))
+    }
+
+    /// List the symbols defined in a file, for an outline view.
+    ///
+    /// Returns an empty outline if the file isn't indexed.
+    pub async fn symbol_outline(
+        &self,
+        repo_ref: &RepoRef,
+        relative_path: &str,
+    ) -> Result<Vec<OutlineItem>> {
+        match self.by_path(repo_ref, relative_path, None).await {
+            Ok(doc) => Ok(doc.outline()),
+            Err(FileIndexError::NotFound) => Ok(vec![]),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// List the symbols defined in each of `paths`, for outlines of many files at once.
+    ///
+    /// The documents are fetched by a single search, and their symbols are deserialized in
+    /// parallel. Unlike [`Indexer::symbol_outline`], paths have to match exactly, and the ones
+    /// that aren't indexed are left out of the map.
+    pub async fn symbol_outlines(
+        &self,
+        repo_ref: &RepoRef,
+        paths: &[&str],
+    ) -> Result<HashMap<String, Vec<OutlineItem>>> {
+        let reader = self.reader.read().await;
+        let searcher = reader.searcher();
+
+        let query = FileQueryBuilder::new(&self.source, searcher.index())?
+            .repo(repo_ref)
+            .paths_any(paths)
+            .build();
+        let addrs = self.collect_capped(&searcher, &query)?;
+
+        let options =
+            ReadOptions::CONTENT | ReadOptions::LINE_END_INDICES | ReadOptions::SYMBOL_LOCATIONS;
+        let docs = addrs
+            .into_par_iter()
+            .map(|addr| {
+                let last_commit = searcher
+                    .segment_reader(addr.segment_ord)
+                    .fast_fields()
+                    .u64(self.source.last_commit_unix_seconds)?
+                    .get_val(addr.doc_id);
+                let doc =
+                    ContentReader.read_document_with(&self.source, searcher.doc(addr)?, options);
+                Ok((last_commit, doc))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // a file that is being re-indexed can have more than one document
+        let mut versions = HashMap::<String, Vec<_>>::new();
+        for (last_commit, doc) in docs {
+            versions
+                .entry(doc.relative_path.clone())
+                .or_default()
+                .push((last_commit, doc));
+        }
+
+        Ok(versions
+            .into_par_iter()
+            .filter_map(|(path, versions)| Some((path, pick_freshest(versions)?.outline())))
+            .collect())
+    }
+
+    /// Explain how a file scores for a content search, or why it doesn't match it.
+    ///
+    /// This is meant for relevance debugging. The score is the one computed by tantivy, before
+    /// the ranking heuristics of [`DocumentTweaker`](crate::query::ranking::DocumentTweaker).
+    /// The query gets the defaults of a content search, so vendored files don't match it.
+    pub async fn by_repo_explain(
+        &self,
+        repo_ref: &RepoRef,
+        query: &str,
+        relative_path: &str,
+    ) -> Result<QueryExplanation> {
+        let queries = parser::parse(query)?
+            .into_iter()
+            .filter(|q| ContentReader.query_matches(q))
+            .map(|mut q| {
+                q.vendored.get_or_insert(false);
+                q
+            })
+            .collect::<Vec<_>>();
+        if queries.is_empty() {
+            bail!("query has no content or symbol target");
+        }
+
+        let reader = self.reader.read().await;
+        let searcher = reader.searcher();
+
+        let file_query = FileQueryBuilder::new(&self.source, searcher.index())?
+            .repo(repo_ref)
+            .exact_path(relative_path)
+            .build();
+        let Some(&(_, addr)) = searcher
+            .search(&file_query, &TopDocs::with_limit(1))?
+            .first()
+        else {
+            return Err(FileIndexError::NotFound.into());
+        };
+
+        let compiled = ContentReader.compile(&self.source, queries.iter(), &self.index)?;
+        let Some(explanation) = explain(compiled.as_ref(), &searcher, addr)? else {
+            return Ok(QueryExplanation::Excluded {
+                reasons: self.exclusion_reasons(&queries, &searcher, addr)?,
+            });
+        };
+
+        // search results are further filtered by their content
+        let doc = ContentReader.read_document(&self.source, searcher.doc(addr)?);
+        let targets = queries
+            .iter()
+            .filter_map(|q| Some((q.target.as_ref()?, q.is_case_sensitive())));
+        if !target_regexes(targets)
+            .iter()
+            .any(|re| re.is_match(doc.content.as_bytes()))
+        {
+            return Ok(QueryExplanation::Excluded {
+                reasons: vec!["file contains the trigrams of the target, but not its text".into()],
+            });
+        }
+
+        Ok(QueryExplanation::Matched { explanation })
+    }
+
+    /// List the filters of `queries` that the document at `addr` fails.
+    fn exclusion_reasons(
+        &self,
+        queries: &[parser::Query<'_>],
+        searcher: &tantivy::Searcher,
+        addr: DocAddress,
+    ) -> Result<Vec<String>> {
+        let mut reasons = vec![];
+        for (i, q) in queries.iter().enumerate() {
+            let only = parser::Query {
+                case_sensitive: q.case_sensitive,
+                ..Default::default()
+            };
+            let filters = [
+                (
+                    "repo",
+                    q.repo.clone().map(|repo| parser::Query {
+                        repo: Some(repo),
+                        ..only.clone()
+                    }),
+                ),
+                (
+                    "path",
+                    q.path.clone().map(|path| parser::Query {
+                        path: Some(path),
+                        ..only.clone()
+                    }),
+                ),
+                (
+                    "lang",
+                    q.lang.clone().map(|lang| parser::Query {
+                        lang: Some(lang),
+                        ..only.clone()
+                    }),
+                ),
+                (
+                    "content_type",
+                    q.content_type.clone().map(|content_type| parser::Query {
+                        content_type: Some(content_type),
+                        ..only.clone()
+                    }),
+                ),
+                (
+                    "branch",
+                    q.branch.clone().map(|branch| parser::Query {
+                        branch: Some(branch),
+                        ..only.clone()
+                    }),
+                ),
+                (
+                    "owner",
+                    q.owner.clone().map(|owner| parser::Query {
+                        owner: Some(owner),
+                        ..only.clone()
+                    }),
+                ),
+                (
+                    "tag",
+                    q.tag.clone().map(|tag| parser::Query {
+                        tag: Some(tag),
+                        ..only.clone()
+                    }),
+                ),
+                (
+                    "line_ending",
+                    q.line_ending.clone().map(|line_ending| parser::Query {
+                        line_ending: Some(line_ending),
+                        ..only.clone()
+                    }),
+                ),
+                (
+                    "has_symbols",
+                    q.has_symbols.map(|has_symbols| parser::Query {
+                        has_symbols: Some(has_symbols),
+                        ..only.clone()
+                    }),
+                ),
+                (
+                    "vendored",
+                    q.vendored.map(|vendored| parser::Query {
+                        vendored: Some(vendored),
+                        ..only.clone()
+                    }),
+                ),
+                (
+                    "small",
+                    q.small.map(|small| parser::Query {
+                        small: Some(small),
+                        ..only.clone()
+                    }),
+                ),
+                (
+                    "target",
+                    q.target.clone().map(|target| parser::Query {
+                        target: Some(target),
+                        ..only.clone()
+                    }),
+                ),
+            ];
+
+            for (name, filter) in filters {
+                let Some(filter) = filter else {
+                    continue;
+                };
+
+                let compiled = ContentReader.compile(&self.source, [filter].iter(), &self.index)?;
+                if explain(compiled.as_ref(), searcher, addr)?.is_none() {
+                    reasons.push(match queries.len() {
+                        1 => format!("file does not match the {name} filter"),
+                        _ => format!(
+                            "file does not match the {name} filter of alternative {}",
+                            i + 1
+                        ),
+                    });
+                }
+            }
+        }
+
+        if reasons.is_empty() {
+            reasons.push("file does not match the query".into());
+        }
+
+        Ok(reasons)
+    }
+
+    async fn top_hit(
+        &self,
+        relative_path: &str,
+        filter: FileQueryBuilder<'_>,
+        searcher: tantivy::Searcher,
+    ) -> Result<ContentDocument, FileIndexError> {
+        let file_source = &self.source;
+        let search_error = |source| FileIndexError::Search {
+            path: relative_path.to_owned(),
+            source,
+        };
+
+        // the exact path goes first, as longer paths that contain it can outrank it in the
+        // path search
+        let exact = filter.clone().exact_path(relative_path).build();
+        let mut search_results = self
+            .collect_capped(&searcher, &exact)
+            .map_err(search_error)?;
+
+        if search_results.is_empty() {
+            // leave some headroom for duplicates that can transiently
+            // exist while a file is being re-indexed
+            let collector = TopDocs::with_limit(TOP_HIT_DUPLICATE_HEADROOM);
+            search_results = searcher
+                .search(&filter.path(relative_path).build(), &collector)
+                .map_err(search_error)?
+                .into_iter()
+                .map(|(_, addr)| addr)
+                .collect();
+        }
+
+        let last_commit = |addr: DocAddress| {
+            searcher
+                .segment_reader(addr.segment_ord)
+                .fast_fields()
+                .u64(file_source.last_commit_unix_seconds)
+                .map(|column| column.get_val(addr.doc_id))
+                .unwrap_or_default()
+        };
+
+        let mut hits = search_results
+            .into_iter()
+            .map(|addr| {
+                let retrieved_doc = searcher.doc(addr).map_err(FileIndexError::Corrupt)?;
+                let unique_hash = retrieved_doc
+                    .get_first(file_source.unique_hash)
+                    .and_then(|value| value.as_text())
+                    .unwrap_or_default()
+                    .to_owned();
+                let doc = ContentReader.read_document(file_source, retrieved_doc);
+                Ok((last_commit(addr), (unique_hash, doc)))
+            })
+            .collect::<Result<Vec<_>, FileIndexError>>()?;
+
+        // the path filter also matches longer paths that contain this one
+        if hits
+            .iter()
+            .any(|(_, (_, doc))| doc.relative_path == relative_path)
+        {
+            hits.retain(|(_, (_, doc))| doc.relative_path == relative_path);
+        }
+
+        let paths = hits
+            .iter()
+            .map(|(_, (_, doc))| doc.relative_path.as_str())
+            .collect::<HashSet<_>>()
+            .len();
+        if paths > 1 {
+            return Err(FileIndexError::Ambiguous { count: paths });
+        }
+
+        // the shards of a large file are one version of it
+        let versions = hits
+            .iter()
+            .map(|(_, (unique_hash, _))| unique_hash.as_str())
+            .collect::<HashSet<_>>()
+            .len();
+        if versions > 1 {
+            warn!(
+                count = versions,
+                "index contains duplicates; picking the freshest document"
+            );
+        }
+
+        let (unique_hash, doc) = pick_freshest(hits).ok_or(FileIndexError::NotFound)?;
+        if doc.shard_index.is_none() {
+            return Ok(doc);
+        }
+
+        self.reassemble_shards(&searcher, &unique_hash)
+    }
+
+    /// Put the shards of a large file, as found by the key they share, back together into a
+    /// document of the whole file.
+    ///
+    /// Shards have no symbols or line blame, so neither does the document.
+    fn reassemble_shards(
+        &self,
+        searcher: &tantivy::Searcher,
+        unique_hash: &str,
+    ) -> Result<ContentDocument, FileIndexError> {
+        let query = TermQuery::new(
+            Term::from_field_text(self.source.unique_hash, unique_hash),
+            IndexRecordOption::Basic,
+        );
+        let mut shards = searcher
+            .search(&query, &DocSetCollector)
+            .map_err(FileIndexError::Corrupt)?
+            .into_iter()
+            .map(|addr| {
+                let doc = searcher.doc(addr).map_err(FileIndexError::Corrupt)?;
+                Ok(ContentReader.read_document(&self.source, doc))
+            })
+            .collect::<Result<Vec<_>, FileIndexError>>()?;
+        shards.sort_by_key(|shard| shard.shard_index);
+
+        let mut shards = shards.into_iter();
+        let mut doc = shards.next().ok_or(FileIndexError::NotFound)?;
+        for shard in shards {
+            doc.content.push_str(&shard.content);
+        }
+        doc.line_end_indices = LineIndex::new(&doc.content);
+        doc.shard_index = None;
+        doc.shard_start = None;
+
+        Ok(doc)
+    }
+
+    // Produce all files in a repo, a page at a time
+    //
+    // Documents are ordered by path, so that consecutive pages neither overlap nor skip
+    // documents as long as the index doesn't change in between. The shards of a large file are
+    // put back together, and count as one document.
+    //
+    // TODO: Look at this again when:
+    //  - directory retrieval is ready
+    //  - unified referencing is ready
+    pub async fn by_repo<S: AsRef<str>>(
+        &self,
+        repo_ref: &RepoRef,
+        langs: impl Iterator<Item = S>,
+        branch: Option<&str>,
+        page: Pagination,
+    ) -> Result<RepoPage> {
+        let reader = self.reader.read().await;
+        let searcher = reader.searcher();
+
+        let query = FileQueryBuilder::new(&self.source, searcher.index())
+            .context("failed to build file query")?
+            .repo(repo_ref)
+            .branch(branch)
+            .langs(langs)
+            .first_shards()
+            .build();
+
+        let paths = searcher
+            .segment_readers()
+            .iter()
+            .map(|segment_reader| {
+                segment_reader
+                    .fast_fields()
+                    .bytes(self.source.raw_relative_path)
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .context("failed to read path fast field")?;
+
+        // the address breaks ties between the versions of a file
+        let mut addrs = searcher
+            .search(&query, &DocSetCollector)
+            .with_context(|| format!("failed to search files of {repo_ref}"))?
+            .into_iter()
+            .map(|addr| {
+                let path = paths[addr.segment_ord as usize].get_bytes(addr.doc_id);
+                (path, addr)
+            })
+            .collect::<Vec<_>>();
+        addrs.sort_unstable();
+
+        let total = addrs.len();
+        let docs = addrs
+            .into_iter()
+            .skip(page.offset)
+            .take(self.clamp_limit(page.limit))
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|(_, doc_addr)| {
+                let retrieved_doc = searcher
+                    .doc(doc_addr)
+                    .with_context(|| format!("failed to get document at {doc_addr:?}"))?;
+                let unique_hash = retrieved_doc
+                    .get_first(self.source.unique_hash)
+                    .and_then(|value| value.as_text())
+                    .unwrap_or_default()
+                    .to_owned();
+                let doc = ContentReader.read_document(&self.source, retrieved_doc);
+                match doc.shard_index {
+                    Some(_) => self
+                        .reassemble_shards(&searcher, &unique_hash)
+                        .with_context(|| format!("failed to reassemble {}", doc.relative_path)),
+                    None => Ok(doc),
+                }
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(RepoPage { docs, total })
+    }
+
+    /// List every repository that has at least one live document in the index.
+    ///
+    /// Repo references that fail to parse are skipped.
+    pub async fn all_repos(&self) -> Vec<RepoRef> {
+        let reader = self.reader.read().await;
+        let searcher = reader.searcher();
+
+        let mut repo_refs = BTreeSet::new();
+        for segment_reader in searcher.segment_readers() {
+            if let Err(err) = live_terms(segment_reader, self.source.repo_ref, &mut repo_refs) {
+                warn!(?err, "failed to read repo refs from segment");
+            }
+        }
+
+        repo_refs
+            .into_iter()
+            .filter_map(|repo_ref| match repo_ref.parse() {
+                Ok(parsed) => Some(parsed),
+                Err(err) => {
+                    warn!(%repo_ref, ?err, "skipping unparseable repo ref");
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Delete every repository in the index that matches `pred`, along with its file and chunk
+    /// caches, and its embeddings.
+    ///
+    /// The documents of all matched repositories are deleted in a single commit, after their
+    /// caches. This opens a writer, so callers have to make sure no indexing runs at the same
+    /// time, as [`Indexes::delete_repos_where`](super::Indexes::delete_repos_where) does.
+    ///
+    /// At most [`Indexer::max_result_limit`] repositories are deleted at once, so callers that
+    /// need every match gone call this again until it returns nothing.
+    ///
+    /// Returns the deleted repositories.
+    pub async fn delete_repos_where(
+        &self,
+        pred: impl Fn(&RepoRef) -> bool,
+    ) -> Result<Vec<RepoRef>> {
+        let matched = self
+            .all_repos()
+            .await
+            .into_iter()
+            .filter(pred)
+            .take(self.max_result_limit)
+            .collect::<Vec<_>>();
+        if matched.is_empty() {
+            return Ok(matched);
+        }
+
+        let mut handle = self.write_handle()?;
+        for repo_ref in &matched {
+            let repo_str = repo_ref.to_string();
+            if let Some(semantic) = &self.source.semantic {
+                semantic
+                    .delete_points_for_hash(&repo_str, std::iter::empty())
+                    .await;
+            }
+
+            FileCache::for_repo(&self.source.sql, repo_ref)
+                .delete()
+                .await?;
+
+            handle
+                .writer
+                .read()
+                .unwrap()
+                .delete_term(Term::from_field_text(self.source.repo_ref, &repo_str));
+        }
+
+        handle.commit().await?;
+        Ok(matched)
+    }
+
+    /// Write every document of the index to `writer`, one JSON object per line.
+    ///
+    /// Each object holds the stored fields of a document in tantivy's JSON format, along with
+    /// its last commit, which is kept in a column only. The other unstored fields are derived
+    /// from these, see [`Indexer::import_jsonl`]. Languages are written by name, as their ids
+    /// are only meaningful to the database of this index.
+    ///
+    /// Returns the number of documents written.
+    pub async fn dump_jsonl(&self, mut writer: impl Write) -> Result<usize> {
+        let schema = &self.source;
+        let searcher = self.reader.read().await.searcher();
+
+        let mut addrs = searcher
+            .search(&AllQuery, &DocSetCollector)?
+            .into_iter()
+            .collect::<Vec<_>>();
+        addrs.sort();
+
+        for &addr in &addrs {
+            let mut doc = searcher.doc(addr)?;
+            let last_commit = searcher
+                .segment_reader(addr.segment_ord)
+                .fast_fields()
+                .u64(schema.last_commit_unix_seconds)?
+                .get_val(addr.doc_id);
+            doc.add_u64(schema.last_commit_unix_seconds, last_commit);
+
+            let lang = doc
+                .get_first(schema.lang)
+                .and_then(|value| value.as_u64())
+                .and_then(|id| schema.langs.name(id))
+                .unwrap_or_default();
+            let mut json = serde_json::from_str::<serde_json::Value>(&schema.schema.to_json(&doc))?;
+            json["lang"] = serde_json::json!([lang]);
+
+            writeln!(writer, "{json}")?;
+        }
+
+        Ok(addrs.len())
+    }
+
+    /// Add the documents of a [`Indexer::dump_jsonl`] to the index, without reading any files.
+    ///
+    /// Fields that aren't stored are derived from the stored ones, and the cache keys of the
+    /// documents are added to the file cache of their repositories, so the next index of a
+    /// repository skips the files that haven't changed since the dump. Documents replace the
+    /// ones with the same cache key. Embeddings aren't part of the dump, so the semantic index
+    /// isn't touched.
+    ///
+    /// Lines that don't hold a valid document are skipped and reported. The documents are
+    /// visible once the import returns.
+    pub async fn import_jsonl(&self, reader: impl BufRead) -> Result<ImportReport> {
+        let schema = &self.source;
+        let mut report = ImportReport::default();
+        let mut cache_keys = HashMap::<String, Vec<String>>::new();
+
+        let mut handle = self.write_handle()?;
+        {
+            let writer = handle.writer.read().unwrap();
+            for (i, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                match schema.imported_document(&line) {
+                    Ok((repo_ref, unique_hash, doc)) => {
+                        writer.delete_term(Term::from_field_text(schema.unique_hash, &unique_hash));
+                        writer.add_document(doc)?;
+                        cache_keys.entry(repo_ref).or_default().push(unique_hash);
+                        report.imported += 1;
+                    }
+                    Err(err) => {
+                        warn!(line = i + 1, %err, "skipping malformed document");
+                        report.malformed.push((i + 1, err));
+                    }
+                }
+            }
+        }
+        let cache_keys = cache_keys
+            .into_iter()
+            .filter_map(|(repo_ref, keys)| Some((repo_ref.parse::<RepoRef>().ok()?, keys)))
+            .collect::<Vec<_>>();
+        for (reporef, _) in &cache_keys {
+            FileCache::for_repo(&schema.sql, reporef)
+                .begin_commit()
+                .await?;
+        }
+
+        schema.langs.persist().await?;
+        handle.commit().await?;
+
+        for (reporef, keys) in cache_keys {
+            let file_cache = FileCache::for_repo(&schema.sql, &reporef);
+            let snapshot = file_cache.retrieve().await;
+            for key in keys {
+                _ = snapshot.insert(key, ().into());
+            }
+            file_cache.persist(snapshot).await?;
+        }
+
+        Ok(report)
+    }
+}
+
+/// The outcome of [`Indexer::import_jsonl`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Number of documents added to the index
+    pub imported: usize,
+    /// Line numbers of the skipped lines, counting from 1, and why they were skipped
+    pub malformed: Vec<(usize, String)>,
+}
+
+impl File {
+    /// Parse a line of [`Indexer::dump_jsonl`] into a document, deriving its unstored fields.
+    ///
+    /// Returns the repository and the cache key of the document along with it.
+    fn imported_document(&self, line: &str) -> Result<(String, String, Document), String> {
+        let mut json =
+            serde_json::from_str::<serde_json::Value>(line).map_err(|err| err.to_string())?;
+        let lang = json
+            .get("lang")
+            .and_then(|lang| lang.get(0))
+            .and_then(|lang| lang.as_str())
+            .map(|lang| self.langs.intern(&parse_alias(lang.into())));
+        if let Some(id) = lang {
+            json["lang"] = serde_json::json!([id]);
+        }
+
+        let parsed = self
+            .schema
+            .parse_document(&json.to_string())
+            .map_err(|err| err.to_string())?;
+        let text = |field, name| {
+            parsed
+                .get_first(field)
+                .and_then(|value| value.as_text())
+                .map(ToOwned::to_owned)
+                .ok_or(format!("missing `{name}`"))
+        };
+
+        let repo_ref = text(self.repo_ref, "repo_ref")?;
+        let unique_hash = text(self.unique_hash, "unique_hash")?;
+        let doc = self.restored_document(&parsed)?;
+
+        Ok((repo_ref, unique_hash, doc))
+    }
+
+    /// Build a document to index from the stored fields of `stored`, along with its last commit.
+    ///
+    /// The fields that aren't stored are derived again, whatever `stored` holds for them.
+    fn restored_document(&self, stored: &Document) -> Result<Document, String> {
+        let text = |field, name| {
+            stored
+                .get_first(field)
+                .and_then(|value| value.as_text())
+                .map(ToOwned::to_owned)
+                .ok_or(format!("missing `{name}`"))
+        };
+
+        let repo_ref = text(self.repo_ref, "repo_ref")?;
+        let relative_path = text(self.relative_path, "relative_path")?;
+        let repo_name = text(self.repo_name, "repo_name")?;
+        let content = text(self.content, "content").unwrap_or_default();
+        let is_dir = relative_path.ends_with('/');
+
+        let reporef = repo_ref
+            .parse::<RepoRef>()
+            .map_err(|err| format!("invalid `repo_ref`: {err}"))?;
+        // remote repositories are cloned under their name, like a sync does
+        let repo_disk_path = reporef.local_path().unwrap_or_else(|| {
+            self.config
+                .source
+                .repo_path_for_name(&repo_ref.replace('/', "_"))
+        });
+
+        let symbol_count = match stored
+            .get_first(self.symbol_locations)
+            .and_then(|value| value.as_bytes())
+        {
+            Some(bytes) => bincode::deserialize::<SymbolLocations>(bytes)
+                .map_err(|err| format!("invalid `symbol_locations`: {err}"))?
+                .list()
+                .len() as u64,
+            None => 0,
+        };
+        let avg_line_length = if is_dir {
+            0.0
+        } else {
+            avg_line_length(&content)
+        };
+
+        let derived = [
+            self.repo_disk_path,
+            self.symbol_count,
+            self.avg_line_length,
+            self.raw_content,
+            self.raw_repo_name,
+            self.raw_relative_path,
+            self.is_directory,
+            self.vendored,
+            self.content_prose,
+        ];
+        let mut doc = Document::new();
+        for value in stored.field_values() {
+            if !derived.contains(&value.field()) {
+                doc.add_field_value(value.field(), value.value().clone());
+            }
+        }
+
+        doc.add_text(self.repo_disk_path, repo_disk_path.to_string_lossy());
+        doc.add_u64(self.symbol_count, symbol_count);
+        doc.add_f64(self.avg_line_length, avg_line_length);
+        doc.add_bytes(self.raw_content, content.as_bytes());
+        doc.add_bytes(self.raw_repo_name, repo_name.as_bytes());
+        doc.add_bytes(self.raw_relative_path, relative_path.as_bytes());
+        doc.add_bool(self.is_directory, is_dir);
+        doc.add_bool(
+            self.vendored,
+            self.is_vendored(Path::new(relative_path.trim_end_matches('/')), is_dir),
+        );
+        self.add_content_prose(&mut doc, &content);
+
+        Ok(doc)
+    }
+
+    /// Add `content` to `doc` as prose as well, if the configuration asks for it.
+    fn add_content_prose(&self, doc: &mut Document, content: &str) {
+        if self.indexes_content_prose() {
+            doc.add_text(self.content_prose, content);
+        }
+    }
+
+    /// Whether the entry at `relative_path` matches the vendored globs of the configuration.
+    fn is_vendored(&self, relative_path: &Path, is_dir: bool) -> bool {
+        self.vendored_paths
+            .matched(relative_path, is_dir)
+            .is_whitelist()
+    }
+
+    fn file_symbols(
+        &self,
+        entry_pathbuf: &Path,
+        buffer: &str,
+        lang_str: &str,
+        is_generated: bool,
+    ) -> SymbolLocations {
+        if is_generated {
+            trace!(?entry_pathbuf, "generated file; skipping symbol extraction");
+            SymbolLocations::Empty
+        } else if self.corpus_mode() == CorpusMode::Prose {
+            SymbolLocations::Empty
+        } else {
+            catch_grammar_panic(entry_pathbuf, "symbols", || {
+                (self.extract_symbols)(buffer, lang_str)
+            })
+            .unwrap_or(SymbolLocations::Empty)
+        }
+    }
+
+    /// Rewrite the file documents of a repository with symbols extracted from their stored
+    /// content.
+    ///
+    /// Nothing is read from disk, and cache keys are kept as they are, so this is only meant for
+    /// changes to symbol extraction. Tantivy can't update a field in place, so each document is
+    /// deleted and added again, and the new symbols are visible once `writer` is committed.
+    ///
+    /// The shards of a large file are left as they are, since sharded files have no symbols.
+    ///
+    /// Returns the number of documents rewritten.
+    pub(super) fn reindex_symbols(
+        &self,
+        searcher: &tantivy::Searcher,
+        writer: &IndexWriter,
+        repo: &Repository,
+        repo_ref: &RepoRef,
+        repo_metadata: &RepoMetadata,
+    ) -> Result<usize> {
+        let query = TermQuery::new(
+            Term::from_field_text(self.repo_ref, &repo_ref.to_string()),
+            IndexRecordOption::Basic,
+        );
+
+        let path_root = repo.resolve_path_root()?;
+        let mut seen = HashSet::new();
+        let mut rewritten = 0;
+        for addr in searcher.search(&query, &DocSetCollector)? {
+            let stored = searcher.doc(addr)?;
+            let text = |field| {
+                stored
+                    .get_first(field)
+                    .and_then(|value| value.as_text())
+                    .unwrap_or_default()
+            };
+
+            let relative_path = text(self.relative_path);
+            let unique_hash = text(self.unique_hash);
+            // deleting by the key of a shard would delete the other shards of its file too
+            if relative_path.ends_with('/')
+                || stored.get_first(self.shard_index).is_some()
+                || !seen.insert(unique_hash.to_owned())
+            {
+                continue;
+            }
+
+            let content = text(self.content);
+            let lang = stored
+                .get_first(self.lang)
+                .and_then(|value| value.as_u64())
+                .and_then(|id| self.langs.name(id))
+                .unwrap_or_default();
+
+            // documents under a path root are matched at their path in the repository, the same
+            // as when they were indexed
+            let repo_path = match &path_root {
+                Some(root) => root.join(relative_path),
+                None => PathBuf::from(relative_path),
+            };
+            let entry_pathbuf = repo.disk_path.join(&repo_path);
+            let is_generated = repo_metadata
+                .attributes
+                .get(&repo_path)
+                .generated
+                .unwrap_or_else(|| self.generated.matches(&repo_path, content));
+
+            let symbol_locations = self.file_symbols(&entry_pathbuf, content, &lang, is_generated);
+            let (symbols, symbol_count) = flatten_symbols(content, &symbol_locations);
+
+            // unstored fields are derived again, the last commit is read back from its column
+            let last_commit = searcher
+                .segment_reader(addr.segment_ord)
+                .fast_fields()
+                .u64(self.last_commit_unix_seconds)?
+                .get_val(addr.doc_id);
+
+            let mut doc = tantivy::Document::new();
+            for value in stored.field_values() {
+                if value.field() != self.symbols && value.field() != self.symbol_locations {
+                    doc.add_field_value(value.field(), value.value().clone());
+                }
+            }
+
+            doc.add_text(self.symbols, symbols);
+            doc.add_bytes(
+                self.symbol_locations,
+                bincode::serialize(&symbol_locations).unwrap(),
+            );
+            doc.add_u64(self.symbol_count, symbol_count);
+            doc.add_text(self.repo_disk_path, repo.disk_path.to_string_lossy());
+            doc.add_bytes(self.raw_content, content.as_bytes());
+            doc.add_bytes(self.raw_repo_name, text(self.repo_name).as_bytes());
+            doc.add_bytes(self.raw_relative_path, relative_path.as_bytes());
+            doc.add_f64(self.avg_line_length, avg_line_length(content));
+            doc.add_u64(self.last_commit_unix_seconds, last_commit);
+            doc.add_bool(self.is_directory, false);
+            doc.add_bool(
+                self.vendored,
+                self.is_vendored(Path::new(relative_path), false),
+            );
+            self.add_content_prose(&mut doc, content);
+
+            writer.delete_term(Term::from_field_text(self.unique_hash, unique_hash));
+            writer.add_document(doc)?;
+            rewritten += 1;
+        }
+
+        Ok(rewritten)
+    }
+
+    /// Rewrite the documents of a repository that are on `from_branch` to be on `into_branch`
+    /// as well, the way [`FileCache::merge_branch`] does for chunks.
+    ///
+    /// Nothing is read from disk, and cache keys are kept as they are, so the next index of the
+    /// repository settles the documents the way it would have without a merge. As with
+    /// [`File::reindex_symbols`], the new branches are visible once `writer` is committed.
+    ///
+    /// Returns the number of documents rewritten.
+    pub(super) fn merge_branch(
+        &self,
+        searcher: &tantivy::Searcher,
+        writer: &IndexWriter,
+        repo_ref: &RepoRef,
+        from_branch: &str,
+        into_branch: &str,
+    ) -> Result<usize> {
+        let query = FileQueryBuilder::new(self, searcher.index())?
+            .repo(repo_ref)
+            .branch(Some(from_branch))
+            .build();
+
+        // the shards of a file share their cache key, so they are all deleted before any of
+        // them is added again
+        let mut merged = HashMap::<String, Vec<Document>>::new();
+        for addr in searcher.search(&query, &DocSetCollector)? {
+            let stored = searcher.doc(addr)?;
+            let text = |field| {
+                stored
+                    .get_first(field)
+                    .and_then(|value| value.as_text())
+                    .unwrap_or_default()
+            };
+
+            // branches only need to contain the name to match, so the list is checked again
+            let Some(branches) = merged_branch_list(text(self.branches), from_branch, into_branch)
+            else {
+                continue;
+            };
+
+            let last_commit = searcher
+                .segment_reader(addr.segment_ord)
+                .fast_fields()
+                .u64(self.last_commit_unix_seconds)?
+                .get_val(addr.doc_id);
+
+            let mut doc = Document::new();
+            for value in stored.field_values() {
+                if value.field() != self.branches {
+                    doc.add_field_value(value.field(), value.value().clone());
+                }
+            }
+            doc.add_text(self.branches, branches.join("\n"));
+            doc.add_u64(self.last_commit_unix_seconds, last_commit);
+
+            let doc = self.restored_document(&doc).map_err(anyhow::Error::msg)?;
+            merged
+                .entry(text(self.unique_hash).to_owned())
+                .or_default()
+                .push(doc);
+        }
+
+        for unique_hash in merged.keys() {
+            writer.delete_term(Term::from_field_text(self.unique_hash, unique_hash));
+        }
+
+        let mut rewritten = 0;
+        for doc in merged.into_values().flatten() {
+            writer.add_document(doc)?;
+            rewritten += 1;
+        }
+
+        Ok(rewritten)
+    }
+
+    /// Sort the files written by a run into added and modified ones, depending on whether they
+    /// replace a document of `stale` cache keys, and report the other stale files as removed.
+    ///
+    /// `searcher` has to predate the run's deletes.
+    fn change_set(
+        &self,
+        searcher: &tantivy::Searcher,
+        stale: &[String],
+        written: Vec<String>,
+    ) -> Result<ChangeSet> {
+        let mut replaced = BTreeSet::new();
+        for key in stale {
+            let query = TermQuery::new(
+                Term::from_field_text(self.unique_hash, key),
+                IndexRecordOption::Basic,
+            );
+
+            for (_, addr) in searcher.search(&query, &TopDocs::with_limit(1))? {
+                let doc = searcher.doc(addr)?;
+                let path = doc
+                    .get_first(self.relative_path)
+                    .and_then(|value| value.as_text())
+                    .unwrap_or_default();
+
+                // directories aren't reported
+                if !path.is_empty() && !path.ends_with('/') {
+                    replaced.insert(path.to_owned());
+                }
+            }
+        }
+
+        let written = written.into_iter().collect::<BTreeSet<_>>();
+        Ok(ChangeSet {
+            added: written.difference(&replaced).cloned().collect(),
+            modified: written.intersection(&replaced).cloned().collect(),
+            removed: replaced.difference(&written).cloned().collect(),
+        })
+    }
+
+    /// Apply the indexing options that change or skip the content of `dir_entry`, and hash the
+    /// result into the keys it's cached under.
+    ///
+    /// Returns `None` for entries that aren't indexed. Both [`File::worker`] and
+    /// [`Indexer::is_fresh`] go through this, so they always agree on the keys.
+    #[allow(clippy::too_many_arguments)]
+    fn prepare_entry(
+        &self,
+        repo_ref: &str,
+        repo_disk_path: &Path,
+        repo_metadata: &RepoMetadata,
+        repo_path: &Path,
+        relative_path: &Path,
+        semantic: bool,
+        line_blame: bool,
+        mut dir_entry: RepoDirEntry,
+    ) -> Option<PreparedEntry> {
+        // pointer files stand in for content that may not be checked out
+        let mut is_lfs_pointer = false;
+        if let RepoDirEntry::File(file) = &mut dir_entry {
+            if let Some(pointer) = LfsPointer::parse(&file.buffer) {
+                match self.config.lfs_mode {
+                    LfsMode::Skip => {
+                        trace!("git lfs pointer; skipping");
+                        return None;
+                    }
+                    LfsMode::IndexPointerMeta => is_lfs_pointer = true,
+                    LfsMode::ResolveIfLocal => {
+                        match pointer.resolve(repo_disk_path, self.config.max_file_size_bytes) {
+                            // binary objects are only indexed by path, like other binary files
+                            Some(data) => {
+                                let path = std::mem::take(&mut file.path);
+                                let branches = std::mem::take(&mut file.branches);
+                                *file = file_entry(path, data, branches);
+                            }
+                            None => {
+                                trace!("git lfs object not available locally; skipping");
+                                return None;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // a byte order mark would shift every offset of the file, so it isn't indexed
+        let mut has_bom = false;
+        if let RepoDirEntry::File(file) = &mut dir_entry {
+            if let Some(content) = file.buffer.strip_prefix('\u{feff}') {
+                file.buffer = content.to_owned();
+                has_bom = true;
+            }
+        }
+
+        // an unresolved merge would otherwise index both sides, markers included
+        let mut has_conflicts = false;
+        if let RepoDirEntry::File(file) = &mut dir_entry {
+            if let Some(conflicts) = Conflicts::parse(&file.buffer) {
+                has_conflicts = true;
+                match self.config.conflict_mode {
+                    ConflictMode::SkipConflicted => {
+                        trace!("merge conflicts; skipping");
+                        return None;
+                    }
+                    ConflictMode::IndexOurs => file.buffer = conflicts.ours(),
+                    ConflictMode::IndexTheirs => file.buffer = conflicts.theirs(),
+                    ConflictMode::IndexRaw => {}
+                }
+            }
+        }
+
+        // `linguist-generated` attributes win over our own heuristics
+        let attributes = repo_metadata.attributes.get(repo_path);
+        let is_binary = matches!(&dir_entry, RepoDirEntry::File(file) if file.is_binary);
+        let language = match is_binary {
+            // binary files only have a path, so they get a language of their own
+            true => Some("binary"),
+            false => attributes
+                .language
+                .as_deref()
+                .or_else(|| self.language_overrides.get(repo_path)),
+        };
+        let is_generated = attributes.generated.unwrap_or_else(|| {
+            dir_entry
+                .buffer()
+                .map(|buffer| self.generated.matches(repo_path, buffer))
+                .unwrap_or_default()
+        });
+
+        // tiny files rarely carry search value, so only their path is indexed
+        let is_small = dir_entry
+            .buffer()
+            .map(|buffer| (buffer.len() as u64) < self.config.min_file_size_bytes)
+            .unwrap_or_default();
+
+        // directories aren't owned or tagged
+        let (owners, tags) = match &dir_entry {
+            RepoDirEntry::File(_) => (
+                repo_metadata.owners.get(repo_path).to_vec(),
+                repo_metadata.tags.get(repo_path),
+            ),
+            _ => Default::default(),
+        };
+
+        // large files are only walked when they're to be sharded
+        let lines_per_shard = self.lines_per_shard();
+        let is_sharded = lines_per_shard.is_some()
+            && dir_entry
+                .buffer()
+                .map(|buffer| buffer.len() as u64 > MAX_FILE_LEN || exceeds_line_limit(buffer))
+                .unwrap_or_default();
+
+        let semantic_hash = {
+            let mut hash = blake3::Hasher::new();
+            hash.update(self.config.payload_schema.index_version().as_bytes());
+            hash.update(relative_path.to_string_lossy().as_ref().as_ref());
+            hash.update(repo_ref.as_bytes());
+            hash.update(dir_entry.buffer().unwrap_or_default().as_bytes());
+            if self.config.normalize_line_endings {
+                hash.update(b"normalize_line_endings");
+            }
+            // symbol-aware chunks follow the symbols extracted for the lexical index
+            if self.config.chunk_strategy == ChunkStrategy::SymbolAware {
+                hash.update(b"symbol_aware");
+                hash.update(crate::state::LEXICAL_VERSION.as_bytes());
+                if is_generated {
+                    hash.update(b"generated");
+                }
+            }
+            hash.finalize().to_hex().to_string()
+        };
+
+        let tantivy_hash = {
+            let branch_list = dir_entry.branches().unwrap_or_default();
+            let mut hash = blake3::Hasher::new();
+            hash.update(crate::state::LEXICAL_VERSION.as_bytes());
+            hash.update(semantic_hash.as_ref());
+            hash.update(branch_list.join("\n").as_bytes());
+            // symbols are only stored for files that aren't generated
+            if is_generated {
+                hash.update(b"generated");
+            }
+            if let Some(lang) = language {
+                hash.update(lang.as_bytes());
+            }
+            if has_bom {
+                hash.update(b"bom");
+            }
+            if has_conflicts {
+                hash.update(b"conflicts");
+            }
+            if is_small {
+                hash.update(b"small");
+            }
+            if let Some(lines) = lines_per_shard.filter(|_| is_sharded) {
+                hash.update(b"sharded");
+                hash.update(lines.to_string().as_bytes());
+            }
+            if !owners.is_empty() {
+                hash.update(b"owners");
+                hash.update(owners.join("\n").as_bytes());
+            }
+            if !tags.is_empty() {
+                hash.update(b"tags");
+                hash.update(tags.join("\n").as_bytes());
+            }
+            if !semantic {
+                hash.update(b"lexical_only");
+            }
+            if line_blame {
+                hash.update(b"line_blame");
+            }
+            if self.is_vendored(relative_path, matches!(dir_entry, RepoDirEntry::Dir(_))) {
+                hash.update(b"vendored");
+            }
+            hash.finalize().to_hex().to_string()
+        };
+
+        Some(PreparedEntry {
+            dir_entry,
+            is_lfs_pointer,
+            has_bom,
+            has_conflicts,
+            language: language.map(str::to_owned),
+            is_generated,
+            is_small,
+            is_sharded,
+            owners,
+            tags,
+            semantic_hash,
+            tantivy_hash,
+        })
+    }
+
+    /// The entry [`File::worker`] would write for `dir_entry`, or why it would drop it, so that
+    /// dry runs count what a real run indexes.
+    fn admit_entry(
+        &self,
+        repo_ref: &str,
+        repo_disk_path: &Path,
+        path_root: Option<&Path>,
+        repo_metadata: &RepoMetadata,
+        semantic: bool,
+        line_blame: bool,
+        dir_entry: RepoDirEntry,
+    ) -> Result<RepoDirEntry, Dropped> {
+        let Some(path) = dir_entry.path() else {
+            return Ok(dir_entry);
+        };
+        let repo_path = Path::new(path)
+            .strip_prefix(repo_disk_path)
+            .map(ToOwned::to_owned)
+            .unwrap_or_else(|_| PathBuf::from(path));
+        let relative_path = root_relative(&repo_path, path_root).ok_or(Dropped::Ignored)?;
+
+        self.prepare_entry(
+            repo_ref,
+            repo_disk_path,
+            repo_metadata,
+            &repo_path,
+            &relative_path,
+            semantic,
+            line_blame,
+            dir_entry,
+        )
+        .map(|prepared| prepared.dir_entry)
+        .ok_or(Dropped::Skipped)
+    }
+
+    /// Index a single entry, and return whether a document was written for it.
+    #[tracing::instrument(fields(repo=%workload.repo_ref, entry_disk_path=?workload.dir_entry.path()), skip_all)]
+    fn worker(&self, workload: Workload<'_>, writer: &IndexWriter) -> Result<bool> {
+        let Workload {
+            repo_ref,
+            repo_disk_path,
+            path_root,
+            repo_name,
+            repo_metadata,
+            file_cache,
+            cache_snapshot,
+            totals,
+            blame_repo,
+            semantic,
+            near_duplicates,
+            dir_entry,
+        } = workload;
+
+        let start = Instant::now();
+        trace!("processing file");
+
+        let relative_path = {
+            let entry_srcpath = PathBuf::from(dir_entry.path().ok_or(anyhow::anyhow!(
+                "dir entry is not a valid file or directory"
+            ))?);
+            entry_srcpath
+                .strip_prefix(repo_disk_path)
+                .map(ToOwned::to_owned)
+                .unwrap_or(entry_srcpath)
+        };
+        let entry_pathbuf = repo_disk_path.join(&relative_path);
+
+        // under a path root, documents get paths relative to the root, while git attributes
+        // and the configured patterns keep matching the path in the repository
+        let repo_path = relative_path;
+        let Some(relative_path) = root_relative(&repo_path, path_root) else {
+            trace!("outside of the path root; skipping");
+            return Ok(false);
+        };
+
+        let Some(PreparedEntry {
+            dir_entry,
+            is_lfs_pointer,
+            has_bom,
+            has_conflicts,
+            language,
+            is_generated,
+            is_small,
+            is_sharded,
+            owners,
+            tags,
+            semantic_hash,
+            tantivy_hash,
+        }) = self.prepare_entry(
+            &repo_ref,
+            repo_disk_path,
+            repo_metadata,
+            &repo_path,
+            &relative_path,
+            semantic,
+            blame_repo.is_some(),
+            dir_entry,
+        )
+        else {
+            return Ok(false);
+        };
+        let language = language.as_deref();
+
+        let last_commit = repo_metadata.last_commit_unix_secs.unwrap_or(0);
+        let stats = match &dir_entry {
+            RepoDirEntry::File(file) => RepoStats::file(&file.buffer),
+            _ => RepoStats::default(),
+        };
+
+        if let RepoDirEntry::File(_) = dir_entry {
+            totals.indexable_files.fetch_add(1, Ordering::Relaxed);
+        }
+
+        match dir_entry {
+            _ if is_cache_fresh(cache_snapshot, &tantivy_hash, stats) => {
+                info!("fresh; skipping");
+                return Ok(false);
+            }
+            RepoDirEntry::Dir(dir) => {
+                trace!("writing dir document");
+                let doc = dir.build_document(
+                    self,
+                    repo_name,
+                    relative_path.as_path(),
+                    repo_disk_path,
+                    repo_ref.as_str(),
+                    last_commit,
+                    tantivy_hash,
+                );
+                writer.add_document(doc)?;
+                trace!("dir document written");
+            }
+            RepoDirEntry::File(file) => {
+                trace!("writing file document");
+                let bytes = file.buffer.len() as u64;
+                let mut timings = FileTimings {
+                    read: start.elapsed(),
+                    ..Default::default()
+                };
+                // only the version of `HEAD` has the history that is blamed, and it's blamed
+                // by the lines of the whole file, which shards don't have
+                let line_blame = match blame_repo {
+                    Some(git) if !is_sharded && file.branches.iter().any(|b| b == "HEAD") => {
+                        LineBlame::compute(&git.to_thread_local(), &repo_path)
+                            .map_err(|err| warn!(?err, ?repo_path, "failed to blame file"))
+                            .ok()
+                            .flatten()
+                    }
+                    _ => None,
+                };
+
+                // shards share the keys of the whole file, so they're cached, replaced and
+                // deleted together
+                let shards = match self.lines_per_shard().filter(|_| is_sharded) {
+                    Some(lines) => split_shards(&file.buffer, lines)
+                        .into_iter()
+                        .map(|buffer| RepoFile {
+                            path: file.path.clone(),
+                            buffer,
+                            branches: file.branches.clone(),
+                            is_binary: false,
+                        })
+                        .collect(),
+                    None => vec![file],
+                };
+                let mut line_blame = line_blame;
+                let (mut start_line, mut start_byte) = (0, 0);
+                for (shard_index, file) in shards.into_iter().enumerate() {
+                    let mut doc = file
+                        .build_document(
+                            self,
+                            repo_name,
+                            relative_path.as_path(),
+                            repo_disk_path,
+                            semantic_hash.clone(),
+                            tantivy_hash.clone(),
+                            entry_pathbuf.as_path(),
+                            repo_ref.as_str(),
+                            last_commit,
+                            repo_metadata,
+                            file_cache,
+                            // symbols of one shard couldn't be put back together with the
+                            // others, so sharded files have none
+                            is_generated || is_sharded,
+                            // pointers have no language, so aren't parsed as source
+                            if is_lfs_pointer { Some("") } else { language },
+                            has_bom,
+                            has_conflicts,
+                            is_small,
+                            line_blame.take(),
+                            // tiny files are indexed by path only, so there's nothing to embed,
+                            // and large ones would take most of the run to embed
+                            self.semantic
+                                .as_ref()
+                                .filter(|_| semantic && !is_small && !is_sharded),
+                            near_duplicates,
+                            totals,
+                            &mut timings,
+                        )
+                        .ok_or(anyhow::anyhow!("failed to build document"))?;
+                    if is_sharded {
+                        // offsets are counted on the stored content, which is what's put back
+                        // together
+                        let stored = doc
+                            .get_first(self.content)
+                            .and_then(|value| value.as_text())
+                            .unwrap_or_default();
+                        doc.add_u64(self.shard_index, shard_index as u64);
+                        doc.add_u64(self.shard_start_line, start_line);
+                        doc.add_u64(self.shard_start_byte, start_byte);
+                        start_line += stored.matches('\n').count() as u64;
+                        start_byte += stored.len() as u64;
+                    }
+                    for owner in &owners {
+                        doc.add_bytes(self.owners, owner.as_bytes());
+                    }
+                    for tag in &tags {
+                        doc.add_bytes(self.tags, tag.as_bytes());
+                    }
+                    writer.add_document(doc)?;
+                }
+                totals.files.fetch_add(1, Ordering::Relaxed);
+                totals.bytes.fetch_add(bytes, Ordering::Relaxed);
+
+                timings.write = start.elapsed() - timings.read - timings.symbols;
+                if timings.total() > Duration::from_millis(self.config.slow_file_ms) {
+                    warn!(?relative_path, ?timings, "file took too long to index");
+                }
+                totals.record_timings(&relative_path, timings);
+
+                let written = relative_path.to_string_lossy().into_owned();
+                #[cfg(windows)]
+                let written = written.replace('\\', "/");
+                totals.written.lock().unwrap().push(written);
+
+                trace!("file document written");
+            }
+            RepoDirEntry::Other => anyhow::bail!("dir entry was neither a file nor a directory"),
+        }
+
+        #[cfg(feature = "debug")]
+        {
+            let elapsed = start.elapsed();
+            let time: u64 = elapsed
+                .as_millis()
+                .try_into()
+                .expect("nobody waits this long");
+            self.histogram.write().unwrap().increment(time, 1).unwrap();
+
+            if time
+                > self
+                    .histogram
+                    .read()
+                    .unwrap()
+                    .percentile(99.9)
+                    .unwrap()
+                    .low()
+            {
+                // default console formatter is different when we're debugging. need to print more info here.
+                warn!(?relative_path, ?elapsed, "file took too long to process")
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl RepoDir {
+    #[allow(clippy::too_many_arguments)]
+    fn build_document(
+        self,
+        schema: &File,
+        repo_name: &str,
+        relative_path: &Path,
+        repo_disk_path: &Path,
+        repo_ref: &str,
+        last_commit: u64,
+        tantivy_cache_key: String,
+    ) -> tantivy::schema::Document {
+        let relative_path_str = format!("{}/", relative_path.to_string_lossy());
+        #[cfg(windows)]
+        let relative_path_str = relative_path_str.replace('\\', "/");
+
+        let branches = self.branches.join("\n");
+
+        doc!(
+                schema.raw_repo_name => repo_name.as_bytes(),
+                schema.raw_relative_path => relative_path_str.as_bytes(),
+                schema.repo_disk_path => repo_disk_path.to_string_lossy().as_ref(),
+                schema.relative_path => relative_path_str,
+                schema.repo_ref => repo_ref,
+                schema.repo_name => repo_name,
+                schema.last_commit_unix_seconds => last_commit,
+                schema.branches => branches,
+                schema.is_directory => true,
+                schema.vendored => schema.is_vendored(relative_path, true),
+                schema.has_conflicts => false,
+                schema.is_small => false,
+                schema.unique_hash => tantivy_cache_key,
+
+                // nulls
+                schema.semantic_hash => String::default(),
+                schema.raw_content => Vec::<u8>::default(),
+                schema.content => String::default(),
+                schema.line_end_indices => Vec::<u8>::default(),
+                schema.lang => LangDictionary::NONE,
+                schema.content_type => Vec::<u8>::default(),
+                schema.summary => String::default(),
+                schema.avg_line_length => f64::default(),
+                schema.symbol_locations => bincode::serialize(&SymbolLocations::default()).unwrap(),
+                schema.symbols => String::default(),
+                schema.symbol_count => 0u64,
+        )
+    }
+}
+
+impl RepoFile {
+    #[allow(clippy::too_many_arguments)]
+    fn build_document(
+        mut self,
+        schema: &File,
+        repo_name: &str,
+        relative_path: &Path,
+        repo_disk_path: &Path,
+        semantic_cache_key: String,
+        tantivy_cache_key: String,
+        entry_pathbuf: &Path,
+        repo_ref: &str,
+        last_commit: u64,
+        repo_metadata: &RepoMetadata,
+        file_cache: &FileCache,
+        is_generated: bool,
+        lang_override: Option<&str>,
+        has_bom: bool,
+        has_conflicts: bool,
+        is_small: bool,
+        line_blame: Option<LineBlame>,
+        semantic: Option<&Semantic>,
+        near_duplicates: Option<&NearDuplicates>,
+        totals: &RunTotals,
+        timings: &mut FileTimings,
+    ) -> Option<tantivy::schema::Document> {
+        let phase = Instant::now();
+        let relative_path_str = relative_path.to_string_lossy().to_string();
+        #[cfg(windows)]
+        let relative_path_str = relative_path_str.replace('\\', "/");
+
+        let branches = self.branches.join("\n");
+
+        // line endings are those of the file on disk, before any rewriting
+        let line_ending = LineEnding::detect(&self.buffer);
+        if line_ending == LineEnding::Mixed {
+            info!(?entry_pathbuf, "file has mixed line endings");
+        }
+
+        if is_small {
+            self.buffer.clear();
+        }
+
+        if schema.config.normalize_line_endings && line_ending != LineEnding::Lf {
+            self.buffer = self.buffer.replace("\r\n", "\n");
+        }
+
+        let lang_str = lang_override.unwrap_or_else(|| {
+            repo_metadata
+                .langs
+                .get(entry_pathbuf, self.buffer.as_ref())
+                .unwrap_or_else(|| {
+                    warn!(?entry_pathbuf, "Path not found in language map");
+                    ""
+                })
+        });
+
+        let content_type = content_type::detect(entry_pathbuf, &self.buffer);
+        let summary = catch_grammar_panic(entry_pathbuf, "summary", || {
+            extract_summary(&self.buffer, lang_str)
+        })
+        .unwrap_or_default();
+
+        timings.read += phase.elapsed();
+        let phase = Instant::now();
+        let symbol_locations =
+            schema.file_symbols(entry_pathbuf, &self.buffer, lang_str, is_generated);
+        timings.symbols = phase.elapsed();
+        let (symbols, symbol_count) = flatten_symbols(&self.buffer, &symbol_locations);
+
+        // add an NL if this file is not NL-terminated
+        if !is_small && !self.is_binary && !self.buffer.ends_with('\n') {
+            self.buffer += "\n";
+        }
+
+        let line_end_indices = LineIndex::encode(&self.buffer);
+
+        // Skip files that are too long. This is not necessarily caught in the filesize check, e.g.
+        // for a file like `vocab.txt` which has thousands of very short lines.
+        //
+        // Walkers already skip these, this only guards against other callers.
+        if exceeds_line_limit(&self.buffer) {
+            return None;
+        }
+
+        let lines_avg = avg_line_length(&self.buffer);
+
+        if let Some(semantic) = semantic {
+            let embedded = tokio::task::block_in_place(|| {
+                Handle::current().block_on(async {
+                    semantic
+                        .insert_points_for_buffer(
+                            repo_name,
+                            repo_ref,
+                            &relative_path_str,
+                            &self.buffer,
+                            lang_str,
+                            &self.branches,
+                            &symbol_locations,
+                            file_cache
+                                .chunks_for_file(&semantic_cache_key, &schema.config.payload_schema)
+                                .await
+                                .with_near_duplicates(near_duplicates),
+                        )
+                        .await
+                })
+            });
+
+            if !embedded {
+                totals.semantic_failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        // the buffer is moved into `content`, so it's only copied when it's indexed twice
+        let content_prose = schema.indexes_content_prose().then(|| self.buffer.clone());
+        let mut doc = doc!(
+            schema.raw_content => self.buffer.as_bytes(),
+            schema.raw_repo_name => repo_name.as_bytes(),
+            schema.raw_relative_path => relative_path_str.as_bytes(),
+            schema.unique_hash => tantivy_cache_key,
+            schema.semantic_hash => semantic_cache_key,
+            schema.repo_disk_path => repo_disk_path.to_string_lossy().as_ref(),
+            schema.relative_path => relative_path_str,
+            schema.repo_ref => repo_ref,
+            schema.repo_name => repo_name,
+            schema.content => self.buffer,
+            schema.line_end_indices => line_end_indices,
+            schema.line_ending => line_ending.as_str(),
+            schema.has_bom => has_bom,
+            schema.has_conflicts => has_conflicts,
+            schema.line_blame => line_blame.as_ref().map(LineBlame::encode).unwrap_or_default(),
+            schema.lang => schema.langs.intern(&parse_alias(lang_str.into())),
+            schema.content_type => content_type.as_bytes(),
+            schema.summary => summary,
+            schema.avg_line_length => lines_avg,
+            schema.last_commit_unix_seconds => last_commit,
+            schema.symbol_locations => bincode::serialize(&symbol_locations).unwrap(),
+            schema.symbols => symbols,
+            schema.symbol_count => symbol_count,
+            schema.branches => branches,
+            schema.is_directory => false,
+            schema.vendored => schema.is_vendored(relative_path, false),
+            schema.is_small => is_small,
+        );
+        if let Some(content) = content_prose {
+            doc.add_text(schema.content_prose, content);
+        }
+
+        Some(doc)
+    }
+}
+
+/// Flatten the symbols of a file into a string with just their text, and count them.
+fn flatten_symbols(buffer: &str, symbol_locations: &SymbolLocations) -> (String, u64) {
+    let symbol_list = symbol_locations.list();
+    let symbols = symbol_list
+        .iter()
+        .map(|sym| buffer[sym.range.start.byte..sym.range.end.byte].to_owned())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    (symbols, symbol_list.len() as u64)
+}
+
+/// Run a step of indexing a file that parses it with tree-sitter, turning a panic into `None`.
+///
+/// Grammars can panic on input they don't expect, which would otherwise abort every file of the
+/// batch; this is called before the document is written, so the writer is left untouched.
+fn catch_grammar_panic<T>(entry_pathbuf: &Path, step: &str, f: impl FnOnce() -> T) -> Option<T> {
+    std::panic::catch_unwind(AssertUnwindSafe(f))
+        .map_err(|payload| {
+            let message = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            error!(?entry_pathbuf, step, message, "grammar panicked");
+        })
+        .ok()
+}
+
+/// Summarize a file in one line: the first line of its leading doc comment if the language is
+/// supported by tree-sitter, otherwise its first non-blank line.
+fn extract_summary(buffer: &str, lang_str: &str) -> String {
+    // leading comments are all we need, so only parse the top of the file
+    let mut head_len = buffer.len().min(SUMMARY_HEAD_LEN);
+    while !buffer.is_char_boundary(head_len) {
+        head_len -= 1;
+    }
+
+    let comments = TreeSitterFile::try_build(buffer[..head_len].as_bytes(), lang_str)
+        .map(|file| file.leading_comments())
+        .unwrap_or_default();
+
+    // doc comments win over license headers and the like
+    let comment = comments
+        .iter()
+        .find(|c| DOC_COMMENT_MARKERS.iter().any(|m| c.starts_with(m)))
+        .or_else(|| comments.first());
+
+    let line = match comment {
+        Some(comment) => comment
+            .lines()
+            .map(strip_comment_markers)
+            .find(|line| !line.is_empty()),
+        None => buffer.lines().map(str::trim).find(|line| !line.is_empty()),
+    }
+    .unwrap_or_default();
+
+    let mut end = line.len().min(MAX_SUMMARY_LEN);
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    line[..end].to_owned()
+}
+
+const SUMMARY_HEAD_LEN: usize = 4096;
+const MAX_SUMMARY_LEN: usize = 200;
+const DOC_COMMENT_MARKERS: &[&str] = &["//!", "///", "/**", "/*!"];
+
+fn strip_comment_markers(line: &str) -> &str {
+    let line = line.trim();
+    let line = line.strip_suffix("*/").unwrap_or(line);
+    ["//!", "///", "//", "/**", "/*!", "/*", "*", "#", "--"]
+        .iter()
+        .find_map(|marker| line.strip_prefix(marker))
+        .unwrap_or(line)
+        .trim()
+}
+
+/// Build a syntax aware representation of the file, if the language is supported.
+pub(super) fn extract_symbols(buffer: &str, lang_str: &str) -> SymbolLocations {
+    let scope_graph = TreeSitterFile::try_build(buffer.as_bytes(), lang_str)
+        .and_then(TreeSitterFile::scope_graph);
+
+    match scope_graph {
+        // we have a graph, use that
+        Ok(graph) => SymbolLocations::TreeSitter(graph),
+        // no graph, it's empty
+        Err(_) => SymbolLocations::Empty,
+    }
+}
+
+/// The number of leading lines of a file searched for generated file markers.
+const GENERATED_HEADER_LINES: usize = 5;
+
+/// Heuristics to detect generated files, which are indexed without symbols.
+///
+/// Building scope graphs for protobuf output or bundled code is expensive,
+/// and the symbols are rarely useful.
+#[derive(Clone)]
+pub(super) struct GeneratedFiles {
+    globs: Override,
+    markers: Vec<String>,
+}
+
+impl GeneratedFiles {
+    pub(super) fn new(globs: &[String], markers: &[String]) -> Self {
+        Self {
+            globs: path_globs(globs, "generated file"),
+            markers: markers.to_vec(),
+        }
+    }
+
+    fn matches(&self, relative_path: &Path, buffer: &str) -> bool {
+        self.globs.matched(relative_path, false).is_whitelist()
+            || buffer.lines().take(GENERATED_HEADER_LINES).any(|line| {
+                self.markers
+                    .iter()
+                    .any(|marker| line.contains(marker.as_str()))
+            })
+    }
+}
+
+/// Languages assigned to files in the configuration, by extension or path glob.
+///
+/// Globs win over extensions, and a `linguist-language` attribute of the repository wins over
+/// both.
+#[derive(Clone, Default)]
+pub(super) struct LanguageOverrides {
+    extensions: HashMap<String, String>,
+    globs: Vec<(Override, String)>,
+}
+
+impl LanguageOverrides {
+    pub(super) fn new(overrides: &HashMap<String, String>) -> Self {
+        let mut this = Self::default();
+
+        // sorted, so overlapping globs always resolve the same way
+        let mut overrides = overrides.iter().collect::<Vec<_>>();
+        overrides.sort();
+
+        for (key, lang) in overrides {
+            if key.contains(['*', '?', '[', '{', '/']) {
+                let glob = path_globs(std::slice::from_ref(key), "language override");
+                this.globs.push((glob, lang.clone()));
+            } else {
+                let extension = key.trim_start_matches('.').to_ascii_lowercase();
+                this.extensions.insert(extension, lang.clone());
+            }
+        }
+
+        this
+    }
+
+    fn get(&self, relative_path: &Path) -> Option<&str> {
+        self.globs
+            .iter()
+            .find(|(glob, _)| glob.matched(relative_path, false).is_whitelist())
+            .map(|(_, lang)| lang)
+            .or_else(|| {
+                let extension = relative_path.extension()?.to_str()?.to_ascii_lowercase();
+                self.extensions.get(&extension)
+            })
+            .map(String::as_str)
+    }
+}
+
+/// Match relative paths against a set of globs, skipping the invalid ones.
+pub(super) fn path_globs(globs: &[String], kind: &str) -> Override {
+    let mut builder = OverrideBuilder::new("");
+    for glob in globs {
+        if let Err(err) = builder.add(glob) {
+            warn!(%err, glob, "invalid {kind} glob; ignoring");
+        }
+    }
+
+    builder.build().unwrap_or_else(|err| {
+        warn!(%err, "failed to build {kind} globs");
+        Override::empty()
+    })
+}
+
+/// The line ending convention used throughout a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+    /// Both `\n` and `\r\n` line endings occur in the same file
+    Mixed,
+}
+
+impl LineEnding {
+    fn detect(buffer: &str) -> Self {
+        let bytes = buffer.as_bytes();
+        let (mut lf, mut crlf) = (0usize, 0usize);
+
+        for (i, _) in buffer.match_indices('\n') {
+            if i > 0 && bytes[i - 1] == b'\r' {
+                crlf += 1;
+            } else {
+                lf += 1;
+            }
+        }
+
+        match (lf, crlf) {
+            (_, 0) => Self::Lf,
+            (0, _) => Self::CrLf,
+            _ => Self::Mixed,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Lf => "lf",
+            Self::CrLf => "crlf",
+            Self::Mixed => "mixed",
+        }
+    }
+}
+
+/// The average length of the lines of `content`.
+///
+/// Empty content, like that of files indexed by path only, has no lines, and an average of 0.
+fn avg_line_length(content: &str) -> f64 {
+    content.len() as f64 / content.lines().count().max(1) as f64
+}
+
+/// Split `buffer` into shards of `lines` lines, the last of which has the lines left over.
+fn split_shards(buffer: &str, lines: usize) -> Vec<String> {
+    let mut shards = vec![];
+    let mut shard = String::new();
+    for (i, line) in buffer.split_inclusive('\n').enumerate() {
+        if i > 0 && i % lines == 0 {
+            shards.push(std::mem::take(&mut shard));
+        }
+        shard.push_str(line);
+    }
+    shards.push(shard);
+    shards
+}
+
+/// Pick the hit with the most recent commit timestamp.
+///
+/// Ties are broken in favour of the earliest hit, which is the
+/// highest scoring one for results coming from `TopDocs`.
+fn pick_freshest<T>(hits: impl IntoIterator<Item = (u64, T)>) -> Option<T> {
+    hits.into_iter()
+        .reduce(|best, next| if next.0 > best.0 { next } else { best })
+        .map(|(_, hit)| hit)
+}
+
+/// Collect the text terms of `field` that still point to a live document.
+///
+/// Terms of deleted documents stay in the dictionary until the segment
+/// is merged, so their postings have to be checked explicitly.
+fn live_terms(
+    segment_reader: &SegmentReader,
+    field: Field,
+    out: &mut BTreeSet<String>,
+) -> tantivy::Result<()> {
+    let inverted_index = segment_reader.inverted_index(field)?;
+    let mut terms = inverted_index.terms().stream()?;
+
+    while terms.advance() {
+        let Ok(term) = std::str::from_utf8(terms.key()) else {
+            continue;
+        };
+
+        if out.contains(term) {
+            continue;
+        }
+
+        let is_live = match segment_reader.alive_bitset() {
+            None => true,
+            Some(alive) => {
+                let mut postings = inverted_index
+                    .read_postings_from_terminfo(terms.value(), IndexRecordOption::Basic)?;
+                let mut doc = postings.doc();
+                while doc != TERMINATED && !alive.is_alive(doc) {
+                    doc = postings.advance();
+                }
+                doc != TERMINATED
+            }
+        };
+
+        if is_live {
+            out.insert(term.to_owned());
+        }
+    }
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(cache))]
+fn is_cache_fresh(cache: &FileCacheSnapshot, unique_hash: &str, stats: RepoStats) -> bool {
+    match cache.entry(unique_hash.into()) {
+        Entry::Occupied(mut val) => {
+            // skip processing if contents are up-to-date in the cache
+            val.get_mut().fresh = true;
+
+            trace!("cache hit");
+            return true;
+        }
+        Entry::Vacant(val) => {
+            _ = val.insert_entry(stats.into());
+        }
+    }
+
+    trace!("cache miss");
+    false
+}
+
+fn build_fuzzy_regex_filter(query_str: &str) -> Option<regex::RegexSet> {
+    fn additions(s: &str, i: usize, j: usize) -> String {
+        if i > j {
+            additions(s, j, i)
+        } else {
+            let mut s = s.to_owned();
+            s.insert_str(j, ".?");
+            s.insert_str(i, ".?");
+            s
+        }
+    }
+
+    fn replacements(s: &str, i: usize, j: usize) -> String {
+        if i > j {
+            replacements(s, j, i)
+        } else {
+            let mut s = s.to_owned();
+            s.remove(j);
+            s.insert_str(j, ".?");
+
+            s.remove(i);
+            s.insert_str(i, ".?");
+
+            s
+        }
+    }
+
+    fn one_of_each(s: &str, i: usize, j: usize) -> String {
+        if i > j {
+            one_of_each(s, j, i)
+        } else {
+            let mut s = s.to_owned();
+            s.remove(j);
+            s.insert_str(j, ".?");
+
+            s.insert_str(i, ".?");
+            s
+        }
+    }
+
+    let all_regexes = (query_str.char_indices().map(|(idx, _)| idx))
+        .flat_map(|i| (query_str.char_indices().map(|(idx, _)| idx)).map(move |j| (i, j)))
+        .filter(|(i, j)| i <= j)
+        .flat_map(|(i, j)| {
+            let mut v = vec![];
+            if j != query_str.len() {
+                v.push(one_of_each(query_str, i, j));
+                v.push(replacements(query_str, i, j));
+            }
+            v.push(additions(query_str, i, j));
+            v
+        });
+
+    regex::RegexSetBuilder::new(all_regexes)
+        // Increased from the default to account for long paths. At the time of writing,
+        // the default was `10 * (1 << 20)`.
+        .size_limit(10 * (1 << 25))
+        .case_insensitive(true)
+        .build()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuzzy_multibyte_should_compile() {
+        let multibyte_str = "查询解析器在哪";
+        let filter = build_fuzzy_regex_filter(multibyte_str);
+        assert!(filter.is_some());
+
+        // tests removal of second character
+        assert!(filter.as_ref().unwrap().is_match("查解析器在哪"));
+
+        // tests replacement of second character with `n`
+        assert!(filter.as_ref().unwrap().is_match("查n析器在哪"));
+
+        // tests addition of character `n`
+        assert!(filter.as_ref().unwrap().is_match("查询解析器在哪n"));
+    }
+
+    #[test]
+    fn live_terms_skip_deleted_documents() {
+        use tantivy::schema::{STORED, STRING};
+
+        let mut builder = Schema::builder();
+        let repo_ref = builder.add_text_field("repo_ref", STRING | STORED);
+        let index = tantivy::Index::create_in_ram(builder.build());
+
+        let mut writer = index.writer(15_000_000).unwrap();
+        writer
+            .add_document(doc!(repo_ref => "local//kept"))
+            .unwrap();
+        writer
+            .add_document(doc!(repo_ref => "local//removed"))
+            .unwrap();
+        writer.commit().unwrap();
+
+        writer.delete_term(Term::from_field_text(repo_ref, "local//removed"));
+        writer.commit().unwrap();
+
+        let searcher = index.reader().unwrap().searcher();
+        let mut terms = BTreeSet::new();
+        for segment_reader in searcher.segment_readers() {
+            live_terms(segment_reader, repo_ref, &mut terms).unwrap();
+        }
+
+        assert_eq!(terms.into_iter().collect::<Vec<_>>(), ["local//kept"]);
+    }
+
+    #[test]
+    fn generated_files_skip_symbol_extraction() {
+        let generated = GeneratedFiles::new(
+            &["*.pb.go".to_owned(), "*_generated.*".to_owned()],
+            &["Code generated".to_owned()],
+        );
+
+        let source = "package api\n\nfunc Hello() string { return \"hi\" }\n";
+        let with_header = format!("// Code generated by protoc-gen-go. DO NOT EDIT.\n{source}");
+
+        assert!(generated.matches(Path::new("api/hello.pb.go"), source));
+        assert!(generated.matches(Path::new("schema_generated.rs"), source));
+        assert!(generated.matches(Path::new("api/hello.go"), &with_header));
+        assert!(!generated.matches(Path::new("api/hello.go"), source));
+
+        // hand-written code still gets a scope graph
+        assert!(matches!(
+            extract_symbols(source, "Go"),
+            SymbolLocations::TreeSitter(_)
+        ));
+    }
+
+    #[test]
+    fn summary_of_rust_module_docs() {
+        let source = "// Copyright 2023 bloop\n\
+                      \n\
+                      //! Parsing of the bloop query language.\n\
+                      //!\n\
+                      //! More details follow.\n\
+                      \n\
+                      use std::borrow::Cow;\n";
+        assert_eq!(
+            extract_summary(source, "Rust"),
+            "Parsing of the bloop query language."
+        );
+
+        // no comments at all
+        assert_eq!(
+            extract_summary("\nuse std::borrow::Cow;\n", "Rust"),
+            "use std::borrow::Cow;"
+        );
+    }
+
+    #[test]
+    fn summary_of_plain_text() {
+        let text = "\n   \n  Release notes for 1.0  \n\nEverything is new.\n";
+        assert_eq!(extract_summary(text, ""), "Release notes for 1.0");
+        assert_eq!(extract_summary("", ""), "");
+
+        let long_line = "ü".repeat(MAX_SUMMARY_LEN);
+        assert!(extract_summary(&long_line, "").len() <= MAX_SUMMARY_LEN);
+    }
+
+    #[test]
+    fn transient_duplicates_pick_freshest() {
+        assert_eq!(pick_freshest::<&str>([]), None);
+        assert_eq!(pick_freshest([(10, "only")]), Some("only"));
+        assert_eq!(
+            pick_freshest([(10, "old"), (30, "new"), (20, "older")]),
+            Some("new")
+        );
+
+        // equal timestamps keep the highest scoring hit
+        assert_eq!(
+            pick_freshest([(10, "first"), (10, "second")]),
+            Some("first")
+        );
+    }
+
+    #[test]
+    fn detect_line_endings() {
+        assert_eq!(LineEnding::detect(""), LineEnding::Lf);
+        assert_eq!(LineEnding::detect("foo\nbar\n"), LineEnding::Lf);
+        assert_eq!(LineEnding::detect("foo\r\nbar\r\n"), LineEnding::CrLf);
+        assert_eq!(LineEnding::detect("foo\r\nbar\nbaz"), LineEnding::Mixed);
+    }
+
+    /// A local repository on disk, and an empty file index to index it into.
+    struct LocalRepo {
+        indexer: Indexer<File>,
+        reporef: RepoRef,
+        repo: Repository,
+        _dirs: [tempdir::TempDir; 2],
+    }
+
+    impl LocalRepo {
+        async fn new(files: &[(&str, &str)], args: &[&str]) -> Self {
+            use crate::repo::{RepoRemote, SyncStatus};
+            use clap::Parser;
+            use sqlx::sqlite::SqlitePoolOptions;
+            use tempdir::TempDir;
+
+            let repo_dir = TempDir::new("local-repo").unwrap();
+            for (name, content) in files {
+                let path = repo_dir.path().join(name);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, content).unwrap();
+            }
+
+            let pool = SqlitePoolOptions::new()
+                .max_connections(1)
+                .connect("sqlite::memory:")
+                .await
+                .unwrap();
+            sqlx::migrate!().run(&pool).await.unwrap();
+            let sql = Arc::new(pool);
+
+            let config =
+                crate::Configuration::parse_from(["bleep"].into_iter().chain(args.iter().copied()));
+            let index_dir = TempDir::new("local-repo-index").unwrap();
+            let max_result_limit = config.max_result_limit;
+            let indexer = Indexer::create(
+                File::new(config.into(), sql, None),
+                index_dir.path(),
+                15_000_000,
+                1,
+            )
+            .unwrap()
+            .with_max_result_limit(max_result_limit);
+
+            let disk_path = crate::canonicalize(repo_dir.path()).unwrap();
+            let reporef: RepoRef = format!("local/{}", disk_path.display()).parse().unwrap();
+            let repo = Repository {
+                disk_path,
+                remote: RepoRemote::from(&reporef),
+                sync_status: SyncStatus::Queued,
+                last_commit_unix_secs: 0,
+                last_index_unix_secs: 0,
+                most_common_lang: None,
+                branch_filter: None,
+                semantic: true,
+                semantic_status: Default::default(),
+                path_root: None,
+            };
+
+            Self {
+                indexer,
+                reporef,
+                repo,
+                _dirs: [repo_dir, index_dir],
+            }
+        }
+
+        async fn index(&self, handle: &super::super::IndexWriteHandle<'_>) -> IndexOutcome {
+            let metadata = self.repo.get_repo_metadata().await;
+            let (progress, _) = tokio::sync::broadcast::channel(16);
+            let pipes = SyncPipes::new(self.reporef.clone(), None, progress);
+
+            handle
+                .index(&self.reporef, &self.repo, &metadata, &pipes, false, None)
+                .await
+                .unwrap()
+        }
+
+        async fn dry_run(&self) -> WalkBreakdown {
+            let handle = self.indexer.write_handle().unwrap();
+            let metadata = self.repo.get_repo_metadata().await;
+            let (progress, _) = tokio::sync::broadcast::channel(16);
+            let pipes = SyncPipes::new(self.reporef.clone(), None, progress);
+
+            handle
+                .index(&self.reporef, &self.repo, &metadata, &pipes, true, None)
+                .await
+                .unwrap()
+                .breakdown
+                .unwrap()
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dry_runs_skip_lfs_pointers() {
+        let pointer = format!(
+            "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize 12\n",
+            "a".repeat(64)
+        );
+        let local = LocalRepo::new(
+            &[
+                ("weights.pt", pointer.as_str()),
+                ("lib.rs", "fn lib() {}\n"),
+            ],
+            &[],
+        )
+        .await;
+
+        let breakdown = local.dry_run().await;
+        assert_eq!(breakdown.would_index, 1);
+        assert_eq!(breakdown.skipped, 1);
+
+        let handle = local.indexer.write_handle().unwrap();
+        let outcome = local.index(&handle).await;
+        assert_eq!(outcome.indexable_files, breakdown.would_index);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dry_runs_ignore_files_outside_the_path_root() {
+        let mut local = LocalRepo::new(
+            &[
+                ("services/api/lib.rs", "fn api() {}\n"),
+                ("services/web/index.js", "web();\n"),
+                ("README.md", "# monorepo\n"),
+            ],
+            &[],
+        )
+        .await;
+        local.repo.path_root = Some("services/api".into());
+
+        let breakdown = local.dry_run().await;
+        assert_eq!(breakdown.would_index, 1);
+
+        let handle = local.indexer.write_handle().unwrap();
+        let outcome = local.index(&handle).await;
+        assert_eq!(outcome.indexable_files, breakdown.would_index);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dry_runs_skip_conflicted_files() {
+        let conflicted = "<<<<<<< HEAD\nfn ours() {}\n=======\nfn theirs() {}\n>>>>>>> dev\n";
+        let files = [("merged.rs", conflicted), ("lib.rs", "fn lib() {}\n")];
+
+        // conflicted files are only dropped when they're to be skipped
+        let raw = LocalRepo::new(&files, &[]).await;
+        assert_eq!(raw.dry_run().await.would_index, 2);
+
+        let local = LocalRepo::new(&files, &["--conflict-mode", "skip-conflicted"]).await;
+        let breakdown = local.dry_run().await;
+        assert_eq!(breakdown.would_index, 1);
+        assert_eq!(breakdown.skipped, 1);
+
+        let handle = local.indexer.write_handle().unwrap();
+        let outcome = local.index(&handle).await;
+        assert_eq!(outcome.indexable_files, breakdown.would_index);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn slowest_files_are_reported_by_phase() {
+        let mut local = LocalRepo::new(
+            &[
+                ("slow.rs", "fn slow() {}\n"),
+                ("fast.rs", "fn fast() {}\n"),
+                ("notes.md", "# notes\n"),
+            ],
+            &[],
+        )
+        .await;
+
+        local.indexer.source.extract_symbols = |buffer, lang| {
+            if buffer.contains("slow") {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            extract_symbols(buffer, lang)
+        };
+
+        let handle = local.indexer.write_handle().unwrap();
+        let outcome = local.index(&handle).await;
+
+        assert_eq!(outcome.slowest.len(), 3);
+        let slowest = &outcome.slowest[0];
+        assert_eq!(slowest.relative_path, "slow.rs");
+        assert!(slowest.timings.symbols >= Duration::from_millis(100));
+        assert!(slowest.timings.read < slowest.timings.symbols);
+        assert!(slowest.timings.write < slowest.timings.symbols);
+        assert!(outcome.slowest[1..]
+            .iter()
+            .all(|f| f.timings.total() < slowest.timings.total()));
+    }
+
+    #[tokio::test]
+    async fn low_commit_interval_commits_during_run() {
+        let local = LocalRepo::new(
+            &[
+                ("a.rs", "// a.rs\n"),
+                ("b.rs", "// b.rs\n"),
+                ("c.rs", "// c.rs\n"),
+            ],
+            &["--commit-interval", "1"],
+        )
+        .await;
+        let indexer = &local.indexer;
+
+        let entries =
+            FileWalker::index_directory(&local.repo.disk_path, Default::default()).len() as u64;
+        let mut handle = indexer.write_handle().unwrap();
+        local.index(&handle).await;
+
+        // every batch but the last one is committed by the run itself
+        let committed = || indexer.index.reader().unwrap().searcher().num_docs();
+        assert_eq!(committed(), entries - 1);
+
+        handle.commit().await.unwrap();
+        assert_eq!(committed(), entries);
+    }
+
+    #[tokio::test]
+    async fn memory_budget_commits_during_run() {
+        let files = [
+            ("a.rs", "// a.rs\n"),
+            ("b.rs", "// the longest of the files\n"),
+            ("c.rs", "// c.rs\n"),
+        ];
+        let committed =
+            |local: &LocalRepo| local.indexer.index.reader().unwrap().searcher().num_docs();
+
+        // without a budget, the whole repository is committed at the end
+        let unbounded = LocalRepo::new(&files, &[]).await;
+        let handle = unbounded.indexer.write_handle().unwrap();
+        let outcome = unbounded.index(&handle).await;
+        assert_eq!(committed(&unbounded), 0);
+        assert_eq!(outcome.peak_memory_bytes, 0);
+
+        // a budget smaller than any file commits after every file but the last, and only
+        // lets one file in at a time
+        let local = LocalRepo::new(&files, &["--memory-budget-bytes", "1"]).await;
+        let mut handle = local.indexer.write_handle().unwrap();
+        let outcome = local.index(&handle).await;
+        assert!(committed(&local) >= files.len() as u64 - 1);
+        assert_eq!(outcome.peak_memory_bytes, files[1].1.len() as u64);
+
+        handle.commit().await.unwrap();
+        let entries =
+            FileWalker::index_directory(&local.repo.disk_path, Default::default()).len() as u64;
+        assert_eq!(committed(&local), entries);
+        drop(handle);
+
+        // fresh files aren't written again, so they don't spend the budget or force commits
+        let opstamp = || local.indexer.index.load_metas().unwrap().opstamp;
+        let before = opstamp();
+        let handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        assert_eq!(opstamp(), before);
+    }
+
+    #[test]
+    fn files_are_read_within_the_memory_budget() {
+        let dir = tempdir::TempDir::new("memory-budget").unwrap();
+        for name in ["a.rs", "b.rs", "c.rs", "d.rs"] {
+            std::fs::write(dir.path().join(name), "// a file\n").unwrap();
+        }
+        let (progress, _) = tokio::sync::broadcast::channel(1);
+        let pipes = SyncPipes::new("local//memory-budget".parse().unwrap(), None, progress);
+
+        // every file spends the whole budget, so a file is only read once the one before it
+        // was written
+        let budget = MemoryBudget::new(1);
+        let read = AtomicU64::default();
+        let most_read = AtomicU64::default();
+        FileWalker::index_directory(dir.path(), Default::default()).for_each_reserved(
+            &pipes,
+            |bytes| {
+                let reserved = budget.acquire(bytes);
+                if bytes > 0 {
+                    let now = read.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_read.fetch_max(now, Ordering::SeqCst);
+                }
+                (reserved, bytes)
+            },
+            |_, (reserved, bytes)| {
+                std::thread::sleep(Duration::from_millis(10));
+                if bytes > 0 {
+                    read.fetch_sub(1, Ordering::SeqCst);
+                }
+                reserved.written();
+            },
+        );
+        assert_eq!(most_read.load(Ordering::SeqCst), 1);
+        assert!(budget.is_spent());
+
+        // files that are dropped before they're written don't hold the budget until the commit
+        budget.committed();
+        drop(budget.acquire(10));
+        assert!(!budget.is_spent());
+    }
+
+    #[tokio::test]
+    async fn grammar_panics_only_drop_symbols_of_the_file() {
+        let mut local = LocalRepo::new(
+            &[("boom.rs", "fn boom() {}\n"), ("fine.rs", "fn fine() {}\n")],
+            &[],
+        )
+        .await;
+        local.indexer.source.extract_symbols = |buffer, lang| {
+            if buffer.contains("boom") {
+                panic!("malformed grammar");
+            }
+            extract_symbols(buffer, lang)
+        };
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let outline = |path: &'static str| local.indexer.symbol_outline(&local.reporef, path);
+        assert!(outline("boom.rs").await.unwrap().is_empty());
+        assert_eq!(outline("fine.rs").await.unwrap().len(), 1);
+
+        let boom = local
+            .indexer
+            .by_path(&local.reporef, "boom.rs", None)
+            .await
+            .unwrap();
+        assert_eq!(boom.content, "fn boom() {}\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reindexing_symbols_keeps_stored_content() {
+        let mut local = LocalRepo::new(
+            &[
+                ("lib.rs", "pub fn parse() {}\nfn helper() {}\n"),
+                ("notes.md", "# parse\n"),
+            ],
+            &[],
+        )
+        .await;
+        local.indexer.source.extract_symbols = |_, _| SymbolLocations::Empty;
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let num_docs = local.indexer.reader.read().await.searcher().num_docs();
+        let before = local
+            .indexer
+            .by_path(&local.reporef, "lib.rs", None)
+            .await
+            .unwrap();
+        assert!(local
+            .indexer
+            .symbol_outline(&local.reporef, "lib.rs")
+            .await
+            .unwrap()
+            .is_empty());
+
+        // the file is gone from disk, only the index has it
+        std::fs::remove_file(local.repo.disk_path.join("lib.rs")).unwrap();
+        local.indexer.source.extract_symbols = extract_symbols;
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        let searcher = local.indexer.reader.read().await.searcher();
+        let rewritten = local
+            .indexer
+            .source
+            .reindex_symbols(
+                &searcher,
+                &handle.writer.read().unwrap(),
+                &local.repo,
+                &local.reporef,
+                &local.repo.get_repo_metadata().await,
+            )
+            .unwrap();
+        handle.commit().await.unwrap();
+        assert_eq!(rewritten, 2);
+
+        let after = local
+            .indexer
+            .by_path(&local.reporef, "lib.rs", None)
+            .await
+            .unwrap();
+        assert_eq!(after.content, before.content);
+        assert_eq!(after.line_end_indices, before.line_end_indices);
+        assert_eq!(
+            local
+                .indexer
+                .symbol_outline(&local.reporef, "lib.rs")
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+
+        let matches = local
+            .indexer
+            .by_symbols(&local.reporef, &["helper"], 10)
+            .await
+            .unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].doc.relative_path, "lib.rs");
+
+        // documents were replaced, not duplicated
+        let searcher = local.indexer.reader.read().await.searcher();
+        assert_eq!(searcher.num_docs(), num_docs);
+    }
+
+    #[tokio::test]
+    async fn by_symbols_attributes_names_to_files() {
+        let local = LocalRepo::new(
+            &[
+                (
+                    "config.rs",
+                    "pub struct Config {}\nfn load() -> Config { Config {} }\n",
+                ),
+                ("app.rs", "fn start() {}\nfn load() {}\n"),
+                ("loader.rs", "struct ConfigLoader;\nfn loads() {}\n"),
+                ("README.md", "Config, load and start\n"),
+            ],
+            &[],
+        )
+        .await;
+        let indexer = &local.indexer;
+
+        let mut handle = indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let mut matches = indexer
+            .by_symbols(&local.reporef, &["start", "Config", "load"], 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| (m.doc.relative_path, m.names))
+            .collect::<Vec<_>>();
+        matches.sort();
+
+        assert_eq!(
+            matches,
+            [
+                (
+                    "app.rs".to_owned(),
+                    vec!["start".to_owned(), "load".to_owned()]
+                ),
+                (
+                    "config.rs".to_owned(),
+                    vec!["Config".to_owned(), "load".to_owned()]
+                ),
+            ]
+        );
+
+        assert_eq!(
+            indexer
+                .by_symbols(&local.reporef, &["start", "Config", "load"], 1)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let names = vec!["load"; MAX_SYMBOL_NAMES + 1];
+        assert!(indexer
+            .by_symbols(&local.reporef, &names, 10)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn prose_mode_matches_words_and_skips_symbols() {
+        use crate::query::parser;
+        use tantivy::collector::Count;
+
+        let search = |mode: &'static str| async move {
+            let local = LocalRepo::new(
+                &[
+                    (
+                        "README.md",
+                        "# Deployment\n\nThe servers run on their own ports.\n",
+                    ),
+                    ("main.rs", "fn main() {}\n"),
+                ],
+                &["--corpus-mode", mode],
+            )
+            .await;
+            let indexer = &local.indexer;
+
+            let mut handle = indexer.write_handle().unwrap();
+            local.index(&handle).await;
+            handle.commit().await.unwrap();
+
+            let count = |query: &str| {
+                let queries = parser::parse(query).unwrap();
+                let compiled = ContentReader
+                    .compile(&indexer.source, queries.iter(), &indexer.index)
+                    .unwrap();
+                let searcher = indexer.index.reader().unwrap().searcher();
+                searcher.search(&compiled, &Count).unwrap()
+            };
+
+            let outline = indexer
+                .symbol_outline(&local.reporef, "main.rs")
+                .await
+                .unwrap();
+            let regex = ContentReader.compile(
+                &indexer.source,
+                parser::parse("/serv[a-z]+/").unwrap().iter(),
+                &indexer.index,
+            );
+            (
+                count("running server"),
+                count("the servers"),
+                outline.len(),
+                regex.is_ok(),
+            )
+        };
+
+        // words are matched by their stem, and stop words are dropped, which regexes can't see
+        assert_eq!(search("prose").await, (1, 1, 0, false));
+        assert_eq!(search("code").await, (0, 1, 1, true));
+    }
+
+    #[tokio::test]
+    async fn content_prose_matches_words_and_identifiers() {
+        use crate::query::parser;
+
+        let search = |args: &'static [&'static str]| async move {
+            let local = LocalRepo::new(
+                &[
+                    (
+                        "dates.rs",
+                        "/// Reads a timestamp\nfn parse_iso8601(input: &str) -> Date {\n    todo!()\n}\n",
+                    ),
+                    ("notes.md", "We parse every ISO8601 timestamp the same way.\n"),
+                ],
+                args,
+            )
+            .await;
+            let indexer = &local.indexer;
+
+            let mut handle = indexer.write_handle().unwrap();
+            local.index(&handle).await;
+            handle.commit().await.unwrap();
+
+            let paths = |query: &str| {
+                let queries = parser::parse(query).unwrap();
+                let compiled = ContentReader
+                    .compile(&indexer.source, queries.iter(), &indexer.index)
+                    .unwrap();
+                let searcher = indexer.index.reader().unwrap().searcher();
+                searcher
+                    .search(&compiled, &TopDocs::with_limit(10))
+                    .unwrap()
+                    .into_iter()
+                    .map(|(_, addr)| {
+                        let doc = searcher.doc(addr).unwrap();
+                        let path = doc.get_first(indexer.source.relative_path).unwrap();
+                        path.as_text().unwrap().to_owned()
+                    })
+                    .collect::<Vec<_>>()
+            };
+
+            (paths("parsing dates"), paths("parse_iso8601"))
+        };
+
+        // stems of the words match, and the exact identifier still ranks first
+        let (prose, identifier) = search(&["--index-content-prose"]).await;
+        assert_eq!(prose, ["dates.rs"]);
+        assert_eq!(identifier, ["dates.rs", "notes.md"]);
+
+        let (prose, identifier) = search(&[]).await;
+        assert!(prose.is_empty());
+        assert_eq!(identifier, ["dates.rs"]);
+    }
+
+    #[tokio::test]
+    async fn has_symbols_filter() {
+        use crate::query::parser;
+        use tantivy::collector::Count;
+
+        let local = LocalRepo::new(
+            &[
+                ("main.rs", "fn main() {}\n"),
+                ("config.json", "{\"main\": true}\n"),
+            ],
+            &[],
+        )
+        .await;
+        let indexer = &local.indexer;
+
+        let mut handle = indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let count = |query: &str| {
+            let queries = parser::parse(query).unwrap();
+            let compiled = ContentReader
+                .compile(&indexer.source, queries.iter(), &indexer.index)
+                .unwrap();
+            let searcher = indexer.index.reader().unwrap().searcher();
+            searcher.search(&compiled, &Count).unwrap()
+        };
+
+        assert_eq!(count("main"), 2);
+        assert_eq!(count("has_symbols:true main"), 1);
+        assert_eq!(count("has_symbols:false main"), 1);
+        assert_eq!(count("has_symbols:true path:json main"), 0);
+        assert_eq!(count("has_symbols:false path:json main"), 1);
+    }
+
+    #[tokio::test]
+    async fn owner_and_tag_filters() {
+        use tantivy::collector::Count;
+
+        let local = LocalRepo::new(
+            &[
+                (
+                    ".github/CODEOWNERS",
+                    "*.rs @org/rust\nsrc/payments/ @org/Payments @alice\n",
+                ),
+                (".bloop/tags.toml", "critical = [\"src/payments/\"]\n"),
+                ("src/payments/refund.rs", "fn refund() {}\n"),
+                ("src/main.rs", "fn main() { refund() }\n"),
+                ("README.md", "refund policy\n"),
+            ],
+            &[],
+        )
+        .await;
+        let indexer = &local.indexer;
+
+        let mut handle = indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let doc = indexer
+            .by_path(&local.reporef, "src/payments/refund.rs", None)
+            .await
+            .unwrap();
+        assert_eq!(doc.owners, ["@org/payments", "@alice"]);
+        assert_eq!(doc.tags, ["critical"]);
+
+        let readme = indexer
+            .by_path(&local.reporef, "README.md", None)
+            .await
+            .unwrap();
+        assert!(readme.owners.is_empty() && readme.tags.is_empty());
+
+        let count = |query: &str| {
+            let queries = parser::parse(query).unwrap();
+            let compiled = ContentReader
+                .compile(&indexer.source, queries.iter(), &indexer.index)
+                .unwrap();
+            let searcher = indexer.index.reader().unwrap().searcher();
+            searcher.search(&compiled, &Count).unwrap()
+        };
+
+        assert_eq!(count("refund"), 3);
+        assert_eq!(count("owner:@org/rust refund"), 1);
+        assert_eq!(count("owner:@ALICE refund"), 1);
+        assert_eq!(count("tag:critical refund"), 1);
+        assert_eq!(count("tag:critical path:main refund"), 0);
+        assert_eq!(count("tag:unknown refund"), 0);
+    }
+
+    #[tokio::test]
+    async fn lfs_pointers_by_mode() {
+        const OID: &str = "4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393";
+        let object = "def predict():\n    return 42\n";
+        let pointer = format!(
+            "version https://git-lfs.github.com/spec/v1\noid sha256:{OID}\nsize {}\n",
+            object.len()
+        );
+
+        let indexed = |mode: &'static str, fetched: bool| {
+            let pointer = pointer.clone();
+            async move {
+                let local =
+                    LocalRepo::new(&[("model.py", pointer.as_str())], &["--lfs-mode", mode]).await;
+                if fetched {
+                    let objects = local.repo.disk_path.join(".git/lfs/objects/4d/7a");
+                    std::fs::create_dir_all(&objects).unwrap();
+                    std::fs::write(objects.join(OID), object).unwrap();
+                }
+
+                let mut handle = local.indexer.write_handle().unwrap();
+                local.index(&handle).await;
+                handle.commit().await.unwrap();
+
+                local
+                    .indexer
+                    .by_path(&local.reporef, "model.py", None)
+                    .await
+                    .ok()
+                    .map(|doc| (doc.content, doc.lang))
+            }
+        };
+
+        assert_eq!(indexed("skip", true).await, None);
+
+        let (content, lang) = indexed("index-pointer-meta", true).await.unwrap();
+        assert_eq!(content, pointer);
+        assert_eq!(lang, None);
+
+        assert_eq!(
+            indexed("resolve-if-local", true).await,
+            Some((object.to_owned(), Some("Python".to_owned())))
+        );
+        assert_eq!(indexed("resolve-if-local", false).await, None);
+    }
+
+    #[tokio::test]
+    async fn resolved_lfs_objects_are_checked_like_files() {
+        let indexed = |object: &'static [u8], args: &'static [&'static str]| async move {
+            let oid = blake3::hash(object).to_hex().to_string();
+            let pointer = format!(
+                "version https://git-lfs.github.com/spec/v1\noid sha256:{oid}\nsize {}\n",
+                object.len()
+            );
+
+            let args = [&["--lfs-mode", "resolve-if-local"][..], args].concat();
+            let local = LocalRepo::new(&[("model.bin", pointer.as_str())], &args).await;
+            let objects = local
+                .repo
+                .disk_path
+                .join(".git/lfs/objects")
+                .join(&oid[..2])
+                .join(&oid[2..4]);
+            std::fs::create_dir_all(&objects).unwrap();
+            std::fs::write(objects.join(&oid), object).unwrap();
+
+            let mut handle = local.indexer.write_handle().unwrap();
+            local.index(&handle).await;
+            handle.commit().await.unwrap();
+
+            local
+                .indexer
+                .by_path(&local.reporef, "model.bin", None)
+                .await
+                .ok()
+                .map(|doc| doc.content)
+        };
+
+        // binary objects are found by path only
+        assert_eq!(
+            indexed(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", &[]).await,
+            Some(String::new())
+        );
+
+        let object = b"weights = [1, 2, 3]\n";
+        assert_eq!(
+            indexed(object, &["--max-file-size-bytes", "10"]).await,
+            None
+        );
+        assert_eq!(
+            indexed(object, &[]).await.as_deref(),
+            Some("weights = [1, 2, 3]\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn conflicted_files_by_mode() {
+        use tantivy::collector::Count;
+
+        let conflicted =
+            "fn main() {\n<<<<<<< HEAD\n    ours();\n=======\n    theirs();\n>>>>>>> feature\n}\n";
+
+        let indexed = |mode: &'static str| async move {
+            let local = LocalRepo::new(
+                &[("main.rs", conflicted), ("lib.rs", "fn lib() {}\n")],
+                &["--conflict-mode", mode],
+            )
+            .await;
+            let mut handle = local.indexer.write_handle().unwrap();
+            local.index(&handle).await;
+            handle.commit().await.unwrap();
+
+            // files without markers are indexed the same in every mode
+            let lib = local.indexer.by_path(&local.reporef, "lib.rs", None).await;
+            assert_eq!(lib.unwrap().content, "fn lib() {}\n");
+
+            let searcher = local.indexer.reader.read().await.searcher();
+            let query = TermQuery::new(
+                Term::from_field_bool(local.indexer.source.has_conflicts, true),
+                IndexRecordOption::Basic,
+            );
+            let tagged = searcher.search(&query, &Count).unwrap();
+
+            let content = local
+                .indexer
+                .by_path(&local.reporef, "main.rs", None)
+                .await
+                .ok()
+                .map(|doc| doc.content);
+            (content, tagged)
+        };
+
+        assert_eq!(indexed("skip-conflicted").await, (None, 0));
+        assert_eq!(
+            indexed("index-ours").await,
+            (Some("fn main() {\n    ours();\n}\n".to_owned()), 1)
+        );
+        assert_eq!(
+            indexed("index-theirs").await,
+            (Some("fn main() {\n    theirs();\n}\n".to_owned()), 1)
+        );
+        assert_eq!(indexed("index-raw").await, (Some(conflicted.to_owned()), 1));
+    }
+
+    #[tokio::test]
+    async fn repo_of_ignored_files_has_nothing_to_index() {
+        use crate::repo::SyncStatus;
+
+        let local = LocalRepo::new(
+            &[
+                ("logo.png", "not really a png\n"),
+                ("Cargo.lock", "# generated\n"),
+                ("debug.log", "started\n"),
+            ],
+            &[],
+        )
+        .await;
+        let mut handle = local.indexer.write_handle().unwrap();
+        let outcome = local.index(&handle).await;
+        assert_eq!(outcome.indexable_files, 0);
+        handle.commit().await.unwrap();
+
+        let metadata = local.repo.get_repo_metadata().await;
+        let mut repo = local.repo.clone();
+        repo.sync_done_with(None, metadata.clone(), outcome.indexable_files, true, 0);
+        assert_eq!(repo.sync_status, SyncStatus::DoneEmpty);
+        assert!(repo.sync_status.is_done());
+
+        // a file to index turns it into a regular repository
+        std::fs::write(local.repo.disk_path.join("main.rs"), "fn main() {}\n").unwrap();
+        let mut handle = local.indexer.write_handle().unwrap();
+        let outcome = local.index(&handle).await;
+        assert_eq!(outcome.indexable_files, 1);
+        handle.commit().await.unwrap();
+
+        repo.sync_done_with(None, metadata, outcome.indexable_files, true, 0);
+        assert_eq!(repo.sync_status, SyncStatus::Done);
+
+        let local = LocalRepo::new(&[("main.rs", "fn main() {}\n"), ("debug.log", "")], &[]).await;
+        let mut handle = local.indexer.write_handle().unwrap();
+        assert_eq!(local.index(&handle).await.indexable_files, 1);
+        handle.commit().await.unwrap();
+
+        // files that are already up to date still count
+        let handle = local.indexer.write_handle().unwrap();
+        assert_eq!(local.index(&handle).await.indexable_files, 1);
+    }
+
+    #[tokio::test]
+    async fn reindexing_reports_the_files_that_changed() {
+        let local = LocalRepo::new(
+            &[
+                ("a.rs", "// a.rs\n"),
+                ("b.rs", "// b.rs\n"),
+                ("c.rs", "// c.rs\n"),
+            ],
+            &[],
+        )
+        .await;
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        let changes = local.index(&handle).await.changes;
+        handle.commit().await.unwrap();
+        assert_eq!(changes.added, ["a.rs", "b.rs", "c.rs"]);
+        assert!(changes.modified.is_empty() && changes.removed.is_empty());
+
+        let dir = &local.repo.disk_path;
+        std::fs::write(dir.join("b.rs"), "// b.rs, again\n").unwrap();
+        std::fs::remove_file(dir.join("c.rs")).unwrap();
+        std::fs::write(dir.join("d.rs"), "// d.rs\n").unwrap();
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        let changes = local.index(&handle).await.changes;
+        handle.commit().await.unwrap();
+        assert_eq!(
+            changes,
+            ChangeSet {
+                added: vec!["d.rs".to_owned()],
+                modified: vec!["b.rs".to_owned()],
+                removed: vec!["c.rs".to_owned()],
+            }
+        );
+
+        // nothing changed since
+        let handle = local.indexer.write_handle().unwrap();
+        assert_eq!(local.index(&handle).await.changes, ChangeSet::default());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn renamed_files_leave_no_stale_documents() {
+        let local =
+            LocalRepo::new(&[("foo.rs", "fn foo() {}\n"), ("b.rs", "// b.rs\n")], &[]).await;
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        // the contents stay the same, only the path changes
+        let dir = &local.repo.disk_path;
+        std::fs::create_dir(dir.join("src")).unwrap();
+        std::fs::rename(dir.join("foo.rs"), dir.join("src/bar.rs")).unwrap();
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        let changes = local.index(&handle).await.changes;
+        handle.commit().await.unwrap();
+        assert_eq!(
+            changes,
+            ChangeSet {
+                added: vec!["src/bar.rs".to_owned()],
+                modified: vec![],
+                removed: vec!["foo.rs".to_owned()],
+            }
+        );
+
+        assert!(matches!(
+            local.indexer.by_path(&local.reporef, "foo.rs", None).await,
+            Err(FileIndexError::NotFound)
+        ));
+        let versions = local
+            .indexer
+            .by_path_all_versions(&local.reporef, "src/bar.rs")
+            .await
+            .unwrap();
+        assert_eq!(versions.len(), 1);
+
+        // both files, and the documents of the root and `src/` directories
+        assert_eq!(local.indexer.reader.read().await.searcher().num_docs(), 4);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn verify_finds_missing_stale_and_orphaned_files() {
+        let local = LocalRepo::new(
+            &[
+                ("a.rs", "// a.rs\n"),
+                ("b.rs", "// b.rs\n"),
+                ("c.rs", "// c.rs\n"),
+            ],
+            &[],
+        )
+        .await;
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let verify = || local.indexer.verify_repo(&local.reporef, &local.repo);
+        assert!(verify().await.unwrap().is_consistent());
+
+        let dir = &local.repo.disk_path;
+        std::fs::write(dir.join("b.rs"), "// b.rs, again\n").unwrap();
+        std::fs::remove_file(dir.join("c.rs")).unwrap();
+        std::fs::write(dir.join("d.rs"), "// d.rs\n").unwrap();
+        assert_eq!(
+            verify().await.unwrap(),
+            VerifyReport {
+                missing: vec!["d.rs".to_owned()],
+                stale: vec!["b.rs".to_owned()],
+                orphaned: vec!["c.rs".to_owned()],
+            }
+        );
+
+        // re-indexing fixes every discrepancy
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+        assert!(verify().await.unwrap().is_consistent());
+    }
+
+    #[tokio::test]
+    async fn content_search_leaves_out_vendored_files() {
+        use crate::query::{
+            execute::{ApiQuery, ExecuteQuery, QueryResult},
+            parser,
+        };
+
+        let local = LocalRepo::new(
+            &[
+                ("src/config.rs", "pub fn load_config() {}\n"),
+                ("vendor/toml/lib.rs", "pub fn load_config() {}\n"),
+            ],
+            &[],
+        )
+        .await;
+        let indexer = &local.indexer;
+
+        let mut handle = indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let paths = |query: serde_json::Value| {
+            let api_query: ApiQuery = serde_json::from_value(query).unwrap();
+            async move {
+                let queries = parser::parse(&api_query.q).unwrap();
+                let mut paths = ContentReader
+                    .execute(indexer, &queries, &api_query)
+                    .await
+                    .unwrap()
+                    .data
+                    .into_iter()
+                    .map(|result| match result {
+                        QueryResult::Snippets(file) => file.relative_path,
+                        _ => panic!("not a content result"),
+                    })
+                    .collect::<Vec<_>>();
+                paths.sort();
+                paths
+            }
+        };
+
+        assert_eq!(
+            paths(serde_json::json!({ "q": "load_config" })).await,
+            ["src/config.rs"]
+        );
+        assert_eq!(
+            paths(serde_json::json!({ "q": "load_config", "include_vendored": true })).await,
+            ["src/config.rs", "vendor/toml/lib.rs"]
+        );
+    }
+
+    #[tokio::test]
+    async fn content_search_within_dirty_paths() {
+        use crate::{
+            query::{
+                execute::{ApiQuery, ExecuteQuery, QueryResult, MAX_DIRTY_PATHS},
+                parser,
+            },
+            state::RepositoryPool,
+        };
+
+        let local = LocalRepo::new(
+            &[
+                ("src/a.rs", "pub fn parse_args() {}\n"),
+                ("src/b.rs", "pub fn parse_args() {}\n"),
+                ("src/c.rs", "pub fn parse_args() {}\n"),
+            ],
+            &[],
+        )
+        .await;
+        let indexer = &local.indexer;
+        let repo_ref = local.reporef.to_string();
+
+        let mut handle = indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let paths = |query: serde_json::Value| {
+            let api_query: ApiQuery = serde_json::from_value(query).unwrap();
+            async move {
+                let queries = parser::parse(&api_query.q).unwrap();
+                let mut paths = ContentReader
+                    .execute(indexer, &queries, &api_query)
+                    .await
+                    .unwrap()
+                    .data
+                    .into_iter()
+                    .map(|result| match result {
+                        QueryResult::Snippets(file) => file.relative_path,
+                        _ => panic!("not a content result"),
+                    })
+                    .collect::<Vec<_>>();
+                paths.sort();
+                paths
+            }
+        };
+
+        assert_eq!(
+            paths(serde_json::json!({ "q": "parse_args", "dirty_paths": "src/c.rs,src/a.rs" }))
+                .await,
+            ["src/a.rs", "src/c.rs"]
+        );
+        assert_eq!(
+            paths(serde_json::json!({
+                "q": "parse_args",
+                "dirty_paths": "src/b.rs",
+                "dirty_repo": repo_ref,
+            }))
+            .await,
+            ["src/b.rs"]
+        );
+
+        // an empty scope has nothing to search, rather than everything
+        assert!(
+            paths(serde_json::json!({ "q": "parse_args", "dirty_paths": "" }))
+                .await
+                .is_empty()
+        );
+
+        // a repository's dirty files can only be found through the pool of known repositories
+        let mut api_query: ApiQuery = serde_json::from_value(
+            serde_json::json!({ "q": "parse_args", "dirty_repo": repo_ref }),
+        )
+        .unwrap();
+        assert!(api_query
+            .resolve_dirty_repo(&RepositoryPool::default())
+            .await
+            .is_err());
+        let queries = parser::parse(&api_query.q).unwrap();
+        assert!(ContentReader
+            .execute(indexer, &queries, &api_query)
+            .await
+            .is_err());
+
+        let too_many = vec!["src/a.rs"; MAX_DIRTY_PATHS + 1].join(",");
+        let api_query: ApiQuery = serde_json::from_value(
+            serde_json::json!({ "q": "parse_args", "dirty_paths": too_many }),
+        )
+        .unwrap();
+        let queries = parser::parse(&api_query.q).unwrap();
+        assert!(ContentReader
+            .execute(indexer, &queries, &api_query)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn fuzzy_content_search_tolerates_typos() {
+        use crate::query::{
+            execute::{ApiQuery, ExecuteQuery, QueryResult},
+            parser,
+        };
+
+        let local = LocalRepo::new(
+            &[
+                ("src/net.rs", "pub fn receive() {}\n"),
+                ("src/io.rs", "pub fn retrieve() {}\n"),
+            ],
+            &[],
+        )
+        .await;
+        let indexer = &local.indexer;
+
+        let mut handle = indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let paths = |query: serde_json::Value| {
+            let api_query: ApiQuery = serde_json::from_value(query).unwrap();
+            async move {
+                let queries = parser::parse(&api_query.q).unwrap();
+                let mut paths = ContentReader
+                    .execute(indexer, &queries, &api_query)
+                    .await
+                    .unwrap()
+                    .data
+                    .into_iter()
+                    .map(|result| match result {
+                        QueryResult::Snippets(file) => file.relative_path,
+                        _ => panic!("not a content result"),
+                    })
+                    .collect::<Vec<_>>();
+                paths.sort();
+                paths
+            }
+        };
+
+        assert!(paths(serde_json::json!({ "q": "recieve" }))
+            .await
+            .is_empty());
+        assert_eq!(
+            paths(serde_json::json!({ "q": "recieve", "fuzziness": 1 })).await,
+            ["src/net.rs"]
+        );
+        assert_eq!(
+            paths(serde_json::json!({ "q": "recieve", "fuzziness": 2 })).await,
+            ["src/io.rs", "src/net.rs"]
+        );
+    }
+
+    #[tokio::test]
+    async fn content_search_counts_matches_per_language() {
+        use crate::query::{
+            execute::{ApiQuery, ExecuteQuery},
+            parser,
+        };
+
+        let local = LocalRepo::new(
+            &[
+                ("main.rs", "fn main() { needle() }\n"),
+                ("lib.rs", "pub fn needle() {}\n"),
+                ("other.rs", "pub fn haystack() {}\n"),
+                ("README.md", "Look for the needle.\n"),
+                ("index.ts", "export const needle = 1;\n"),
+            ],
+            &[],
+        )
+        .await;
+        let indexer = &local.indexer;
+
+        let mut handle = indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let facets = |query: &str| {
+            let api_query: ApiQuery =
+                serde_json::from_value(serde_json::json!({ "q": query })).unwrap();
+            let queries = parser::parse(query).unwrap();
+            async move {
+                ContentReader
+                    .execute(indexer, &queries, &api_query)
+                    .await
+                    .unwrap()
+                    .stats
+                    .lang_facets()
+            }
+        };
+
+        assert_eq!(
+            facets("needle").await,
+            [
+                ("Rust".to_owned(), 2),
+                ("Markdown".to_owned(), 1),
+                ("TypeScript".to_owned(), 1),
+            ]
+        );
+
+        // facets follow the filters of the query
+        assert_eq!(facets("needle lang:rust").await, [("Rust".to_owned(), 2)]);
+        assert!(facets("nothing").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn explain_matches_and_exclusions() {
+        let local = LocalRepo::new(
+            &[
+                ("main.rs", "fn main() {}\n"),
+                ("lib.rs", "pub fn helper() {}\n"),
+                ("vendor/dep.rs", "fn main() {}\n"),
+            ],
+            &[],
+        )
+        .await;
+        let indexer = &local.indexer;
+
+        let mut handle = indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let explain = |query, path| indexer.by_repo_explain(&local.reporef, query, path);
+
+        // vendored files are left out, as they are from content search
+        let QueryExplanation::Excluded { reasons } =
+            explain("main", "vendor/dep.rs").await.unwrap()
+        else {
+            panic!("vendored files should not match");
+        };
+        assert_eq!(reasons, ["file does not match the vendored filter"]);
+
+        let QueryExplanation::Matched { explanation } = explain("main", "main.rs").await.unwrap()
+        else {
+            panic!("main.rs should match");
+        };
+        assert!(explanation.value() > 0.0);
+
+        let QueryExplanation::Excluded { reasons } =
+            explain("helper path:main", "main.rs").await.unwrap()
+        else {
+            panic!("main.rs should not match");
+        };
+        assert_eq!(reasons, ["file does not match the target filter"]);
+
+        let QueryExplanation::Excluded { reasons } =
+            explain("helper path:main or lang:go helper", "lib.rs")
+                .await
+                .unwrap()
+        else {
+            panic!("lib.rs should not match");
+        };
+        assert_eq!(
+            reasons,
+            [
+                "file does not match the path filter of alternative 1",
+                "file does not match the lang filter of alternative 2",
+            ]
+        );
+
+        assert!(explain("main", "missing.rs").await.is_err());
+        assert!(explain("path:main", "main.rs").await.is_err());
+    }
+
+    fn content_document(content: &str, lang: &str) -> ContentDocument {
+        ContentDocument {
+            content: content.to_owned(),
+            lang: Some(lang.to_owned()),
+            line_end_indices: LineIndex::new(content),
+            symbol_locations: extract_symbols(content, lang),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn outline_of_tree_sitter_file() {
+        let doc = content_document(
+            "struct Config {\n    port: u16,\n}\n\nfn main() {\n    let config = load();\n}\n",
+            "Rust",
+        );
+
+        let outline = doc
+            .outline()
+            .into_iter()
+            .map(|item| (item.text, item.kind, item.start_line, item.end_line))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            outline,
+            [
+                ("Config".to_owned(), "struct".to_owned(), 0, 0),
+                ("port".to_owned(), "field".to_owned(), 1, 1),
+                ("main".to_owned(), "function".to_owned(), 4, 4),
+                ("config".to_owned(), "variable".to_owned(), 5, 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn outline_without_symbols_is_empty() {
+        // plain text has no scope graph, so no symbol locations are stored
+        let doc = content_document("Config\nmain\n", "");
+        assert!(matches!(doc.symbol_locations, SymbolLocations::Empty));
+        assert!(doc.outline().is_empty());
+    }
+
+    #[tokio::test]
+    async fn by_path_reports_missing_and_ambiguous_paths() {
+        use clap::Parser;
+        use sqlx::SqlitePool;
+        use tempdir::TempDir;
+
+        let config = crate::Configuration::parse_from(["bleep"]);
+        let sql = Arc::new(SqlitePool::connect_lazy("sqlite::memory:").unwrap());
+        let dir = TempDir::new("by-path").unwrap();
+        let indexer = Indexer::create(
+            File::new(config.into(), sql, None),
+            dir.path(),
+            15_000_000,
+            1,
+        )
+        .unwrap();
+        let reporef: RepoRef = "local//tmp/repo".parse().unwrap();
+
+        // longer paths that repeat a path can outrank it in the path search
+        let decoys = (0..TOP_HIT_DUPLICATE_HEADROOM + 1)
+            .map(|i| format!("{i}/src/main.rs/src/main.rs/src/main.rs"))
+            .collect::<Vec<_>>();
+
+        let mut handle = indexer.write_handle().unwrap();
+        for path in [
+            "src/main.rs",
+            "web/src/main.rs",
+            "lib/util.rs",
+            "sub/util.rs",
+        ]
+        .into_iter()
+        .chain(decoys.iter().map(String::as_str))
+        {
+            let schema = &indexer.source;
+            handle
+                .writer
+                .read()
+                .unwrap()
+                .add_document(tantivy::doc!(
+                    schema.repo_ref => reporef.to_string(),
+                    schema.repo_name => "repo",
+                    schema.relative_path => path,
+                    schema.raw_relative_path => path.as_bytes().to_vec(),
+                    schema.content => path,
+                    schema.line_end_indices => Vec::<u8>::new(),
+                    schema.symbol_locations => Vec::<u8>::new(),
+                    schema.last_commit_unix_seconds => 0u64,
+                ))
+                .unwrap();
+        }
+        handle.commit().await.unwrap();
+
+        let by_path = |path| indexer.by_path(&reporef, path, None);
+        assert_eq!(
+            by_path("src/main.rs").await.unwrap().relative_path,
+            "src/main.rs"
+        );
+        assert_eq!(
+            by_path("web/src").await.unwrap().relative_path,
+            "web/src/main.rs"
+        );
+        assert!(matches!(
+            by_path("util.rs").await,
+            Err(FileIndexError::Ambiguous { count: 2 })
+        ));
+        assert!(matches!(
+            by_path("missing.rs").await,
+            Err(FileIndexError::NotFound)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn healthcheck_fails_without_index_files() {
+        let local = LocalRepo::new(&[("lib.rs", "fn main() {}\n")], &[]).await;
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        local.indexer.healthcheck().await.unwrap();
+
+        std::fs::remove_file(local._dirs[1].path().join("meta.json")).unwrap();
+        assert!(matches!(
+            local.indexer.healthcheck().await,
+            Err(FileIndexError::Corrupt(_))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn file_body_bytes_reads_byte_ranges() {
+        let content = "fn main() {\n    println!(\"héllo\");\n}\n";
+        let local = LocalRepo::new(&[("main.rs", content)], &[]).await;
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let read = |range| {
+            local
+                .indexer
+                .file_body_bytes(&local.reporef, "main.rs", None, range)
+        };
+
+        let full = read(0..usize::MAX).await.unwrap();
+        assert_eq!(full.bytes, content.as_bytes());
+        assert_eq!(full.total_len, content.len());
+
+        // ranges can split characters
+        let partial = read(3..27).await.unwrap();
+        assert_eq!(partial.bytes, &content.as_bytes()[3..27]);
+        assert_eq!(partial.total_len, content.len());
+
+        assert!(read(content.len()..content.len())
+            .await
+            .unwrap()
+            .bytes
+            .is_empty());
+        assert!(matches!(
+            read(content.len() + 1..content.len() + 2).await,
+            Err(FileIndexError::BadRange { .. })
+        ));
+        assert!(matches!(
+            read(5..2).await,
+            Err(FileIndexError::BadRange { .. })
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn language_overrides_map_custom_extensions() {
+        let source = "def parse():\n    pass\n";
+        let mut local = LocalRepo::new(
+            &[("lib.mylang", source), ("templates/page.tpl", source)],
+            &[],
+        )
+        .await;
+        local.indexer.source.language_overrides = LanguageOverrides::new(&HashMap::from([
+            (".mylang".to_owned(), "python".to_owned()),
+            ("templates/*.tpl".to_owned(), "python".to_owned()),
+        ]));
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        for path in ["lib.mylang", "templates/page.tpl"] {
+            let doc = local
+                .indexer
+                .by_path(&local.reporef, path, None)
+                .await
+                .unwrap();
+            assert_eq!(doc.lang.as_deref(), Some("Python"));
+
+            let outline = local
+                .indexer
+                .symbol_outline(&local.reporef, path)
+                .await
+                .unwrap();
+            assert_eq!(outline.len(), 1);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn outlines_of_several_files_at_once() {
+        let local = LocalRepo::new(
+            &[
+                ("config.rs", "struct Config {\n    port: u16,\n}\n"),
+                ("main.rs", "fn main() {}\nfn exit() {}\n"),
+                ("notes.txt", "no symbols here\n"),
+            ],
+            &[],
+        )
+        .await;
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let paths = ["config.rs", "main.rs", "notes.txt", "missing.rs"];
+        let outlines = local
+            .indexer
+            .symbol_outlines(&local.reporef, &paths)
+            .await
+            .unwrap();
+
+        let mut found = outlines.keys().map(String::as_str).collect::<Vec<_>>();
+        found.sort();
+        assert_eq!(found, ["config.rs", "main.rs", "notes.txt"]);
+        assert_eq!(outlines["main.rs"].len(), 2);
+        assert!(outlines["notes.txt"].is_empty());
+
+        // the same outlines as fetching them one file at a time
+        for path in ["config.rs", "main.rs"] {
+            let outline = local
+                .indexer
+                .symbol_outline(&local.reporef, path)
+                .await
+                .unwrap();
+            assert_eq!(outlines[path], outline);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn several_files_by_path_at_once() {
+        let local = LocalRepo::new(
+            &[
+                ("src/main.rs", "fn main() {}\n"),
+                ("src/lib.rs", "pub fn lib() {}\n"),
+                ("README.md", "# readme\n"),
+            ],
+            &[],
+        )
+        .await;
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let paths = ["src/main.rs", "README.md", "src/missing.rs"];
+        let docs = local
+            .indexer
+            .by_paths(&local.reporef, &paths)
+            .await
+            .unwrap();
+
+        let mut found = docs.keys().map(String::as_str).collect::<Vec<_>>();
+        found.sort();
+        assert_eq!(found, ["README.md", "src/main.rs"]);
+        assert_eq!(docs["src/main.rs"].content, "fn main() {}\n");
+
+        // the same documents as fetching them one by one
+        for path in ["src/main.rs", "README.md"] {
+            let doc = local
+                .indexer
+                .by_path(&local.reporef, path, None)
+                .await
+                .unwrap();
+            assert_eq!(docs[path].content, doc.content);
+            assert_eq!(docs[path].line_end_indices, doc.line_end_indices);
+        }
+
+        let paths = vec!["src/main.rs"; MAX_PATHS + 1];
+        assert!(local
+            .indexer
+            .by_paths(&local.reporef, &paths)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn files_over_the_maximum_size_are_skipped() {
+        let content = "x".repeat(2048);
+        let files = [("big.txt", content.as_str()), ("small.txt", "hello\n")];
+        // sharding doesn't bring files over the maximum back
+        let local = LocalRepo::new(
+            &files,
+            &["--max-file-size-bytes", "1024", "--shard-lines", "2500"],
+        )
+        .await;
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        let outcome = local.index(&handle).await;
+        handle.commit().await.unwrap();
+        assert_eq!(outcome.changes.added, ["small.txt"]);
+
+        let by_path = |path: &'static str| local.indexer.by_path(&local.reporef, path, None);
+        assert!(matches!(
+            by_path("big.txt").await,
+            Err(FileIndexError::NotFound)
+        ));
+        assert_eq!(by_path("small.txt").await.unwrap().content, "hello\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn large_files_are_indexed_in_shards() {
+        use crate::query::{
+            execute::{ApiQuery, ExecuteQuery, QueryResult},
+            parser,
+        };
+
+        let content = (0..6000).map(|i| format!("line {i}\n")).collect::<String>();
+        let files = [("big.txt", content.as_str()), ("small.txt", "hello\n")];
+
+        // without sharding, the file is over the line limit and skipped
+        let skipped = LocalRepo::new(&files, &[]).await;
+        let mut handle = skipped.indexer.write_handle().unwrap();
+        skipped.index(&handle).await;
+        handle.commit().await.unwrap();
+        assert!(matches!(
+            skipped
+                .indexer
+                .by_path(&skipped.reporef, "big.txt", None)
+                .await,
+            Err(FileIndexError::NotFound)
+        ));
+
+        let local = LocalRepo::new(&files, &["--shard-lines", "2500"]).await;
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let mut shards = local
+            .indexer
+            .by_path_all_versions(&local.reporef, "big.txt")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|version| {
+                let start = version.doc.shard_start.unwrap();
+                (version.doc.shard_index, start.line, start.byte)
+            })
+            .collect::<Vec<_>>();
+        shards.sort();
+        let bytes = |lines| {
+            content
+                .split_inclusive('\n')
+                .take(lines)
+                .map(str::len)
+                .sum::<usize>()
+        };
+        assert_eq!(
+            shards,
+            [
+                (Some(0), 0, 0),
+                (Some(1), 2500, bytes(2500)),
+                (Some(2), 5000, bytes(5000))
+            ]
+        );
+
+        // matches in later shards are found at their line in the whole file
+        let api_query: ApiQuery =
+            serde_json::from_value(serde_json::json!({ "q": "4321" })).unwrap();
+        let queries = parser::parse(&api_query.q).unwrap();
+        let results = ContentReader
+            .execute(&local.indexer, &queries, &api_query)
+            .await
+            .unwrap();
+        let [QueryResult::Snippets(file)] = results.data.as_slice() else {
+            panic!("expected the snippets of one file");
+        };
+        assert!(file
+            .snippets
+            .iter()
+            .any(|snippet| snippet.line_range.contains(&4321)));
+
+        // the shards are put back together into the whole file
+        let doc = local
+            .indexer
+            .by_path(&local.reporef, "big.txt", None)
+            .await
+            .unwrap();
+        assert_eq!(doc.content, content);
+        assert_eq!(doc.shard_index, None);
+        assert_eq!(doc.line_end_indices, LineIndex::new(&content));
+        let docs = local
+            .indexer
+            .by_paths(&local.reporef, &["big.txt"])
+            .await
+            .unwrap();
+        assert_eq!(docs["big.txt"].content, content);
+        let page = local
+            .indexer
+            .by_repo(
+                &local.reporef,
+                std::iter::empty::<&str>(),
+                None,
+                Pagination {
+                    limit: 10,
+                    offset: 0,
+                },
+            )
+            .await
+            .unwrap();
+        let files = page
+            .docs
+            .iter()
+            .filter(|doc| !doc.relative_path.ends_with('/'))
+            .collect::<Vec<_>>();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].relative_path, "big.txt");
+        assert_eq!(files[0].content, content);
+        assert_eq!(files[0].shard_index, None);
+
+        // small files are still indexed whole
+        let small = local
+            .indexer
+            .by_path(&local.reporef, "small.txt", None)
+            .await
+            .unwrap();
+        assert_eq!(small.shard_index, None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reindexing_symbols_keeps_every_shard() {
+        let content = (0..6000).map(|i| format!("line {i}\n")).collect::<String>();
+        let files = [
+            ("big.txt", content.as_str()),
+            ("lib.rs", "fn helper() {}\n"),
+        ];
+        let local = LocalRepo::new(&files, &["--shard-lines", "2500"]).await;
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let shard_count = || async {
+            local
+                .indexer
+                .by_path_all_versions(&local.reporef, "big.txt")
+                .await
+                .unwrap()
+                .len()
+        };
+        assert_eq!(shard_count().await, 3);
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        let searcher = local.indexer.reader.read().await.searcher();
+        let rewritten = local
+            .indexer
+            .source
+            .reindex_symbols(
+                &searcher,
+                &handle.writer.read().unwrap(),
+                &local.repo,
+                &local.reporef,
+                &local.repo.get_repo_metadata().await,
+            )
+            .unwrap();
+        handle.commit().await.unwrap();
+
+        // only the whole file is rewritten
+        assert_eq!(rewritten, 1);
+        assert_eq!(shard_count().await, 3);
+
+        let doc = local
+            .indexer
+            .by_path(&local.reporef, "big.txt", None)
+            .await
+            .unwrap();
+        assert_eq!(doc.content, content);
+        assert_eq!(doc.line_end_indices, LineIndex::new(&content));
+        assert!(local
+            .indexer
+            .symbol_outline(&local.reporef, "big.txt")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn delete_repos_where_removes_matching_repos() {
+        use crate::repo::{RepoRemote, SyncStatus};
+
+        let local = LocalRepo::new(&[("main.rs", "fn main() {}\n")], &[]).await;
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+
+        // two more repositories, in the same index
+        let dirs = ["keep", "drop"].map(|name| tempdir::TempDir::new(name).unwrap());
+        let mut reporefs = vec![local.reporef.clone()];
+        for dir in &dirs {
+            std::fs::write(dir.path().join("lib.rs"), "fn lib() {}\n").unwrap();
+            let disk_path = crate::canonicalize(dir.path()).unwrap();
+            let reporef: RepoRef = format!("local/{}", disk_path.display()).parse().unwrap();
+            let repo = Repository {
+                disk_path,
+                remote: RepoRemote::from(&reporef),
+                sync_status: SyncStatus::Queued,
+                last_commit_unix_secs: 0,
+                last_index_unix_secs: 0,
+                most_common_lang: None,
+                branch_filter: None,
+                semantic: true,
+                semantic_status: Default::default(),
+                path_root: None,
+            };
+
+            let metadata = repo.get_repo_metadata().await;
+            let (progress, _) = tokio::sync::broadcast::channel(16);
+            let pipes = SyncPipes::new(reporef.clone(), None, progress);
+            handle
+                .index(&reporef, &repo, &metadata, &pipes, false, None)
+                .await
+                .unwrap();
+            reporefs.push(reporef);
+        }
+        handle.commit().await.unwrap();
+        drop(handle);
+
+        let sql = &local.indexer.source.sql;
+        let cached =
+            |reporef| async move { FileCache::for_repo(sql, reporef).retrieve().await.len() };
+        for reporef in &reporefs {
+            assert!(cached(reporef).await > 0);
+        }
+
+        let kept = &reporefs[1];
+        let deleted = local
+            .indexer
+            .delete_repos_where(|reporef| reporef != kept)
+            .await
+            .unwrap();
+        let mut expected = vec![reporefs[0].clone(), reporefs[2].clone()];
+        expected.sort_by_key(ToString::to_string);
+        assert_eq!(deleted, expected);
+
+        assert_eq!(local.indexer.all_repos().await, [kept.clone()]);
+        assert!(cached(kept).await > 0);
+        assert_eq!(cached(&reporefs[0]).await, 0);
+        assert_eq!(cached(&reporefs[2]).await, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn byte_order_marks_are_stripped() {
+        use crate::query::{
+            execute::{ApiQuery, ExecuteQuery, QueryResult},
+            parser,
+        };
+
+        let local = LocalRepo::new(
+            &[
+                ("bom.rs", "\u{feff}fn first() {}\nfn second() {}\n"),
+                ("plain.rs", "fn third() {}\n"),
+            ],
+            &[],
+        )
+        .await;
+        let indexer = &local.indexer;
+
+        let mut handle = indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let doc = indexer
+            .by_path(&local.reporef, "bom.rs", None)
+            .await
+            .unwrap();
+        assert_eq!(doc.content, "fn first() {}\nfn second() {}\n");
+        assert_eq!(doc.line_end_indices.ends(), [13, 28]);
+        assert_eq!(doc.outline()[0].text, "first");
+
+        let api_query: ApiQuery =
+            serde_json::from_value(serde_json::json!({ "q": "symbol:first" })).unwrap();
+        let queries = parser::parse(&api_query.q).unwrap();
+        let results = ContentReader
+            .execute(indexer, &queries, &api_query)
+            .await
+            .unwrap()
+            .data;
+        assert!(matches!(
+            results.as_slice(),
+            [QueryResult::Snippets(file)] if file.relative_path == "bom.rs"
+        ));
+
+        let searcher = indexer.reader.read().await.searcher();
+        let has_bom = |path: &str| {
+            let query = TermQuery::new(
+                Term::from_field_bytes(indexer.source.raw_relative_path, path.as_bytes()),
+                IndexRecordOption::Basic,
+            );
+            let (_, addr) = searcher.search(&query, &TopDocs::with_limit(1)).unwrap()[0];
+            searcher
+                .doc(addr)
+                .unwrap()
+                .get_first(indexer.source.has_bom)
+                .and_then(|value| value.as_bool())
+        };
+        assert_eq!(has_bom("bom.rs"), Some(true));
+        assert_eq!(has_bom("plain.rs"), Some(false));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn find_symbols_combines_filters() {
+        let local = LocalRepo::new(
+            &[
+                (
+                    "main.rs",
+                    "fn handle() {}\nstruct handle {}\nfn main() {}\n",
+                ),
+                ("lib.py", "def handle():\n    pass\n"),
+            ],
+            &[],
+        )
+        .await;
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let find = |name, kind, lang, limit| {
+            let local = &local;
+            async move {
+                let mut found = local
+                    .indexer
+                    .find_symbols(&local.reporef, name, kind, lang, limit)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|found| {
+                        format!(
+                            "{}:{}:{}",
+                            found.relative_path, found.symbol.kind, found.symbol.text
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                found.sort();
+                found
+            }
+        };
+
+        assert_eq!(
+            find(Some("handle"), Some("function"), Some("rust"), 10).await,
+            ["main.rs:function:handle"]
+        );
+        assert_eq!(
+            find(Some("handle"), None, None, 10).await,
+            [
+                "lib.py:function:handle",
+                "main.rs:function:handle",
+                "main.rs:struct:handle"
+            ]
+        );
+        assert_eq!(
+            find(None, Some("Function"), None, 10).await,
+            [
+                "lib.py:function:handle",
+                "main.rs:function:handle",
+                "main.rs:function:main"
+            ]
+        );
+        assert_eq!(
+            find(None, None, Some("py"), 10).await,
+            ["lib.py:function:handle"]
+        );
+        assert_eq!(find(None, None, None, 10).await.len(), 4);
+        assert_eq!(find(None, None, None, 2).await.len(), 2);
+        assert!(find(Some("missing"), None, None, 10).await.is_empty());
+        assert!(find(Some("handle"), Some("class"), None, 10)
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn by_symbol_finds_every_occurrence() {
+        let local = LocalRepo::new(
+            &[
+                ("main.rs", "fn handle() {}\nstruct handle {}\n"),
+                ("lib.rs", "fn handled() {}\nfn handle() {}\n"),
+                ("other.rs", "fn other() {}\n"),
+            ],
+            &[],
+        )
+        .await;
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let by_symbol = |symbol, limit| {
+            let local = &local;
+            async move {
+                let mut hits = local
+                    .indexer
+                    .by_symbol(&local.reporef, symbol, limit)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|hit| {
+                        format!(
+                            "{}:{}:{}",
+                            hit.relative_path, hit.range.start.line, hit.kind
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                hits.sort();
+                hits
+            }
+        };
+
+        // symbols that only contain the name don't count
+        assert_eq!(
+            by_symbol("handle", 10).await,
+            [
+                "lib.rs:1:function",
+                "main.rs:0:function",
+                "main.rs:1:struct"
+            ]
+        );
+        assert_eq!(by_symbol("handle", 2).await.len(), 2);
+        assert!(by_symbol("missing", 10).await.is_empty());
+
+        let hits = local
+            .indexer
+            .by_symbol(&local.reporef, "other", 10)
+            .await
+            .unwrap();
+        let doc = local
+            .indexer
+            .by_path(&local.reporef, "other.rs", None)
+            .await
+            .unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(
+            &doc.content[hits[0].range.start.byte..hits[0].range.end.byte],
+            "other"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn generated_files_are_indexed_without_symbols() {
+        let source = "package api\n\nfunc Hello() string { return \"hi\" }\n";
+        let with_header = format!("// Code generated by protoc-gen-go. DO NOT EDIT.\n{source}");
+        let local = LocalRepo::new(
+            &[
+                ("api/hello.go", source),
+                ("api/hello.pb.go", source),
+                ("api/marked.go", &with_header),
+            ],
+            &[],
+        )
+        .await;
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let found = local
+            .indexer
+            .find_symbols(&local.reporef, Some("Hello"), None, None, 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|found| found.relative_path)
+            .collect::<Vec<_>>();
+        assert_eq!(found, ["api/hello.go"]);
+
+        // generated files are still searchable by path and content
+        for path in ["api/hello.pb.go", "api/marked.go"] {
+            let doc = local
+                .indexer
+                .by_path(&local.reporef, path, None)
+                .await
+                .unwrap();
+            assert!(doc.content.contains("func Hello()"));
+            assert!(doc.symbol_locations.list().is_empty());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn absurd_limits_are_clamped() {
+        let local = LocalRepo::new(
+            &[
+                ("a.rs", "fn first() {}\n"),
+                ("b.rs", "fn second() {}\n"),
+                ("c.rs", "fn third() {}\n"),
+            ],
+            &["--max-result-limit", "2"],
+        )
+        .await;
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        assert_eq!(local.indexer.clamp_limit(usize::MAX), 2);
+        assert_eq!(local.indexer.clamp_limit(1), 1);
+
+        let found = local
+            .indexer
+            .find_symbols(&local.reporef, None, Some("function"), None, usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 2);
+
+        let paths = local
+            .indexer
+            .fuzzy_path_match(&local.reporef, ".rs", None, usize::MAX)
+            .await
+            .unwrap()
+            .count();
+        assert_eq!(paths, 2);
+
+        // lookups without a limit of their own are capped as well
+        let docs = local
+            .indexer
+            .by_paths(&local.reporef, &["a.rs", "b.rs", "c.rs"])
+            .await
+            .unwrap();
+        assert_eq!(docs.len(), 2);
+
+        let outlines = local
+            .indexer
+            .symbol_outlines(&local.reporef, &["a.rs", "b.rs", "c.rs"])
+            .await
+            .unwrap();
+        assert_eq!(outlines.len(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn line_blame_follows_two_commits() {
+        let local = LocalRepo::new(
+            &[("main.rs", "fn first() {}\nfn second() {}\n")],
+            &["--compute-line-blame"],
+        )
+        .await;
+
+        let git = |date: &str, args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args(["-c", "user.name=bloop", "-c", "user.email=bloop@bloop.ai"])
+                .args(args)
+                .env("GIT_COMMITTER_DATE", date)
+                .current_dir(&local.repo.disk_path)
+                .status()
+                .unwrap();
+            assert!(status.success(), "git {args:?} failed");
+        };
+        git("@1600000000 +0000", &["init", "-q", "-b", "main"]);
+        git("@1600000000 +0000", &["add", "."]);
+        git("@1600000000 +0000", &["commit", "-q", "-m", "first"]);
+
+        std::fs::write(
+            local.repo.disk_path.join("main.rs"),
+            "fn first() {}\nfn changed() {}\nfn second() {}\n",
+        )
+        .unwrap();
+        git("@1700000000 +0000", &["commit", "-q", "-am", "second"]);
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let doc = local
+            .indexer
+            .by_path(&local.reporef, "main.rs", None)
+            .await
+            .unwrap();
+        let blame = doc.line_blame.unwrap();
+        let timestamps = blame
+            .lines()
+            .map(|commit| commit.unix_seconds)
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps, [1600000000, 1700000000, 1600000000]);
+        assert_eq!(blame.commits.len(), 2);
+        assert_ne!(blame.commits[0].oid, blame.commits[1].oid);
+        assert_eq!(
+            blame.commit_for_line(1).map(|commit| commit.unix_seconds),
+            Some(1700000000)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn indexing_a_ref_keeps_the_files_of_head() {
+        let local = LocalRepo::new(&[("a.rs", "fn a() {}\n")], &[]).await;
+
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args(["-c", "user.name=bloop", "-c", "user.email=bloop@bloop.ai"])
+                .args(args)
+                .current_dir(&local.repo.disk_path)
+                .status()
+                .unwrap();
+            assert!(status.success(), "git {args:?} failed");
+        };
+        git(&["init", "-q", "-b", "main"]);
+        git(&["add", "."]);
+        git(&["commit", "-q", "-m", "a"]);
+        git(&["checkout", "-q", "-b", "dev"]);
+        std::fs::write(local.repo.disk_path.join("dev.rs"), "fn dev() {}\n").unwrap();
+        git(&["add", "."]);
+        git(&["commit", "-q", "-m", "dev"]);
+        git(&["checkout", "-q", "main"]);
+        std::fs::write(local.repo.disk_path.join("main.rs"), "fn main() {}\n").unwrap();
+        git(&["add", "."]);
+        git(&["commit", "-q", "-m", "main"]);
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let metadata = local.repo.get_repo_metadata().await;
+        let (progress, _) = tokio::sync::broadcast::channel(16);
+        let pipes = SyncPipes::new(local.reporef.clone(), None, progress);
+        let outcome = handle
+            .index(
+                &local.reporef,
+                &local.repo,
+                &metadata,
+                &pipes,
+                false,
+                Some("dev"),
+            )
+            .await
+            .unwrap();
+        handle.commit().await.unwrap();
+        assert!(outcome.changes.removed.is_empty());
+
+        let by_path = |path: &'static str| local.indexer.by_path(&local.reporef, path, None);
+        assert_eq!(by_path("main.rs").await.unwrap().content, "fn main() {}\n");
+        assert_eq!(by_path("dev.rs").await.unwrap().content, "fn dev() {}\n");
+        assert_eq!(by_path("a.rs").await.unwrap().content, "fn a() {}\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn merged_branches_are_searchable_on_the_target_branch() {
+        let local = LocalRepo::new(&[("a.rs", "fn a() {}\n")], &[]).await;
+
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args(["-c", "user.name=bloop", "-c", "user.email=bloop@bloop.ai"])
+                .args(args)
+                .current_dir(&local.repo.disk_path)
+                .status()
+                .unwrap();
+            assert!(status.success(), "git {args:?} failed");
+        };
+        git(&["init", "-q", "-b", "main"]);
+        git(&["add", "."]);
+        git(&["commit", "-q", "-m", "a"]);
+        git(&["checkout", "-q", "-b", "feature"]);
+        std::fs::write(local.repo.disk_path.join("feature.rs"), "fn feature() {}\n").unwrap();
+        git(&["add", "."]);
+        git(&["commit", "-q", "-m", "feature"]);
+        git(&["checkout", "-q", "main"]);
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        let metadata = local.repo.get_repo_metadata().await;
+        let (progress, _) = tokio::sync::broadcast::channel(16);
+        let pipes = SyncPipes::new(local.reporef.clone(), None, progress);
+        handle
+            .index(
+                &local.reporef,
+                &local.repo,
+                &metadata,
+                &pipes,
+                false,
+                Some("feature"),
+            )
+            .await
+            .unwrap();
+        handle.commit().await.unwrap();
+
+        let on_main =
+            |path: &'static str| local.indexer.by_path(&local.reporef, path, Some("main"));
+        assert!(matches!(
+            on_main("feature.rs").await,
+            Err(FileIndexError::NotFound)
+        ));
+
+        let searcher = local.indexer.reader.read().await.searcher();
+        let rewritten = local
+            .indexer
+            .source
+            .merge_branch(
+                &searcher,
+                &handle.writer.read().unwrap(),
+                &local.reporef,
+                "feature",
+                "main",
+            )
+            .unwrap();
+        handle.commit().await.unwrap();
+
+        // `a.rs` is on both branches, only the document of the `feature` run is rewritten
+        assert_eq!(rewritten, 2);
+        let doc = on_main("feature.rs").await.unwrap();
+        assert_eq!(doc.content, "fn feature() {}\n");
+        assert_eq!(doc.symbol_locations.list().len(), 1);
+        assert_eq!(on_main("a.rs").await.unwrap().content, "fn a() {}\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn missing_git_objects_are_reported_as_skipped() {
+        let local = LocalRepo::new(
+            &[("kept.rs", "fn kept() {}\n"), ("gone.rs", "fn gone() {}\n")],
+            &[],
+        )
+        .await;
+
+        let git = |args: &[&str]| {
+            let output = std::process::Command::new("git")
+                .args(["-c", "user.name=bloop", "-c", "user.email=bloop@bloop.ai"])
+                .args(args)
+                .current_dir(&local.repo.disk_path)
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "git {args:?} failed");
+            String::from_utf8(output.stdout).unwrap()
+        };
+        git(&["init", "-q", "-b", "main"]);
+        git(&["add", "."]);
+        git(&["commit", "-q", "-m", "files"]);
+
+        // the blob of `gone.rs` is a loose object, which can be removed from under the tree
+        let oid = git(&["rev-parse", "HEAD:gone.rs"]);
+        let (dir, file) = oid.trim().split_at(2);
+        std::fs::remove_file(
+            local
+                .repo
+                .disk_path
+                .join(".git/objects")
+                .join(dir)
+                .join(file),
+        )
+        .unwrap();
+
+        let handle = local.indexer.write_handle().unwrap();
+        let outcome = local.index(&handle).await;
+        assert_eq!(
+            outcome.skipped,
+            crate::repo::iterator::SkippedEntries {
+                not_found: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn line_blame_matches_the_stored_lines() {
+        let local = LocalRepo::new(
+            &[
+                ("main.rs", "\u{feff}fn a() {}\r\n\r\nfn b() {}"),
+                ("other.rs", "fn other() {}\n"),
+            ],
+            &["--compute-line-blame"],
+        )
+        .await;
+
+        let git = |date: &str, args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args(["-c", "user.name=bloop", "-c", "user.email=bloop@bloop.ai"])
+                .args(args)
+                .env("GIT_COMMITTER_DATE", date)
+                .current_dir(&local.repo.disk_path)
+                .status()
+                .unwrap();
+            assert!(status.success(), "git {args:?} failed");
+        };
+        let blame = || async {
+            let mut handle = local.indexer.write_handle().unwrap();
+            local.index(&handle).await;
+            handle.commit().await.unwrap();
+
+            let doc = local
+                .indexer
+                .by_path(&local.reporef, "main.rs", None)
+                .await
+                .unwrap();
+            let blame = doc.line_blame.unwrap();
+            assert_eq!(blame.lines().count(), doc.line_end_indices.line_count());
+            blame
+                .lines()
+                .map(|commit| commit.unix_seconds)
+                .collect::<Vec<_>>()
+        };
+
+        // byte order marks, carriage returns and a missing final newline don't shift lines
+        git("@1600000000 +0000", &["init", "-q", "-b", "main"]);
+        git("@1600000000 +0000", &["add", "."]);
+        git("@1600000000 +0000", &["commit", "-q", "-m", "first"]);
+        assert_eq!(blame().await, [1600000000; 3]);
+
+        // commits to other files leave the document, and its blame, as they were
+        std::fs::write(local.repo.disk_path.join("other.rs"), "fn changed() {}\n").unwrap();
+        git("@1700000000 +0000", &["commit", "-q", "-am", "other"]);
+        assert_eq!(blame().await, [1600000000; 3]);
+
+        // only the lines a commit adds are blamed on it
+        std::fs::write(
+            local.repo.disk_path.join("main.rs"),
+            "\u{feff}fn a() {}\r\n\r\nfn b() {}\r\n// footer\r\n",
+        )
+        .unwrap();
+        git("@1800000000 +0000", &["commit", "-q", "-am", "footer"]);
+        assert_eq!(
+            blame().await,
+            [1600000000, 1600000000, 1600000000, 1800000000]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn estimated_hits_bound_the_actual_count() {
+        let local = LocalRepo::new(
+            &[
+                ("a.rs", "fn needle() {}\n"),
+                ("b.rs", "fn haystack() { needle() }\n"),
+                ("c.rs", "fn haystack() {}\n"),
+                ("d.py", "def needles():\n    pass\n"),
+                ("e.py", "def straw():\n    pass\n"),
+            ],
+            &[],
+        )
+        .await;
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let actual = |query: &str| {
+            let queries = parser::parse(query).unwrap();
+            let searcher = local.indexer.reader.try_read().unwrap().searcher();
+            let compiled = ContentReader
+                .compile(&local.indexer.source, queries.iter(), searcher.index())
+                .unwrap();
+            searcher
+                .search(&compiled, &tantivy::collector::Count)
+                .unwrap() as u64
+        };
+
+        for (query, ballpark) in [
+            ("needle", 3),
+            ("needle lang:rust", 3),
+            ("haystack or straw", 3),
+            ("path:d.py", 1),
+        ] {
+            let estimate = local
+                .indexer
+                .estimate_hits(&local.reporef, query)
+                .await
+                .unwrap();
+            assert!(estimate >= actual(query), "{query}: {estimate}");
+            assert!(estimate <= ballpark, "{query}: {estimate}");
+        }
+
+        assert_eq!(
+            local
+                .indexer
+                .estimate_hits(&local.reporef, "zqxj")
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn small_files_are_indexed_by_path() {
+        use crate::query::{
+            execute::{ApiQuery, ExecuteQuery},
+            parser,
+        };
+
+        let local = LocalRepo::new(
+            &[
+                ("src/foo/mod.rs", "mod bar;\n"),
+                ("src/__init__.py", ""),
+                ("src/main.rs", "fn main() { foo::bar::run() }\n"),
+            ],
+            &["--min-file-size-bytes", "16"],
+        )
+        .await;
+        let indexer = &local.indexer;
+
+        let mut handle = indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        for path in ["src/foo/mod.rs", "src/__init__.py"] {
+            let doc = indexer.by_path(&local.reporef, path, None).await.unwrap();
+            assert_eq!(doc.content, "");
+            assert_eq!(doc.line_end_indices.line_count(), 0);
+        }
+
+        let count = |query: serde_json::Value| {
+            let api_query: ApiQuery = serde_json::from_value(query).unwrap();
+            async move {
+                let queries = parser::parse(&api_query.q).unwrap();
+                let response = if ContentReader.query_matches(&queries[0]) {
+                    ContentReader.execute(indexer, &queries, &api_query).await
+                } else {
+                    FileReader.execute(indexer, &queries, &api_query).await
+                };
+                response.unwrap().count
+            }
+        };
+
+        // only the content of large enough files is searched
+        assert_eq!(count(serde_json::json!({ "q": "bar" })).await, 1);
+        assert_eq!(count(serde_json::json!({ "q": "path:mod.rs" })).await, 1);
+        assert_eq!(
+            count(serde_json::json!({ "q": "path:mod.rs", "exclude_small_files": true })).await,
+            0
+        );
+        assert_eq!(
+            count(serde_json::json!({ "q": "path:main.rs", "exclude_small_files": true })).await,
+            1
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn empty_files_have_a_line_length() {
+        let local = LocalRepo::new(&[("__init__.py", ""), ("main.py", "x = 1\n")], &[]).await;
+        let indexer = &local.indexer;
+
+        let mut handle = indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let assert_finite = |searcher: tantivy::Searcher| {
+            for segment in searcher.segment_readers() {
+                let line_length = segment
+                    .fast_fields()
+                    .f64(indexer.source.avg_line_length)
+                    .unwrap();
+                for doc in 0..segment.max_doc() {
+                    assert!(line_length.get_val(doc).is_finite());
+                }
+            }
+        };
+        assert_finite(indexer.reader.read().await.searcher());
+
+        // rewritten documents get their line length the same way
+        let mut handle = indexer.write_handle().unwrap();
+        let searcher = indexer.reader.read().await.searcher();
+        indexer
+            .source
+            .reindex_symbols(
+                &searcher,
+                &handle.writer.read().unwrap(),
+                &local.repo,
+                &local.reporef,
+                &local.repo.get_repo_metadata().await,
+            )
+            .unwrap();
+        handle.commit().await.unwrap();
+        assert_finite(indexer.reader.read().await.searcher());
+
+        let doc = indexer
+            .by_path(&local.reporef, "__init__.py", None)
+            .await
+            .unwrap();
+        assert_eq!(doc.content, "\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn semantic_opt_out_still_indexes_files() {
+        let mut local = LocalRepo::new(&[("src/lib.rs", "fn needle() {}\n")], &[]).await;
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        let outcome = local.index(&handle).await;
+        handle.commit().await.unwrap();
+        assert_eq!(outcome.changes.added, ["src/lib.rs"]);
+        drop(handle);
+
+        // opting out rewrites the documents, so opting back in embeds them again
+        local.repo.semantic = false;
+        let mut handle = local.indexer.write_handle().unwrap();
+        let outcome = local.index(&handle).await;
+        handle.commit().await.unwrap();
+        assert_eq!(outcome.changes.modified, ["src/lib.rs"]);
+        assert_eq!(outcome.semantic_failures, 0);
+
+        let doc = local
+            .indexer
+            .by_path(&local.reporef, "src/lib.rs", None)
+            .await
+            .unwrap();
+        assert_eq!(doc.content, "fn needle() {}\n");
+
+        let chunks = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM chunk_cache")
+            .fetch_one(local.indexer.source.sql.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(chunks, 0);
+
+        let mut repo = local.repo.clone();
+        repo.sync_done_with(None, local.repo.get_repo_metadata().await, 1, false, 0);
+        assert_eq!(repo.semantic_status, SemanticStatus::Disabled);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn lexical_only_repos_are_not_embedded() {
+        use clap::Parser;
+
+        let mut local = LocalRepo::new(&[("src/lib.rs", "fn needle() {}\n")], &[]).await;
+        assert!(local.indexer.source.embeds(&local.reporef, &local.repo));
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+        drop(handle);
+
+        // the configuration opts the repository out, although the repository itself doesn't
+        let reporef = local.reporef.to_string();
+        local.indexer.source.config = Arc::new(crate::Configuration::parse_from([
+            "bleep",
+            "--lexical-only-repos",
+            &reporef,
+        ]));
+        assert!(local.repo.semantic);
+        assert!(!local.indexer.source.embeds(&local.reporef, &local.repo));
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        let outcome = local.index(&handle).await;
+        handle.commit().await.unwrap();
+        assert_eq!(outcome.changes.modified, ["src/lib.rs"]);
+
+        let doc = local
+            .indexer
+            .by_path(&local.reporef, "src/lib.rs", None)
+            .await
+            .unwrap();
+        assert_eq!(doc.content, "fn needle() {}\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dump_then_import_round_trips() {
+        let source = LocalRepo::new(
+            &[
+                ("src/lib.rs", "pub fn needle() {}\n"),
+                ("vendor/dep.rs", "fn dep() {}\n"),
+            ],
+            &[],
+        )
+        .await;
+        let mut handle = source.indexer.write_handle().unwrap();
+        source.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let mut dump = vec![];
+        let dumped = source.indexer.dump_jsonl(&mut dump).await.unwrap();
+        assert!(dumped > 2);
+
+        let mut input = dump.clone();
+        input.extend_from_slice(
+            b"\nnot json\n{\"unknown\": [\"x\"]}\n{\"relative_path\": [\"a.rs\"]}\n",
+        );
+
+        let target = LocalRepo::new(&[], &[]).await;
+        // language ids are local to each database
+        target.indexer.source.langs.intern("python");
+        let report = target.indexer.import_jsonl(input.as_slice()).await.unwrap();
+        assert_eq!(report.imported, dumped);
+        assert_eq!(
+            report
+                .malformed
+                .iter()
+                .map(|(line, _)| *line)
+                .collect::<Vec<_>>(),
+            [dumped + 2, dumped + 3, dumped + 4]
+        );
+
+        let mut redump = vec![];
+        target.indexer.dump_jsonl(&mut redump).await.unwrap();
+        let lines = |bytes: &[u8]| {
+            let mut lines = String::from_utf8(bytes.to_vec())
+                .unwrap()
+                .lines()
+                .map(ToOwned::to_owned)
+                .collect::<Vec<_>>();
+            lines.sort();
+            lines
+        };
+        assert_eq!(lines(&redump), lines(&dump));
+
+        let doc = target
+            .indexer
+            .by_path(&source.reporef, "src/lib.rs", None)
+            .await
+            .unwrap();
+        assert_eq!(doc.content, "pub fn needle() {}\n");
+        assert_eq!(doc.lang.as_deref(), Some("Rust"));
+
+        // the next index of the repository finds its files up to date
+        let cached = FileCache::for_repo(&target.indexer.source.sql, &source.reporef)
+            .retrieve()
+            .await;
+        assert_eq!(cached.len(), dumped);
+    }
+
+    async fn references_of(
+        files: &[(&str, &str)],
+        target: &str,
+        lang: Option<&str>,
+    ) -> Vec<(String, Vec<Range<usize>>)> {
+        let local = LocalRepo::new(files, &[]).await;
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        local
+            .indexer
+            .find_references(&local.reporef, target, lang, 100)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|reference| (reference.relative_path, reference.lines))
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn references_span_shards_and_are_limited() {
+        let big = (0..6000)
+            .map(|i| match i {
+                0 => "use crate::indexes::file::File;\n".to_owned(),
+                4000 => "use crate::indexes::file;\n".to_owned(),
+                _ => format!("// filler {i}\n"),
+            })
+            .collect::<String>();
+        let local = LocalRepo::new(
+            &[
+                ("src/big.rs", big.as_str()),
+                ("src/main.rs", "use crate::indexes::file::File;\n"),
+            ],
+            &["--shard-lines", "2500"],
+        )
+        .await;
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let references = |limit| {
+            local
+                .indexer
+                .find_references(&local.reporef, "src/indexes/file.rs", None, limit)
+        };
+
+        // lines of later shards are found at their line in the whole file
+        let all = references(10).await.unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].relative_path, "src/big.rs");
+        assert_eq!(all[0].lines, [0..1, 4000..4001]);
+
+        let limited = references(1).await.unwrap();
+        assert_eq!(limited.len(), 1);
+        assert_eq!(limited[0].lines, all[0].lines);
+    }
+
+    #[tokio::test]
+    async fn references_by_rust_use() {
+        let references = references_of(
+            &[
+                ("src/indexes/file.rs", "pub struct File;\n"),
+                (
+                    "src/main.rs",
+                    "use crate::indexes::file::File;\nuse std::fs;\n\nfn main() {}\n",
+                ),
+                (
+                    "src/lib.rs",
+                    "pub use crate::{\n    indexes::file,\n    query,\n};\n",
+                ),
+                ("src/repo.rs", "use crate::repo::file;\n"),
+            ],
+            "src/indexes/file.rs",
+            None,
+        )
+        .await;
+
+        assert_eq!(
+            references,
+            [
+                ("src/lib.rs".to_owned(), vec![0..4]),
+                ("src/main.rs".to_owned(), vec![0..1]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn references_by_python_import() {
+        let files = [
+            ("pkg/utils.py", "def helper():\n    pass\n"),
+            ("app.py", "import os\nfrom pkg.utils import helper\n"),
+            ("other.py", "import pkg.utils as u\n"),
+            ("unrelated.py", "from other.utils import helper\n"),
+            ("notes.txt", "import pkg.utils\n"),
+        ];
+        let references = references_of(&files, "pkg/utils.py", Some("python")).await;
+
+        assert_eq!(
+            references,
+            [
+                ("app.py".to_owned(), vec![1..2]),
+                ("other.py".to_owned(), vec![0..1]),
+            ]
+        );
+
+        // module names work as targets too
+        assert_eq!(references_of(&files, "pkg.utils", None).await, references);
+    }
+
+    #[tokio::test]
+    async fn interrupted_commits_are_reconciled() {
+        use tantivy::collector::Count;
+
+        let local = LocalRepo::new(&[("a.rs", "fn a() {}\n"), ("b.rs", "fn b() {}\n")], &[]).await;
+        let sql = &local.indexer.source.sql;
+        let cache = FileCache::for_repo(sql, &local.reporef);
+        let (indexer, reporef) = (&local.indexer, &local.reporef);
+        let docs = || async move {
+            let searcher = indexer.reader.read().await.searcher();
+            let query = TermQuery::new(
+                Term::from_field_text(indexer.source.repo_ref, &reporef.to_string()),
+                IndexRecordOption::Basic,
+            );
+            searcher.search(&query, &Count).unwrap()
+        };
+
+        // the process stops after committing the index, but before persisting the cache
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.writer.get_mut().unwrap().commit().unwrap();
+        handle.refresh_reader().await.unwrap();
+        drop(handle);
+        assert_eq!(cache.retrieve().await.len(), 0);
+
+        let reconciled = local.indexer.reconcile_file_caches().await.unwrap();
+        assert_eq!(reconciled, [local.reporef.clone()]);
+        assert_eq!(cache.retrieve().await.len(), docs().await);
+        assert!(local
+            .indexer
+            .reconcile_file_caches()
+            .await
+            .unwrap()
+            .is_empty());
+
+        // so re-indexing doesn't duplicate the documents
+        let indexed = docs().await;
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+        assert_eq!(docs().await, indexed);
+        assert_eq!(
+            local
+                .indexer
+                .by_path(&local.reporef, "a.rs", None)
+                .await
+                .unwrap()
+                .content,
+            "fn a() {}\n"
+        );
+
+        // the cache is persisted ahead of a commit that never happens
+        std::fs::write(local.repo.disk_path.join("c.rs"), "fn c() {}\n").unwrap();
+        let handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        let (_, ahead, _) = local.indexer.source.uncommitted_caches.lock().unwrap()[0].clone();
+        cache.persist(ahead).await.unwrap();
+        // as if the commit had started after the cache was persisted
+        cache.begin_commit().await.unwrap();
+        drop(handle);
+        assert_eq!(cache.retrieve().await.len(), indexed + 1);
+
+        local.indexer.reconcile_file_caches().await.unwrap();
+        assert_eq!(cache.retrieve().await.len(), indexed);
+
+        // and nothing is left pending once a run is committed
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+        assert_eq!(cache.retrieve().await.len(), docs().await);
+        assert!(FileCache::interrupted_commits(sql)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn index_runs_are_recorded_once_committed() {
+        let local = LocalRepo::new(&[("a.rs", "fn a() {}\n")], &[]).await;
+        let cache = FileCache::for_repo(&local.indexer.source.sql, &local.reporef);
+
+        // a run whose documents are dropped leaves no trace
+        let handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        drop(handle);
+        assert!(cache.index_history(10).await.unwrap().is_empty());
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        assert!(cache.index_history(10).await.unwrap().is_empty());
+        handle.commit().await.unwrap();
+
+        let history = cache.index_history(10).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert!(history[0].files_indexed > 0);
+    }
+
+    #[tokio::test]
+    async fn snapshot_then_restore() {
+        use clap::Parser;
+        use sqlx::sqlite::SqlitePoolOptions;
+
+        let local = LocalRepo::new(&[("a.rs", "fn a() {}\n"), ("b.rs", "fn b() {}\n")], &[]).await;
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+        drop(handle);
+        let cached = FileCache::for_repo(&local.indexer.source.sql, &local.reporef)
+            .retrieve()
+            .await
+            .len();
+
+        let dir = tempdir::TempDir::new("index-snapshot").unwrap();
+        let bundle = dir.path().join("bundle");
+
+        // an open writer could commit halfway through the copy
+        let handle = local.indexer.write_handle().unwrap();
+        assert!(local.indexer.snapshot(&bundle, false).await.is_err());
+        drop(handle);
+        assert!(local.indexer.snapshot(&bundle, true).await.is_err());
+        assert!(!bundle.exists());
+
+        let manifest = local.indexer.snapshot(&bundle, false).await.unwrap();
+        assert!(!manifest.index_files.is_empty());
+        assert_eq!(manifest.qdrant_snapshot, None);
+        assert!(local.indexer.snapshot(&bundle, false).await.is_err());
+
+        // later commits aren't part of the snapshot
+        std::fs::write(local.repo.disk_path.join("c.rs"), "fn c() {}\n").unwrap();
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        // restoring replaces whatever index was there before
+        let data_dir = dir.path().join("data");
+        let mut config =
+            crate::Configuration::parse_from(["bleep", "--index-dir", data_dir.to_str().unwrap()]);
+        config.source.set_default_dir(&data_dir);
+        std::fs::create_dir_all(data_dir.join("content")).unwrap();
+        std::fs::write(data_dir.join("content/stale"), "").unwrap();
+
+        // bundles of other index versions are rejected before anything is replaced
+        let mut outdated = manifest.clone();
+        outdated.version.lexical.push_str("-outdated");
+        let outdated_bundle = dir.path().join("outdated");
+        std::fs::create_dir_all(&outdated_bundle).unwrap();
+        std::fs::write(
+            outdated_bundle.join(SNAPSHOT_MANIFEST),
+            serde_json::to_vec(&outdated).unwrap(),
+        )
+        .unwrap();
+        assert!(Indexer::<File>::restore(&outdated_bundle, &config).is_err());
+        assert!(data_dir.join("content/stale").exists());
+
+        // the leftovers of a restore that failed halfway don't get in the way
+        std::fs::create_dir_all(data_dir.join("restore.tmp/content")).unwrap();
+        std::fs::write(data_dir.join("restore.tmp/content/partial"), "").unwrap();
+
+        assert_eq!(
+            Indexer::<File>::restore(&bundle, &config).unwrap(),
+            manifest
+        );
+        assert!(!data_dir.join("content/stale").exists());
+        assert!(!data_dir.join("content/partial").exists());
+        assert!(!data_dir.join("restore.tmp").exists());
+        assert_eq!(
+            config.source.index_version_mismatch(&manifest.version),
+            Default::default()
+        );
+
+        let db_url = format!("sqlite://{}", data_dir.join("bleep.db").display());
+        let sql = Arc::new(
+            SqlitePoolOptions::new()
+                .max_connections(1)
+                .connect(&db_url)
+                .await
+                .unwrap(),
+        );
+        let restored = Indexer::create(
+            File::new(local.indexer.source.config.clone(), sql.clone(), None),
+            &data_dir.join("content"),
+            15_000_000,
+            1,
+        )
+        .unwrap();
+        restored.source.langs.load().await.unwrap();
+
+        let doc = restored
+            .by_path(&local.reporef, "a.rs", None)
+            .await
+            .unwrap();
+        assert_eq!(doc.content, "fn a() {}\n");
+        assert_eq!(doc.lang.as_deref(), Some("Rust"));
+        assert!(restored
+            .by_path(&local.reporef, "c.rs", None)
+            .await
+            .is_err());
+        assert_eq!(
+            FileCache::for_repo(&sql, &local.reporef)
+                .retrieve()
+                .await
+                .len(),
+            cached
+        );
     }
-}
 
-impl RepoDir {
-    #[allow(clippy::too_many_arguments)]
-    fn build_document(
-        self,
-        schema: &File,
-        repo_name: &str,
-        relative_path: &Path,
-        repo_disk_path: &Path,
-        repo_ref: &str,
-        last_commit: u64,
-        tantivy_cache_key: String,
-    ) -> tantivy::schema::Document {
-        let relative_path_str = format!("{}/", relative_path.to_string_lossy());
-        #[cfg(windows)]
-        let relative_path_str = relative_path_str.replace('\\', "/");
+    #[tokio::test]
+    async fn all_versions_of_a_path() {
+        use clap::Parser;
+        use sqlx::SqlitePool;
+        use tempdir::TempDir;
 
-        let branches = self.branches.join("\n");
+        let config = crate::Configuration::parse_from(["bleep"]);
+        let sql = Arc::new(SqlitePool::connect_lazy("sqlite::memory:").unwrap());
+        let dir = TempDir::new("all-versions").unwrap();
+        let indexer = Indexer::create(
+            File::new(config.into(), sql, None),
+            dir.path(),
+            15_000_000,
+            1,
+        )
+        .unwrap();
+        let reporef: RepoRef = "local//tmp/repo".parse().unwrap();
 
-        doc!(
-                schema.raw_repo_name => repo_name.as_bytes(),
-                schema.raw_relative_path => relative_path_str.as_bytes(),
-                schema.repo_disk_path => repo_disk_path.to_string_lossy().as_ref(),
-                schema.relative_path => relative_path_str,
-                schema.repo_ref => repo_ref,
-                schema.repo_name => repo_name,
-                schema.last_commit_unix_seconds => last_commit,
-                schema.branches => branches,
-                schema.is_directory => true,
-                schema.unique_hash => tantivy_cache_key,
+        let mut handle = indexer.write_handle().unwrap();
+        for (path, content, last_commit) in [
+            ("src/lib.rs", "old", 10u64),
+            ("src/lib.rs", "new", 20),
+            ("src/lib.rs.bak", "backup", 30),
+        ] {
+            let schema = &indexer.source;
+            handle
+                .writer
+                .read()
+                .unwrap()
+                .add_document(tantivy::doc!(
+                    schema.repo_ref => reporef.to_string(),
+                    schema.repo_name => "repo",
+                    schema.relative_path => path,
+                    schema.raw_relative_path => path.as_bytes(),
+                    schema.content => content,
+                    schema.line_end_indices => Vec::<u8>::new(),
+                    schema.symbol_locations => Vec::<u8>::new(),
+                    schema.last_commit_unix_seconds => last_commit,
+                ))
+                .unwrap();
+        }
+        handle.commit().await.unwrap();
 
-                // nulls
-                schema.raw_content => Vec::<u8>::default(),
-                schema.content => String::default(),
-                schema.line_end_indices => Vec::<u8>::default(),
-                schema.lang => Vec::<u8>::default(),
-                schema.avg_line_length => f64::default(),
-                schema.symbol_locations => bincode::serialize(&SymbolLocations::default()).unwrap(),
-                schema.symbols => String::default(),
+        let versions = indexer
+            .by_path_all_versions(&reporef, "src/lib.rs")
+            .await
+            .unwrap();
+        assert_eq!(
+            versions
+                .iter()
+                .map(|v| (v.doc.content.as_str(), v.last_commit_unix_seconds))
+                .collect::<Vec<_>>(),
+            [("new", 20), ("old", 10)]
+        );
+        assert_ne!(versions[0].addr, versions[1].addr);
+
+        // the freshest version is the one `by_path` picks
+        let doc = indexer.by_path(&reporef, "src/lib.rs", None).await.unwrap();
+        assert_eq!(doc.content, versions[0].doc.content);
+
+        assert!(indexer
+            .by_path_all_versions(&reporef, "src/main.rs")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn symbols_are_reindexed_under_the_path_root() {
+        let mut local = LocalRepo::new(
+            &[
+                (".gitattributes", "services/api/gen.rs linguist-generated\n"),
+                ("services/api/gen.rs", "fn generated() {}\n"),
+                ("services/api/lib.rs", "fn handwritten() {}\n"),
+            ],
+            &[],
         )
+        .await;
+        local.repo.path_root = Some("services/api".into());
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        let searcher = local.indexer.reader.read().await.searcher();
+        local
+            .indexer
+            .source
+            .reindex_symbols(
+                &searcher,
+                &handle.writer.read().unwrap(),
+                &local.repo,
+                &local.reporef,
+                &local.repo.get_repo_metadata().await,
+            )
+            .unwrap();
+        handle.commit().await.unwrap();
+
+        // attributes apply to the path in the repository, not the one of the document
+        let outline = |path| local.indexer.symbol_outline(&local.reporef, path);
+        assert!(outline("gen.rs").await.unwrap().is_empty());
+        assert_eq!(outline("lib.rs").await.unwrap().len(), 1);
     }
-}
 
-impl RepoFile {
-    #[allow(clippy::too_many_arguments)]
-    fn build_document(
-        mut self,
-        schema: &File,
-        repo_name: &str,
-        relative_path: &Path,
-        repo_disk_path: &Path,
-        semantic_cache_key: String,
-        tantivy_cache_key: String,
-        entry_pathbuf: &Path,
-        repo_ref: &str,
-        last_commit: u64,
-        repo_metadata: &RepoMetadata,
-        file_cache: &FileCache,
-    ) -> Option<tantivy::schema::Document> {
-        let relative_path_str = relative_path.to_string_lossy().to_string();
-        #[cfg(windows)]
-        let relative_path_str = relative_path_str.replace('\\', "/");
+    #[tokio::test(flavor = "multi_thread")]
+    async fn paths_are_relative_to_the_path_root() {
+        let mut local = LocalRepo::new(
+            &[
+                ("services/api/src/main.rs", "fn main() {}\n"),
+                ("services/api/Cargo.toml", "[package]\n"),
+                ("services/web/index.js", "main();\n"),
+                ("README.md", "# monorepo\n"),
+            ],
+            &[],
+        )
+        .await;
 
-        let branches = self.branches.join("\n");
-        let lang_str = repo_metadata
-            .langs
-            .get(entry_pathbuf, self.buffer.as_ref())
-            .unwrap_or_else(|| {
-                warn!(?entry_pathbuf, "Path not found in language map");
-                ""
-            });
+        local.repo.path_root = Some("../".into());
+        assert!(local.repo.resolve_path_root().is_err());
+        local.repo.path_root = Some("services/missing".into());
+        assert!(local.repo.resolve_path_root().is_err());
+        local.repo.path_root = Some("README.md".into());
+        assert!(local.repo.resolve_path_root().is_err());
 
-        let symbol_locations = {
-            // build a syntax aware representation of the file
-            let scope_graph = TreeSitterFile::try_build(self.buffer.as_bytes(), lang_str)
-                .and_then(TreeSitterFile::scope_graph);
+        local.repo.path_root = Some(local.repo.disk_path.join("services/api"));
+        assert_eq!(
+            local.repo.resolve_path_root().unwrap(),
+            Some(PathBuf::from("services/api"))
+        );
 
-            match scope_graph {
-                // we have a graph, use that
-                Ok(graph) => SymbolLocations::TreeSitter(graph),
-                // no graph, it's empty
-                Err(_) => SymbolLocations::Empty,
-            }
-        };
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
 
-        // flatten the list of symbols into a string with just text
-        let symbols = symbol_locations
-            .list()
-            .iter()
-            .map(|sym| self.buffer[sym.range.start.byte..sym.range.end.byte].to_owned())
-            .collect::<HashSet<_>>()
+        let page = Pagination {
+            limit: 10,
+            offset: 0,
+        };
+        let mut files = local
+            .indexer
+            .by_repo(&local.reporef, std::iter::empty::<&str>(), None, page)
+            .await
+            .unwrap()
+            .docs
             .into_iter()
-            .collect::<Vec<_>>()
-            .join("\n");
+            .map(|doc| doc.relative_path)
+            .filter(|path| !path.ends_with('/'))
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, ["Cargo.toml", "src/main.rs"]);
 
-        // add an NL if this file is not NL-terminated
-        if !self.buffer.ends_with('\n') {
-            self.buffer += "\n";
-        }
+        let doc = local
+            .indexer
+            .by_path(&local.reporef, "src/main.rs", None)
+            .await
+            .unwrap();
+        assert_eq!(doc.content, "fn main() {}\n");
+        assert_eq!(doc.lang.as_deref(), Some("Rust"));
+    }
 
-        let line_end_indices = self
-            .buffer
-            .match_indices('\n')
-            .flat_map(|(i, _)| u32::to_le_bytes(i as u32))
-            .collect::<Vec<_>>();
+    #[tokio::test(flavor = "multi_thread")]
+    async fn line_endings_are_recorded_before_normalizing() {
+        let local = LocalRepo::new(
+            &[
+                ("lf.rs", "fn foo() {}\nfn bar() {}\n"),
+                ("crlf.rs", "fn foo() {}\r\nfn bar() {}\r\n"),
+                ("mixed.rs", "fn foo() {}\r\nfn bar() {}\nfn baz() {}\r\n"),
+            ],
+            &["--normalize-line-endings"],
+        )
+        .await;
+        let indexer = &local.indexer;
 
-        // Skip files that are too long. This is not necessarily caught in the filesize check, e.g.
-        // for a file like `vocab.txt` which has thousands of very short lines.
-        if line_end_indices.len() > MAX_LINE_COUNT as usize {
-            return None;
+        let mut handle = indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        for path in ["crlf.rs", "mixed.rs"] {
+            let doc = indexer.by_path(&local.reporef, path, None).await.unwrap();
+            assert!(!doc.content.contains('\r'));
         }
 
-        let lines_avg = self.buffer.len() as f64 / self.buffer.lines().count() as f64;
+        let paths = |query: &str| {
+            let queries = parser::parse(query).unwrap();
+            let compiled = ContentReader
+                .compile(&indexer.source, queries.iter(), &indexer.index)
+                .unwrap();
+            let searcher = indexer.index.reader().unwrap().searcher();
+            let mut paths = searcher
+                .search(&compiled, &DocSetCollector)
+                .unwrap()
+                .into_iter()
+                .map(|addr| {
+                    let doc = searcher.doc(addr).unwrap();
+                    ContentReader
+                        .read_document(&indexer.source, doc)
+                        .relative_path
+                })
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+
+        // normalized files keep the line endings they had on disk
+        assert_eq!(paths("line_ending:crlf"), ["crlf.rs"]);
+        assert_eq!(paths("line_ending:Mixed"), ["mixed.rs"]);
+        assert_eq!(paths("line_ending:mixed bar"), ["mixed.rs"]);
+        assert_eq!(paths("line_ending:lf"), ["lf.rs"]);
+        assert!(paths("line_ending:cr").is_empty());
+    }
 
-        if let Some(semantic) = &schema.semantic {
-            tokio::task::block_in_place(|| {
-                Handle::current().block_on(async {
-                    semantic
-                        .insert_points_for_buffer(
-                            repo_name,
-                            repo_ref,
-                            &relative_path_str,
-                            &self.buffer,
-                            lang_str,
-                            &self.branches,
-                            file_cache.chunks_for_file(&semantic_cache_key).await,
-                        )
-                        .await
+    #[tokio::test(flavor = "multi_thread")]
+    async fn crlf_files_are_indexed_with_lf_line_ends() {
+        let files = [("win.rs", "fn a() {}\r\nfn b() {}\r\n")];
+        let lines = |doc: &ContentDocument| {
+            (0..doc.line_end_indices.line_count())
+                .map(|line| {
+                    doc.content[doc.line_end_indices.line_range(&doc.content, line)].to_owned()
                 })
-            });
-        }
+                .collect::<Vec<_>>()
+        };
 
-        Some(doc!(
-            schema.raw_content => self.buffer.as_bytes(),
-            schema.raw_repo_name => repo_name.as_bytes(),
-            schema.raw_relative_path => relative_path_str.as_bytes(),
-            schema.unique_hash => tantivy_cache_key,
-            schema.repo_disk_path => repo_disk_path.to_string_lossy().as_ref(),
-            schema.relative_path => relative_path_str,
-            schema.repo_ref => repo_ref,
-            schema.repo_name => repo_name,
-            schema.content => self.buffer,
-            schema.line_end_indices => line_end_indices,
-            schema.lang => lang_str.to_ascii_lowercase().as_bytes(),
-            schema.avg_line_length => lines_avg,
-            schema.last_commit_unix_seconds => last_commit,
-            schema.symbol_locations => bincode::serialize(&symbol_locations).unwrap(),
-            schema.symbols => symbols,
-            schema.branches => branches,
-            schema.is_directory => false,
-        ))
+        let local = LocalRepo::new(&files, &["--normalize-line-endings"]).await;
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let doc = local
+            .indexer
+            .by_path(&local.reporef, "win.rs", None)
+            .await
+            .unwrap();
+        assert_eq!(doc.content, "fn a() {}\nfn b() {}\n");
+        assert_eq!(lines(&doc), ["fn a() {}", "fn b() {}"]);
+
+        // by default, the content is stored as it is on disk, but lines still end before the
+        // carriage return
+        let local = LocalRepo::new(&files, &[]).await;
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let doc = local
+            .indexer
+            .by_path(&local.reporef, "win.rs", None)
+            .await
+            .unwrap();
+        assert_eq!(doc.content, files[0].1);
+        assert_eq!(lines(&doc), ["fn a() {}", "fn b() {}"]);
     }
-}
 
-#[tracing::instrument(skip(cache))]
-fn is_cache_fresh(cache: &FileCacheSnapshot, unique_hash: &str, entry_pathbuf: &PathBuf) -> bool {
-    match cache.entry(unique_hash.into()) {
-        Entry::Occupied(mut val) => {
-            // skip processing if contents are up-to-date in the cache
-            val.get_mut().fresh = true;
+    #[tokio::test(flavor = "multi_thread")]
+    async fn binary_files_are_indexed_by_path() {
+        let local = LocalRepo::new(
+            &[("blob.bin", "\0\u{1}\u{2}"), ("main.rs", "fn main() {}\n")],
+            &[],
+        )
+        .await;
+        // `café` in Latin-1
+        std::fs::write(local.repo.disk_path.join("latin1.txt"), b"caf\xe9\n").unwrap();
 
-            trace!("cache hit");
-            return true;
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let by_path = |path: &'static str| local.indexer.by_path(&local.reporef, path, None);
+        let blob = by_path("blob.bin").await.unwrap();
+        assert_eq!(blob.content, "");
+        assert_eq!(blob.lang.as_deref(), Some("binary"));
+        assert!(blob.symbol_locations.list().is_empty());
+
+        let latin1 = by_path("latin1.txt").await.unwrap();
+        assert_eq!(latin1.content, "caf\u{fffd}\n");
+        assert_ne!(latin1.lang.as_deref(), Some("binary"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn paths_with_query_syntax_are_looked_up() {
+        let paths = ["src/foo(bar).rs", "docs/a:b \"c\".md", "lang:rust OR -x"];
+        let files = paths.map(|path| (path, "fn f() {}\n"));
+        let local = LocalRepo::new(&files, &[]).await;
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        // paths go into term queries, so nothing is parsed as query syntax
+        for path in paths {
+            let doc = local
+                .indexer
+                .by_path(&local.reporef, path, None)
+                .await
+                .unwrap();
+            assert_eq!(doc.relative_path, path);
         }
-        Entry::Vacant(val) => {
-            _ = val.insert_entry(().into());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn repo_files_are_listed_in_pages() {
+        let local = LocalRepo::new(
+            &[
+                ("e.rs", "// e.rs\n"),
+                ("b.rs", "// b.rs\n"),
+                ("d.rs", "// d.rs\n"),
+                ("a.rs", "// a.rs\n"),
+                ("c.rs", "// c.rs\n"),
+            ],
+            &[],
+        )
+        .await;
+
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let by_repo = |limit, offset| {
+            local.indexer.by_repo(
+                &local.reporef,
+                std::iter::empty::<&str>(),
+                None,
+                Pagination { limit, offset },
+            )
+        };
+        let all = by_repo(usize::MAX, 0).await.unwrap();
+        assert_eq!(all.total, all.docs.len());
+        let all = all
+            .docs
+            .into_iter()
+            .map(|doc| doc.relative_path)
+            .collect::<Vec<_>>();
+        let mut sorted = all.clone();
+        sorted.sort();
+        assert_eq!(all, sorted);
+
+        // consecutive pages cover every file once, in the same order
+        let mut paged = vec![];
+        for offset in (0..all.len()).step_by(2) {
+            let page = by_repo(2, offset).await.unwrap();
+            assert_eq!(page.total, all.len());
+            paged.extend(page.docs.into_iter().map(|doc| doc.relative_path));
         }
+        assert_eq!(paged, all);
+
+        let past_the_end = by_repo(2, all.len()).await.unwrap();
+        assert!(past_the_end.docs.is_empty());
+        assert_eq!(past_the_end.total, all.len());
     }
 
-    trace!("cache miss");
-    false
-}
+    /// Index a repository, then index it again once its directory is gone.
+    async fn reindex_missing_repo(args: &[&str]) -> (LocalRepo, Result<IndexOutcome>) {
+        let local = LocalRepo::new(&[("lib.rs", "fn lib() {}\n")], args).await;
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
 
-fn build_fuzzy_regex_filter(query_str: &str) -> Option<regex::RegexSet> {
-    fn additions(s: &str, i: usize, j: usize) -> String {
-        if i > j {
-            additions(s, j, i)
-        } else {
-            let mut s = s.to_owned();
-            s.insert_str(j, ".?");
-            s.insert_str(i, ".?");
-            s
+        std::fs::remove_dir_all(&local.repo.disk_path).unwrap();
+        let metadata = local.repo.get_repo_metadata().await;
+        let (progress, _) = tokio::sync::broadcast::channel(16);
+        let pipes = SyncPipes::new(local.reporef.clone(), None, progress);
+        let outcome = handle
+            .index(&local.reporef, &local.repo, &metadata, &pipes, false, None)
+            .await;
+        if outcome.is_ok() {
+            handle.commit().await.unwrap();
         }
+
+        drop(handle);
+        (local, outcome)
     }
 
-    fn replacements(s: &str, i: usize, j: usize) -> String {
-        if i > j {
-            replacements(s, j, i)
-        } else {
-            let mut s = s.to_owned();
-            s.remove(j);
-            s.insert_str(j, ".?");
+    #[tokio::test(flavor = "multi_thread")]
+    async fn missing_repos_can_be_kept() {
+        use crate::repo::SyncStatus;
 
-            s.remove(i);
-            s.insert_str(i, ".?");
+        let (mut local, outcome) =
+            reindex_missing_repo(&["--on-missing-repo", "skip-and-keep"]).await;
+        assert!(outcome.unwrap().kept_missing);
 
-            s
-        }
+        // the repository isn't recorded as indexed again, or as empty
+        local.repo.sync_status = SyncStatus::Done;
+        local.repo.last_index_unix_secs = 10;
+        local.repo.sync_skipped(SyncStatus::Done);
+        assert_eq!(local.repo.sync_status, SyncStatus::Done);
+        assert_eq!(local.repo.last_index_unix_secs, 10);
+        local.repo.sync_skipped(SyncStatus::Queued);
+        assert!(matches!(local.repo.sync_status, SyncStatus::Error { .. }));
+
+        let doc = local.indexer.by_path(&local.reporef, "lib.rs", None).await;
+        assert_eq!(doc.unwrap().content, "fn lib() {}\n");
+        let cached = FileCache::for_repo(&local.indexer.source.sql, &local.reporef)
+            .retrieve()
+            .await;
+        assert!(!cached.is_empty());
     }
 
-    fn one_of_each(s: &str, i: usize, j: usize) -> String {
-        if i > j {
-            one_of_each(s, j, i)
-        } else {
-            let mut s = s.to_owned();
-            s.remove(j);
-            s.insert_str(j, ".?");
+    #[tokio::test(flavor = "multi_thread")]
+    async fn missing_repos_are_deleted_by_default() {
+        let (local, outcome) = reindex_missing_repo(&[]).await;
+        assert_eq!(outcome.unwrap().changes.removed, ["lib.rs"]);
 
-            s.insert_str(i, ".?");
-            s
+        assert!(matches!(
+            local.indexer.by_path(&local.reporef, "lib.rs", None).await,
+            Err(FileIndexError::NotFound)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn missing_repos_can_fail_indexing() {
+        let (local, outcome) = reindex_missing_repo(&["--on-missing-repo", "error"]).await;
+        assert!(outcome.is_err());
+
+        let doc = local.indexer.by_path(&local.reporef, "lib.rs", None).await;
+        assert_eq!(doc.unwrap().content, "fn lib() {}\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn repo_stats_follow_added_modified_and_removed_files() {
+        let local = LocalRepo::new(
+            &[("a.rs", "fn a() {}\n"), ("src/b.rs", "fn b() {\n}\n")],
+            &[],
+        )
+        .await;
+        let local = &local;
+        let disk_path = &local.repo.disk_path;
+
+        let reindex = || async move {
+            let mut handle = local.indexer.write_handle().unwrap();
+            local.index(&handle).await;
+            handle.commit().await.unwrap();
+            local.indexer.repo_stats(&local.reporef).await.unwrap()
+        };
+        let expected = |files: &[&str]| {
+            let mut stats = RepoStats::default();
+            for content in files {
+                stats += RepoStats::file(content);
+            }
+            stats
+        };
+
+        assert_eq!(reindex().await, expected(&["fn a() {}\n", "fn b() {\n}\n"]));
+
+        // an unchanged repository leaves the stats as they are
+        assert_eq!(reindex().await, expected(&["fn a() {}\n", "fn b() {\n}\n"]));
+
+        std::fs::write(disk_path.join("a.rs"), "fn a() {\n    todo!()\n}\n").unwrap();
+        std::fs::write(disk_path.join("src/c.rs"), "fn c() {}").unwrap();
+        assert_eq!(
+            reindex().await,
+            expected(&["fn a() {\n    todo!()\n}\n", "fn b() {\n}\n", "fn c() {}"])
+        );
+
+        std::fs::remove_dir_all(disk_path.join("src")).unwrap();
+        let stats = reindex().await;
+        assert_eq!(stats, expected(&["fn a() {\n    todo!()\n}\n"]));
+        assert_eq!(stats.mean_file_bytes(), Some(23.0));
+
+        // the sums match the entries of the cache they're kept with
+        let mut cached = RepoStats::default();
+        FileCache::for_repo(&local.indexer.source.sql, &local.reporef)
+            .retrieve()
+            .await
+            .scan(|_, v| cached += v.value);
+        assert_eq!(cached, stats);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn only_repos_under_allowed_roots_are_indexed() {
+        async fn index(local: &LocalRepo) -> Result<IndexOutcome> {
+            let handle = local.indexer.write_handle().unwrap();
+            let metadata = local.repo.get_repo_metadata().await;
+            let (progress, _) = tokio::sync::broadcast::channel(16);
+            let pipes = SyncPipes::new(local.reporef.clone(), None, progress);
+            handle
+                .index(&local.reporef, &local.repo, &metadata, &pipes, false, None)
+                .await
         }
+
+        let files = [("lib.rs", "fn lib() {}\n")];
+        let tmp = std::env::temp_dir();
+        let allowed =
+            LocalRepo::new(&files, &["--allowed-repo-roots", tmp.to_str().unwrap()]).await;
+        index(&allowed).await.unwrap();
+
+        let roots = tempdir::TempDir::new("allowed-roots").unwrap();
+        let root = roots.path().join("allowed");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(roots.path().join("outside")).unwrap();
+        let args = ["--allowed-repo-roots", root.to_str().unwrap()];
+
+        let denied = LocalRepo::new(&files, &args).await;
+        let err = index(&denied).await.unwrap_err();
+        assert!(err.to_string().contains("allowed repo roots"), "{err}");
+
+        // the path starts with the root, but leads out of it
+        let mut escaping = LocalRepo::new(&files, &args).await;
+        escaping.repo.disk_path = root.join("../outside");
+        assert!(escaping.repo.disk_path.starts_with(&root));
+        assert!(index(&escaping).await.is_err());
     }
 
-    let all_regexes = (query_str.char_indices().map(|(idx, _)| idx))
-        .flat_map(|i| (query_str.char_indices().map(|(idx, _)| idx)).map(move |j| (i, j)))
-        .filter(|(i, j)| i <= j)
-        .flat_map(|(i, j)| {
-            let mut v = vec![];
-            if j != query_str.len() {
-                v.push(one_of_each(query_str, i, j));
-                v.push(replacements(query_str, i, j));
+    #[tokio::test]
+    async fn unchanged_content_is_fresh() {
+        let local = LocalRepo::new(&[("a.rs", "fn a() {}\n"), ("b.rs", "fn b() {}\n")], &[]).await;
+        let mut handle = local.indexer.write_handle().unwrap();
+        local.index(&handle).await;
+        handle.commit().await.unwrap();
+
+        let is_fresh = |path: PathBuf, content: &'static str| {
+            let local = &local;
+            async move {
+                local
+                    .indexer
+                    .is_fresh(&local.reporef, &local.repo, &path, content)
+                    .await
+                    .unwrap()
             }
-            v.push(additions(query_str, i, j));
-            v
-        });
+        };
 
-    regex::RegexSetBuilder::new(all_regexes)
-        // Increased from the default to account for long paths. At the time of writing,
-        // the default was `10 * (1 << 20)`.
-        .size_limit(10 * (1 << 25))
-        .case_insensitive(true)
-        .build()
-        .ok()
-}
+        assert!(is_fresh("a.rs".into(), "fn a() {}\n").await);
+        assert!(is_fresh(local.repo.disk_path.join("b.rs"), "fn b() {}\n").await);
+        assert!(!is_fresh("a.rs".into(), "fn a() { todo!() }\n").await);
+        assert!(!is_fresh("c.rs".into(), "fn a() {}\n").await);
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[tokio::test]
+    async fn concurrent_writers_find_the_index_busy() {
+        use crate::indexes::IndexBusy;
 
-    #[test]
-    fn fuzzy_multibyte_should_compile() {
-        let multibyte_str = "查询解析器在哪";
-        let filter = build_fuzzy_regex_filter(multibyte_str);
-        assert!(filter.is_some());
+        let local = LocalRepo::new(&[("a.rs", "fn a() {}\n")], &[]).await;
+        let indexer = &local.indexer;
 
-        // tests removal of second character
-        assert!(filter.as_ref().unwrap().is_match("查解析器在哪"));
+        // the first run holds the lease of its repository while it writes
+        let file_cache = FileCache::for_repo(&indexer.source.sql, &local.reporef);
+        let lease = file_cache.lease().await.unwrap().unwrap();
+        let mut handle = indexer.write_handle().unwrap();
+        local.index(&handle).await;
 
-        // tests replacement of second character with `n`
-        assert!(filter.as_ref().unwrap().is_match("查n析器在哪"));
+        let err = indexer
+            .write_handle_within(Duration::ZERO)
+            .await
+            .err()
+            .unwrap();
+        let busy = err.downcast_ref::<IndexBusy>().unwrap();
+        assert_eq!(busy.holder.as_ref(), Some(&local.reporef));
+        assert!(err.to_string().contains(&local.reporef.to_string()));
 
-        // tests addition of character `n`
-        assert!(filter.as_ref().unwrap().is_match("查询解析器在哪n"));
+        handle.commit().await.unwrap();
+        drop(handle);
+        lease.release().await.unwrap();
+
+        // runs that wait for the writer take turns, each holding it until it has committed
+        let events = std::sync::Mutex::new(vec![]);
+        let run = |name: &'static str| {
+            let events = &events;
+            let local = &local;
+            async move {
+                let mut handle = indexer
+                    .write_handle_within(Duration::from_secs(10))
+                    .await
+                    .unwrap();
+                events.lock().unwrap().push((name, "started"));
+                local.index(&handle).await;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                handle.commit().await.unwrap();
+                events.lock().unwrap().push((name, "committed"));
+            }
+        };
+        tokio::join!(run("first"), run("second"));
+
+        let events = events.into_inner().unwrap();
+        assert_eq!(events.len(), 4);
+        for turn in events.chunks(2) {
+            assert_eq!(turn[0].0, turn[1].0);
+            assert_eq!([turn[0].1, turn[1].1], ["started", "committed"]);
+        }
+        assert_ne!(events[0].0, events[2].0);
     }
 }
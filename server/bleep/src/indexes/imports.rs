@@ -0,0 +1,249 @@
+use std::{collections::HashMap, ops::Range};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::query::languages::parse_alias;
+
+/// Patterns of the statements that import other files, by language.
+///
+/// Each pattern is a regex with a `path` group, which captures the imported module as written,
+/// such as `crate::indexes::file` or `./utils`. Patterns are matched against whole files with
+/// `^` and `$` matching at line boundaries, so statements may span lines.
+///
+/// Languages set in the config file replace the built-in patterns of that language, and the
+/// others are kept.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(from = "HashMap<String, Vec<String>>")]
+pub struct ImportPatterns(HashMap<String, Vec<String>>);
+
+const JS_PATTERNS: &[&str] = &[
+    r#"^[ \t]*import\s[^;]*?\bfrom\s+['"](?P<path>[^'"]+)['"]"#,
+    r#"^[ \t]*import\s+['"](?P<path>[^'"]+)['"]"#,
+    r#"\brequire\(\s*['"](?P<path>[^'"]+)['"]\s*\)"#,
+];
+
+const C_PATTERNS: &[&str] = &[r#"^[ \t]*#\s*include\s*[<"](?P<path>[^>"]+)[>"]"#];
+
+impl Default for ImportPatterns {
+    fn default() -> Self {
+        let patterns: &[(&str, &[&str])] = &[
+            (
+                "rust",
+                &[r"^[ \t]*(?:pub(?:\([^)]*\))?\s+)?use\s+(?P<path>[^;]+);"],
+            ),
+            (
+                "python",
+                &[
+                    r"^[ \t]*from\s+(?P<path>[\w.]+)\s+import\b",
+                    r"^[ \t]*import\s+(?P<path>[\w.]+)",
+                ],
+            ),
+            ("javascript", JS_PATTERNS),
+            ("typescript", JS_PATTERNS),
+            ("tsx", JS_PATTERNS),
+            ("c", C_PATTERNS),
+            ("c++", C_PATTERNS),
+            (
+                "go",
+                &[r#"^[ \t]*import\s+(?:[\w.]+\s+)?"(?P<path>[^"]+)""#],
+            ),
+            ("java", &[r"^[ \t]*import\s+(?:static\s+)?(?P<path>[\w.]+)"]),
+        ];
+
+        Self(
+            patterns
+                .iter()
+                .map(|(lang, patterns)| {
+                    let patterns = patterns.iter().map(|p| p.to_string()).collect();
+                    (lang.to_string(), patterns)
+                })
+                .collect(),
+        )
+    }
+}
+
+impl From<HashMap<String, Vec<String>>> for ImportPatterns {
+    fn from(overrides: HashMap<String, Vec<String>>) -> Self {
+        let mut patterns = Self::default();
+        patterns.0.extend(
+            overrides
+                .into_iter()
+                .map(|(lang, p)| (parse_alias(lang.into()).into_owned(), p)),
+        );
+        patterns
+    }
+}
+
+impl ImportPatterns {
+    /// Compile the patterns of `lang`, given by any of its names or aliases, such as `Rust` or
+    /// `cpp`.
+    ///
+    /// Languages without patterns have no imports.
+    pub fn compile(&self, lang: &str) -> Result<Vec<Regex>> {
+        self.0
+            .get(parse_alias(lang.into()).as_ref())
+            .into_iter()
+            .flatten()
+            .map(|pattern| {
+                Regex::new(&format!("(?m){pattern}"))
+                    .with_context(|| format!("invalid import pattern for {lang}: {pattern}"))
+            })
+            .collect()
+    }
+}
+
+/// A file or module that imports are matched against.
+///
+/// Targets are either paths, as in `src/indexes/file.rs`, or module names, as in
+/// `indexes::file` or `utils.strings`.
+#[derive(Debug)]
+pub struct ImportTarget {
+    /// Components of the target, outermost first
+    components: Vec<String>,
+}
+
+impl ImportTarget {
+    pub fn new(target: &str) -> Self {
+        let mut components = if target.contains('/') {
+            let mut components = target
+                .split('/')
+                .filter(|c| !c.is_empty())
+                .map(str::to_owned)
+                .collect::<Vec<_>>();
+
+            // the extension isn't part of the module name
+            if let Some(last) = components.last_mut() {
+                if let Some((stem, _)) = last.rsplit_once('.') {
+                    *last = stem.to_owned();
+                }
+            }
+
+            // `foo/mod.rs` and `foo/index.ts` are imported as `foo`
+            if components.len() > 1
+                && ["mod", "index", "__init__"].contains(&components.last().unwrap().as_str())
+            {
+                components.pop();
+            }
+
+            components
+        } else {
+            split_path(target).map(str::to_owned).collect()
+        };
+        components.retain(|c| !matches!(c.as_str(), "" | "." | ".."));
+
+        Self { components }
+    }
+
+    /// The innermost name of the target, which every import of it mentions.
+    pub fn name(&self) -> Option<&str> {
+        self.components.last().map(String::as_str)
+    }
+
+    /// Whether an imported module, as captured by an [`ImportPatterns`] pattern, refers to this
+    /// target.
+    ///
+    /// The import has to mention the name of the target, and the components in front of the
+    /// name have to agree with the directories of the target for as long as both go. Relative
+    /// prefixes like `crate::` or `../` stop the comparison, as we don't resolve them.
+    pub fn is_imported_by(&self, import: &str) -> bool {
+        let Some(name) = self.name() else {
+            return false;
+        };
+
+        let import = split_path(import).collect::<Vec<_>>();
+        import
+            .iter()
+            .enumerate()
+            .filter(|(_, component)| **component == name)
+            .any(|(pos, _)| {
+                import[..pos]
+                    .iter()
+                    .rev()
+                    .take_while(|c| !matches!(**c, "crate" | "self" | "super"))
+                    .zip(self.components.iter().rev().skip(1))
+                    .all(|(import, target)| *import == target.as_str())
+            })
+    }
+
+    /// The byte ranges of the statements in `content` that import this target.
+    pub fn find_imports(&self, content: &str, patterns: &[Regex]) -> Vec<Range<usize>> {
+        let mut ranges = patterns
+            .iter()
+            .flat_map(|pattern| pattern.captures_iter(content))
+            .filter(|captures| {
+                captures
+                    .name("path")
+                    .map_or(false, |path| self.is_imported_by(path.as_str()))
+            })
+            .map(|captures| captures.get(0).unwrap().range())
+            .collect::<Vec<_>>();
+
+        ranges.sort_by_key(|range| range.start);
+        ranges.dedup_by_key(|range| range.start);
+        ranges
+    }
+}
+
+/// Split a module path into its names, whatever the separators of the language.
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split(|c: char| !(c.is_alphanumeric() || "_-@$".contains(c)))
+        .filter(|c| !c.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn import_paths_match_targets() {
+        let file = ImportTarget::new("src/indexes/file.rs");
+        assert!(file.is_imported_by("crate::indexes::file::File"));
+        assert!(file.is_imported_by("crate::{indexes::file, query}"));
+        assert!(file.is_imported_by("super::file"));
+        assert!(!file.is_imported_by("crate::repo::file"));
+        assert!(!file.is_imported_by("crate::indexes::files"));
+
+        let utils = ImportTarget::new("pkg/utils/__init__.py");
+        assert!(utils.is_imported_by("pkg.utils"));
+        assert!(utils.is_imported_by(".utils"));
+        assert!(!utils.is_imported_by("other.utils"));
+
+        let header = ImportTarget::new("include/net/socket.h");
+        assert!(header.is_imported_by("net/socket.h"));
+        assert!(ImportTarget::new("./lib/socket").is_imported_by("../socket"));
+
+        // module names work as well as paths
+        assert!(ImportTarget::new("indexes::file").is_imported_by("crate::indexes::file"));
+        assert!(!ImportTarget::new("").is_imported_by("crate::indexes::file"));
+    }
+
+    #[test]
+    fn overrides_keep_other_languages() {
+        let patterns = ImportPatterns::from(HashMap::from([(
+            "rust".to_owned(),
+            vec![r"^mod\s+(?P<path>\w+);".to_owned()],
+        )]));
+
+        let rust = patterns.compile("rust").unwrap();
+        let target = ImportTarget::new("src/file.rs");
+        assert_eq!(target.find_imports("mod file;\nuse file;\n", &rust), [0..9]);
+        assert_eq!(patterns.compile("python").unwrap().len(), 2);
+        assert!(patterns.compile("cobol").unwrap().is_empty());
+    }
+
+    #[test]
+    fn languages_are_looked_up_by_any_name() {
+        let patterns = ImportPatterns::from(HashMap::from([(
+            "Python".to_owned(),
+            vec![r"^import\s+(?P<path>\w+)".to_owned()],
+        )]));
+
+        // languages of indexed documents are in proper case
+        assert_eq!(patterns.compile("Rust").unwrap().len(), 1);
+        assert_eq!(patterns.compile("C++").unwrap().len(), 1);
+        assert_eq!(patterns.compile("cpp").unwrap().len(), 1);
+        assert_eq!(patterns.compile("python").unwrap().len(), 1);
+    }
+}
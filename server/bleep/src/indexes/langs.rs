@@ -0,0 +1,172 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use anyhow::Result;
+
+use crate::db::SqlDb;
+
+/// Interns the language names of the file index into small integers.
+///
+/// Documents store the id of their language, and the names are kept in the `languages` table,
+/// so a language costs a few bytes per document however long its name. Ids are assigned in
+/// memory as documents are built, and written to the table by [`LangDictionary::persist`],
+/// which callers run before committing the documents that use them.
+pub struct LangDictionary {
+    sql: SqlDb,
+    langs: RwLock<Langs>,
+}
+
+#[derive(Default)]
+struct Langs {
+    ids: HashMap<String, u64>,
+    names: HashMap<u64, String>,
+    /// Ids assigned since the last persist
+    unsaved: Vec<u64>,
+}
+
+impl LangDictionary {
+    /// The id of documents without a language, such as directories.
+    pub const NONE: u64 = 0;
+
+    pub fn new(sql: SqlDb) -> Self {
+        Self {
+            sql,
+            langs: Default::default(),
+        }
+    }
+
+    /// Read the languages interned so far, including by other processes using the same
+    /// database.
+    pub async fn load(&self) -> Result<()> {
+        let rows = sqlx::query!("SELECT id, name FROM languages")
+            .fetch_all(self.sql.as_ref())
+            .await?;
+
+        let mut langs = self.langs.write().unwrap();
+        for row in rows {
+            let id = row.id as u64;
+            langs.ids.insert(row.name.clone(), id);
+            langs.names.insert(id, row.name);
+        }
+
+        Ok(())
+    }
+
+    /// Write the ids assigned since the last call to the database.
+    pub async fn persist(&self) -> Result<()> {
+        let unsaved = {
+            let langs = self.langs.read().unwrap();
+            langs
+                .unsaved
+                .iter()
+                .map(|id| (*id as i64, langs.names[id].clone()))
+                .collect::<Vec<_>>()
+        };
+        if unsaved.is_empty() {
+            return Ok(());
+        }
+
+        let mut tx = self.sql.begin().await?;
+        for (id, name) in &unsaved {
+            sqlx::query!(
+                "INSERT INTO languages (id, name) VALUES (?, ?) ON CONFLICT DO NOTHING",
+                id,
+                name,
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        let mut langs = self.langs.write().unwrap();
+        langs
+            .unsaved
+            .retain(|id| !unsaved.iter().any(|(saved, _)| *saved as u64 == *id));
+
+        Ok(())
+    }
+
+    /// The id of `lang`, assigning it one if it doesn't have one yet.
+    ///
+    /// Languages are expected in their canonical, lowercase form.
+    pub fn intern(&self, lang: &str) -> u64 {
+        if let Some(id) = self.id(lang) {
+            return id;
+        }
+
+        let mut langs = self.langs.write().unwrap();
+        if let Some(id) = langs.ids.get(lang) {
+            return *id;
+        }
+
+        let id = langs.names.keys().max().copied().unwrap_or(Self::NONE) + 1;
+        langs.ids.insert(lang.to_owned(), id);
+        langs.names.insert(id, lang.to_owned());
+        langs.unsaved.push(id);
+        id
+    }
+
+    /// The id of `lang`, if it was interned.
+    pub fn id(&self, lang: &str) -> Option<u64> {
+        if lang.is_empty() {
+            return Some(Self::NONE);
+        }
+
+        self.langs.read().unwrap().ids.get(lang).copied()
+    }
+
+    /// The name of the language with `id`.
+    ///
+    /// Returns `None` for [`LangDictionary::NONE`], and ids this dictionary doesn't know of.
+    pub fn name(&self, id: u64) -> Option<String> {
+        self.langs.read().unwrap().names.get(&id).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::*;
+
+    async fn test_db() -> SqlDb {
+        // a single connection, as every in-memory connection gets its own database
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        Arc::new(pool)
+    }
+
+    #[tokio::test]
+    async fn intern_and_reload() {
+        let sql = test_db().await;
+        let langs = LangDictionary::new(sql.clone());
+
+        let rust = langs.intern("rust");
+        assert_ne!(rust, LangDictionary::NONE);
+        assert_eq!(langs.intern("rust"), rust);
+        assert_eq!(langs.intern(""), LangDictionary::NONE);
+        let python = langs.intern("python");
+        assert_ne!(python, rust);
+
+        assert_eq!(langs.id("python"), Some(python));
+        assert_eq!(langs.id("go"), None);
+        assert_eq!(langs.name(rust).as_deref(), Some("rust"));
+        assert_eq!(langs.name(LangDictionary::NONE), None);
+
+        // ids are only seen by other processes once persisted
+        let other = LangDictionary::new(sql.clone());
+        other.load().await.unwrap();
+        assert_eq!(other.id("rust"), None);
+
+        langs.persist().await.unwrap();
+        other.load().await.unwrap();
+        assert_eq!(other.id("rust"), Some(rust));
+        assert_eq!(other.name(python).as_deref(), Some("python"));
+        assert_eq!(other.intern("go"), python + 1);
+    }
+}
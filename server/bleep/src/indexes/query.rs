@@ -0,0 +1,376 @@
+use std::ops::Range;
+
+use tantivy::{
+    query::{BooleanQuery, EmptyQuery, Occur, PhraseQuery, Query, RangeQuery, TermQuery},
+    schema::{Field, IndexRecordOption, Term},
+    tokenizer::{TextAnalyzer, TokenStream},
+};
+
+use super::File;
+use crate::{query::languages::parse_alias, repo::RepoRef};
+
+/// Compose filters over the `File` index without going through tantivy's query language.
+///
+/// Every filter is a required or prohibited clause, so the built query matches documents that
+/// pass all of them. User-provided strings are tokenized with the same analyzer the field was indexed
+/// with, which means there is no syntax to escape.
+pub struct FileQueryBuilder<'a> {
+    schema: &'a File,
+    path_analyzer: TextAnalyzer,
+    branch_analyzer: TextAnalyzer,
+    symbol_analyzer: TextAnalyzer,
+    clauses: Vec<(Occur, Box<dyn Query>)>,
+}
+
+impl<'a> FileQueryBuilder<'a> {
+    pub fn new(schema: &'a File, index: &tantivy::Index) -> tantivy::Result<Self> {
+        Ok(Self {
+            schema,
+            path_analyzer: index.tokenizer_for_field(schema.relative_path)?,
+            branch_analyzer: index.tokenizer_for_field(schema.branches)?,
+            symbol_analyzer: index.tokenizer_for_field(schema.symbols)?,
+            clauses: vec![],
+        })
+    }
+
+    /// Only match documents from this repository.
+    pub fn repo(mut self, repo_ref: &RepoRef) -> Self {
+        self.clauses.push((
+            Occur::Must,
+            Box::new(TermQuery::new(
+                Term::from_field_text(self.schema.repo_ref, &repo_ref.to_string()),
+                IndexRecordOption::Basic,
+            )),
+        ));
+        self
+    }
+
+    /// Only match documents whose path contains `relative_path`.
+    pub fn path(mut self, relative_path: &str) -> Self {
+        let query = text_query(
+            self.schema.relative_path,
+            &self.path_analyzer,
+            relative_path,
+        );
+        self.clauses.push((Occur::Must, query));
+        self
+    }
+
+    /// Only match the document at exactly `relative_path`.
+    ///
+    /// Unlike [`FileQueryBuilder::path`], this doesn't match partial paths.
+    pub fn exact_path(mut self, relative_path: &str) -> Self {
+        let query = self.raw_path_query(relative_path);
+        self.clauses.push((Occur::Must, query));
+        self
+    }
+
+    /// Exclude the document at exactly `relative_path`.
+    ///
+    /// Unlike [`FileQueryBuilder::path`], this doesn't match partial paths.
+    pub fn exclude_path(mut self, relative_path: &str) -> Self {
+        let query = self.raw_path_query(relative_path);
+        self.clauses.push((Occur::MustNot, query));
+        self
+    }
+
+    /// Only match the documents at exactly one of `relative_paths`.
+    ///
+    /// An empty set of paths matches nothing.
+    pub fn paths_any<S: AsRef<str>>(mut self, relative_paths: impl IntoIterator<Item = S>) -> Self {
+        let queries = relative_paths
+            .into_iter()
+            .map(|path| self.raw_path_query(path.as_ref()))
+            .collect::<Vec<_>>();
+
+        self.clauses
+            .push((Occur::Must, Box::new(BooleanQuery::union(queries))));
+        self
+    }
+
+    /// Only match documents present on `branch`, if one is given.
+    pub fn branch(mut self, branch: Option<&str>) -> Self {
+        if let Some(b) = branch {
+            let query = text_query(self.schema.branches, &self.branch_analyzer, b);
+            self.clauses.push((Occur::Must, query));
+        }
+        self
+    }
+
+    /// Only match documents written in one of `langs`.
+    ///
+    /// Aliases are resolved to canonical language names. An empty set of languages matches
+    /// nothing, and so do languages no document was indexed with.
+    pub fn langs<S: AsRef<str>>(mut self, langs: impl IntoIterator<Item = S>) -> Self {
+        let queries = langs
+            .into_iter()
+            .filter_map(|lang| self.schema.langs.id(&parse_alias(lang.as_ref().into())))
+            .map(|id| {
+                Box::new(TermQuery::new(
+                    Term::from_field_u64(self.schema.lang, id),
+                    IndexRecordOption::Basic,
+                )) as Box<dyn Query>
+            })
+            .collect::<Vec<_>>();
+
+        self.clauses
+            .push((Occur::Must, Box::new(BooleanQuery::union(queries))));
+        self
+    }
+
+    /// Only match documents with a symbol containing one of `names`.
+    ///
+    /// Symbols only need to contain a name, so callers looking for exact names should check the
+    /// symbols of the documents this matches. An empty set of names matches nothing.
+    pub fn symbols_any<S: AsRef<str>>(mut self, names: impl IntoIterator<Item = S>) -> Self {
+        let queries = names
+            .into_iter()
+            .map(|name| text_query(self.schema.symbols, &self.symbol_analyzer, name.as_ref()))
+            .collect::<Vec<_>>();
+
+        self.clauses
+            .push((Occur::Must, Box::new(BooleanQuery::union(queries))));
+        self
+    }
+
+    /// Only match documents whose last commit falls within `range`, in unix seconds.
+    pub fn last_commit_between(mut self, range: Range<u64>) -> Self {
+        self.clauses.push((
+            Occur::Must,
+            Box::new(RangeQuery::new_u64(
+                self.schema.last_commit_unix_seconds,
+                range,
+            )),
+        ));
+        self
+    }
+
+    /// Exclude every shard of a large file but the first, so that the file matches once.
+    pub fn first_shards(mut self) -> Self {
+        self.clauses.push((
+            Occur::MustNot,
+            Box::new(RangeQuery::new_u64(self.schema.shard_index, 1..u64::MAX)),
+        ));
+        self
+    }
+
+    /// Add an arbitrary required clause.
+    pub fn clause(mut self, query: Box<dyn Query>) -> Self {
+        self.clauses.push((Occur::Must, query));
+        self
+    }
+
+    pub fn build(self) -> BooleanQuery {
+        BooleanQuery::new(self.clauses)
+    }
+
+    fn raw_path_query(&self, relative_path: &str) -> Box<dyn Query> {
+        Box::new(TermQuery::new(
+            Term::from_field_bytes(self.schema.raw_relative_path, relative_path.as_bytes()),
+            IndexRecordOption::Basic,
+        ))
+    }
+}
+
+impl Clone for FileQueryBuilder<'_> {
+    fn clone(&self) -> Self {
+        Self {
+            schema: self.schema,
+            path_analyzer: self.path_analyzer.clone(),
+            branch_analyzer: self.branch_analyzer.clone(),
+            symbol_analyzer: self.symbol_analyzer.clone(),
+            clauses: self
+                .clauses
+                .iter()
+                .map(|(occur, q)| (*occur, q.box_clone()))
+                .collect(),
+        }
+    }
+}
+
+/// Match `text` against a tokenized field, the way tantivy's query parser handles a quoted
+/// phrase.
+fn text_query(field: Field, analyzer: &TextAnalyzer, text: &str) -> Box<dyn Query> {
+    let mut terms = vec![];
+    analyzer.token_stream(text).process(&mut |token| {
+        terms.push((token.position, Term::from_field_text(field, &token.text)));
+    });
+
+    match terms.len() {
+        0 => Box::new(EmptyQuery),
+        1 => Box::new(TermQuery::new(
+            terms.pop().unwrap().1,
+            IndexRecordOption::WithFreqs,
+        )),
+        _ => Box::new(PhraseQuery::new_with_offset(terms)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use clap::Parser;
+    use sqlx::SqlitePool;
+    use tantivy::{collector::Count, doc, query::QueryParser, Index};
+
+    use super::*;
+    use crate::Configuration;
+
+    struct Fixture {
+        schema: File,
+        index: Index,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let config = Arc::new(Configuration::parse_from(["bleep"]));
+            let sql = Arc::new(SqlitePool::connect_lazy("sqlite::memory:").unwrap());
+            let schema = File::new(config, sql, None);
+            let index = Index::create_in_ram(schema.schema.clone());
+            super::super::register_tokenizers(&index);
+
+            let mut writer = index.writer(15_000_000).unwrap();
+            for (repo, path, branch, lang, last_commit, symbols) in [
+                (
+                    "local//a",
+                    "src/main.rs",
+                    "main",
+                    "Rust",
+                    100,
+                    "Config\nmain",
+                ),
+                ("local//a", "src/lib.rs", "dev", "Rust", 200, "Config"),
+                ("local//a", "web/main.ts", "main", "TypeScript", 300, "App"),
+                (
+                    "local//b",
+                    "src/main.rs",
+                    "main",
+                    "Rust",
+                    400,
+                    "Config\nmain",
+                ),
+            ] {
+                writer
+                    .add_document(doc!(
+                        schema.repo_ref => repo,
+                        schema.relative_path => path,
+                        schema.raw_relative_path => path.as_bytes(),
+                        schema.symbols => symbols,
+                        schema.branches => branch,
+                        schema.lang => schema.langs.intern(&parse_alias(lang.into())),
+                        schema.last_commit_unix_seconds => last_commit as u64,
+                    ))
+                    .unwrap();
+            }
+            writer.commit().unwrap();
+
+            Self { schema, index }
+        }
+
+        fn builder(&self) -> FileQueryBuilder<'_> {
+            FileQueryBuilder::new(&self.schema, &self.index).unwrap()
+        }
+
+        fn count(&self, builder: FileQueryBuilder<'_>) -> usize {
+            let searcher = self.index.reader().unwrap().searcher();
+            searcher.search(&builder.build(), &Count).unwrap()
+        }
+    }
+
+    fn repo(name: &str) -> RepoRef {
+        format!("local//{name}").parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn repo_filter() {
+        let f = Fixture::new();
+        assert_eq!(f.count(f.builder().repo(&repo("a"))), 3);
+        assert_eq!(f.count(f.builder().repo(&repo("b"))), 1);
+        assert_eq!(f.count(f.builder().repo(&repo("c"))), 0);
+    }
+
+    #[tokio::test]
+    async fn path_filter() {
+        let f = Fixture::new();
+        assert_eq!(f.count(f.builder().path("src/main.rs")), 2);
+        assert_eq!(f.count(f.builder().repo(&repo("a")).path("main")), 2);
+        assert_eq!(f.count(f.builder().path("src/missing.rs")), 0);
+        assert_eq!(f.count(f.builder().path("")), 0);
+
+        // query syntax is just text
+        assert_eq!(f.count(f.builder().path(r#"src/main.rs" OR repo_ref:"#)), 0);
+    }
+
+    #[tokio::test]
+    async fn branch_filter() {
+        let f = Fixture::new();
+        assert_eq!(f.count(f.builder().repo(&repo("a")).branch(None)), 3);
+        assert_eq!(
+            f.count(f.builder().repo(&repo("a")).branch(Some("main"))),
+            2
+        );
+        assert_eq!(f.count(f.builder().repo(&repo("a")).branch(Some("dev"))), 1);
+    }
+
+    #[tokio::test]
+    async fn lang_filter() {
+        let f = Fixture::new();
+        assert_eq!(f.count(f.builder().langs(["rust"])), 3);
+        assert_eq!(f.count(f.builder().langs(["ts", "rust"])), 4);
+        assert_eq!(f.count(f.builder().langs(Vec::<&str>::new())), 0);
+        assert_eq!(f.count(f.builder().langs(["go"])), 0);
+    }
+
+    #[tokio::test]
+    async fn last_commit_filter() {
+        let f = Fixture::new();
+        assert_eq!(f.count(f.builder().last_commit_between(100..300)), 2);
+        assert_eq!(
+            f.count(f.builder().repo(&repo("b")).last_commit_between(0..400)),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn exclude_path_filter() {
+        let f = Fixture::new();
+        let symbol = |name: &str| {
+            QueryParser::for_index(&f.index, vec![f.schema.symbols])
+                .parse_query(&format!("\"{name}\""))
+                .unwrap()
+        };
+        let config = || f.builder().repo(&repo("a")).clause(symbol("Config"));
+
+        assert_eq!(f.count(config()), 2);
+        assert_eq!(f.count(config().exclude_path("src/lib.rs")), 1);
+
+        // only the exact path is excluded
+        assert_eq!(f.count(config().exclude_path("lib.rs")), 2);
+        assert_eq!(f.count(config().exclude_path("src/")), 2);
+    }
+
+    #[tokio::test]
+    async fn symbols_filter() {
+        let f = Fixture::new();
+        assert_eq!(f.count(f.builder().symbols_any(["main"])), 2);
+        assert_eq!(f.count(f.builder().symbols_any(["App", "main"])), 3);
+        assert_eq!(
+            f.count(f.builder().repo(&repo("a")).symbols_any(["Conf"])),
+            2
+        );
+        assert_eq!(f.count(f.builder().symbols_any(["Missing"])), 0);
+        assert_eq!(f.count(f.builder().symbols_any(Vec::<&str>::new())), 0);
+    }
+
+    #[tokio::test]
+    async fn exact_path_filter() {
+        let f = Fixture::new();
+        assert_eq!(f.count(f.builder().exact_path("src/main.rs")), 2);
+        assert_eq!(
+            f.count(f.builder().repo(&repo("a")).exact_path("src/main.rs")),
+            1
+        );
+        assert_eq!(f.count(f.builder().exact_path("main.rs")), 0);
+    }
+}
@@ -5,7 +5,7 @@ use tantivy::{
     Index,
 };
 
-use super::{file::File, repo::Repo, DocumentRead};
+use super::{blame::LineBlame, file::File, repo::Repo, CorpusMode, DocumentRead};
 use crate::{
     intelligence::TreeSitterFile,
     query::{
@@ -13,19 +13,35 @@ use crate::{
         parser::{self, Query, Target},
     },
     symbol::SymbolLocations,
-    text_range::TextRange,
+    text_range::{LineIndex, Point, TextRange},
 };
 
 #[derive(Default, Debug, Clone)]
 pub struct ContentDocument {
     pub content: String,
     pub lang: Option<String>,
+    pub content_type: Option<String>,
+    /// One-line summary of the file, for display
+    pub summary: Option<String>,
     pub relative_path: String,
     pub repo_name: String,
     pub repo_ref: String,
-    pub line_end_indices: Vec<u32>,
+    pub line_end_indices: LineIndex,
     pub symbol_locations: SymbolLocations,
     pub branches: Option<String>,
+    /// The last commit that changed each line, for files indexed with line blame
+    pub line_blame: Option<LineBlame>,
+    /// The position of the document among the shards of its file, for a shard of a large file
+    pub shard_index: Option<u64>,
+    /// Where a shard starts in its file
+    ///
+    /// The content, line ends and positions of a shard are relative to the shard, so this is
+    /// added to them to find them in the whole file.
+    pub shard_start: Option<Point>,
+    /// Owners of the file in the repository's CODEOWNERS
+    pub owners: Vec<String>,
+    /// Tags of the file in the repository's `.bloop/tags.toml`
+    pub tags: Vec<String>,
 }
 
 impl ContentDocument {
@@ -34,6 +50,38 @@ impl ContentDocument {
             .and_then(TreeSitterFile::hoverable_ranges)
             .ok()
     }
+
+    /// List the symbols defined in this file, in order of appearance.
+    ///
+    /// Files without symbol locations have an empty outline.
+    pub fn outline(&self) -> Vec<OutlineItem> {
+        let mut symbols = self.symbol_locations.list();
+        symbols.sort_by_key(|sym| sym.range);
+
+        symbols
+            .into_iter()
+            .filter_map(|sym| {
+                let text = self.content.get(sym.range.start.byte..sym.range.end.byte)?;
+                Some(OutlineItem {
+                    text: text.to_owned(),
+                    kind: sym.kind,
+                    start_line: Point::from_byte(sym.range.start.byte, &self.line_end_indices).line,
+                    end_line: Point::from_byte(sym.range.end.byte, &self.line_end_indices).line,
+                })
+            })
+            .collect()
+    }
+}
+
+/// A symbol in the outline of a file.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct OutlineItem {
+    pub text: String,
+    pub kind: String,
+    /// 0-indexed line the symbol starts on
+    pub start_line: usize,
+    /// 0-indexed line the symbol ends on, inclusive
+    pub end_line: usize,
 }
 
 #[derive(Debug)]
@@ -42,6 +90,7 @@ pub struct FileDocument {
     pub repo_name: String,
     pub repo_ref: String,
     pub lang: Option<String>,
+    pub content_type: Option<String>,
     pub branches: String,
 }
 
@@ -51,8 +100,145 @@ pub struct RepoDocument {
     pub repo_ref: String,
 }
 
+/// Number of bytes the words of a multi-word content query can be shifted by, and still count as
+/// a phrase.
+const CONTENT_PHRASE_SLOP: u32 = 2;
+
+/// Number of words the words of a multi-word content query can be shifted by in prose, and
+/// still count as a phrase.
+const PROSE_PHRASE_SLOP: u32 = 1;
+
+/// Weight of content matching as prose, relative to matching the n-grams of code, so that the
+/// files with a query's exact text rank above those with its words.
+const CONTENT_PROSE_BOOST: f32 = 0.5;
+
 pub struct ContentReader;
 
+/// The optional fields of a [`ContentDocument`] to read out of the index.
+///
+/// The path, repository, owners and tags of a document are always read. Fields left out keep their default
+/// value, so callers that only list files don't pay for decoding content and symbols.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadOptions(u8);
+
+impl ReadOptions {
+    pub const CONTENT: Self = Self(1 << 0);
+    pub const LANG: Self = Self(1 << 1);
+    pub const CONTENT_TYPE: Self = Self(1 << 2);
+    pub const SUMMARY: Self = Self(1 << 3);
+    pub const BRANCHES: Self = Self(1 << 4);
+    pub const LINE_END_INDICES: Self = Self(1 << 5);
+    pub const SYMBOL_LOCATIONS: Self = Self(1 << 6);
+    pub const LINE_BLAME: Self = Self(1 << 7);
+
+    pub const NONE: Self = Self(0);
+    pub const ALL: Self = Self(u8::MAX);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl std::ops::BitOr for ReadOptions {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl ContentReader {
+    /// Read the fields of `doc` selected by `options`.
+    pub fn read_document_with(
+        &self,
+        schema: &File,
+        doc: tantivy::Document,
+        options: ReadOptions,
+    ) -> ContentDocument {
+        let read = |field| options.contains(field);
+
+        let mut document = ContentDocument {
+            relative_path: read_text_field(&doc, schema.relative_path),
+            repo_ref: read_text_field(&doc, schema.repo_ref),
+            repo_name: read_text_field(&doc, schema.repo_name),
+            shard_index: doc.get_first(schema.shard_index).and_then(Value::as_u64),
+            shard_start: doc
+                .get_first(schema.shard_start_line)
+                .and_then(Value::as_u64)
+                .zip(
+                    doc.get_first(schema.shard_start_byte)
+                        .and_then(Value::as_u64),
+                )
+                .map(|(line, byte)| Point {
+                    byte: byte as usize,
+                    line: line as usize,
+                    column: 0,
+                }),
+            owners: read_bytes_fields(&doc, schema.owners),
+            tags: read_bytes_fields(&doc, schema.tags),
+            ..Default::default()
+        };
+
+        if read(ReadOptions::CONTENT) {
+            document.content = read_text_field(&doc, schema.content);
+        }
+
+        if read(ReadOptions::LANG) {
+            document.lang = read_lang_field(&doc, schema);
+        }
+
+        if read(ReadOptions::CONTENT_TYPE) {
+            document.content_type = read_bytes_field(&doc, schema.content_type);
+        }
+
+        if read(ReadOptions::SUMMARY) {
+            document.summary = doc
+                .get_first(schema.summary)
+                .and_then(Value::as_text)
+                .filter(|summary| !summary.is_empty())
+                .map(ToOwned::to_owned);
+        }
+
+        if read(ReadOptions::BRANCHES) {
+            document.branches = read_lang_field(&doc, schema.branches);
+        }
+
+        if read(ReadOptions::LINE_END_INDICES) {
+            document.line_end_indices = LineIndex::decode(
+                doc.get_first(schema.line_end_indices)
+                    .unwrap()
+                    .as_bytes()
+                    .unwrap(),
+            );
+        }
+
+        if read(ReadOptions::SYMBOL_LOCATIONS) {
+            document.symbol_locations = bincode::deserialize(
+                doc.get_first(schema.symbol_locations)
+                    .unwrap()
+                    .as_bytes()
+                    .unwrap(),
+            )
+            .unwrap_or_default();
+        }
+
+        if read(ReadOptions::LINE_BLAME) {
+            document.line_blame = doc
+                .get_first(schema.line_blame)
+                .and_then(Value::as_bytes)
+                .and_then(LineBlame::decode);
+        }
+
+        document
+    }
+}
+
 #[async_trait]
 impl DocumentRead for ContentReader {
     type Schema = File;
@@ -78,12 +264,33 @@ impl DocumentRead for ContentReader {
     where
         I: Iterator<Item = &'a Query<'a>>,
     {
-        Compiler::new()
-            .priority(&[schema.relative_path])
+        let compiler = Compiler::new().priority(&[schema.relative_path]);
+        let compiler = match schema.corpus_mode() {
+            CorpusMode::Code => compiler
+                .phrase(&[schema.content], CONTENT_PHRASE_SLOP)
+                .min_ngram_len(&[schema.content], schema.min_ngram_len()),
+            CorpusMode::Prose => compiler.word_phrase(&[schema.content], PROSE_PHRASE_SLOP),
+        };
+        let compiler = if schema.indexes_content_prose() {
+            compiler
+                .word_phrase(&[schema.content_prose], PROSE_PHRASE_SLOP)
+                .alternative(schema.content, schema.content_prose, CONTENT_PROSE_BOOST)
+        } else {
+            compiler
+        };
+
+        compiler
             .literal(schema.relative_path, |q| q.path.clone())
             .literal(schema.repo_name, |q| q.repo.clone())
             .literal(schema.branches, |q| q.branch.clone())
-            .byte_string(schema.lang, |q| q.lang.as_ref())
+            .interned(schema.lang, lang_id(schema))
+            .byte_string(schema.content_type, |q| q.content_type.as_ref())
+            .byte_string(schema.owners, |q| q.owner.as_ref())
+            .byte_string(schema.tags, |q| q.tag.as_ref())
+            .keyword(schema.line_ending, |q| q.line_ending.as_ref())
+            .non_zero(schema.symbol_count, |q| q.has_symbols)
+            .flag(schema.vendored, |q| q.vendored)
+            .flag(schema.is_small, |q| q.small)
             .literal(schema.symbols, |q| {
                 q.target.as_ref().and_then(Target::symbol).cloned()
             })
@@ -94,40 +301,7 @@ impl DocumentRead for ContentReader {
     }
 
     fn read_document(&self, schema: &File, doc: tantivy::Document) -> Self::Document {
-        let relative_path = read_text_field(&doc, schema.relative_path);
-        let repo_ref = read_text_field(&doc, schema.repo_ref);
-        let repo_name = read_text_field(&doc, schema.repo_name);
-        let content = read_text_field(&doc, schema.content);
-        let lang = read_lang_field(&doc, schema.lang);
-        let branches = read_lang_field(&doc, schema.branches);
-
-        let line_end_indices = doc
-            .get_first(schema.line_end_indices)
-            .unwrap()
-            .as_bytes()
-            .unwrap()
-            .chunks_exact(4)
-            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
-            .collect();
-
-        let symbol_locations = bincode::deserialize(
-            doc.get_first(schema.symbol_locations)
-                .unwrap()
-                .as_bytes()
-                .unwrap(),
-        )
-        .unwrap_or_default();
-
-        ContentDocument {
-            relative_path,
-            repo_name,
-            repo_ref,
-            content,
-            symbol_locations,
-            line_end_indices,
-            lang,
-            branches,
-        }
+        self.read_document_with(schema, doc, ReadOptions::ALL)
     }
 }
 
@@ -145,11 +319,35 @@ impl DocumentRead for FileReader {
             //   lang:Rust
             //   path:server
             //   lang:Rust path:server
+            //   content_type:application/yaml
+            //   owner:@org/team
+            //   tag:critical
+            //   line_ending:mixed
             Query {
                 open: Some(false) | None,
                 target: None,
                 lang: Some(..),
                 ..
+            } | Query {
+                open: Some(false) | None,
+                target: None,
+                content_type: Some(..),
+                ..
+            } | Query {
+                open: Some(false) | None,
+                target: None,
+                owner: Some(..),
+                ..
+            } | Query {
+                open: Some(false) | None,
+                target: None,
+                tag: Some(..),
+                ..
+            } | Query {
+                open: Some(false) | None,
+                target: None,
+                line_ending: Some(..),
+                ..
             } | Query {
                 open: Some(false) | None,
                 target: None,
@@ -172,7 +370,12 @@ impl DocumentRead for FileReader {
             .literal(schema.relative_path, |q| q.path.clone())
             .literal(schema.repo_name, |q| q.repo.clone())
             .literal(schema.branches, |q| q.branch.clone())
-            .byte_string(schema.lang, |q| q.lang.as_ref())
+            .interned(schema.lang, lang_id(schema))
+            .byte_string(schema.content_type, |q| q.content_type.as_ref())
+            .byte_string(schema.owners, |q| q.owner.as_ref())
+            .byte_string(schema.tags, |q| q.tag.as_ref())
+            .keyword(schema.line_ending, |q| q.line_ending.as_ref())
+            .flag(schema.is_small, |q| q.small)
             .compile(queries, tantivy_index)
     }
 
@@ -180,7 +383,8 @@ impl DocumentRead for FileReader {
         let relative_path = read_text_field(&doc, schema.relative_path);
         let repo_ref = read_text_field(&doc, schema.repo_ref);
         let repo_name = read_text_field(&doc, schema.repo_name);
-        let lang = read_lang_field(&doc, schema.lang);
+        let lang = read_lang_field(&doc, schema);
+        let content_type = read_bytes_field(&doc, schema.content_type);
         let branches = read_text_field(&doc, schema.branches);
 
         FileDocument {
@@ -188,6 +392,7 @@ impl DocumentRead for FileReader {
             repo_name,
             repo_ref,
             lang,
+            content_type,
             branches,
         }
     }
@@ -249,7 +454,7 @@ pub struct OpenDocument {
     pub repo_ref: String,
     pub lang: Option<String>,
     pub content: String,
-    pub line_end_indices: Vec<u32>,
+    pub line_end_indices: LineIndex,
 }
 
 #[async_trait]
@@ -299,7 +504,7 @@ impl DocumentRead for OpenReader {
                 }
                 _ => None,
             })
-            .byte_string(schema.lang, |q| q.lang.as_ref())
+            .interned(schema.lang, lang_id(schema))
             .compile(queries, tantivy_index)
     }
 
@@ -307,16 +512,14 @@ impl DocumentRead for OpenReader {
         let relative_path = read_text_field(&doc, schema.relative_path);
         let repo_name = read_text_field(&doc, schema.repo_name);
         let repo_ref = read_text_field(&doc, schema.repo_ref);
-        let lang = read_lang_field(&doc, schema.lang);
+        let lang = read_lang_field(&doc, schema);
         let content = read_text_field(&doc, schema.content);
-        let line_end_indices = doc
-            .get_first(schema.line_end_indices)
-            .unwrap()
-            .as_bytes()
-            .unwrap()
-            .chunks_exact(4)
-            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
-            .collect();
+        let line_end_indices = LineIndex::decode(
+            doc.get_first(schema.line_end_indices)
+                .unwrap()
+                .as_bytes()
+                .unwrap(),
+        );
 
         Self::Document {
             relative_path,
@@ -344,26 +547,46 @@ fn read_text_field(doc: &tantivy::Document, field: Field) -> String {
     doc.get_first(field).unwrap().as_text().unwrap().to_owned()
 }
 
-fn read_lang_field(doc: &tantivy::Document, lang: Field) -> Option<String> {
-    let lang_str = crate::query::languages::proper_case(
-        doc.get_first(lang)
-            .and_then(Value::as_bytes)
-            .map(String::from_utf8_lossy)
-            .unwrap_or_default(),
-    )
-    .into_owned();
-
-    // None if "", Some(l) otherwise
-    if lang_str.is_empty() {
-        None
-    } else {
-        Some(lang_str)
-    }
+fn read_lang_field(doc: &tantivy::Document, schema: &File) -> Option<String> {
+    let lang = doc
+        .get_first(schema.lang)
+        .and_then(Value::as_u64)
+        .and_then(|id| schema.langs.name(id))?;
+
+    Some(crate::query::languages::proper_case(lang.into()).into_owned())
+}
+
+/// Look up the language of a query in the language dictionary of `schema`.
+fn lang_id(schema: &File) -> impl for<'b> FnMut(&'b Query<'b>) -> Option<Option<u64>> + 'static {
+    let langs = schema.langs.clone();
+    move |q| q.lang.as_ref().map(|lang| langs.id(lang))
+}
+
+fn read_bytes_field(doc: &tantivy::Document, field: Field) -> Option<String> {
+    doc.get_first(field)
+        .and_then(Value::as_bytes)
+        .filter(|bytes| !bytes.is_empty())
+        .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Read every value of a multi-valued bytes field.
+fn read_bytes_fields(doc: &tantivy::Document, field: Field) -> Vec<String> {
+    doc.get_all(field)
+        .filter_map(Value::as_bytes)
+        .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+        .collect()
 }
 
 #[cfg(test)]
 mod test {
+    use std::sync::Arc;
+
+    use clap::Parser;
+    use sqlx::SqlitePool;
+    use tantivy::doc;
+
     use super::*;
+    use crate::Configuration;
 
     #[test]
     fn test_base_name() {
@@ -371,4 +594,36 @@ mod test {
         assert_eq!(base_name(&format!("bar/")), format!("bar/"));
         assert_eq!(base_name("foo.txt"), "");
     }
+
+    #[tokio::test]
+    async fn read_selected_fields() {
+        let config = Arc::new(Configuration::parse_from(["bleep"]));
+        let sql = Arc::new(SqlitePool::connect_lazy("sqlite::memory:").unwrap());
+        let schema = File::new(config, sql, None);
+        let symbols = bincode::serialize(&SymbolLocations::Empty).unwrap();
+        let document = doc!(
+            schema.repo_ref => "local//a",
+            schema.repo_name => "a",
+            schema.relative_path => "src/main.rs",
+            schema.content => "fn main() {}\n",
+            schema.lang => schema.langs.intern("rust"),
+            schema.branches => "main",
+            schema.line_end_indices => LineIndex::encode("fn main() {}\n"),
+            schema.symbol_locations => symbols,
+        );
+
+        let listed = ContentReader.read_document_with(&schema, document.clone(), ReadOptions::LANG);
+        assert_eq!(listed.relative_path, "src/main.rs");
+        assert_eq!(listed.repo_ref, "local//a");
+        assert_eq!(listed.lang.as_deref(), Some("Rust"));
+        assert!(listed.content.is_empty());
+        assert_eq!(listed.line_end_indices.line_count(), 0);
+
+        let full = ContentReader.read_document(&schema, document);
+        assert_eq!(full.content, "fn main() {}\n");
+        assert_eq!(full.line_end_indices.ends(), [12]);
+        assert_eq!(full.lang.as_deref(), Some("Rust"));
+        assert_eq!(ReadOptions::default(), ReadOptions::ALL);
+        assert!(ReadOptions::ALL.contains(ReadOptions::CONTENT | ReadOptions::SYMBOL_LOCATIONS));
+    }
 }
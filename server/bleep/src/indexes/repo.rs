@@ -4,7 +4,7 @@ use tantivy::{doc, schema::Schema, IndexWriter, Term};
 use tracing::info;
 
 pub use super::schema::Repo;
-use super::Indexable;
+use super::{IndexOutcome, Indexable, SharedWriter};
 use crate::{
     background::SyncPipes,
     repo::{RepoMetadata, RepoRef, Repository},
@@ -23,11 +23,19 @@ impl Indexable for Repo {
         repo_ref: &RepoRef,
         repo: &Repository,
         _metadata: &RepoMetadata,
-        writer: &IndexWriter,
+        writer: &SharedWriter,
         _pipes: &SyncPipes,
-    ) -> Result<()> {
+        dry_run: bool,
+        _target_ref: Option<&str>,
+    ) -> Result<IndexOutcome> {
+        if dry_run {
+            return Ok(IndexOutcome::default());
+        }
+
+        let writer = writer.read().unwrap();
+
         // Make sure we delete any stale references to this repository when indexing.
-        self.delete_by_repo(writer, repo);
+        self.delete_by_repo(&writer, repo);
 
         writer.add_document(doc!(
             // We don't have organization support for now.
@@ -43,7 +51,7 @@ impl Indexable for Repo {
             "finished indexing repo metadata"
         );
 
-        Ok(())
+        Ok(IndexOutcome::default())
     }
 
     fn delete_by_repo(&self, writer: &IndexWriter, repo: &Repository) {
@@ -1,16 +1,27 @@
 use tantivy::schema::{
     BytesOptions, Field, IndexRecordOption, Schema, SchemaBuilder, TextFieldIndexing, TextOptions,
-    FAST, STORED, STRING,
+    FAST, INDEXED, STORED, STRING,
 };
 
-use crate::{db::SqlDb, semantic::Semantic};
+use ignore::overrides::Override;
+use std::sync::{Arc, Mutex};
 
-#[cfg(feature = "debug")]
-use {
-    histogram::Histogram,
-    std::sync::{Arc, RwLock},
+use super::{
+    file::{path_globs, GeneratedFiles, LanguageOverrides},
+    CorpusMode, LangDictionary,
+};
+use crate::{
+    cache::{FileCacheSnapshot, IndexRun},
+    db::SqlDb,
+    repo::{iterator::MAX_LINE_COUNT, RepoRef, Repository},
+    semantic::Semantic,
+    symbol::SymbolLocations,
+    Configuration,
 };
 
+#[cfg(feature = "debug")]
+use {histogram::Histogram, std::sync::RwLock};
+
 /// A schema for indexing all files and directories, linked to a
 /// single repository on disk.
 #[derive(Clone)]
@@ -18,12 +29,25 @@ pub struct File {
     pub(super) schema: Schema,
     pub(super) semantic: Option<Semantic>,
     pub(super) sql: SqlDb,
+    /// The names of the languages that `lang` holds the ids of
+    pub langs: Arc<LangDictionary>,
+    pub(super) config: Arc<Configuration>,
+    pub(super) generated: GeneratedFiles,
+    pub(super) vendored_paths: Override,
+    pub(super) language_overrides: LanguageOverrides,
+    /// Extract the symbols of a file from its contents and language
+    pub(super) extract_symbols: fn(&str, &str) -> SymbolLocations,
+    /// The file caches and runs of the repositories indexed since the last commit, which are
+    /// persisted once their documents are committed
+    pub(super) uncommitted_caches: Arc<Mutex<Vec<(RepoRef, FileCacheSnapshot, IndexRun)>>>,
 
     #[cfg(feature = "debug")]
     pub histogram: Arc<RwLock<Histogram>>,
 
     /// Unique ID for the file in a repo
     pub unique_hash: Field,
+    /// Cache key of the file's chunks in the semantic index
+    pub semantic_hash: Field,
 
     /// Path to the root of the repo on disk
     pub repo_disk_path: Field,
@@ -41,19 +65,48 @@ pub struct File {
     pub repo_name: Field,
 
     pub content: Field,
+    /// The content of source code, tokenized into stemmed words like prose, if
+    /// [`File::indexes_content_prose`]
+    pub content_prose: Field,
     pub line_end_indices: Field,
 
+    /// The dominant line ending of the file: `lf`, `crlf` or `mixed`
+    pub line_ending: Field,
+    /// Whether the file started with a UTF-8 byte order mark, which isn't part of `content`
+    pub has_bom: Field,
+    /// Whether the file had merge conflict markers, before any side of them was picked
+    pub has_conflicts: Field,
+    /// The encoded [`LineBlame`](super::blame::LineBlame) of the file, if it was computed
+    pub line_blame: Field,
+
     /// a flat list of every symbol's text, for searching, e.g.:
     /// ["File", "Repo", "worker"]
     pub symbols: Field,
     pub symbol_locations: Field,
+    /// Number of symbols extracted from the file
+    pub symbol_count: Field,
 
     /// fast fields for scoring
+    ///
+    /// The id of the file's language in [`File::langs`]
     pub lang: Field,
+
+    /// MIME type of the file, independent of `lang`
+    pub content_type: Field,
+
+    /// Owners of the file in the repository's CODEOWNERS, one value per owner
+    pub owners: Field,
+    /// Tags of the file in the repository's `.bloop/tags.toml`, one value per tag
+    pub tags: Field,
+
+    /// One-line summary of the file, for display
+    pub summary: Field,
     pub avg_line_length: Field,
     pub last_commit_unix_seconds: Field,
 
     /// fast byte versions of certain fields for collector-level filtering
+    ///
+    /// `raw_relative_path` is also indexed, to match exact paths
     pub raw_content: Field,
     pub raw_repo_name: Field,
     pub raw_relative_path: Field,
@@ -63,10 +116,24 @@ pub struct File {
 
     /// Whether this entry is a file or a directory
     pub is_directory: Field,
+
+    /// Whether this entry matches the vendored globs of the configuration
+    pub vendored: Field,
+
+    /// Whether this file is smaller than the minimum file size, and only indexed by path
+    pub is_small: Field,
+
+    /// The position of this document among the shards of a large file, which is only set for
+    /// sharded files
+    pub shard_index: Field,
+    /// The line of its file that a shard starts at
+    pub shard_start_line: Field,
+    /// The byte offset in its file that a shard starts at
+    pub shard_start_byte: Field,
 }
 
 impl File {
-    pub fn new(sql: SqlDb, semantic: Option<Semantic>) -> Self {
+    pub fn new(config: Arc<Configuration>, sql: SqlDb, semantic: Option<Semantic>) -> Self {
         let mut builder = tantivy::schema::SchemaBuilder::new();
         let trigram = TextOptions::default().set_stored().set_indexing_options(
             TextFieldIndexing::default()
@@ -75,46 +142,95 @@ impl File {
         );
 
         let unique_hash = builder.add_text_field("unique_hash", STRING | STORED);
+        let semantic_hash = builder.add_text_field("semantic_hash", STRING | STORED);
 
         let repo_disk_path = builder.add_text_field("repo_disk_path", STRING);
         let repo_ref = builder.add_text_field("repo_ref", STRING | STORED);
         let repo_name = builder.add_text_field("repo_name", trigram.clone());
         let relative_path = builder.add_text_field("relative_path", trigram.clone());
 
-        let content = builder.add_text_field("content", trigram.clone());
+        let content = builder.add_text_field(
+            "content",
+            TextOptions::default().set_stored().set_indexing_options(
+                TextFieldIndexing::default()
+                    .set_tokenizer(config.corpus_mode.content_tokenizer(config.min_ngram_len))
+                    .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+            ),
+        );
+        let content_prose = builder.add_text_field(
+            "content_prose",
+            TextOptions::default().set_indexing_options(
+                TextFieldIndexing::default()
+                    .set_tokenizer("prose")
+                    .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+            ),
+        );
         let line_end_indices =
             builder.add_bytes_field("line_end_indices", BytesOptions::default().set_stored());
+        let line_ending = builder.add_text_field("line_ending", STRING | STORED);
+        let has_bom = builder.add_bool_field("has_bom", STORED);
+        let has_conflicts = builder.add_bool_field("has_conflicts", INDEXED | STORED);
+        let line_blame =
+            builder.add_bytes_field("line_blame", BytesOptions::default().set_stored());
 
         let symbols = builder.add_text_field("symbols", trigram.clone());
         let symbol_locations =
             builder.add_bytes_field("symbol_locations", BytesOptions::default().set_stored());
+        let symbol_count = builder.add_u64_field("symbol_count", FAST | INDEXED);
 
         let branches = builder.add_text_field("branches", trigram);
 
-        let lang = builder.add_bytes_field(
-            "lang",
-            BytesOptions::default().set_stored().set_indexed() | FAST,
+        let lang = builder.add_u64_field("lang", FAST | INDEXED | STORED);
+        let content_type = builder.add_bytes_field(
+            "content_type",
+            BytesOptions::default().set_stored().set_indexed(),
         );
+        let owners =
+            builder.add_bytes_field("owners", BytesOptions::default().set_stored().set_indexed());
+        let tags =
+            builder.add_bytes_field("tags", BytesOptions::default().set_stored().set_indexed());
+        let summary = builder.add_text_field("summary", STORED);
         let avg_line_length = builder.add_f64_field("line_length", FAST);
-        let last_commit_unix_seconds = builder.add_u64_field("last_commit_unix_seconds", FAST);
+        let last_commit_unix_seconds =
+            builder.add_u64_field("last_commit_unix_seconds", FAST | INDEXED);
 
         let raw_content = builder.add_bytes_field("raw_content", FAST);
         let raw_repo_name = builder.add_bytes_field("raw_repo_name", FAST);
-        let raw_relative_path = builder.add_bytes_field("raw_relative_path", FAST);
+        let raw_relative_path = builder.add_bytes_field(
+            "raw_relative_path",
+            BytesOptions::default().set_indexed() | FAST,
+        );
 
         let is_directory = builder.add_bool_field("is_directory", FAST);
+        let vendored = builder.add_bool_field("vendored", INDEXED);
+        let is_small = builder.add_bool_field("is_small", INDEXED | STORED);
+        let shard_index = builder.add_u64_field("shard_index", INDEXED | STORED);
+        let shard_start_line = builder.add_u64_field("shard_start_line", STORED);
+        let shard_start_byte = builder.add_u64_field("shard_start_byte", STORED);
 
         Self {
             repo_disk_path,
             relative_path,
             unique_hash,
+            semantic_hash,
             repo_ref,
             repo_name,
             content,
+            content_prose,
             line_end_indices,
+            line_ending,
+            has_bom,
+            has_conflicts,
+            line_blame,
             symbols,
             symbol_locations,
+            symbol_count,
             lang,
+            langs: Arc::new(LangDictionary::new(sql.clone())),
+            content_type,
+            owners,
+            tags,
+            summary,
             avg_line_length,
             last_commit_unix_seconds,
             schema: builder.build(),
@@ -124,12 +240,56 @@ impl File {
             raw_relative_path,
             branches,
             is_directory,
+            vendored,
+            is_small,
+            shard_index,
+            shard_start_line,
+            shard_start_byte,
             sql,
+            generated: GeneratedFiles::new(
+                &config.generated_file_globs,
+                &config.generated_file_markers,
+            ),
+            vendored_paths: path_globs(&config.vendored_globs, "vendored"),
+            language_overrides: LanguageOverrides::new(&config.language_overrides),
+            extract_symbols: super::file::extract_symbols,
+            uncommitted_caches: Default::default(),
+            config,
 
             #[cfg(feature = "debug")]
             histogram: Arc::new(Histogram::builder().build().unwrap().into()),
         }
     }
+
+    /// Whether the files of `repo` are embedded: neither the repository nor the configuration
+    /// opted it out of semantic search.
+    pub(crate) fn embeds(&self, reporef: &RepoRef, repo: &Repository) -> bool {
+        repo.semantic && self.config.semantic_enabled(reporef)
+    }
+
+    pub fn corpus_mode(&self) -> CorpusMode {
+        self.config.corpus_mode
+    }
+
+    /// Whether `content_prose` is indexed, next to the n-grams of `content`.
+    pub fn indexes_content_prose(&self) -> bool {
+        self.corpus_mode()
+            .indexes_content_prose(self.config.index_content_prose)
+    }
+
+    /// Number of lines in each shard of a large file, if large files are sharded.
+    ///
+    /// Shards are kept within the line limit of a single document.
+    pub fn lines_per_shard(&self) -> Option<usize> {
+        self.config
+            .shard_lines
+            .map(|lines| lines.clamp(1, MAX_LINE_COUNT as usize / std::mem::size_of::<u32>()))
+    }
+
+    /// Length of the shortest n-grams in `content`.
+    pub fn min_ngram_len(&self) -> usize {
+        self.config.min_ngram_len.clamp(1, 3)
+    }
 }
 
 /// An index representing a repository to allow free-text search on
@@ -82,6 +82,19 @@ impl<'a> TreeSitterFile<'a> {
             .collect::<Vec<_>>())
     }
 
+    /// The source text of the comments at the top of this file, before any other syntax node.
+    pub fn leading_comments(&self) -> Vec<&'a str> {
+        let root_node = self.tree.root_node();
+        let mut cursor = root_node.walk();
+        let src = self.src;
+
+        root_node
+            .children(&mut cursor)
+            .take_while(|node| node.kind().contains("comment"))
+            .filter_map(|node| std::str::from_utf8(&src[node.byte_range()]).ok())
+            .collect()
+    }
+
     /// Produce a lexical scope-graph for this TreeSitterFile.
     pub fn scope_graph(self) -> Result<ScopeGraph, TreeSitterFileError> {
         let query = self
@@ -389,6 +389,27 @@ impl ScopeGraph {
             .collect()
     }
 
+    /// The symbols defined directly in the root scope, such as the functions and types of a
+    /// module, as opposed to the locals inside them.
+    pub fn top_level_symbols(&self) -> Vec<Symbol> {
+        let namespaces = ALL_LANGUAGES[self.lang_id].namespaces;
+        self.graph
+            .node_indices()
+            .filter(|&idx| self.is_top_level(idx))
+            .filter_map(|idx| match &self.graph[idx] {
+                NodeKind::Def(LocalDef {
+                    range,
+                    symbol_id: Some(symbol_id),
+                    ..
+                }) => Some(Symbol {
+                    kind: symbol_id.name(namespaces).to_owned(),
+                    range: *range,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
     // produce a stringified name of a def/ref's symbol
     pub fn symbol_name_of(&self, idx: NodeIndex<u32>) -> Option<&'static str> {
         let namespaces = ALL_LANGUAGES[self.lang_id].namespaces;
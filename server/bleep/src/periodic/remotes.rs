@@ -175,7 +175,6 @@ async fn periodic_repo_poll(app: Application, reporef: RepoRef) -> Option<()> {
     let mut poller = Poller::start(&app, &reporef)?;
 
     loop {
-        use SyncStatus::*;
         let (last_updated, status) = check_repo(&app, &reporef)?;
         if status.indexable().not() {
             warn!(?status, "skipping indexing of repo");
@@ -195,7 +194,7 @@ async fn periodic_repo_poll(app: Application, reporef: RepoRef) -> Option<()> {
             return None;
         }
 
-        if last_updated == updated && status == Done {
+        if last_updated == updated && status.is_done() {
             let poll_interval = poller.increase_interval();
 
             debug!(
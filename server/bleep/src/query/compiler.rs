@@ -4,13 +4,16 @@ use std::{
     mem,
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use compact_str::CompactString;
 use either::Either;
 use smallvec::SmallVec;
 use tantivy::{
-    query::{AllQuery, BooleanQuery, BoostQuery, TermQuery},
+    query::{
+        AllQuery, BooleanQuery, BoostQuery, EmptyQuery, Occur, PhraseQuery, RangeQuery, TermQuery,
+    },
     schema::{Field, IndexRecordOption},
+    tokenizer::TextAnalyzer,
     Index, Term,
 };
 
@@ -21,20 +24,47 @@ use crate::query::{
 
 type DynQuery = Box<dyn tantivy::query::Query>;
 
+/// Boost of the phrase clause of a multi-word literal, relative to its words matching anywhere.
+const PHRASE_BOOST: f32 = 5.0;
+
 enum Extraction<'a> {
     /// Match a literal against a tantivy `text` field.
     Literal(Literal<'a>),
 
     /// Match a string against a tantivy `bytes` field.
     ByteString(&'a Cow<'a, str>),
+
+    /// Match whether a tantivy `u64` field is non-zero.
+    NonZero(bool),
+
+    /// Match the value of a tantivy `bool` field.
+    Flag(bool),
+
+    /// Match an interned id in a tantivy `u64` field, or nothing for values without one.
+    Id(Option<u64>),
+
+    /// Match a whole string against an untokenized tantivy `text` field.
+    Keyword(&'a Cow<'a, str>),
 }
 
 /// A closure that tries to pull out an `Extraction` variant, given a `Query` reference.
 type Extractor = dyn for<'a> FnMut(&'a Query<'a>) -> Option<Extraction<'a>>;
 
+/// How the words of a multi-word literal are matched together in a field.
+#[derive(Clone, Copy)]
+enum Phrase {
+    /// The field is tokenized into n-grams positioned at their byte offset
+    Ngrams { slop: u32 },
+    /// The field is tokenized into words
+    Words { slop: u32 },
+}
+
 #[derive(Default)]
 pub struct Compiler {
     priority: HashSet<Field>,
+    phrases: HashMap<Field, Phrase>,
+    min_ngram_lens: HashMap<Field, usize>,
+    alternatives: HashMap<Field, (Field, f32)>,
     extractors: HashMap<Field, Box<Extractor>>,
 }
 
@@ -50,6 +80,46 @@ impl Compiler {
         self
     }
 
+    /// Rank documents where the words of a multi-word literal appear together above those where
+    /// they're scattered, for a list of fields.
+    ///
+    /// `slop` is the number of bytes the words may be shifted by. Phrases are matched with the case
+    /// of the query, even in case-insensitive queries.
+    pub fn phrase(mut self, fields: &[Field], slop: u32) -> Self {
+        self.phrases
+            .extend(fields.iter().map(|f| (*f, Phrase::Ngrams { slop })));
+        self
+    }
+
+    /// Like [`Compiler::phrase`], for fields that are tokenized into words rather than n-grams.
+    ///
+    /// `slop` is the number of words the query words may be shifted by. Regexes are rejected in
+    /// these fields, as the words may be stemmed or left out.
+    pub fn word_phrase(mut self, fields: &[Field], slop: u32) -> Self {
+        self.phrases
+            .extend(fields.iter().map(|f| (*f, Phrase::Words { slop })));
+        self
+    }
+
+    /// Declare that a list of n-gram fields leave out n-grams shorter than `len` characters.
+    ///
+    /// Regex fragments too short to be in these fields match every document, and are left to
+    /// the content filter of the caller.
+    pub fn min_ngram_len(mut self, fields: &[Field], len: usize) -> Self {
+        self.min_ngram_lens.extend(fields.iter().map(|f| (*f, len)));
+        self
+    }
+
+    /// Also match the plain literals of `field` against `other`, which holds the same text
+    /// tokenized another way.
+    ///
+    /// Documents match if the literal is found in either field, and the score of `other` is
+    /// multiplied by `boost`. Regexes are only matched against `field`.
+    pub fn alternative(mut self, field: Field, other: Field, boost: f32) -> Self {
+        self.alternatives.insert(field, (other, boost));
+        self
+    }
+
     /// Add a literal field to the compiler.
     ///
     /// This takes a Tantivy `Field`, alongside a closure that returns an `Option<&Literal>` when
@@ -81,6 +151,64 @@ impl Compiler {
         self
     }
 
+    /// Add a keyword field to the compiler.
+    ///
+    /// Matches `Cow<str>` exactly against a tantivy `text` field indexed as a single token.
+    pub fn keyword<F>(mut self, tantivy_field: Field, mut extractor: F) -> Self
+    where
+        F: for<'b> FnMut(&'b Query<'b>) -> Option<&'b Cow<'b, str>> + 'static,
+    {
+        self.extractors.insert(
+            tantivy_field,
+            Box::new(move |q| extractor(q).map(Extraction::Keyword)),
+        );
+        self
+    }
+
+    /// Add a count field to the compiler.
+    ///
+    /// Matches documents with a non-zero count in a tantivy `u64` field if the closure returns
+    /// `Some(true)`, and documents with a count of zero on `Some(false)`.
+    pub fn non_zero<F>(mut self, tantivy_field: Field, mut extractor: F) -> Self
+    where
+        F: for<'b> FnMut(&'b Query<'b>) -> Option<bool> + 'static,
+    {
+        self.extractors.insert(
+            tantivy_field,
+            Box::new(move |q| extractor(q).map(Extraction::NonZero)),
+        );
+        self
+    }
+
+    /// Add a flag field to the compiler.
+    ///
+    /// Matches documents whose indexed `bool` field has the value the closure returns.
+    pub fn flag<F>(mut self, tantivy_field: Field, mut extractor: F) -> Self
+    where
+        F: for<'b> FnMut(&'b Query<'b>) -> Option<bool> + 'static,
+    {
+        self.extractors.insert(
+            tantivy_field,
+            Box::new(move |q| extractor(q).map(Extraction::Flag)),
+        );
+        self
+    }
+
+    /// Add an interned field to the compiler.
+    ///
+    /// Matches documents whose `u64` field holds the id the closure returns. The closure returns
+    /// `Some(None)` for values that were never interned, which match no documents.
+    pub fn interned<F>(mut self, tantivy_field: Field, mut extractor: F) -> Self
+    where
+        F: for<'b> FnMut(&'b Query<'b>) -> Option<Option<u64>> + 'static,
+    {
+        self.extractors.insert(
+            tantivy_field,
+            Box::new(move |q| extractor(q).map(Extraction::Id)),
+        );
+        self
+    }
+
     /// Compile a list of queries into a single Tantivy query that matches any
     /// of them.
     pub fn compile<'a, I>(mut self, queries: I, index: &Index) -> Result<DynQuery>
@@ -88,51 +216,42 @@ impl Compiler {
         I: Iterator<Item = &'a Query<'a>>,
     {
         let mut sub_queries: SmallVec<[DynQuery; 2]> = SmallVec::new();
+        // the extractors are called mutably while the rest of the compiler is read
+        let mut extractors = mem::take(&mut self.extractors);
 
         for query in queries {
             let mut intersection = Vec::new();
 
-            for (field, extractor) in &mut self.extractors {
+            for (field, extractor) in &mut extractors {
                 let Some(extraction) = extractor(query) else {
-                    continue
+                    continue;
                 };
 
                 let field_query = match extraction {
                     Extraction::Literal(Literal::Plain(text)) => {
-                        let tokenizer = index
-                            .tokenizer_for_field(*field)
-                            .context("field is missing tokenizer")?;
-
-                        let mut token_stream = tokenizer.token_stream(&text);
-                        let tokens = std::iter::from_fn(move || {
-                            token_stream.next().map(|tok| CompactString::new(&tok.text))
-                        });
-
-                        let terms = if query.is_case_sensitive() {
-                            tokens.map(|s| str_to_query(*field, &s)).collect::<Vec<_>>()
-                        } else {
-                            tokens
-                                .map(|s| {
-                                    let terms = case_permutations(&s)
-                                        .map(|s| str_to_query(*field, &s))
-                                        .collect();
-
-                                    Box::new(BooleanQuery::union(terms)) as DynQuery
-                                })
-                                .collect()
-                        };
-
-                        let mut field_query: DynQuery = Box::new(BooleanQuery::intersection(terms));
-
-                        if self.priority.contains(field) {
-                            field_query = Box::new(BoostQuery::new(field_query, 10.0));
+                        let case_sensitive = query.is_case_sensitive();
+                        let field_query = self.plain_query(*field, &text, case_sensitive, index)?;
+
+                        match self.alternatives.get(field) {
+                            Some((other, boost)) => {
+                                let other_query =
+                                    self.plain_query(*other, &text, case_sensitive, index)?;
+                                Box::new(BooleanQuery::union(vec![
+                                    field_query,
+                                    Box::new(BoostQuery::new(other_query, *boost)),
+                                ]))
+                            }
+                            None => field_query,
                         }
-
-                        field_query
                     }
                     Extraction::Literal(Literal::Regex(regex)) => {
+                        if let Some(Phrase::Words { .. }) = self.phrases.get(field) {
+                            bail!("regexes are not supported by prose indexes");
+                        }
+
                         let plan = planner::plan(&regex)?;
-                        plan_to_query(plan, *field, query.is_case_sensitive())
+                        let min_ngram_len = self.min_ngram_lens.get(field).copied().unwrap_or(1);
+                        plan_to_query(plan, *field, query.is_case_sensitive(), min_ngram_len)
                     }
 
                     Extraction::ByteString(bs) => {
@@ -140,6 +259,28 @@ impl Compiler {
                         let q = TermQuery::new(term, IndexRecordOption::Basic);
                         Box::new(q) as DynQuery
                     }
+
+                    Extraction::NonZero(non_zero) => {
+                        let range = if non_zero { 1..u64::MAX } else { 0..1 };
+                        Box::new(RangeQuery::new_u64(*field, range)) as DynQuery
+                    }
+
+                    Extraction::Flag(value) => {
+                        let term = Term::from_field_bool(*field, value);
+                        Box::new(TermQuery::new(term, IndexRecordOption::Basic)) as DynQuery
+                    }
+
+                    Extraction::Id(Some(id)) => {
+                        let term = Term::from_field_u64(*field, id);
+                        Box::new(TermQuery::new(term, IndexRecordOption::Basic)) as DynQuery
+                    }
+
+                    Extraction::Id(None) => Box::new(EmptyQuery) as DynQuery,
+
+                    Extraction::Keyword(keyword) => {
+                        let term = Term::from_field_text(*field, keyword);
+                        Box::new(TermQuery::new(term, IndexRecordOption::Basic)) as DynQuery
+                    }
                 };
 
                 intersection.push(field_query);
@@ -154,10 +295,72 @@ impl Compiler {
             Box::new(BooleanQuery::union(sub_queries.into_vec()))
         })
     }
+
+    /// Match the tokens of a plain literal in `field`, ranking them higher as a phrase.
+    fn plain_query(
+        &self,
+        field: Field,
+        text: &str,
+        case_sensitive: bool,
+        index: &Index,
+    ) -> Result<DynQuery> {
+        let tokenizer = index
+            .tokenizer_for_field(field)
+            .context("field is missing tokenizer")?;
+
+        let mut token_stream = tokenizer.token_stream(text);
+        let tokens = std::iter::from_fn(move || {
+            token_stream.next().map(|tok| CompactString::new(&tok.text))
+        });
+
+        let terms = if case_sensitive {
+            tokens.map(|s| str_to_query(field, &s)).collect::<Vec<_>>()
+        } else {
+            tokens
+                .map(|s| {
+                    let terms = case_permutations(&s)
+                        .map(|s| str_to_query(field, &s))
+                        .collect();
+
+                    Box::new(BooleanQuery::union(terms)) as DynQuery
+                })
+                .collect()
+        };
+
+        let mut field_query: DynQuery = Box::new(BooleanQuery::intersection(terms));
+
+        let phrase = self.phrases.get(&field).and_then(|phrase| match *phrase {
+            Phrase::Ngrams { slop } => phrase_query(field, &tokenizer, text, slop),
+            Phrase::Words { slop } => word_phrase_query(field, &tokenizer, text, slop),
+        });
+
+        if let Some(phrase) = phrase {
+            field_query = Box::new(BooleanQuery::new(vec![
+                (Occur::Must, field_query),
+                (
+                    Occur::Should,
+                    Box::new(BoostQuery::new(Box::new(phrase), PHRASE_BOOST)),
+                ),
+            ]));
+        }
+
+        if self.priority.contains(&field) {
+            field_query = Box::new(BoostQuery::new(field_query, 10.0));
+        }
+
+        Ok(field_query)
+    }
 }
 
-fn plan_to_query(plan: planner::Fragment, field: Field, case_sensitive: bool) -> DynQuery {
+fn plan_to_query(
+    plan: planner::Fragment,
+    field: Field,
+    case_sensitive: bool,
+    min_ngram_len: usize,
+) -> DynQuery {
     match plan {
+        planner::Fragment::Literal(s) if s.chars().count() < min_ngram_len => Box::new(AllQuery),
+
         planner::Fragment::Literal(s) => {
             let queries = trigrams(&s)
                 .map(|s| {
@@ -183,7 +386,7 @@ fn plan_to_query(plan: planner::Fragment, field: Field, case_sensitive: bool) ->
         planner::Fragment::Dense(op, children) => {
             let subqueries = children
                 .into_iter()
-                .map(|f| plan_to_query(f, field, case_sensitive))
+                .map(|f| plan_to_query(f, field, case_sensitive, min_ngram_len))
                 .collect();
 
             Box::new(match op {
@@ -196,6 +399,65 @@ fn plan_to_query(plan: planner::Fragment, field: Field, case_sensitive: bool) ->
     }
 }
 
+/// Match the words of `text` next to each other, if there are several.
+///
+/// Only the longest n-grams of each word are used, at their offset in `text`, so the words may be
+/// separated by anything as wide as the whitespace between them in the query.
+fn phrase_query(
+    field: Field,
+    tokenizer: &TextAnalyzer,
+    text: &str,
+    slop: u32,
+) -> Option<PhraseQuery> {
+    let words = text
+        .split_whitespace()
+        .map(|word| (word.as_ptr() as usize - text.as_ptr() as usize, word))
+        .collect::<SmallVec<[_; 4]>>();
+
+    if words.len() < 2 {
+        return None;
+    }
+
+    let mut terms = vec![];
+    for (word_offset, word) in words {
+        let longest = word.chars().count().min(3);
+        tokenizer.token_stream(word).process(&mut |token| {
+            if token.text.chars().count() == longest {
+                terms.push((
+                    word_offset + token.offset_from,
+                    Term::from_field_text(field, &token.text),
+                ));
+            }
+        });
+    }
+
+    let mut phrase = PhraseQuery::new_with_offset(terms);
+    phrase.set_slop(slop);
+    Some(phrase)
+}
+
+/// Match the words of `text` next to each other in a field tokenized into words, if there are
+/// several.
+fn word_phrase_query(
+    field: Field,
+    tokenizer: &TextAnalyzer,
+    text: &str,
+    slop: u32,
+) -> Option<PhraseQuery> {
+    let mut terms = vec![];
+    tokenizer.token_stream(text).process(&mut |token| {
+        terms.push((token.position, Term::from_field_text(field, &token.text)));
+    });
+
+    if terms.len() < 2 {
+        return None;
+    }
+
+    let mut phrase = PhraseQuery::new_with_offset(terms);
+    phrase.set_slop(slop);
+    Some(phrase)
+}
+
 fn str_to_query(field: Field, s: &str) -> DynQuery {
     let term = Term::from_field_text(field, s);
     let q = TermQuery::new(term, IndexRecordOption::WithFreqs);
@@ -360,7 +622,7 @@ mod tests {
         let field = Field::from_field_id(123);
         let case_sensitive = true;
 
-        let query = plan_to_query(plan, field, case_sensitive);
+        let query = plan_to_query(plan, field, case_sensitive, 1);
         let query = query.downcast_ref::<BooleanQuery>().unwrap();
 
         assert_eq!(query.clauses().len(), 3);
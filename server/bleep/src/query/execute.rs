@@ -3,23 +3,44 @@ use std::{
     sync::Arc,
 };
 
-use super::{parser, ranking::DocumentTweaker};
+use super::{
+    parser,
+    ranking::{DocumentTweaker, PerRepoTopDocs, ScoreNormalization, StableOrder},
+};
 use crate::{
-    collector::{BytesFilterCollector, FrequencyCollector},
+    collector::{
+        BytesFilterCollector, EitherCollector, FrequencyCollector, IdFrequencyCollector,
+        ScoreFloor, ScoreFloorCollector,
+    },
     indexes::{
+        query::FileQueryBuilder,
         reader::{base_name, ContentReader, FileReader, OpenReader, RepoReader},
-        DocumentRead, File, Indexable, Indexer, Indexes, Repo,
+        DocumentRead, File, Indexable, Indexer, Indexes, LangDictionary, Repo,
     },
+    repo::{iterator::dirty_paths, RepoRef},
     snippet::{HighlightedString, SnippedFile, Snipper},
+    state::RepositoryPool,
 };
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
-use regex::{bytes::RegexBuilder as ByteRegexBuilder, RegexBuilder};
+use regex::{
+    bytes::{Regex as ByteRegex, RegexBuilder as ByteRegexBuilder},
+    RegexBuilder,
+};
 use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 use tantivy::collector::{MultiCollector, TopDocs};
 
+/// The most dirty paths a search can be scoped to.
+pub const MAX_DIRTY_PATHS: usize = 1000;
+
+/// The largest page size a request can ask for.
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// The most results a request can page past.
+pub const MAX_OFFSET: usize = 100_000;
+
 const fn default_page_size() -> usize {
     100
 }
@@ -68,6 +89,72 @@ pub struct ApiQuery {
     /// The number of lines of context in the snippet after the search result
     #[serde(alias = "ca", default = "default_context")]
     context_after: usize,
+
+    /// Only return the given number of best matching snippets of each file.
+    ///
+    /// A value of 0 disables the limit, the same as leaving it unset.
+    #[serde(default)]
+    top_windows: Option<usize>,
+
+    /// Drop content results scoring lower than this
+    ///
+    /// Scores are BM25 scores, adjusted by the usual ranking heuristics.
+    #[serde(default)]
+    min_score: Option<f32>,
+
+    /// Drop content results scoring lower than this fraction of the top result's score
+    ///
+    /// Total counts are computed before either score filter is applied.
+    #[serde(default)]
+    top_fraction: Option<f32>,
+
+    /// How content scores are made comparable across repositories, before results are ranked
+    ///
+    /// Score filters apply to the normalized scores.
+    #[serde(default)]
+    score_normalization: ScoreNormalization,
+
+    /// Whether content search also returns vendored files
+    #[serde(default)]
+    pub include_vendored: bool,
+
+    /// The number of typos content search tolerates in each word, up to 2
+    ///
+    /// Words shorter than 4 characters are always matched exactly.
+    #[serde(default)]
+    pub fuzziness: u8,
+
+    /// Whether path search leaves out files smaller than the minimum file size
+    ///
+    /// Content search never returns these, as their content isn't indexed.
+    #[serde(default)]
+    pub exclude_small_files: bool,
+
+    /// The HNSW `ef` of semantic searches, defaulting to qdrant's configured value
+    ///
+    /// Higher values are slower, but find better matches.
+    #[serde(default)]
+    pub hnsw_ef: Option<u64>,
+
+    /// Only search content in these files, as a comma-separated list of relative paths
+    ///
+    /// This is meant for the files with uncommitted changes in an editor. Paths are matched
+    /// exactly, and an empty list matches no file, so the search returns nothing. At most
+    /// [`MAX_DIRTY_PATHS`] paths are accepted.
+    #[serde(default)]
+    pub dirty_paths: Option<String>,
+
+    /// Only search content in the files with uncommitted changes in this repository
+    ///
+    /// The files are found with [`ApiQuery::resolve_dirty_repo`], unless `dirty_paths` lists
+    /// them. As above, a clean repository has no files to search, and the search returns
+    /// nothing.
+    #[serde(default)]
+    pub dirty_repo: Option<RepoRef>,
+
+    /// The dirty files of `dirty_repo`, once resolved through the repository pool
+    #[serde(skip)]
+    dirty_repo_paths: Option<Vec<String>>,
 }
 
 #[derive(Serialize)]
@@ -208,6 +295,8 @@ impl ApiQuery {
         indexes: Arc<Indexes>,
         queries: Vec<parser::Query<'_>>,
     ) -> Result<QueryResponse> {
+        self.check_paging()?;
+
         // FIXME: this for-loop prevents us from ever producing heterogenous
         // results.
         //
@@ -251,13 +340,93 @@ impl ApiQuery {
         bail!("mangled query")
     }
 
-    fn limit(&self) -> usize {
+    /// Reject pages so far into the results that collecting them would overflow.
+    pub(crate) fn check_paging(&self) -> Result<()> {
+        let offset = self
+            .page
+            .checked_mul(self.page_size.clamp(1, MAX_PAGE_SIZE));
+        if !matches!(offset, Some(offset) if offset <= MAX_OFFSET) {
+            bail!(
+                "page {} is too far into the results, at most {MAX_OFFSET} results can be skipped",
+                self.page
+            );
+        }
+
+        Ok(())
+    }
+
+    fn limit<T: Indexable>(&self, indexer: &Indexer<T>) -> usize {
         // do not permit a page-size of 0
-        self.page_size.max(1)
+        indexer.clamp_limit(self.page_size.clamp(1, MAX_PAGE_SIZE))
     }
 
-    fn offset(&self) -> usize {
-        self.page_size * self.page
+    fn offset<T: Indexable>(&self, indexer: &Indexer<T>) -> usize {
+        // a far away page must not wrap around to the start
+        self.page_size(indexer).saturating_mul(self.page)
+    }
+
+    /// The requested page size, without going over [`MAX_PAGE_SIZE`] or the maximum of the
+    /// index.
+    fn page_size<T: Indexable>(&self, indexer: &Indexer<T>) -> usize {
+        self.page_size
+            .clamp(1, MAX_PAGE_SIZE)
+            .min(indexer.max_result_limit)
+    }
+
+    /// Find the dirty files of `dirty_repo`, unless `dirty_paths` already lists them.
+    ///
+    /// The repository is looked up in `repo_pool`, so only the work trees bloop manages are
+    /// ever read.
+    pub async fn resolve_dirty_repo(&mut self, repo_pool: &RepositoryPool) -> Result<()> {
+        let (None, Some(repo_ref)) = (&self.dirty_paths, &self.dirty_repo) else {
+            return Ok(());
+        };
+
+        let Some(disk_path) = repo_pool
+            .read_async(repo_ref, |_, repo| repo.disk_path.clone())
+            .await
+        else {
+            bail!("unknown repository: {repo_ref}");
+        };
+
+        let paths = tokio::task::spawn_blocking(move || dirty_paths(&disk_path))
+            .await?
+            .with_context(|| format!("failed to find the dirty files of {repo_ref}"))?;
+        self.dirty_repo_paths = Some(paths);
+        Ok(())
+    }
+
+    /// The query restricting content search to dirty files, if a scope was asked for.
+    fn dirty_scope(
+        &self,
+        indexer: &Indexer<File>,
+    ) -> Result<Option<Box<dyn tantivy::query::Query>>> {
+        let paths = match (&self.dirty_paths, &self.dirty_repo_paths) {
+            (Some(paths), _) => paths
+                .split(',')
+                .filter(|p| !p.is_empty())
+                .map(str::to_owned)
+                .collect::<Vec<_>>(),
+            (None, Some(paths)) => paths.clone(),
+            (None, None) if self.dirty_repo.is_some() => {
+                bail!("the dirty files of the repository were not resolved")
+            }
+            (None, None) => return Ok(None),
+        };
+
+        if paths.len() > MAX_DIRTY_PATHS {
+            bail!(
+                "too many dirty paths: {}, at most {MAX_DIRTY_PATHS} are supported",
+                paths.len()
+            );
+        }
+
+        let mut query = FileQueryBuilder::new(&indexer.source, &indexer.index)?.paths_any(paths);
+        if let Some(repo_ref) = &self.dirty_repo {
+            query = query.repo(repo_ref);
+        }
+
+        Ok(Some(Box::new(query.build())))
     }
 }
 
@@ -273,14 +442,26 @@ impl PagingMetadata {
 }
 
 impl ResultStats {
-    fn with_lang_freqs(mut self, mut lang_freqs: HashMap<Vec<u8>, usize>) -> Self {
+    /// Count the matching documents of each language, most common first.
+    ///
+    /// This is meant for displaying facets next to the results, such as `Rust (12), Markdown
+    /// (3)`. Languages with the same count are sorted by name.
+    pub fn lang_facets(&self) -> Vec<(String, usize)> {
+        let mut facets = self
+            .lang
+            .iter()
+            .map(|(lang, count)| (lang.clone(), *count))
+            .collect::<Vec<_>>();
+        facets.sort_by(|(a_lang, a), (b_lang, b)| b.cmp(a).then_with(|| a_lang.cmp(b_lang)));
+        facets
+    }
+
+    fn with_lang_freqs(mut self, lang_freqs: HashMap<u64, usize>, langs: &LangDictionary) -> Self {
         self.lang = lang_freqs
-            .iter_mut()
-            .filter(|(k, _)| !k.is_empty())
-            .map(|(k, v)| {
-                let k =
-                    crate::query::languages::proper_case(String::from_utf8_lossy(k)).to_string();
-                (k, *v)
+            .into_iter()
+            .filter_map(|(id, v)| {
+                let k = crate::query::languages::proper_case(langs.name(id)?.into()).to_string();
+                Some((k, v))
             })
             .collect();
         self
@@ -299,6 +480,25 @@ impl ResultStats {
     }
 }
 
+/// Build a regex for each content target, matching documents that contain its text.
+///
+/// Compiled queries only look for the trigrams of a target, so these are checked against the
+/// content of candidate documents.
+pub(crate) fn target_regexes<'a>(
+    targets: impl IntoIterator<Item = (&'a parser::Target<'a>, bool)>,
+) -> Vec<ByteRegex> {
+    targets
+        .into_iter()
+        .filter_map(|(target, case_sensitive)| {
+            ByteRegexBuilder::new(&target.literal().regex_str())
+                .multi_line(true)
+                .case_insensitive(!case_sensitive)
+                .build()
+                .ok()
+        })
+        .collect()
+}
+
 #[async_trait]
 impl ExecuteQuery for ContentReader {
     type Index = File;
@@ -309,6 +509,22 @@ impl ExecuteQuery for ContentReader {
         queries: &[parser::Query<'_>],
         q: &ApiQuery,
     ) -> Result<QueryResponse> {
+        // vendored files are left out, unless asked for, and typos are tolerated by matching
+        // content as a regex of its misspellings
+        let queries = queries
+            .iter()
+            .cloned()
+            .map(|mut query| {
+                if !q.include_vendored {
+                    query.vendored.get_or_insert(false);
+                }
+                if let Some(parser::Target::Content(lit)) = &mut query.target {
+                    *lit = lit.fuzzy(q.fuzziness);
+                }
+                query
+            })
+            .collect::<Vec<_>>();
+
         // queries that produce content results
         let relevant_queries = queries.iter().filter(|q| self.query_matches(q));
 
@@ -320,29 +536,48 @@ impl ExecuteQuery for ContentReader {
             .collect::<SmallVec<[_; 2]>>();
 
         // a regex filter to get rid of docs that contain the trigrams but not the text
-        let byte_regexes = targets
-            .iter()
-            .filter_map(|(target, case)| {
-                ByteRegexBuilder::new(&target.literal().regex_str())
-                    .multi_line(true)
-                    .case_insensitive(!case)
-                    .build()
-                    .ok()
-            })
-            .collect::<Vec<_>>();
+        let byte_regexes = target_regexes(targets.iter().copied());
 
         let raw_content = indexer.source.raw_content;
         let repo_field = indexer.source.raw_repo_name;
         let lang_field = indexer.source.lang;
 
+        let score_floor = ScoreFloor {
+            min_score: q.min_score,
+            top_fraction: q.top_fraction,
+        };
+
         // our results will consist of the top-k docs...
-        let top_k = TopDocs::with_limit(q.limit())
-            .and_offset(q.offset())
-            .tweak_score(DocumentTweaker(indexer.source.clone()));
+        //
+        // when filtering by score, paging happens after the filter, since the relative floor
+        // depends on the top hit overall
+        let (limit, top_k_offset, offset) = if score_floor.is_set() {
+            (
+                q.offset(indexer).saturating_add(q.limit(indexer)),
+                0,
+                q.offset(indexer),
+            )
+        } else {
+            (q.limit(indexer), q.offset(indexer), 0)
+        };
+        let tweaker = DocumentTweaker(indexer.source.clone());
+        let top_k = match q.score_normalization {
+            ScoreNormalization::None => EitherCollector::Left(StableOrder(
+                TopDocs::with_limit(limit)
+                    .and_offset(top_k_offset)
+                    .tweak_score(tweaker),
+            )),
+            normalization => EitherCollector::Right(PerRepoTopDocs {
+                tweaker,
+                normalization,
+                limit,
+                offset: top_k_offset,
+            }),
+        };
 
         // ...plus some rich search metadata
         let total_count_collector = tantivy::collector::Count;
-        let lang_stats_collector = FrequencyCollector(lang_field);
+        let lang_stats_collector = IdFrequencyCollector(lang_field);
         let repo_stats_collector = FrequencyCollector(repo_field);
 
         let mut metadata_collector = MultiCollector::new();
@@ -352,13 +587,20 @@ impl ExecuteQuery for ContentReader {
 
         // our final search results contain top-k, total count, language stats, repo stats,
         // filtered by the target regex
-        let collector = BytesFilterCollector::new(
-            raw_content,
-            move |b| byte_regexes.iter().any(|r| r.is_match(b)), // a doc is accepted if it contains at least 1 target
-            (top_k, metadata_collector),
+        let collector = ScoreFloorCollector::new(
+            score_floor,
+            offset,
+            BytesFilterCollector::new(
+                raw_content,
+                move |b| byte_regexes.iter().any(|r| r.is_match(b)), // a doc is accepted if it contains at least 1 target
+                (top_k, metadata_collector),
+            ),
         );
 
-        let mut results = indexer.query(queries.iter(), self, collector).await?;
+        let scope = q.dirty_scope(indexer)?;
+        let mut results = indexer
+            .query_within(queries.iter(), self, scope, collector)
+            .await?;
         let data = results
             .docs
             .filter_map(|doc| {
@@ -385,17 +627,39 @@ impl ExecuteQuery for ContentReader {
                     }
                 }
 
-                Some(QueryResult::Snippets(all_snippets?))
+                let mut all_snippets = all_snippets?;
+
+                // lines of a shard count from the start of the shard, not of its file, while
+                // bytes are relative to the text of each snippet
+                if let Some(start) = doc.shard_start {
+                    for snippet in &mut all_snippets.snippets {
+                        snippet.line_range.start += start.line;
+                        snippet.line_range.end += start.line;
+                        for symbol in &mut snippet.symbols {
+                            symbol.range.start.line += start.line;
+                            symbol.range.end.line += start.line;
+                        }
+                    }
+                }
+
+                if let Some(k) = q.top_windows {
+                    all_snippets.retain_top_windows(k);
+                }
+
+                Some(QueryResult::Snippets(all_snippets))
             })
             .collect::<Vec<QueryResult>>();
 
         let total_count = total_count_handle.extract(&mut results.metadata);
 
         let stats = ResultStats::default()
-            .with_lang_freqs(lang_stats_handle.extract(&mut results.metadata))
+            .with_lang_freqs(
+                lang_stats_handle.extract(&mut results.metadata),
+                &indexer.source.langs,
+            )
             .with_repo_freqs(repo_stats_handle.extract(&mut results.metadata));
 
-        let metadata = PagingMetadata::new(q.page, q.page_size, Some(total_count));
+        let metadata = PagingMetadata::new(q.page, q.page_size(indexer), Some(total_count));
 
         let count = data.len();
         let response = QueryResponse {
@@ -436,14 +700,14 @@ impl ExecuteQuery for FileReader {
             })
             .unzip();
 
-        let top_k = TopDocs::with_limit(q.limit()).and_offset(q.offset());
+        let top_k = TopDocs::with_limit(q.limit(indexer)).and_offset(q.offset(indexer));
 
         let path_field = indexer.source.raw_relative_path;
         let repo_field = indexer.source.raw_repo_name;
         let lang_field = indexer.source.lang;
 
         let total_count_collector = tantivy::collector::Count;
-        let lang_stats_collector = FrequencyCollector(lang_field);
+        let lang_stats_collector = IdFrequencyCollector(lang_field);
         let repo_stats_collector = FrequencyCollector(repo_field);
 
         let mut metadata_collector = MultiCollector::new();
@@ -457,6 +721,18 @@ impl ExecuteQuery for FileReader {
             (top_k, metadata_collector),
         );
 
+        // small files are still found by path, unless asked otherwise
+        let queries = queries
+            .iter()
+            .cloned()
+            .map(|mut query| {
+                if q.exclude_small_files {
+                    query.small.get_or_insert(false);
+                }
+                query
+            })
+            .collect::<Vec<_>>();
+
         let mut results = indexer.query(queries.iter(), self, collector).await?;
 
         let data = results
@@ -481,10 +757,13 @@ impl ExecuteQuery for FileReader {
         let total_count = total_count_handle.extract(&mut results.metadata);
 
         let stats = ResultStats::default()
-            .with_lang_freqs(lang_stats_handle.extract(&mut results.metadata))
+            .with_lang_freqs(
+                lang_stats_handle.extract(&mut results.metadata),
+                &indexer.source.langs,
+            )
             .with_repo_freqs(repo_stats_handle.extract(&mut results.metadata));
 
-        let metadata = PagingMetadata::new(q.page, q.page_size, Some(total_count));
+        let metadata = PagingMetadata::new(q.page, q.page_size(indexer), Some(total_count));
 
         let response = QueryResponse {
             count: data.len(),
@@ -525,7 +804,7 @@ impl ExecuteQuery for RepoReader {
             })
             .unzip();
 
-        let top_k = TopDocs::with_limit(q.limit()).and_offset(q.offset());
+        let top_k = TopDocs::with_limit(q.limit(indexer)).and_offset(q.offset(indexer));
 
         let name_field = indexer.source.raw_name;
         let repo_stats_collector = FrequencyCollector(name_field);
@@ -563,7 +842,7 @@ impl ExecuteQuery for RepoReader {
             .with_repo_freqs(repo_stats_handle.extract(&mut results.metadata));
 
         let total_count = total_count_handle.extract(&mut results.metadata);
-        let metadata = PagingMetadata::new(q.page, q.page_size, Some(total_count));
+        let metadata = PagingMetadata::new(q.page, q.page_size(indexer), Some(total_count));
 
         let response = QueryResponse {
             count: data.len(),
@@ -674,11 +953,12 @@ impl ExecuteQuery for OpenReader {
                         lang: doc.lang.clone(),
                         contents: doc.content.clone(),
                         size: doc.content.len(),
-                        loc: doc.line_end_indices.len(),
+                        loc: doc.line_end_indices.line_count(),
                         sloc: doc
                             .line_end_indices
+                            .ends()
                             .iter()
-                            .zip(doc.line_end_indices.iter().skip(1))
+                            .zip(doc.line_end_indices.ends().iter().skip(1))
                             .filter(|(&prev, &next)| next - prev != 1)
                             .count()
                             .saturating_add(1),
@@ -829,4 +1109,28 @@ mod tests {
 
         assert_eq!(expected, observed);
     }
+
+    #[test]
+    fn far_away_pages_are_rejected() {
+        let query = |page: usize, page_size: usize| -> ApiQuery {
+            serde_json::from_value(serde_json::json!({
+                "q": "foo",
+                "page": page,
+                "page_size": page_size,
+            }))
+            .unwrap()
+        };
+
+        assert!(query(0, 100).check_paging().is_ok());
+        assert!(query(MAX_OFFSET / 100, 100).check_paging().is_ok());
+        assert!(query(MAX_OFFSET / 100 + 1, 100).check_paging().is_err());
+
+        // page sizes are clamped before the offset is computed
+        assert!(query(MAX_OFFSET / MAX_PAGE_SIZE, usize::MAX)
+            .check_paging()
+            .is_ok());
+
+        // and the offset itself must not wrap around
+        assert!(query(usize::MAX, 2).check_paging().is_err());
+    }
 }
@@ -2,11 +2,17 @@ use std::borrow::Cow;
 
 include!(concat!(env!("OUT_DIR"), "/languages.rs"));
 
+/// Map a language name, alias or unambiguous extension to the canonical,
+/// lowercase language name.
+///
+/// This is applied to both the stored language of a file and the queried
+/// one, so any spelling that resolves here matches at query time.
 pub fn parse_alias(lang: Cow<str>) -> Cow<str> {
-    if let Some(s) = EXT_MAP.get(&lang) {
+    let lower = lang.to_ascii_lowercase();
+    if let Some(s) = EXT_MAP.get(&lower) {
         (*s).into()
     } else {
-        lang.to_ascii_lowercase().into()
+        lower.into()
     }
 }
 
@@ -31,6 +37,34 @@ mod test {
         assert_eq!(parse_alias("md".into()), "markdown");
     }
 
+    #[test]
+    fn equivalent_aliases_are_canonical() {
+        let pairs = [
+            ("c++", "cpp"),
+            ("c++", "cxx"),
+            ("C++", "CPP"),
+            ("c#", "csharp"),
+            ("Rust", "rs"),
+            ("go", "golang"),
+            ("Objective-C++", "objective-c++"),
+        ];
+
+        for (lhs, rhs) in pairs {
+            assert_eq!(
+                parse_alias(lhs.into()),
+                parse_alias(rhs.into()),
+                "{lhs} vs {rhs}"
+            );
+        }
+    }
+
+    #[test]
+    fn canonical_names_are_stable() {
+        for name in ["rust", "c++", "c#", "typescript", "objective-c++"] {
+            assert_eq!(parse_alias(name.into()), name);
+        }
+    }
+
     #[test]
     fn sample_proper_case() {
         assert_eq!(proper_case("rust".into()), "Rust");
@@ -1,7 +1,22 @@
 use pest::{iterators::Pair, Parser};
 use regex::Regex;
 use smallvec::{smallvec, SmallVec};
-use std::{borrow::Cow, collections::HashSet, mem};
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashSet},
+    mem,
+};
+
+/// The most typos a fuzzy literal tolerates in each word.
+pub const MAX_FUZZINESS: u8 = 2;
+
+/// Words shorter than this are matched exactly by fuzzy literals, as a few edits turn them into
+/// almost anything.
+const MIN_FUZZY_WORD_LEN: usize = 4;
+
+/// The most misspellings a fuzzy literal spells out, over all of its words, to keep the size of
+/// the regex in check. Words that would go over are matched with fewer edits.
+const MAX_FUZZY_VARIANTS: usize = 256;
 
 #[derive(Default, Clone, Debug, PartialEq, Eq)]
 pub struct Query<'a> {
@@ -13,7 +28,20 @@ pub struct Query<'a> {
     pub repo: Option<Literal<'a>>,
     pub path: Option<Literal<'a>>,
     pub lang: Option<Cow<'a, str>>,
+    pub content_type: Option<Cow<'a, str>>,
     pub branch: Option<Literal<'a>>,
+    /// An owner that matching files must have in CODEOWNERS, lowercased
+    pub owner: Option<Cow<'a, str>>,
+    /// A tag that matching files must have in `.bloop/tags.toml`, lowercased
+    pub tag: Option<Cow<'a, str>>,
+    /// The line endings of matching files, one of `lf`, `crlf` or `mixed`, lowercased
+    pub line_ending: Option<Cow<'a, str>>,
+    /// Whether matching files must, or must not, define any symbols
+    pub has_symbols: Option<bool>,
+    /// Whether matching files must, or must not, be vendored
+    pub vendored: Option<bool>,
+    /// Whether matching files must, or must not, be indexed by path only for being too small
+    pub small: Option<bool>,
     pub target: Option<Target<'a>>,
 }
 
@@ -112,7 +140,14 @@ impl<'a> Query<'a> {
             repo: rhs.repo.or(self.repo),
             path: rhs.path.or(self.path),
             lang: rhs.lang.or(self.lang),
+            content_type: rhs.content_type.or(self.content_type),
             branch: rhs.branch.or(self.branch),
+            owner: rhs.owner.or(self.owner),
+            tag: rhs.tag.or(self.tag),
+            line_ending: rhs.line_ending.or(self.line_ending),
+            has_symbols: rhs.has_symbols.or(self.has_symbols),
+            vendored: rhs.vendored.or(self.vendored),
+            small: rhs.small.or(self.small),
 
             target: match (self.target, rhs.target) {
                 (Some(Target::Content(lhs)), Some(Target::Content(rhs))) => {
@@ -301,6 +336,120 @@ impl<'a> Literal<'a> {
             Literal::Regex(cow) => Literal::Regex(Cow::Owned(cow.into_owned())),
         }
     }
+
+    /// Tolerate up to `distance` typos in each word of this literal.
+    ///
+    /// Every word of a plain literal is replaced by an alternation of its misspellings within
+    /// that edit distance, counting a transposition as a single edit. The distance is capped at
+    /// [`MAX_FUZZINESS`], and words shorter than 4 characters are left alone. The words share a
+    /// budget of misspellings, so later words of a long literal may tolerate fewer typos. Regex
+    /// literals are returned unchanged.
+    pub fn fuzzy(&self, distance: u8) -> Self {
+        let distance = distance.min(MAX_FUZZINESS);
+        let Self::Plain(text) = self else {
+            return self.clone();
+        };
+        if distance == 0 {
+            return self.clone();
+        }
+
+        let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+        let mut regex = String::new();
+        let mut budget = MAX_FUZZY_VARIANTS;
+        let mut rest = text.as_ref();
+        while let Some(start) = rest.find(is_word_char) {
+            let len = rest[start..]
+                .find(|c| !is_word_char(c))
+                .unwrap_or(rest.len() - start);
+
+            regex += &regex::escape(&rest[..start]);
+            regex += &fuzzy_word(&rest[start..start + len], distance, &mut budget);
+            rest = &rest[start + len..];
+        }
+        regex += &regex::escape(rest);
+
+        Self::Regex(regex.into())
+    }
+}
+
+/// A character of a misspelling: either the one of the word, or any character or none.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum FuzzyChar {
+    Exact(char),
+    Any,
+}
+
+/// A regex matching `word` with up to `distance` edits, spelling out at most `budget`
+/// misspellings and taking them out of it.
+fn fuzzy_word(word: &str, distance: u8, budget: &mut usize) -> String {
+    if word.chars().count() < MIN_FUZZY_WORD_LEN {
+        return regex::escape(word);
+    }
+
+    let mut variants = BTreeSet::from([word.chars().map(FuzzyChar::Exact).collect::<Vec<_>>()]);
+    for _ in 0..distance {
+        let next = variants
+            .iter()
+            .flat_map(|v| single_edits(v))
+            .collect::<BTreeSet<_>>();
+        if next.len() > *budget {
+            break;
+        }
+        variants = next;
+    }
+
+    if variants.len() == 1 {
+        return regex::escape(word);
+    }
+    *budget -= variants.len();
+
+    let alternatives = variants
+        .iter()
+        .map(|variant| {
+            variant
+                .iter()
+                .map(|c| match c {
+                    FuzzyChar::Exact(c) => regex::escape(c.encode_utf8(&mut [0; 4])),
+                    FuzzyChar::Any => ".?".to_owned(),
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>();
+
+    format!("(?:{})", alternatives.join("|"))
+}
+
+/// `variant`, and every variant one edit away from it.
+///
+/// Substitutions and deletions both turn a character into [`FuzzyChar::Any`], which also
+/// matches the character itself.
+fn single_edits(variant: &[FuzzyChar]) -> Vec<Vec<FuzzyChar>> {
+    let mut edits = vec![variant.to_vec()];
+    for (i, c) in variant.iter().enumerate() {
+        if let FuzzyChar::Exact(_) = c {
+            let mut edit = variant.to_vec();
+            edit[i] = FuzzyChar::Any;
+            edits.push(edit);
+        }
+    }
+
+    for i in 0..=variant.len() {
+        let mut edit = variant.to_vec();
+        edit.insert(i, FuzzyChar::Any);
+        edits.push(edit);
+    }
+
+    for (i, pair) in variant.windows(2).enumerate() {
+        if let [FuzzyChar::Exact(a), FuzzyChar::Exact(b)] = pair {
+            if a != b {
+                let mut edit = variant.to_vec();
+                edit.swap(i, i + 1);
+                edits.push(edit);
+            }
+        }
+    }
+
+    edits
 }
 
 impl<'a> From<Pair<'a, Rule>> for Literal<'a> {
@@ -371,8 +520,13 @@ enum Expr<'a> {
     Symbol(Literal<'a>),
     Path(Literal<'a>),
     Lang(Cow<'a, str>),
+    ContentType(Cow<'a, str>),
     Content(Literal<'a>),
     Branch(Literal<'a>),
+    HasSymbols(bool),
+    Owner(Cow<'a, str>),
+    Tag(Cow<'a, str>),
+    LineEnding(Cow<'a, str>),
 
     CaseSensitive(bool),
     Open(bool),
@@ -400,6 +554,11 @@ impl<'a> Expr<'a> {
             Rule::org => Org(Literal::from(pair.into_inner().next().unwrap())),
             Rule::branch => Branch(Literal::from(pair.into_inner().next().unwrap())),
             Rule::lang => Lang(pair.into_inner().as_str().into()),
+            Rule::content_type => ContentType(pair.into_inner().as_str().into()),
+            Rule::has_symbols => HasSymbols(pair.into_inner().as_str() == "true"),
+            Rule::owner => Owner(pair.into_inner().as_str().into()),
+            Rule::tag => Tag(pair.into_inner().as_str().into()),
+            Rule::line_ending => LineEnding(pair.into_inner().as_str().into()),
 
             Rule::open => {
                 let inner = pair.into_inner().next().unwrap();
@@ -600,6 +759,26 @@ fn flatten(root: Expr<'_>) -> SmallVec<[Query<'_>; 1]> {
             lang: Some(super::languages::parse_alias(lang)),
             ..Default::default()
         }],
+        Expr::ContentType(content_type) => smallvec![Query {
+            content_type: Some(content_type.to_ascii_lowercase().into()),
+            ..Default::default()
+        }],
+        Expr::HasSymbols(has_symbols) => smallvec![Query {
+            has_symbols: Some(has_symbols),
+            ..Default::default()
+        }],
+        Expr::Owner(owner) => smallvec![Query {
+            owner: Some(owner.to_lowercase().into()),
+            ..Default::default()
+        }],
+        Expr::Tag(tag) => smallvec![Query {
+            tag: Some(tag.to_lowercase().into()),
+            ..Default::default()
+        }],
+        Expr::LineEnding(line_ending) => smallvec![Query {
+            line_ending: Some(line_ending.to_ascii_lowercase().into()),
+            ..Default::default()
+        }],
         Expr::Content(lit) => smallvec![Query {
             target: Some(Target::Content(lit)),
             ..Default::default()
@@ -949,6 +1128,64 @@ mod tests {
         );
     }
 
+    #[test]
+    fn content_type_filter() {
+        assert_eq!(
+            parse("content_type:Application/YAML path:ci").unwrap(),
+            vec![Query {
+                path: Some(Literal::Plain("ci".into())),
+                content_type: Some("application/yaml".into()),
+                ..Query::default()
+            }],
+        );
+    }
+
+    #[test]
+    fn line_ending_filter() {
+        assert_eq!(
+            parse("line_ending:Mixed path:src").unwrap(),
+            vec![Query {
+                path: Some(Literal::Plain("src".into())),
+                line_ending: Some("mixed".into()),
+                ..Query::default()
+            }],
+        );
+    }
+
+    #[test]
+    fn owner_and_tag_filters() {
+        assert_eq!(
+            parse("owner:@Org/Payments tag:Critical refund").unwrap(),
+            vec![Query {
+                owner: Some("@org/payments".into()),
+                tag: Some("critical".into()),
+                target: Some(Target::Content(Literal::Plain("refund".into()))),
+                ..Query::default()
+            }],
+        );
+    }
+
+    #[test]
+    fn has_symbols_filter() {
+        assert_eq!(
+            parse("has_symbols:true Config").unwrap(),
+            vec![Query {
+                has_symbols: Some(true),
+                target: Some(Target::Content(Literal::Plain("Config".into()))),
+                ..Query::default()
+            }],
+        );
+
+        assert_eq!(
+            parse("has_symbols:false path:ci").unwrap(),
+            vec![Query {
+                has_symbols: Some(false),
+                path: Some(Literal::Plain("ci".into())),
+                ..Query::default()
+            }],
+        );
+    }
+
     #[test]
     fn enable_open() {
         assert_eq!(
@@ -1184,7 +1421,8 @@ mod tests {
     #[test]
     fn nl_parse_dedup_similar_filters() {
         let ParsedQuery::Semantic(q) =
-            parse_nl("what is background color? lang:tsx repo:bloop repo:bloop").unwrap() else {
+            parse_nl("what is background color? lang:tsx repo:bloop repo:bloop").unwrap()
+        else {
             panic!("down with this sorta thing")
         };
         assert_eq!(q.repos().count(), 1);
@@ -1277,4 +1515,51 @@ mod tests {
             }],
         );
     }
+
+    #[test]
+    fn fuzzy_literals() {
+        let matches = |lit: &str, distance, text| {
+            Literal::Plain(lit.into())
+                .fuzzy(distance)
+                .regex()
+                .unwrap()
+                .is_match(text)
+        };
+
+        assert!(matches("recieve", 1, "fn receive() {}"));
+        assert!(matches("recieve", 1, "recive"));
+        assert!(!matches("recieve", 1, "retrieve"));
+        assert!(matches("recieve", 2, "retrieve"));
+        assert!(matches("recieve", 5, "retrieve"));
+        assert!(!matches("recieve", 0, "receive"));
+
+        // short words are matched exactly
+        assert!(matches("fn recieve", 1, "fn receive"));
+        assert!(!matches("fn recieve", 1, "fx receive"));
+
+        let regex = Literal::Regex("rec(ie|ei)ve".into());
+        assert_eq!(regex.fuzzy(2), regex);
+    }
+
+    #[test]
+    fn fuzzy_literals_are_bounded() {
+        let branches = |lit: &str, distance| match Literal::Plain(lit.into()).fuzzy(distance) {
+            Literal::Regex(regex) => regex.matches('|').count() + regex.matches("(?:").count(),
+            Literal::Plain(_) => 0,
+        };
+
+        // long words, and many words, fall back to fewer edits
+        assert!(branches("abcdefghijklmnopqrst", 2) <= MAX_FUZZY_VARIANTS);
+        assert!(branches(&["recieve"; 16].join(" "), 2) <= MAX_FUZZY_VARIANTS);
+
+        let matches = |lit: &str, distance, text| {
+            Literal::Plain(lit.into())
+                .fuzzy(distance)
+                .regex()
+                .unwrap()
+                .is_match(text)
+        };
+        assert!(matches("abcdefghijklmnopqrst", 2, "abcdefghijkXmnopqrst"));
+        assert!(!matches("abcdefghijklmnopqrst", 2, "abcdefghijkXmnopqrsX"));
+    }
 }
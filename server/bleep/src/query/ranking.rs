@@ -1,27 +1,60 @@
-use std::{sync::Arc, time::SystemTime};
+use std::{cmp::Ordering, collections::HashMap, sync::Arc, time::SystemTime};
 
+use serde::{Deserialize, Serialize};
 use tantivy::{
-    collector::{ScoreSegmentTweaker, ScoreTweaker},
+    collector::{Collector, ScoreSegmentTweaker, ScoreTweaker, SegmentCollector},
     fastfield::{BytesFastFieldReader, Column},
-    DocId, Score,
+    DocAddress, DocId, Score, SegmentReader,
 };
 
-use crate::indexes::file::File;
+use crate::indexes::{file::File, CorpusMode, LangDictionary};
+
+/// The score of a document, with ties broken by a stable key.
+///
+/// `TopDocs` orders documents with equal scores by their address, which changes as segments
+/// are merged. The key is a hash of the repository and path of the document instead, so the
+/// same query ranks tied documents the same way across runs.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct RankedScore {
+    pub score: Score,
+    tie_break: u64,
+}
 
 pub struct DocumentTweaker(pub File);
 pub struct SegmentScorer {
     line_length: Arc<dyn Column<f64>>,
-    lang: BytesFastFieldReader,
+    /// Paragraphs of prose are single long lines, which doesn't make them worse matches
+    penalize_long_lines: bool,
+    lang: Arc<dyn Column<u64>>,
     last_commit: Arc<dyn Column<u64>>,
+    repo_name: BytesFastFieldReader,
+    relative_path: BytesFastFieldReader,
+}
+
+impl SegmentScorer {
+    fn tie_break(&self, doc: DocId) -> u64 {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(self.repo_name.get_bytes(doc));
+        hasher.update(&[0]);
+        hasher.update(self.relative_path.get_bytes(doc));
+
+        let hash = hasher.finalize();
+        u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap())
+    }
 }
 
-impl ScoreSegmentTweaker<Score> for SegmentScorer {
-    fn score(&mut self, doc: DocId, mut score: Score) -> Score {
+impl ScoreSegmentTweaker<RankedScore> for SegmentScorer {
+    fn score(&mut self, doc: DocId, mut score: Score) -> RankedScore {
         // * 1000 if it's a language we understand
-        score *= 1.0 + self.lang.num_bytes(doc).min(1) as f32 * 999.0;
+        if self.lang.get_val(doc) != LangDictionary::NONE {
+            score *= 1000.0;
+        }
 
         // Penalty for lines that are too long
-        score /= self.line_length.get_val(doc).clamp(20.0, 1000.0) as f32;
+        if self.penalize_long_lines {
+            score /= self.line_length.get_val(doc).clamp(20.0, 1000.0) as f32;
+        }
+
         score /= SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
@@ -29,11 +62,14 @@ impl ScoreSegmentTweaker<Score> for SegmentScorer {
             .saturating_sub(self.last_commit.get_val(doc))
             .min(5_000_000) as f32;
 
-        score
+        RankedScore {
+            score,
+            tie_break: self.tie_break(doc),
+        }
     }
 }
 
-impl ScoreTweaker<Score> for DocumentTweaker {
+impl ScoreTweaker<RankedScore> for DocumentTweaker {
     type Child = SegmentScorer;
 
     fn segment_tweaker(
@@ -43,10 +79,404 @@ impl ScoreTweaker<Score> for DocumentTweaker {
         let Self(schema) = self;
         Ok(SegmentScorer {
             line_length: segment_reader.fast_fields().f64(schema.avg_line_length)?,
-            lang: segment_reader.fast_fields().bytes(schema.lang)?,
+            penalize_long_lines: schema.corpus_mode() == CorpusMode::Code,
+            lang: segment_reader.fast_fields().u64(schema.lang)?,
             last_commit: segment_reader
                 .fast_fields()
                 .u64(schema.last_commit_unix_seconds)?,
+            repo_name: segment_reader.fast_fields().bytes(schema.raw_repo_name)?,
+            relative_path: segment_reader
+                .fast_fields()
+                .bytes(schema.raw_relative_path)?,
         })
     }
 }
+
+/// Turn the [`RankedScore`]s of a top-k collector back into plain scores, once the hits are
+/// ordered.
+pub struct StableOrder<TCollector>(pub TCollector);
+
+impl<TCollector> Collector for StableOrder<TCollector>
+where
+    TCollector: Collector<Fruit = Vec<(RankedScore, DocAddress)>>,
+{
+    type Fruit = Vec<(Score, DocAddress)>;
+
+    type Child = TCollector::Child;
+
+    fn for_segment(
+        &self,
+        segment_local_id: u32,
+        segment_reader: &SegmentReader,
+    ) -> tantivy::Result<TCollector::Child> {
+        self.0.for_segment(segment_local_id, segment_reader)
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.0.requires_scoring()
+    }
+
+    fn merge_fruits(
+        &self,
+        segment_fruits: Vec<<TCollector::Child as SegmentCollector>::Fruit>,
+    ) -> tantivy::Result<Self::Fruit> {
+        let hits = self.0.merge_fruits(segment_fruits)?;
+        Ok(hits
+            .into_iter()
+            .map(|(ranked, addr)| (ranked.score, addr))
+            .collect())
+    }
+}
+
+/// How the scores of the hits of different repositories are made comparable, before they're
+/// ranked together.
+///
+/// BM25 scores depend on the statistics of the whole index, and the repositories with the most
+/// matching text get the highest ones, so a large repository can push every hit of a small one
+/// off the first page.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoreNormalization {
+    /// Rank hits by their own score
+    #[default]
+    None,
+    /// Divide scores by the best score in their repository, so the top hit of every
+    /// repository scores 1
+    PerRepoMax,
+    /// Take the number of standard deviations that scores are above the mean score of their
+    /// repository, or keep them as they are for repositories with a single hit or tied hits
+    PerRepoZScore,
+}
+
+/// The running statistics of the scores of a repository's hits.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct ScoreMoments {
+    count: u64,
+    sum: f64,
+    sum_of_squares: f64,
+    max: Score,
+}
+
+impl ScoreMoments {
+    fn add(&mut self, score: Score) {
+        self.count += 1;
+        self.sum += score as f64;
+        self.sum_of_squares += score as f64 * score as f64;
+        self.max = self.max.max(score);
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.sum_of_squares += other.sum_of_squares;
+        self.max = self.max.max(other.max);
+    }
+
+    fn normalize(&self, score: Score, normalization: ScoreNormalization) -> Score {
+        match normalization {
+            ScoreNormalization::None => score,
+            ScoreNormalization::PerRepoMax if self.max > 0.0 => score / self.max,
+            ScoreNormalization::PerRepoMax => 0.0,
+            // a single hit, or hits that all score the same, have no spread to measure against,
+            // so they keep their own score
+            ScoreNormalization::PerRepoZScore if self.count < 2 => score,
+            ScoreNormalization::PerRepoZScore => {
+                let count = self.count as f64;
+                let mean = self.sum / count;
+                let deviation = (self.sum_of_squares / count - mean * mean).max(0.0).sqrt();
+
+                // rounding leaves a little deviation behind for tied scores
+                if deviation > f64::EPSILON * mean.abs() {
+                    ((score as f64 - mean) / deviation) as Score
+                } else {
+                    score
+                }
+            }
+        }
+    }
+}
+
+/// The hits of a repository in a segment, or once segments are merged.
+#[derive(Default)]
+pub struct RepoHits {
+    moments: ScoreMoments,
+    /// The best hits, holding up to twice the number kept between truncations
+    top: Vec<(RankedScore, DocAddress)>,
+}
+
+impl RepoHits {
+    fn truncate(&mut self, keep: usize) {
+        self.top.sort_by(|(a, a_addr), (b, b_addr)| {
+            b.partial_cmp(a)
+                .unwrap_or(Ordering::Equal)
+                .then(a_addr.cmp(b_addr))
+        });
+        self.top.truncate(keep);
+    }
+
+    fn push(&mut self, hit: (RankedScore, DocAddress), keep: usize) {
+        self.moments.add(hit.0.score);
+        self.top.push(hit);
+        if self.top.len() >= 2 * keep.max(1) {
+            self.truncate(keep);
+        }
+    }
+}
+
+/// A top-k collector that normalizes the scores of each repository's hits, as set by a
+/// [`ScoreNormalization`], before ranking them together.
+///
+/// Scores are first adjusted by a [`DocumentTweaker`]. Every matching document adds to the
+/// statistics of its repository, but only the best `offset + limit` hits of each repository
+/// are kept, as no other hit of it can make the page. The fruit holds the normalized scores.
+pub struct PerRepoTopDocs {
+    pub tweaker: DocumentTweaker,
+    pub normalization: ScoreNormalization,
+    pub limit: usize,
+    pub offset: usize,
+}
+
+pub struct PerRepoSegmentCollector {
+    segment_ord: u32,
+    scorer: SegmentScorer,
+    repo_name: BytesFastFieldReader,
+    keep: usize,
+    repos: HashMap<Vec<u8>, RepoHits>,
+}
+
+impl Collector for PerRepoTopDocs {
+    type Fruit = Vec<(Score, DocAddress)>;
+
+    type Child = PerRepoSegmentCollector;
+
+    fn for_segment(
+        &self,
+        segment_local_id: u32,
+        segment_reader: &SegmentReader,
+    ) -> tantivy::Result<PerRepoSegmentCollector> {
+        Ok(PerRepoSegmentCollector {
+            segment_ord: segment_local_id,
+            scorer: self.tweaker.segment_tweaker(segment_reader)?,
+            repo_name: segment_reader
+                .fast_fields()
+                .bytes(self.tweaker.0.raw_repo_name)?,
+            keep: self.offset + self.limit,
+            repos: HashMap::new(),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        true
+    }
+
+    fn merge_fruits(
+        &self,
+        segment_fruits: Vec<HashMap<Vec<u8>, RepoHits>>,
+    ) -> tantivy::Result<Self::Fruit> {
+        let keep = self.offset + self.limit;
+        let mut repos = HashMap::<Vec<u8>, RepoHits>::new();
+        for fruit in segment_fruits {
+            for (repo, hits) in fruit {
+                let merged = repos.entry(repo).or_default();
+                merged.moments.merge(&hits.moments);
+                merged.top.extend(hits.top);
+                merged.truncate(keep);
+            }
+        }
+
+        let mut hits = repos
+            .into_values()
+            .flat_map(|hits| {
+                let moments = hits.moments;
+                hits.top.into_iter().map(move |(ranked, addr)| {
+                    let score = moments.normalize(ranked.score, self.normalization);
+                    (score, ranked, addr)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // hits with the same normalized score keep the order of their own scores
+        hits.sort_by(|(a, a_ranked, a_addr), (b, b_ranked, b_addr)| {
+            b.partial_cmp(a)
+                .unwrap_or(Ordering::Equal)
+                .then(b_ranked.partial_cmp(a_ranked).unwrap_or(Ordering::Equal))
+                .then(a_addr.cmp(b_addr))
+        });
+
+        Ok(hits
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit)
+            .map(|(score, _, addr)| (score, addr))
+            .collect())
+    }
+}
+
+impl SegmentCollector for PerRepoSegmentCollector {
+    type Fruit = HashMap<Vec<u8>, RepoHits>;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        let ranked = self.scorer.score(doc, score);
+        let addr = DocAddress::new(self.segment_ord, doc);
+
+        let repo = self.repo_name.get_bytes(doc);
+        let hits = match self.repos.get_mut(repo) {
+            Some(hits) => hits,
+            None => self.repos.entry(repo.to_vec()).or_default(),
+        };
+        hits.push((ranked, addr), self.keep);
+    }
+
+    fn harvest(mut self) -> Self::Fruit {
+        for hits in self.repos.values_mut() {
+            hits.truncate(self.keep);
+        }
+        self.repos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use clap::Parser;
+    use sqlx::SqlitePool;
+    use tantivy::{collector::TopDocs, doc, query::AllQuery, schema::Value, Index};
+
+    use super::*;
+    use crate::{indexes::Indexable, Configuration};
+
+    /// Rank every document of an index built from `paths`, committing each one on its own.
+    fn rank(paths: &[&str]) -> Vec<String> {
+        let config = Arc::new(Configuration::parse_from(["bleep"]));
+        let sql = Arc::new(SqlitePool::connect_lazy("sqlite::memory:").unwrap());
+        let schema = File::new(config, sql, None);
+        let index = Index::create_in_ram(schema.schema());
+
+        let mut writer = index.writer(15_000_000).unwrap();
+        for path in paths {
+            writer
+                .add_document(doc!(
+                    schema.relative_path => *path,
+                    schema.raw_relative_path => path.as_bytes(),
+                    schema.raw_repo_name => "bloop".as_bytes(),
+                    schema.lang => schema.langs.intern("rust"),
+                    schema.avg_line_length => 40.0,
+                    schema.last_commit_unix_seconds => 0u64,
+                ))
+                .unwrap();
+            writer.commit().unwrap();
+        }
+
+        let searcher = index.reader().unwrap().searcher();
+        let collector =
+            StableOrder(TopDocs::with_limit(10).tweak_score(DocumentTweaker(schema.clone())));
+        searcher
+            .search(&AllQuery, &collector)
+            .unwrap()
+            .into_iter()
+            .map(|(_, addr)| {
+                let doc = searcher.doc(addr).unwrap();
+                doc.get_first(schema.relative_path)
+                    .and_then(Value::as_text)
+                    .unwrap()
+                    .to_owned()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn tied_scores_have_a_stable_order() {
+        let paths = ["src/a.rs", "src/b.rs", "src/c.rs", "src/d.rs"];
+        let reversed = paths.iter().rev().copied().collect::<Vec<_>>();
+
+        let ranked = rank(&paths);
+        assert_eq!(ranked.len(), paths.len());
+        assert_eq!(rank(&reversed), ranked);
+    }
+
+    #[tokio::test]
+    async fn per_repo_normalization_ranks_small_repos() {
+        let config = Arc::new(Configuration::parse_from(["bleep"]));
+        let sql = Arc::new(SqlitePool::connect_lazy("sqlite::memory:").unwrap());
+        let schema = File::new(config, sql, None);
+        let index = Index::create_in_ram(schema.schema());
+
+        // the short lines of the big repository outscore the small one's every time
+        let mut writer = index.writer(15_000_000).unwrap();
+        let big = (0..20).map(|i| ("big", format!("big/{i}.rs"), 20.0 + i as f64));
+        let small = std::iter::once(("small", "small/lib.rs".to_owned(), 200.0));
+        for (repo, path, line_length) in big.chain(small) {
+            writer
+                .add_document(doc!(
+                    schema.relative_path => path.as_str(),
+                    schema.raw_relative_path => path.as_bytes(),
+                    schema.raw_repo_name => repo.as_bytes(),
+                    schema.lang => schema.langs.intern("rust"),
+                    schema.avg_line_length => line_length,
+                    schema.last_commit_unix_seconds => 0u64,
+                ))
+                .unwrap();
+        }
+        writer.commit().unwrap();
+
+        let searcher = index.reader().unwrap().searcher();
+        let top_repos = |normalization| {
+            let collector = PerRepoTopDocs {
+                tweaker: DocumentTweaker(schema.clone()),
+                normalization,
+                limit: 5,
+                offset: 0,
+            };
+            searcher
+                .search(&AllQuery, &collector)
+                .unwrap()
+                .into_iter()
+                .map(|(_, addr)| {
+                    let doc = searcher.doc(addr).unwrap();
+                    let path = doc.get_first(schema.relative_path).and_then(Value::as_text);
+                    path.unwrap().split('/').next().unwrap().to_owned()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let unnormalized = top_repos(ScoreNormalization::None);
+        assert_eq!(unnormalized.len(), 5);
+        assert!(!unnormalized.contains(&"small".to_owned()));
+
+        let normalized = top_repos(ScoreNormalization::PerRepoMax);
+        assert_eq!(normalized.len(), 5);
+        assert!(normalized[..2].contains(&"small".to_owned()));
+    }
+
+    #[test]
+    fn z_scores_center_on_the_repo_mean() {
+        let mut moments = ScoreMoments::default();
+        for score in [1.0, 2.0, 3.0] {
+            moments.add(score);
+        }
+
+        let z = |score| moments.normalize(score, ScoreNormalization::PerRepoZScore);
+        assert_eq!(z(2.0), 0.0);
+        assert!(z(3.0) > 0.0);
+        assert_eq!(z(1.0), -z(3.0));
+
+        // without a spread, scores are left as they are
+        let mut tied = ScoreMoments::default();
+        tied.add(4.0);
+        tied.add(4.0);
+        assert_eq!(tied.normalize(4.0, ScoreNormalization::PerRepoZScore), 4.0);
+        assert_eq!(tied.normalize(2.0, ScoreNormalization::PerRepoMax), 0.5);
+
+        let mut single = ScoreMoments::default();
+        single.add(7.0);
+        assert_eq!(
+            single.normalize(7.0, ScoreNormalization::PerRepoZScore),
+            7.0
+        );
+        assert_eq!(
+            ScoreMoments::default().normalize(1.5, ScoreNormalization::PerRepoZScore),
+            1.5
+        );
+    }
+}
@@ -14,7 +14,7 @@ use tracing::debug;
 use crate::state::get_relative_path;
 
 pub(crate) mod iterator;
-use iterator::language;
+use iterator::{attributes, language, ownership};
 
 // Types of repo
 #[derive(Serialize, Deserialize, Hash, PartialEq, Eq, Clone, Debug)]
@@ -236,6 +236,21 @@ pub struct Repository {
     pub last_index_unix_secs: u64,
     pub most_common_lang: Option<String>,
     pub branch_filter: Option<BranchFilter>,
+    /// Whether files are embedded into the semantic index, or only indexed for lexical search
+    #[serde(default = "default_semantic")]
+    pub semantic: bool,
+    #[serde(default)]
+    pub semantic_status: SemanticStatus,
+    /// The directory of the repository that indexed paths are relative to
+    ///
+    /// Files outside of it aren't indexed, which lets a project of a monorepo be indexed on its
+    /// own. Paths on disk stay absolute.
+    #[serde(default)]
+    pub path_root: Option<PathBuf>,
+}
+
+fn default_semantic() -> bool {
+    true
 }
 
 impl Repository {
@@ -274,6 +289,9 @@ impl Repository {
             remote,
             most_common_lang: None,
             branch_filter: None,
+            semantic: true,
+            semantic_status: SemanticStatus::default(),
+            path_root: None,
         }
     }
 
@@ -286,14 +304,66 @@ impl Repository {
             .ok();
 
         let langs = Default::default();
+        let attributes = attributes::GitAttributes::load(&self.disk_path);
+        let owners = ownership::CodeOwners::load(&self.disk_path);
+        let tags = ownership::PathTags::load(&self.disk_path);
 
         RepoMetadata {
             last_commit_unix_secs,
             langs,
+            attributes,
+            owners,
+            tags,
         }
         .into()
     }
 
+    /// The [`Repository::path_root`] of the repository, relative to its disk path.
+    ///
+    /// The root may be absolute or relative to the repository, but has to be a directory within
+    /// it. A root at the top of the repository is the same as none.
+    pub fn resolve_path_root(&self) -> anyhow::Result<Option<PathBuf>> {
+        let Some(root) = &self.path_root else {
+            return Ok(None);
+        };
+
+        let repo_path = crate::canonicalize(&self.disk_path)
+            .with_context(|| format!("failed to resolve {:?}", self.disk_path))?;
+        let root_path = crate::canonicalize(self.disk_path.join(root))
+            .with_context(|| format!("path root {root:?} does not exist"))?;
+        let Ok(relative) = root_path.strip_prefix(&repo_path) else {
+            anyhow::bail!("path root {root:?} is outside of the repository");
+        };
+        if !root_path.is_dir() {
+            anyhow::bail!("path root {root:?} is not a directory");
+        }
+
+        Ok(Some(relative.to_owned()).filter(|root| !root.as_os_str().is_empty()))
+    }
+
+    /// Check that the repository is in one of the directories of `roots`.
+    ///
+    /// Both sides are canonicalized, so `..` and symlinks can't lead out of a root. A repository
+    /// whose path can't be resolved, because it doesn't exist, isn't in any root.
+    pub fn check_allowed(&self, roots: &[PathBuf]) -> anyhow::Result<()> {
+        let path = crate::canonicalize(&self.disk_path).ok();
+        let allowed = path.as_ref().map_or(false, |path| {
+            roots
+                .iter()
+                .filter_map(|root| crate::canonicalize(root).ok())
+                .any(|root| path.starts_with(root))
+        });
+
+        if !allowed {
+            anyhow::bail!(
+                "repository at {:?} is not under any of the allowed repo roots",
+                self.disk_path
+            );
+        }
+
+        Ok(())
+    }
+
     /// Marks the repository for removal on the next sync
     /// Does not initiate a new sync.
     pub(crate) fn mark_removed(&mut self) {
@@ -306,10 +376,18 @@ impl Repository {
         self.sync_status = SyncStatus::Queued;
     }
 
+    /// Record a completed index, which found `indexable_files` files to index, and failed to
+    /// embed `semantic_failures` of them.
+    ///
+    /// `semantic` is whether files were embedded at all, which the configuration can turn off
+    /// for repositories that don't opt out themselves.
     pub(crate) fn sync_done_with(
         &mut self,
         new_branch_filters: Option<&BranchFilter>,
         metadata: Arc<RepoMetadata>,
+        indexable_files: u64,
+        semantic: bool,
+        semantic_failures: u64,
     ) {
         self.last_index_unix_secs = get_unix_time(SystemTime::now());
         self.last_commit_unix_secs = metadata.last_commit_unix_secs.unwrap_or(0);
@@ -323,7 +401,35 @@ impl Repository {
             self.branch_filter = bf.patch(self.branch_filter.as_ref());
         }
 
-        self.sync_status = SyncStatus::Done;
+        self.sync_status = if indexable_files == 0 {
+            SyncStatus::DoneEmpty
+        } else {
+            SyncStatus::Done
+        };
+
+        self.semantic_status = if !semantic {
+            SemanticStatus::Disabled
+        } else if semantic_failures > 0 {
+            SemanticStatus::Incomplete
+        } else {
+            SemanticStatus::Enabled
+        };
+    }
+
+    /// Record an index that was skipped, because the repository is missing and its documents
+    /// were kept.
+    ///
+    /// The status goes back to `previous`, the one from before the sync, and the time of the
+    /// last index is left alone. A repository that was never indexed has nothing to fall back
+    /// to, so it's marked as failed instead.
+    pub(crate) fn sync_skipped(&mut self, previous: SyncStatus) {
+        self.sync_status = if previous.is_done() {
+            previous
+        } else {
+            SyncStatus::Error {
+                message: "repository is missing or empty".into(),
+            }
+        };
     }
 }
 
@@ -337,6 +443,9 @@ fn get_unix_time(time: SystemTime) -> u64 {
 pub struct RepoMetadata {
     pub last_commit_unix_secs: Option<u64>,
     pub langs: language::LanguageInfo,
+    pub attributes: attributes::GitAttributes,
+    pub owners: ownership::CodeOwners,
+    pub tags: ownership::PathTags,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Hash)]
@@ -371,15 +480,38 @@ pub enum SyncStatus {
 
     /// Successfully indexed
     Done,
+
+    /// Successfully indexed, but every file was ignored or skipped
+    DoneEmpty,
 }
 
 impl SyncStatus {
     pub(crate) fn indexable(&self) -> bool {
         use SyncStatus::*;
-        matches!(self, Queued | Done | Error { .. })
+        matches!(self, Queued | Done | DoneEmpty | Error { .. })
+    }
+
+    /// Whether the last sync & index completed, with or without files.
+    pub(crate) fn is_done(&self) -> bool {
+        matches!(self, SyncStatus::Done | SyncStatus::DoneEmpty)
     }
 }
 
+/// The state of the semantic index of a repository after its last index.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SemanticStatus {
+    /// Files are embedded as they're indexed
+    #[default]
+    Enabled,
+
+    /// The repository is configured for lexical search only
+    Disabled,
+
+    /// Some files failed to embed, so semantic search misses parts of the repository
+    Incomplete,
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 #[serde(rename_all = "snake_case")]
 pub struct GitRemote {
@@ -1,16 +1,27 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use once_cell::sync::Lazy;
 use regex::Regex;
 use smallvec::SmallVec;
 use tracing::warn;
 
+pub(super) mod attributes;
+mod conflicts;
+pub mod content_type;
 mod fs;
 mod git;
 pub(super) mod language;
+mod lfs;
+pub(super) mod ownership;
 
-pub use fs::FileWalker;
-pub use git::{BranchFilter, GitWalker};
+pub use conflicts::{ConflictMode, Conflicts};
+pub use fs::{FileWalker, SymlinkMode};
+pub use git::{dirty_paths, BranchFilter, GitWalker, TargetRefError};
+pub use lfs::{LfsMode, LfsPointer};
 
 use crate::background::SyncPipes;
 
@@ -20,9 +31,210 @@ pub const AVG_LINE_LEN: u64 = 30;
 pub const MAX_LINE_COUNT: u64 = 20000;
 pub const MAX_FILE_LEN: u64 = AVG_LINE_LEN * MAX_LINE_COUNT;
 
+/// Counts of walked entries that had to be skipped, by cause.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SkippedEntries {
+    pub not_found: u64,
+    pub permission_denied: u64,
+    pub other: u64,
+}
+
+/// What a walk would do with the files of a repository, as reported by a dry run.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WalkBreakdown {
+    pub would_index: u64,
+    /// Binary files, which are indexed by path only, see [`is_binary`]
+    pub binary: u64,
+    /// Files over `MAX_FILE_LEN` bytes or `MAX_LINE_COUNT` lines
+    pub too_large: u64,
+    /// Entries excluded by their path, see `should_index`, or outside of the path root
+    pub ignored: u64,
+    /// Files the indexer drops once they're read, like LFS pointers it doesn't index, or files
+    /// with merge conflicts under `ConflictMode::SkipConflicted`
+    pub skipped: u64,
+    /// Total size of the files that would be indexed
+    pub estimated_bytes: u64,
+}
+
 pub trait FileSource {
     fn len(&self) -> usize;
     fn for_each(self, signal: &SyncPipes, iterator: impl Fn(RepoDirEntry) + Sync + Send);
+
+    /// Walk every entry the way `for_each` would, but size it with `reserve` before reading its
+    /// contents.
+    ///
+    /// `reserve` gets the size of every file that will be read, and 0 for other entries. What it
+    /// returns is passed to `iterator` along with the entry, or dropped if the entry turns out to
+    /// be too large once read.
+    fn for_each_reserved<R: Send>(
+        self,
+        signal: &SyncPipes,
+        reserve: impl Fn(u64) -> R + Sync + Send,
+        iterator: impl Fn(RepoDirEntry, R) + Sync + Send,
+    );
+
+    /// Walk every entry the way `for_each` would, but only count them.
+    ///
+    /// `admit` gets every entry that would be handed to the indexer, and returns the entry the
+    /// indexer would write, or why it would drop it, so that the counts match a real run.
+    fn dry_run(
+        self,
+        signal: &SyncPipes,
+        admit: impl Fn(RepoDirEntry) -> Result<RepoDirEntry, Dropped> + Sync + Send,
+    ) -> WalkBreakdown;
+
+    /// Split the entries into sources of at most `size` entries each.
+    fn into_batches(self, size: usize) -> Vec<Self>
+    where
+        Self: Sized;
+}
+
+/// Why the indexer drops an entry that a walker handed it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dropped {
+    /// Excluded by its path
+    Ignored,
+    /// Dropped for its contents
+    Skipped,
+}
+
+/// The decision a walker makes about a single entry.
+///
+/// Both `for_each` and `dry_run` go through the same classification, so
+/// that a dry run reports exactly what a real run would index. Entries to index carry what was
+/// reserved before reading them.
+enum Classified<R = ()> {
+    Index(RepoDirEntry, R),
+    TooLarge,
+    Ignored,
+    Skipped,
+}
+
+/// Thread-safe tally of classified entries, for walkers running in parallel.
+#[derive(Default)]
+struct Tally {
+    would_index: AtomicU64,
+    binary: AtomicU64,
+    too_large: AtomicU64,
+    ignored: AtomicU64,
+    skipped: AtomicU64,
+    estimated_bytes: AtomicU64,
+}
+
+impl Tally {
+    fn add<R>(&self, classified: &Classified<R>) {
+        let counter = match classified {
+            Classified::Index(RepoDirEntry::File(file), _) if file.is_binary => &self.binary,
+            Classified::Index(RepoDirEntry::File(file), _) => {
+                self.estimated_bytes
+                    .fetch_add(file.buffer.len() as u64, Ordering::Relaxed);
+                &self.would_index
+            }
+            Classified::Index(..) => return,
+            Classified::TooLarge => &self.too_large,
+            Classified::Ignored => &self.ignored,
+            Classified::Skipped => &self.skipped,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count `classified` as the indexer would leave it, see [`FileSource::dry_run`].
+    fn admit<R>(
+        &self,
+        classified: Classified<R>,
+        admit: impl Fn(RepoDirEntry) -> Result<RepoDirEntry, Dropped>,
+    ) {
+        let classified = match classified {
+            Classified::Index(entry, reserved) => match admit(entry) {
+                Ok(entry) => Classified::Index(entry, reserved),
+                Err(Dropped::Ignored) => Classified::Ignored,
+                Err(Dropped::Skipped) => Classified::Skipped,
+            },
+            other => other,
+        };
+
+        self.add(&classified);
+    }
+
+    fn breakdown(&self) -> WalkBreakdown {
+        WalkBreakdown {
+            would_index: self.would_index.load(Ordering::Relaxed),
+            binary: self.binary.load(Ordering::Relaxed),
+            too_large: self.too_large.load(Ordering::Relaxed),
+            ignored: self.ignored.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            estimated_bytes: self.estimated_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Thread-safe counts of skipped entries, for walkers that only find them while walking.
+#[derive(Default)]
+pub struct SkipTally {
+    not_found: AtomicU64,
+    other: AtomicU64,
+}
+
+impl SkipTally {
+    fn not_found(&self) {
+        self.not_found.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn other(&self) {
+        self.other.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn entries(&self) -> SkippedEntries {
+        SkippedEntries {
+            not_found: self.not_found.load(Ordering::Relaxed),
+            other: self.other.load(Ordering::Relaxed),
+            ..Default::default()
+        }
+    }
+}
+
+/// Whether a file has more lines than we're willing to index.
+///
+/// Lines are counted the way the file indexer stores them: a final line
+/// without a newline still counts. The limit has always been checked
+/// against the encoded line end indices, at 4 bytes per line, so the
+/// effective limit is a quarter of `MAX_LINE_COUNT`.
+pub(crate) fn exceeds_line_limit(buffer: &str) -> bool {
+    let lines = buffer.matches('\n').count() + usize::from(!buffer.ends_with('\n'));
+    lines * std::mem::size_of::<u32>() > MAX_LINE_COUNT as usize
+}
+
+/// Number of leading bytes searched for a NUL byte, as git does to tell binary files apart.
+const BINARY_PROBE_LEN: usize = 8000;
+
+/// Whether the contents of a file are binary, which text files don't have NUL bytes for.
+pub(crate) fn is_binary(data: &[u8]) -> bool {
+    data[..data.len().min(BINARY_PROBE_LEN)].contains(&0)
+}
+
+/// The entry of a walked file, from its raw contents.
+///
+/// Binary files get an entry without contents, so that they are still found by path. Text that
+/// isn't valid UTF-8, like Latin-1 sources, is decoded lossily.
+pub(crate) fn file_entry(path: String, data: Vec<u8>, branches: Vec<String>) -> RepoFile {
+    if is_binary(&data) {
+        return RepoFile {
+            path,
+            buffer: String::new(),
+            branches,
+            is_binary: true,
+        };
+    }
+
+    let buffer = String::from_utf8(data)
+        .unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned());
+    RepoFile {
+        path,
+        buffer,
+        branches,
+        is_binary: false,
+    }
 }
 
 pub enum RepoDirEntry {
@@ -65,6 +277,8 @@ pub struct RepoFile {
     pub path: String,
     pub buffer: String,
     pub branches: Vec<String>,
+    /// Set for binary files, whose `buffer` is left empty
+    pub is_binary: bool,
 }
 
 #[derive(Hash, Eq, PartialEq)]
@@ -0,0 +1,198 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::path::Path;
+use tracing::{trace, warn};
+
+use crate::query::languages::{parse_alias, proper_case};
+
+/// Linguist attributes set for a single path.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LinguistOverrides {
+    /// Name of the language set with `linguist-language`, in the same proper case as
+    /// detected languages, e.g. `C++` for `cpp`
+    pub language: Option<String>,
+    /// Whether the path was marked with `linguist-generated`, or explicitly unmarked
+    pub generated: Option<bool>,
+}
+
+/// The linguist attributes of a repository, as set in `.gitattributes`.
+///
+/// Only the top-level `.gitattributes` and `.git/info/attributes` are
+/// read, in increasing order of precedence. Within a file, later lines
+/// take precedence over earlier ones, per attribute.
+#[derive(Debug, Default)]
+pub struct GitAttributes {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    pattern: Gitignore,
+    /// `Some(None)` resets the attribute to its unspecified state
+    language: Option<Option<String>>,
+    generated: Option<Option<bool>>,
+}
+
+impl GitAttributes {
+    pub fn load(repo_disk_path: &Path) -> Self {
+        let mut attributes = Self::default();
+
+        for file in [".gitattributes", ".git/info/attributes"] {
+            match std::fs::read_to_string(repo_disk_path.join(file)) {
+                Ok(contents) => attributes.extend(&contents),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => warn!(?err, file, "failed to read git attributes"),
+            }
+        }
+
+        attributes
+    }
+
+    /// Add the rules of a gitattributes file, which take precedence over
+    /// the existing ones.
+    pub fn extend(&mut self, contents: &str) {
+        self.rules.extend(contents.lines().filter_map(Rule::parse));
+    }
+
+    /// Resolve the linguist attributes of a path relative to the repo root.
+    pub fn get(&self, relative_path: &Path) -> LinguistOverrides {
+        let mut overrides = LinguistOverrides::default();
+
+        for rule in &self.rules {
+            // patterns ending in `/` only match directories, which never
+            // receive attributes
+            if !rule.pattern.matched(relative_path, false).is_ignore() {
+                continue;
+            }
+
+            if let Some(language) = &rule.language {
+                overrides.language = language.clone();
+            }
+
+            if let Some(generated) = rule.generated {
+                overrides.generated = generated;
+            }
+        }
+
+        overrides
+    }
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("[attr]") {
+            return None;
+        }
+
+        let mut parts = line.split_whitespace();
+        let pattern = parts.next()?;
+
+        // git rejects negative patterns in attribute files
+        if pattern.starts_with('!') {
+            trace!(pattern, "skipping negative gitattributes pattern");
+            return None;
+        }
+
+        let mut language = None;
+        let mut generated = None;
+        for attr in parts {
+            match attr {
+                "linguist-generated" | "linguist-generated=true" => generated = Some(Some(true)),
+                "-linguist-generated" | "linguist-generated=false" => generated = Some(Some(false)),
+                "!linguist-generated" => generated = Some(None),
+                "-linguist-language" | "!linguist-language" => language = Some(None),
+                _ => {
+                    if let Some(lang) = attr.strip_prefix("linguist-language=") {
+                        let lang = proper_case(parse_alias(lang.into())).into_owned();
+                        language = Some(Some(lang));
+                    }
+                }
+            }
+        }
+
+        if language.is_none() && generated.is_none() {
+            return None;
+        }
+
+        let mut builder = GitignoreBuilder::new("");
+        if let Err(err) = builder.add_line(None, pattern) {
+            warn!(?err, pattern, "invalid gitattributes pattern");
+            return None;
+        }
+
+        Some(Self {
+            pattern: builder.build().ok()?,
+            language,
+            generated,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes(contents: &str) -> GitAttributes {
+        let mut attributes = GitAttributes::default();
+        attributes.extend(contents);
+        attributes
+    }
+
+    #[test]
+    fn language_override() {
+        let attrs = attributes(
+            "# comments are ignored\n\
+             *.h linguist-language=cpp\n\
+             *.rb text\n\
+             docs/*.inc linguist-language=php\n",
+        );
+
+        let lang = |path: &str| attrs.get(Path::new(path)).language;
+        assert_eq!(lang("include/foo.h").as_deref(), Some("C++"));
+        assert_eq!(lang("docs/page.inc").as_deref(), Some("PHP"));
+        assert_eq!(lang("src/docs/page.inc"), None);
+        assert_eq!(lang("lib/foo.rb"), None);
+    }
+
+    #[test]
+    fn generated_marker() {
+        let attrs = attributes(
+            "gen/** linguist-generated\n\
+             gen/handwritten.go -linguist-generated\n\
+             *.pb.go linguist-generated=true\n\
+             vendor/ linguist-generated\n",
+        );
+
+        let generated = |path: &str| attrs.get(Path::new(path)).generated;
+        assert_eq!(generated("gen/api/client.go"), Some(true));
+        assert_eq!(generated("gen/handwritten.go"), Some(false));
+        assert_eq!(generated("api/service.pb.go"), Some(true));
+        assert_eq!(generated("vendor/lib.go"), None);
+        assert_eq!(generated("main.go"), None);
+    }
+
+    #[test]
+    fn later_rules_take_precedence_per_attribute() {
+        let mut attrs = attributes(
+            "*.js linguist-language=TypeScript linguist-generated\n\
+             dist/*.js linguist-language=JavaScript\n",
+        );
+        // `.git/info/attributes` is read last
+        attrs.extend("dist/app.js !linguist-generated\n");
+
+        assert_eq!(
+            attrs.get(Path::new("dist/app.js")),
+            LinguistOverrides {
+                language: Some("JavaScript".to_owned()),
+                generated: None,
+            }
+        );
+        assert_eq!(
+            attrs.get(Path::new("src/app.js")),
+            LinguistOverrides {
+                language: Some("TypeScript".to_owned()),
+                generated: Some(true),
+            }
+        );
+    }
+}
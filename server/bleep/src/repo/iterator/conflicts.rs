@@ -0,0 +1,143 @@
+use serde::{Deserialize, Serialize};
+
+/// How to index files with unresolved merge conflicts.
+#[derive(Serialize, Deserialize, clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictMode {
+    /// Don't index conflicted files
+    SkipConflicted,
+    /// Index our side of every conflict, as if the merge was resolved in its favour
+    IndexOurs,
+    /// Index their side of every conflict, as if the merge was resolved in its favour
+    IndexTheirs,
+    /// Index the file as it is, markers and both sides included
+    #[default]
+    IndexRaw,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Region {
+    Common,
+    Marker,
+    Ours,
+    /// The merge base, as written by the `diff3` conflict style
+    Base,
+    Theirs,
+}
+
+/// The lines of a file with merge conflict markers, each labelled with the side it came from.
+#[derive(Debug)]
+pub struct Conflicts<'a> {
+    lines: Vec<(Region, &'a str)>,
+}
+
+impl<'a> Conflicts<'a> {
+    /// Parse the conflicts of `buffer`, if it has any.
+    ///
+    /// Only complete conflicts count: a `<<<<<<<` line, then a `=======` line, then a `>>>>>>>`
+    /// line. Markers out of that order are treated as plain text.
+    pub fn parse(buffer: &'a str) -> Option<Self> {
+        if !buffer.contains("<<<<<<<") {
+            return None;
+        }
+
+        let mut lines = vec![];
+        let mut region = Region::Common;
+        // where the conflict being read started
+        let mut open = 0;
+        let mut complete = false;
+
+        for line in buffer.split_inclusive('\n') {
+            let next = match region {
+                Region::Common if is_marker(line, '<') => Some(Region::Ours),
+                Region::Ours if is_marker(line, '|') => Some(Region::Base),
+                Region::Ours | Region::Base if line.trim_end() == "=======" => Some(Region::Theirs),
+                Region::Theirs if is_marker(line, '>') => Some(Region::Common),
+                _ => None,
+            };
+
+            match next {
+                Some(next) => {
+                    if region == Region::Common {
+                        open = lines.len();
+                    }
+                    if next == Region::Common {
+                        complete = true;
+                    }
+                    lines.push((Region::Marker, line));
+                    region = next;
+                }
+                None => lines.push((region, line)),
+            }
+        }
+
+        // a conflict cut short is just text
+        if region != Region::Common {
+            for (region, _) in &mut lines[open..] {
+                *region = Region::Common;
+            }
+        }
+
+        complete.then_some(Self { lines })
+    }
+
+    /// The file with every conflict resolved in favour of our side.
+    pub fn ours(&self) -> String {
+        self.keep(Region::Ours)
+    }
+
+    /// The file with every conflict resolved in favour of their side.
+    pub fn theirs(&self) -> String {
+        self.keep(Region::Theirs)
+    }
+
+    fn keep(&self, side: Region) -> String {
+        self.lines
+            .iter()
+            .filter(|(region, _)| *region == Region::Common || *region == side)
+            .map(|(_, line)| *line)
+            .collect()
+    }
+}
+
+/// Whether `line` is a conflict marker made of `c`, optionally followed by a label such as the
+/// name of a branch.
+fn is_marker(line: &str, c: char) -> bool {
+    let line = line.trim_end();
+    let rest = line.trim_start_matches(c);
+    line.len() - rest.len() == 7 && (rest.is_empty() || rest.starts_with(' '))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_each_side() {
+        let buffer =
+            "fn main() {\n<<<<<<< HEAD\n    ours();\n=======\n    theirs();\n>>>>>>> feature\n}\n";
+        let conflicts = Conflicts::parse(buffer).unwrap();
+        assert_eq!(conflicts.ours(), "fn main() {\n    ours();\n}\n");
+        assert_eq!(conflicts.theirs(), "fn main() {\n    theirs();\n}\n");
+
+        // the merge base of diff3-style conflicts is on neither side
+        let diff3 = "<<<<<<< ours\na\r\n||||||| base\nb\r\n=======\nc\r\n>>>>>>> theirs\r\n";
+        let conflicts = Conflicts::parse(diff3).unwrap();
+        assert_eq!(conflicts.ours(), "a\r\n");
+        assert_eq!(conflicts.theirs(), "c\r\n");
+    }
+
+    #[test]
+    fn ignore_incomplete_conflicts() {
+        assert!(Conflicts::parse("fn main() {}\n").is_none());
+        assert!(Conflicts::parse("<<<<<<< HEAD\nours\n=======\ntheirs\n").is_none());
+        assert!(Conflicts::parse("// <<<<<<< HEAD\n=======\n>>>>>>>\n").is_none());
+        assert!(Conflicts::parse("<<<<<<<<<< HEAD\n=======\n>>>>>>>\n").is_none());
+
+        // a trailing unterminated conflict is kept as text on both sides
+        let buffer = "<<<<<<<\na\n=======\nb\n>>>>>>>\n<<<<<<<\nc\n";
+        let conflicts = Conflicts::parse(buffer).unwrap();
+        assert_eq!(conflicts.ours(), "a\n<<<<<<<\nc\n");
+        assert_eq!(conflicts.theirs(), "b\n<<<<<<<\nc\n");
+    }
+}
@@ -0,0 +1,92 @@
+//! Content type classification of indexed files.
+//!
+//! This is orthogonal to the language detection. Configuration and data
+//! files, like YAML or JSON, are often given a language too, yet a UI may
+//! want to tell them apart from source code.
+
+use std::path::Path;
+
+/// Fallback for files that could not be classified otherwise.
+pub const PLAIN_TEXT: &str = "text/plain";
+
+/// Detect the MIME type of a file, by its extension first, then by sniffing the contents.
+pub fn detect(path: &Path, buf: &str) -> &'static str {
+    from_extension(path).unwrap_or_else(|| sniff(buf))
+}
+
+fn from_extension(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+
+    Some(match ext.as_str() {
+        // data & configuration
+        "json" => "application/json",
+        "yaml" | "yml" => "application/yaml",
+        "toml" => "application/toml",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "csv" => "text/csv",
+
+        // markup & documentation
+        "md" | "markdown" => "text/markdown",
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+
+        // source code
+        "rs" => "text/x-rust",
+        "py" => "text/x-python",
+        "js" | "mjs" | "cjs" | "jsx" => "text/javascript",
+        "ts" | "tsx" => "text/x-typescript",
+        "go" => "text/x-go",
+        "c" | "h" => "text/x-c",
+        "cc" | "cpp" | "cxx" | "hh" | "hpp" => "text/x-c++",
+        "java" => "text/x-java",
+        "rb" => "text/x-ruby",
+        "sh" | "bash" => "text/x-shellscript",
+
+        _ => return None,
+    })
+}
+
+fn sniff(buf: &str) -> &'static str {
+    let head = buf.trim_start();
+
+    if head.starts_with("#!") {
+        "text/x-script"
+    } else if head.starts_with("<svg") || (head.starts_with("<?xml") && head.contains("<svg")) {
+        "image/svg+xml"
+    } else if head.starts_with("<?xml") {
+        "application/xml"
+    } else if (head.starts_with('{') || head.starts_with('['))
+        && serde_json::from_str::<serde::de::IgnoredAny>(buf).is_ok()
+    {
+        "application/json"
+    } else {
+        PLAIN_TEXT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_and_source_are_distinct() {
+        let yaml = detect(Path::new("ci/deploy.yaml"), "steps:\n  - run: make\n");
+        let rust = detect(Path::new("src/main.rs"), "fn main() {}\n");
+
+        assert_eq!(yaml, "application/yaml");
+        assert_eq!(rust, "text/x-rust");
+    }
+
+    #[test]
+    fn sniff_without_extension() {
+        assert_eq!(detect(Path::new("LICENSE"), "MIT License"), PLAIN_TEXT);
+        assert_eq!(detect(Path::new("run"), "#!/bin/sh\necho"), "text/x-script");
+        assert_eq!(
+            detect(Path::new("data"), r#"{"a": [1]}"#),
+            "application/json"
+        );
+        assert_eq!(detect(Path::new("data"), "{ not json"), PLAIN_TEXT);
+        assert_eq!(detect(Path::new("logo"), "<svg></svg>"), "image/svg+xml");
+    }
+}
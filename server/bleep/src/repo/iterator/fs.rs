@@ -1,20 +1,65 @@
 use super::*;
 
-use tracing::warn;
+use serde::{Deserialize, Serialize};
+use tracing::{info, trace, warn};
 
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// How to index symbolic links to files, for repositories walked on disk.
+///
+/// Links to directories are never followed.
+#[derive(Serialize, Deserialize, clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkMode {
+    /// Leave links out
+    Ignore,
+    /// Index the content of the target under the path of every link, even if the target is
+    /// indexed under its own path as well
+    IndexAsLinkPath,
+    /// Index the content of every target once: under its own path if it's in the repository,
+    /// and under the first of the links to it otherwise
+    #[default]
+    FollowOnce,
+}
 
 pub struct FileWalker {
     file_list: Vec<PathBuf>,
+    skipped: SkippedEntries,
+    /// Entries that were classified before reading their contents
+    tally: Arc<Tally>,
+    /// Whether files over the size limits are walked
+    large_files: bool,
+    /// Files over this many bytes are skipped without being read
+    max_file_size: u64,
 }
 
 impl FileWalker {
-    pub fn index_directory(dir: impl AsRef<Path>) -> impl FileSource {
+    pub fn index_directory(dir: impl AsRef<Path>, symlink_mode: SymlinkMode) -> Self {
+        let tally = Arc::new(Tally::default());
+
         // note: this WILL observe .gitignore files for the respective repos.
         let walker = ignore::WalkBuilder::new(&dir)
             .standard_filters(true)
             .hidden(false)
-            .filter_entry(should_index_entry)
+            .filter_entry({
+                let tally = tally.clone();
+                move |de| {
+                    // the root itself may well be a link
+                    let is_link = de.depth() > 0 && de.path_is_symlink();
+                    let index = should_index_entry(de)
+                        && !(is_link
+                            && (symlink_mode == SymlinkMode::Ignore || de.path().is_dir()));
+                    if !index {
+                        tally.add(&Classified::<()>::Ignored);
+                    }
+                    index
+                }
+            })
             .build();
 
         let file_list = walker
@@ -25,13 +70,113 @@ impl FileWalker {
                     None
                 }
             })
-            // Preliminarily ignore files that are very large, without reading the contents.
-            .filter(|de| matches!(de.metadata(), Ok(meta) if meta.len() < MAX_FILE_LEN))
-            .filter_map(|de| crate::canonicalize(de.into_path()).ok())
-            .collect();
+            // dangling links are counted once they fail to resolve
+            .filter(|de| de.path_is_symlink() || de.metadata().is_ok())
+            .map(|de| (de.depth() > 0 && de.path_is_symlink(), de.into_path()));
 
-        Self { file_list }
+        let (links, entries): (Vec<_>, Vec<_>) = file_list.partition(|(is_link, _)| *is_link);
+        let (mut file_list, mut skipped) =
+            canonicalize_entries(entries.into_iter().map(|(_, path)| path));
+        let links = links.into_iter().map(|(_, path)| path).collect();
+        file_list.extend(resolve_links(links, symlink_mode, &file_list, &mut skipped));
+
+        Self {
+            file_list,
+            skipped,
+            tally,
+            large_files: false,
+            max_file_size: u64::MAX,
+        }
+    }
+
+    /// Walk files over `MAX_FILE_LEN` bytes or `MAX_LINE_COUNT` lines too, rather than skipping
+    /// them as too large.
+    pub fn index_large_files(mut self, large_files: bool) -> Self {
+        self.large_files = large_files;
+        self
     }
+
+    /// Skip files over `bytes` as too large, even when large files are walked.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = bytes;
+        self
+    }
+
+    /// Entries that were found by the walk, but could not be resolved afterwards.
+    pub fn skipped(&self) -> SkippedEntries {
+        self.skipped
+    }
+}
+
+/// Resolve walked paths, skipping any that have become inaccessible since the walk.
+///
+/// Files can be removed while a repository is being indexed, so a missing
+/// file is expected and only traced. Every other failure is warned about.
+fn canonicalize_entries(paths: impl Iterator<Item = PathBuf>) -> (Vec<PathBuf>, SkippedEntries) {
+    let mut skipped = SkippedEntries::default();
+    let file_list = paths
+        .filter_map(|path| match crate::canonicalize(&path) {
+            Ok(canonical) => Some(canonical),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                trace!(?path, "entry removed during walk; skipping");
+                skipped.not_found += 1;
+                None
+            }
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                warn!(?path, "permission denied; skipping");
+                skipped.permission_denied += 1;
+                None
+            }
+            Err(err) => {
+                warn!(%err, ?path, "failed to resolve path; skipping");
+                skipped.other += 1;
+                None
+            }
+        })
+        .collect();
+
+    (file_list, skipped)
+}
+
+/// Pick the links to index under their own path, as decided by `symlink_mode`.
+///
+/// The paths of the links are returned with their parent directory canonicalized, like the
+/// entries in `file_list`, which are the canonical paths of everything else that was walked.
+/// Links whose target doesn't exist are skipped.
+fn resolve_links(
+    mut links: Vec<PathBuf>,
+    symlink_mode: SymlinkMode,
+    file_list: &[PathBuf],
+    skipped: &mut SkippedEntries,
+) -> Vec<PathBuf> {
+    // the first link to an outside file wins, whatever the order of the walk
+    links.sort();
+
+    let mut targets = file_list.iter().cloned().collect::<HashSet<_>>();
+    links
+        .into_iter()
+        .filter_map(|link| {
+            let resolved = crate::canonicalize(&link).and_then(|target| {
+                let parent = crate::canonicalize(link.parent().unwrap_or(&link))?;
+                Ok((target, parent.join(link.file_name().unwrap_or_default())))
+            });
+
+            match resolved {
+                Ok((target, link)) => match symlink_mode {
+                    SymlinkMode::FollowOnce if !targets.insert(target) => {
+                        trace!(?link, "target of link already indexed; skipping");
+                        None
+                    }
+                    _ => Some(link),
+                },
+                Err(err) => {
+                    trace!(%err, ?link, "link can't be resolved; skipping");
+                    skipped.not_found += 1;
+                    None
+                }
+            }
+        })
+        .collect()
 }
 
 static HEAD: &str = "HEAD";
@@ -42,33 +187,270 @@ impl FileSource for FileWalker {
     }
 
     fn for_each(self, pipes: &SyncPipes, iterator: impl Fn(RepoDirEntry) + Sync + Send) {
+        self.for_each_reserved(pipes, |_| (), |entry, ()| iterator(entry))
+    }
+
+    fn for_each_reserved<R: Send>(
+        self,
+        pipes: &SyncPipes,
+        reserve: impl Fn(u64) -> R + Sync + Send,
+        iterator: impl Fn(RepoDirEntry, R) + Sync + Send,
+    ) {
+        self.walk(pipes, reserve, |classified| {
+            if let Classified::Index(entry, reserved) = classified {
+                iterator(entry, reserved)
+            }
+        })
+    }
+
+    fn dry_run(
+        self,
+        pipes: &SyncPipes,
+        admit: impl Fn(RepoDirEntry) -> Result<RepoDirEntry, Dropped> + Sync + Send,
+    ) -> WalkBreakdown {
+        let tally = self.tally.clone();
+        self.walk(pipes, |_| (), |classified| tally.admit(classified, &admit));
+        tally.breakdown()
+    }
+
+    fn into_batches(self, size: usize) -> Vec<Self> {
+        self.file_list
+            .chunks(size.max(1))
+            .map(|chunk| Self {
+                file_list: chunk.to_vec(),
+                skipped: SkippedEntries::default(),
+                tally: Default::default(),
+                large_files: self.large_files,
+                max_file_size: self.max_file_size,
+            })
+            .collect()
+    }
+}
+
+impl FileWalker {
+    fn walk<R: Send>(
+        self,
+        pipes: &SyncPipes,
+        reserve: impl Fn(u64) -> R + Sync + Send,
+        iterator: impl Fn(Classified<R>) + Sync + Send,
+    ) {
         use rayon::prelude::*;
+        let large_files = self.large_files;
+        let max_file_size = self.max_file_size;
         self.file_list
             .into_par_iter()
             .filter_map(|entry_disk_path| {
                 if entry_disk_path.is_file() {
-                    let buffer = match std::fs::read_to_string(&entry_disk_path) {
+                    // very large files are skipped without reading their contents, and links
+                    // are sized by their target
+                    let len = std::fs::metadata(&entry_disk_path)
+                        .map(|meta| meta.len())
+                        .unwrap_or_default();
+                    if len > max_file_size {
+                        info!(?entry_disk_path, len, "file too large; skipping");
+                        return Some(Classified::TooLarge);
+                    }
+                    if len >= MAX_FILE_LEN && !large_files {
+                        return Some(Classified::TooLarge);
+                    }
+
+                    let reserved = reserve(len);
+                    let data = match std::fs::read(&entry_disk_path) {
                         Err(err) => {
                             warn!(%err, ?entry_disk_path, "read failed; skipping");
                             return None;
                         }
-                        Ok(buffer) => buffer,
+                        Ok(data) => data,
                     };
-                    Some(RepoDirEntry::File(RepoFile {
-                        buffer,
-                        path: entry_disk_path.to_string_lossy().to_string(),
-                        branches: vec![HEAD.into()],
-                    }))
+
+                    let file = file_entry(
+                        entry_disk_path.to_string_lossy().to_string(),
+                        data,
+                        vec![HEAD.into()],
+                    );
+                    if file.is_binary {
+                        trace!(?entry_disk_path, "binary file; indexing its path only");
+                    }
+                    if exceeds_line_limit(&file.buffer) && !large_files {
+                        return Some(Classified::TooLarge);
+                    }
+
+                    Some(Classified::Index(RepoDirEntry::File(file), reserved))
                 } else if entry_disk_path.is_dir() {
-                    Some(RepoDirEntry::Dir(RepoDir {
+                    let dir = RepoDirEntry::Dir(RepoDir {
                         path: entry_disk_path.to_string_lossy().to_string(),
                         branches: vec![HEAD.into()],
-                    }))
+                    });
+                    Some(Classified::Index(dir, reserve(0)))
                 } else {
-                    Some(RepoDirEntry::Other)
+                    Some(Classified::Index(RepoDirEntry::Other, reserve(0)))
                 }
             })
             .take_any_while(|_| !pipes.is_cancelled())
             .for_each(iterator);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tempdir::TempDir;
+
+    fn pipes() -> SyncPipes {
+        let (progress, _) = tokio::sync::broadcast::channel(1);
+        SyncPipes::new("local//dry-run".parse().unwrap(), None, progress)
+    }
+
+    #[test]
+    fn file_removed_mid_walk_is_skipped() {
+        let dir = TempDir::new("file-walker").unwrap();
+        let kept = dir.path().join("kept.rs");
+        let removed = dir.path().join("removed.rs");
+        std::fs::write(&kept, "fn kept() {}").unwrap();
+        std::fs::write(&removed, "fn removed() {}").unwrap();
+
+        // the walk sees both files, but one disappears before it's resolved
+        let walk = [kept.clone(), removed.clone()].into_iter().inspect(|path| {
+            if path == &removed {
+                std::fs::remove_file(path).unwrap();
+            }
+        });
+
+        let (file_list, skipped) = canonicalize_entries(walk);
+
+        assert_eq!(file_list, [crate::canonicalize(&kept).unwrap()]);
+        assert_eq!(
+            skipped,
+            SkippedEntries {
+                not_found: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn dry_run_matches_real_walk() {
+        let dir = TempDir::new("file-walker").unwrap();
+        let root = dir.path();
+        std::fs::create_dir(root.join("src")).unwrap();
+        std::fs::write(root.join("src/main.rs"), "fn main() {}\n").unwrap();
+        std::fs::write(root.join("README.md"), "# readme").unwrap();
+        std::fs::write(root.join("logo.png"), "not really a png").unwrap();
+        std::fs::write(root.join("blob.raw"), [0xff, 0xfe, 0x00, 0x01]).unwrap();
+        std::fs::write(root.join("huge.txt"), "a".repeat(MAX_FILE_LEN as usize)).unwrap();
+        std::fs::write(root.join("vocab.txt"), "word\n".repeat(10_000)).unwrap();
+
+        let pipes = pipes();
+        let breakdown =
+            FileWalker::index_directory(root, SymlinkMode::default()).dry_run(&pipes, Ok);
+
+        let indexed = Mutex::new(vec![]);
+        let binary = Mutex::new(vec![]);
+        FileWalker::index_directory(root, SymlinkMode::default()).for_each(&pipes, |entry| {
+            match entry {
+                RepoDirEntry::File(file) if file.is_binary => binary.lock().unwrap().push(file),
+                RepoDirEntry::File(file) => indexed.lock().unwrap().push(file.buffer.len() as u64),
+                _ => {}
+            }
+        });
+        let indexed = indexed.into_inner().unwrap();
+        let binary = binary.into_inner().unwrap();
+
+        assert_eq!(
+            breakdown,
+            WalkBreakdown {
+                would_index: 2,
+                binary: 1,
+                too_large: 2,
+                ignored: 1,
+                skipped: 0,
+                estimated_bytes: 21,
+            }
+        );
+        assert_eq!(breakdown.would_index, indexed.len() as u64);
+        assert_eq!(breakdown.estimated_bytes, indexed.iter().sum::<u64>());
+
+        // binary files are still walked, without their contents
+        assert_eq!(breakdown.binary, binary.len() as u64);
+        assert!(binary[0].path.ends_with("blob.raw") && binary[0].buffer.is_empty());
+    }
+
+    #[test]
+    fn text_that_isnt_utf8_is_decoded_lossily() {
+        let dir = TempDir::new("file-walker").unwrap();
+        let root = dir.path();
+        // `café` in Latin-1
+        std::fs::write(root.join("latin1.txt"), b"caf\xe9\n").unwrap();
+
+        let files = Mutex::new(vec![]);
+        FileWalker::index_directory(root, SymlinkMode::default()).for_each(&pipes(), |entry| {
+            if let RepoDirEntry::File(file) = entry {
+                files.lock().unwrap().push(file);
+            }
+        });
+
+        let files = files.into_inner().unwrap();
+        assert_eq!(files.len(), 1);
+        assert!(!files[0].is_binary);
+        assert_eq!(files[0].buffer, "caf\u{fffd}\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_by_mode() {
+        use std::os::unix::fs::symlink;
+
+        let dir = TempDir::new("file-walker").unwrap();
+        let outside = TempDir::new("file-walker-outside").unwrap();
+        let root = crate::canonicalize(dir.path()).unwrap();
+        std::fs::write(root.join("lib.rs"), "fn lib() {}\n").unwrap();
+        std::fs::write(outside.path().join("shared.rs"), "fn shared() {}\n").unwrap();
+        symlink(root.join("lib.rs"), root.join("alias.rs")).unwrap();
+        symlink(outside.path().join("shared.rs"), root.join("shared.rs")).unwrap();
+        symlink(outside.path().join("shared.rs"), root.join("shared_too.rs")).unwrap();
+        symlink(outside.path(), root.join("outside")).unwrap();
+        symlink(root.join("missing.rs"), root.join("dangling.rs")).unwrap();
+
+        let files = |symlink_mode| {
+            let walker = FileWalker::index_directory(&root, symlink_mode);
+            let files = Mutex::new(vec![]);
+            walker.for_each(&pipes(), |entry| {
+                if let RepoDirEntry::File(file) = entry {
+                    let path = Path::new(&file.path).strip_prefix(&root).unwrap();
+                    let path = path.to_string_lossy().into_owned();
+                    files.lock().unwrap().push((path, file.buffer));
+                }
+            });
+
+            let mut files = files.into_inner().unwrap();
+            files.sort();
+            files
+        };
+        let file = |path: &str, content: &str| (path.to_owned(), content.to_owned());
+
+        assert_eq!(
+            files(SymlinkMode::Ignore),
+            [file("lib.rs", "fn lib() {}\n")]
+        );
+        assert_eq!(
+            files(SymlinkMode::IndexAsLinkPath),
+            [
+                file("alias.rs", "fn lib() {}\n"),
+                file("lib.rs", "fn lib() {}\n"),
+                file("shared.rs", "fn shared() {}\n"),
+                file("shared_too.rs", "fn shared() {}\n"),
+            ]
+        );
+        assert_eq!(
+            files(SymlinkMode::FollowOnce),
+            [
+                file("lib.rs", "fn lib() {}\n"),
+                file("shared.rs", "fn shared() {}\n"),
+            ]
+        );
+
+        let walker = FileWalker::index_directory(&root, SymlinkMode::FollowOnce);
+        assert_eq!(walker.skipped().not_found, 1);
+    }
+}
@@ -2,14 +2,15 @@ use crate::repo::RepoRef;
 
 use super::*;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use gix::ThreadSafeRepository;
 use regex::RegexSet;
-use tracing::{error, trace};
+use tracing::{error, info, trace};
 
 use std::{
-    collections::{BTreeSet, HashMap},
+    collections::{BTreeSet, HashMap, HashSet},
     path::Path,
+    sync::{atomic::Ordering, Arc},
 };
 
 pub enum BranchFilter {
@@ -42,6 +43,14 @@ fn human_readable_branch_name(r: &gix::Reference<'_>) -> String {
 pub struct GitWalker {
     git: ThreadSafeRepository,
     entries: HashMap<(String, FileType, gix::ObjectId), BTreeSet<String>>,
+    /// Number of entries excluded by their path
+    ignored: u64,
+    /// Whether files over the size limits are walked
+    large_files: bool,
+    /// Files over this many bytes are skipped
+    max_file_size: u64,
+    /// Entries whose objects couldn't be read, shared with the batches of this walker
+    skipped: Arc<SkipTally>,
 }
 
 impl GitWalker {
@@ -128,25 +137,76 @@ impl GitWalker {
                 .collect()
         };
 
-        let entries = trees
+        Ok(Self::from_trees(git, root_dir, trees))
+    }
+
+    /// Walk the tree of a single git reference, rather than the
+    /// checked-out branches.
+    ///
+    /// `target_ref` is looked up the way git resolves ref names, so
+    /// both `dev` and `refs/heads/dev` work. `HEAD` is accepted even
+    /// when it's detached. The working tree is never read.
+    pub fn open_ref(reporef: &RepoRef, dir: impl AsRef<Path>, target_ref: &str) -> Result<Self> {
+        let root_dir = dir.as_ref();
+        let git = gix::open::Options::isolated()
+            .filter_config_section(|_| false)
+            .open(root_dir)?;
+
+        let local_git = git.to_thread_local();
+        let reference = local_git
+            .try_find_reference(target_ref)
+            .map_err(|_| TargetRefError::NotFound(target_ref.to_owned()))?
+            .ok_or_else(|| TargetRefError::NotFound(target_ref.to_owned()))?;
+
+        let branch = if reference.name().as_bstr() == "HEAD" {
+            "HEAD".to_owned()
+        } else {
+            human_readable_branch_name(&reference)
+        };
+
+        let is_head = local_git
+            .head()?
+            .try_into_referent()
+            .map(|head| head.name() == reference.name())
+            .unwrap_or_default();
+
+        let tree = reference
+            .into_fully_peeled_id()
+            .ok()
+            .and_then(|id| id.object().ok())
+            .and_then(|object| object.peel_to_tree().ok())
+            .ok_or_else(|| TargetRefError::NotATree(target_ref.to_owned()))?;
+
+        trace!(?reporef, target_ref, %branch, "walking git reference");
+        Ok(Self::from_trees(
+            git,
+            root_dir,
+            vec![(is_head, branch, tree)],
+        ))
+    }
+
+    fn from_trees(
+        git: ThreadSafeRepository,
+        root_dir: &Path,
+        trees: Vec<(bool, String, gix::Tree<'_>)>,
+    ) -> Self {
+        let mut entries = trees
             .into_iter()
             .flat_map(|(is_head, branch, tree)| {
                 let files = tree.traverse().breadthfirst.files().unwrap().into_iter();
 
-                files
-                    .map(move |entry| {
-                        let strpath = String::from_utf8_lossy(entry.filepath.as_ref());
-                        let full_path = root_dir.join(strpath.as_ref());
-                        trace!(?strpath, ?full_path, "got path from gix");
-                        (
-                            is_head,
-                            branch.clone(),
-                            full_path.to_string_lossy().to_string(),
-                            entry.mode,
-                            entry.oid,
-                        )
-                    })
-                    .filter(|(_, _, path, _, _)| should_index(path))
+                files.map(move |entry| {
+                    let strpath = String::from_utf8_lossy(entry.filepath.as_ref());
+                    let full_path = root_dir.join(strpath.as_ref());
+                    trace!(?strpath, ?full_path, "got path from gix");
+                    (
+                        is_head,
+                        branch.clone(),
+                        full_path.to_string_lossy().to_string(),
+                        entry.mode,
+                        entry.oid,
+                    )
+                })
             })
             .fold(
                 HashMap::new(),
@@ -169,8 +229,54 @@ impl GitWalker {
                 },
             );
 
-        Ok(Self { git, entries })
+        let mut ignored = 0;
+        entries.retain(|(path, _, _), _| {
+            let index = should_index(path);
+            ignored += u64::from(!index);
+            index
+        });
+
+        Self {
+            git,
+            entries,
+            ignored,
+            large_files: false,
+            max_file_size: u64::MAX,
+            skipped: Arc::default(),
+        }
+    }
+
+    /// Walk files over `MAX_FILE_LEN` bytes or `MAX_LINE_COUNT` lines too, rather than skipping
+    /// them as too large.
+    pub fn index_large_files(mut self, large_files: bool) -> Self {
+        self.large_files = large_files;
+        self
+    }
+
+    /// Skip files over `bytes` as too large, even when large files are walked.
+    ///
+    /// Objects are sized by their header, and skipped without being loaded.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = bytes;
+        self
     }
+
+    /// Entries that were found in the trees, but whose objects could not be read.
+    ///
+    /// Objects are only read as they're walked, so the counts are filled in by the walk of this
+    /// walker and of its batches.
+    pub fn skipped(&self) -> Arc<SkipTally> {
+        self.skipped.clone()
+    }
+}
+
+/// Reasons a git reference can't be walked by [`GitWalker::open_ref`].
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum TargetRefError {
+    #[error("no git reference named `{0}`")]
+    NotFound(String),
+    #[error("git reference `{0}` does not point to a tree")]
+    NotATree(String),
 }
 
 impl FileSource for GitWalker {
@@ -179,29 +285,113 @@ impl FileSource for GitWalker {
     }
 
     fn for_each(self, pipes: &SyncPipes, iterator: impl Fn(RepoDirEntry) + Sync + Send) {
+        self.for_each_reserved(pipes, |_| (), |entry, ()| iterator(entry))
+    }
+
+    fn for_each_reserved<R: Send>(
+        self,
+        pipes: &SyncPipes,
+        reserve: impl Fn(u64) -> R + Sync + Send,
+        iterator: impl Fn(RepoDirEntry, R) + Sync + Send,
+    ) {
+        self.walk(pipes, reserve, |classified| {
+            if let Classified::Index(entry, reserved) = classified {
+                iterator(entry, reserved)
+            }
+        })
+    }
+
+    fn dry_run(
+        self,
+        pipes: &SyncPipes,
+        admit: impl Fn(RepoDirEntry) -> Result<RepoDirEntry, Dropped> + Sync + Send,
+    ) -> WalkBreakdown {
+        let tally = Tally::default();
+        tally.ignored.store(self.ignored, Ordering::Relaxed);
+        self.walk(pipes, |_| (), |classified| tally.admit(classified, &admit));
+        tally.breakdown()
+    }
+
+    fn into_batches(self, size: usize) -> Vec<Self> {
+        let mut entries = self.entries.into_iter().peekable();
+        let mut batches = vec![];
+        while entries.peek().is_some() {
+            batches.push(Self {
+                git: self.git.clone(),
+                entries: entries.by_ref().take(size.max(1)).collect(),
+                ignored: 0,
+                large_files: self.large_files,
+                max_file_size: self.max_file_size,
+                skipped: self.skipped.clone(),
+            });
+        }
+
+        batches
+    }
+}
+
+impl GitWalker {
+    fn walk<R: Send>(
+        self,
+        pipes: &SyncPipes,
+        reserve: impl Fn(u64) -> R + Sync + Send,
+        iterator: impl Fn(Classified<R>) + Sync + Send,
+    ) {
         use rayon::prelude::*;
         self.entries
             .into_par_iter()
             .filter_map(|((path, kind, oid), branches)| {
                 trace!(?path, "walking over path");
                 let git = self.git.to_thread_local();
-                let Ok(Some(object)) = git.try_find_object(oid) else {
-                    error!(?path, ?branches, "can't find object for file");
-                    return None;
+
+                // objects are sized by their header, so that the ones we skip are never loaded
+                let header = match git.try_find_header(oid) {
+                    Ok(Some(header)) => header,
+                    Ok(None) => {
+                        error!(?path, ?branches, "can't find object for file");
+                        self.skipped.not_found();
+                        return None;
+                    }
+                    Err(err) => {
+                        error!(?err, ?path, ?branches, "failed to read object header");
+                        self.skipped.other();
+                        return None;
+                    }
                 };
 
-                if object.data.len() as u64 > MAX_FILE_LEN {
-                    return None;
+                let len = header.size();
+                if len > self.max_file_size {
+                    info!(?path, len, "file too large; skipping");
+                    return Some(Classified::TooLarge);
                 }
+                if len > MAX_FILE_LEN && !self.large_files {
+                    return Some(Classified::TooLarge);
+                }
+
+                let reserved = reserve(if kind == FileType::File { len } else { 0 });
+                let mut object = match git.try_find_object(oid) {
+                    Ok(Some(object)) => object,
+                    Ok(None) => {
+                        error!(?path, ?branches, "can't find object for file");
+                        self.skipped.not_found();
+                        return None;
+                    }
+                    Err(err) => {
+                        error!(?err, ?path, ?branches, "failed to read object");
+                        self.skipped.other();
+                        return None;
+                    }
+                };
 
                 let entry = match kind {
                     FileType::File => {
-                        let buffer = String::from_utf8_lossy(&object.data).to_string();
-                        RepoDirEntry::File(RepoFile {
-                            path,
-                            branches: branches.into_iter().collect(),
-                            buffer,
-                        })
+                        let data = std::mem::take(&mut object.data);
+                        let file = file_entry(path, data, branches.into_iter().collect());
+                        if exceeds_line_limit(&file.buffer) && !self.large_files {
+                            return Some(Classified::TooLarge);
+                        }
+
+                        RepoDirEntry::File(file)
                     }
                     FileType::Dir => RepoDirEntry::Dir(RepoDir {
                         path,
@@ -210,9 +400,264 @@ impl FileSource for GitWalker {
                     FileType::Other => return None,
                 };
 
-                Some(entry)
+                Some(Classified::Index(entry, reserved))
             })
             .take_any_while(|_| !pipes.is_cancelled())
             .for_each(iterator)
     }
 }
+
+/// The paths with uncommitted changes in the work tree at `dir`.
+///
+/// Paths are relative to the root of the repository, and sorted. Files count as changed
+/// when they are staged, when their content differs from the index, or when they are
+/// untracked and not ignored. Only files that still exist are reported, so renamed files
+/// show up at their new path only.
+pub fn dirty_paths(dir: &Path) -> Result<Vec<String>> {
+    use gix::{bstr::ByteSlice, index::entry::Mode};
+
+    let git = gix::open::Options::isolated()
+        .filter_config_section(|_| false)
+        .open(dir)?
+        .to_thread_local();
+    let work_dir = git
+        .work_dir()
+        .context("repository has no work tree")?
+        .to_owned();
+
+    // a repository without commits has everything staged
+    let head = match git.head_commit() {
+        Ok(commit) => commit
+            .tree()?
+            .traverse()
+            .breadthfirst
+            .files()?
+            .into_iter()
+            .map(|entry| (entry.filepath, entry.oid))
+            .collect(),
+        Err(_) => HashMap::new(),
+    };
+
+    let index = git.index().context("failed to read the git index")?;
+    let mut tracked = HashSet::new();
+    let mut dirty = BTreeSet::new();
+    for entry in index.entries() {
+        let path = entry.path(&index);
+        tracked.insert(path.to_owned());
+
+        // submodules are indexed as their own repositories
+        if entry.mode.contains(Mode::COMMIT) {
+            continue;
+        }
+
+        let full_path = work_dir.join(gix::path::from_bstr(path));
+        let content = if entry.mode.contains(Mode::SYMLINK) {
+            std::fs::read_link(&full_path)
+                .map(|target| Vec::from(gix::path::into_bstr(target).into_owned()))
+        } else {
+            std::fs::read(&full_path)
+        };
+        let Ok(content) = content else {
+            continue;
+        };
+
+        let staged = head.get(path) != Some(&entry.id);
+        let modified = content.len() as u32 != entry.stat.size
+            || gix::objs::compute_hash(git.object_hash(), gix::objs::Kind::Blob, &content)
+                != entry.id;
+        if staged || modified {
+            dirty.insert(path.to_str_lossy().into_owned());
+        }
+    }
+
+    let untracked = ignore::WalkBuilder::new(&work_dir)
+        .hidden(false)
+        .require_git(false)
+        .filter_entry(|entry| entry.file_name() != ".git")
+        .build()
+        .filter_map(Result::ok)
+        .filter(|entry| !entry.file_type().map_or(true, |kind| kind.is_dir()));
+    for entry in untracked {
+        let Ok(relative) = entry.path().strip_prefix(&work_dir) else {
+            continue;
+        };
+
+        let path = gix::path::to_unix_separators_on_windows(gix::path::into_bstr(relative));
+        if !tracked.contains(&*path) {
+            dirty.insert(path.to_str_lossy().into_owned());
+        }
+    }
+
+    Ok(dirty.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use tempdir::TempDir;
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .args(["-c", "user.name=bloop", "-c", "user.email=bloop@bloop.ai"])
+            .args(args)
+            .current_dir(dir)
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {args:?} failed");
+    }
+
+    /// A repository with `a.rs` on `main`, and `b.rs` added on `dev`.
+    fn two_branches() -> TempDir {
+        let dir = TempDir::new("git-walker").unwrap();
+        let root = dir.path();
+        git(root, &["init", "-q", "-b", "main"]);
+        std::fs::write(root.join("a.rs"), "fn a() {}").unwrap();
+        git(root, &["add", "."]);
+        git(root, &["commit", "-q", "-m", "a"]);
+        git(root, &["checkout", "-q", "-b", "dev"]);
+        std::fs::write(root.join("b.rs"), "fn b() {}").unwrap();
+        git(root, &["add", "."]);
+        git(root, &["commit", "-q", "-m", "b"]);
+        git(root, &["checkout", "-q", "main"]);
+        dir
+    }
+
+    fn files(walker: &GitWalker, root: &Path) -> Vec<(String, Vec<String>)> {
+        let mut files = walker
+            .entries
+            .iter()
+            .filter(|((_, kind, _), _)| matches!(kind, FileType::File))
+            .map(|((path, _, _), branches)| {
+                let path = Path::new(path).strip_prefix(root).unwrap();
+                (
+                    path.to_string_lossy().into_owned(),
+                    branches.iter().cloned().collect(),
+                )
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn walk_branch_other_than_head() {
+        let dir = two_branches();
+        let root = dir.path();
+        let reporef = RepoRef::from(&root);
+
+        let walker = GitWalker::open_ref(&reporef, root, "dev").unwrap();
+        assert_eq!(
+            files(&walker, root),
+            [
+                ("a.rs".to_owned(), vec!["dev".to_owned()]),
+                ("b.rs".to_owned(), vec!["dev".to_owned()]),
+            ]
+        );
+
+        let walker = GitWalker::open_ref(&reporef, root, "refs/heads/main").unwrap();
+        assert_eq!(
+            files(&walker, root),
+            [(
+                "a.rs".to_owned(),
+                vec!["HEAD".to_owned(), "main".to_owned()]
+            )]
+        );
+
+        // the working tree is still on `main`
+        assert!(!root.join("b.rs").exists());
+    }
+
+    #[test]
+    fn walk_detached_head_and_missing_ref() {
+        let dir = two_branches();
+        let root = dir.path();
+        let reporef = RepoRef::from(&root);
+
+        git(root, &["checkout", "-q", "--detach", "dev"]);
+        let walker = GitWalker::open_ref(&reporef, root, "HEAD").unwrap();
+        assert_eq!(files(&walker, root).len(), 2);
+        assert!(files(&walker, root)
+            .iter()
+            .all(|(_, branches)| branches == &["HEAD"]));
+
+        let err = GitWalker::open_ref(&reporef, root, "missing")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.downcast_ref::<TargetRefError>(),
+            Some(&TargetRefError::NotFound("missing".to_owned()))
+        );
+    }
+
+    #[test]
+    fn objects_over_the_size_limit_are_not_loaded() {
+        let dir = TempDir::new("git-walker").unwrap();
+        let root = dir.path();
+        git(root, &["init", "-q", "-b", "main"]);
+        std::fs::write(root.join("small.rs"), "fn small() {}").unwrap();
+        std::fs::write(root.join("large.rs"), "// large\n".repeat(100)).unwrap();
+        git(root, &["add", "."]);
+        git(root, &["commit", "-q", "-m", "files"]);
+
+        let (progress, _) = tokio::sync::broadcast::channel(1);
+        let pipes = SyncPipes::new(RepoRef::from(&root), None, progress);
+        let walker = || {
+            GitWalker::open_repository(&RepoRef::from(&root), root, None)
+                .unwrap()
+                .max_file_size(100)
+        };
+
+        // objects are reserved right before they're loaded, so the large one never is
+        let reserved = std::sync::Mutex::new(vec![]);
+        let walked = std::sync::Mutex::new(vec![]);
+        walker().for_each_reserved(
+            &pipes,
+            |bytes| reserved.lock().unwrap().push(bytes),
+            |entry, ()| {
+                walked
+                    .lock()
+                    .unwrap()
+                    .push(entry.path().unwrap().to_owned())
+            },
+        );
+        assert_eq!(
+            reserved.into_inner().unwrap(),
+            ["fn small() {}".len() as u64]
+        );
+        assert_eq!(
+            walked.into_inner().unwrap(),
+            [root.join("small.rs").to_string_lossy()]
+        );
+
+        assert_eq!(walker().dry_run(&pipes, Ok).too_large, 1);
+    }
+
+    #[test]
+    fn dirty_paths_of_work_tree() {
+        let dir = two_branches();
+        let root = dir.path();
+        assert!(dirty_paths(root).unwrap().is_empty());
+
+        std::fs::write(root.join("a.rs"), "fn a() { changed() }").unwrap();
+        std::fs::create_dir(root.join("new dir")).unwrap();
+        std::fs::write(root.join("new dir/c.rs"), "fn c() {}").unwrap();
+        std::fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        std::fs::write(root.join("build.log"), "ignored").unwrap();
+
+        assert_eq!(
+            dirty_paths(root).unwrap(),
+            [".gitignore", "a.rs", "new dir/c.rs"]
+        );
+
+        // writing the same content back leaves the file clean
+        std::fs::write(root.join("a.rs"), "fn a() {}").unwrap();
+        assert_eq!(dirty_paths(root).unwrap(), [".gitignore", "new dir/c.rs"]);
+
+        // renames only report where the file is now
+        git(root, &["add", "."]);
+        git(root, &["commit", "-q", "-m", "c"]);
+        git(root, &["mv", "a.rs", "z.rs"]);
+        assert_eq!(dirty_paths(root).unwrap(), ["z.rs"]);
+    }
+}
@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tracing::{trace, warn};
+
+/// Pointer files are at most this large, per the Git LFS spec.
+const MAX_POINTER_LEN: usize = 1024;
+
+/// How to index files that are Git LFS pointers, rather than the content they stand for.
+#[derive(Serialize, Deserialize, clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LfsMode {
+    /// Don't index pointer files
+    #[default]
+    Skip,
+    /// Index the text of the pointer, as a file without a language
+    IndexPointerMeta,
+    /// Index the content of the LFS object if it was fetched to `.git/lfs`, and skip the file
+    /// otherwise
+    ResolveIfLocal,
+}
+
+/// The parsed contents of a Git LFS pointer file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LfsPointer {
+    /// Hex-encoded SHA-256 of the object
+    pub oid: String,
+    pub size: u64,
+}
+
+impl LfsPointer {
+    /// Parse `buffer` as a pointer file, if it is one.
+    ///
+    /// Files are recognized as pointers by their `version https://git-lfs...` header.
+    pub fn parse(buffer: &str) -> Option<Self> {
+        if buffer.len() > MAX_POINTER_LEN || !buffer.starts_with("version https://git-lfs") {
+            return None;
+        }
+
+        let mut oid = None;
+        let mut size = None;
+        for line in buffer.lines().skip(1) {
+            match line.split_once(' ') {
+                Some(("oid", value)) => oid = value.strip_prefix("sha256:"),
+                Some(("size", value)) => size = value.parse().ok(),
+                _ => {}
+            }
+        }
+
+        let oid = oid.filter(|oid| oid.len() == 64 && oid.bytes().all(|b| b.is_ascii_hexdigit()));
+        Some(Self {
+            oid: oid?.to_owned(),
+            size: size?,
+        })
+    }
+
+    /// Read the content of the object from the local LFS store of the repository.
+    ///
+    /// Returns `None` if the object wasn't fetched, or is over `max_file_size` bytes, the limit
+    /// walkers apply to the files they read. Callers check the content for binary data, the same
+    /// as for other files.
+    pub fn resolve(&self, repo_disk_path: &Path, max_file_size: u64) -> Option<Vec<u8>> {
+        let path = self.object_path(repo_disk_path);
+        if self.size > max_file_size {
+            trace!(?path, "LFS object is too large to index");
+            return None;
+        }
+
+        match std::fs::read(&path) {
+            Ok(data) if data.len() as u64 == self.size => Some(data),
+            Ok(_) => {
+                warn!(?path, "LFS object doesn't match the size of its pointer");
+                None
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => {
+                warn!(?err, ?path, "failed to read LFS object");
+                None
+            }
+        }
+    }
+
+    fn object_path(&self, repo_disk_path: &Path) -> PathBuf {
+        repo_disk_path
+            .join(".git/lfs/objects")
+            .join(&self.oid[..2])
+            .join(&self.oid[2..4])
+            .join(&self.oid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OID: &str = "4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393";
+
+    fn pointer(size: usize) -> String {
+        format!("version https://git-lfs.github.com/spec/v1\noid sha256:{OID}\nsize {size}\n")
+    }
+
+    #[test]
+    fn parse_pointer_files() {
+        assert_eq!(
+            LfsPointer::parse(&pointer(12345)),
+            Some(LfsPointer {
+                oid: OID.to_owned(),
+                size: 12345
+            })
+        );
+
+        assert_eq!(LfsPointer::parse("fn main() {}\n"), None);
+        assert_eq!(
+            LfsPointer::parse("version https://git-lfs.github.com/spec/v1\nsize 12\n"),
+            None
+        );
+        assert_eq!(
+            LfsPointer::parse(&format!("{}{}", pointer(12), "x".repeat(MAX_POINTER_LEN))),
+            None
+        );
+    }
+
+    #[test]
+    fn resolve_local_objects() {
+        let repo = tempdir::TempDir::new("lfs-repo").unwrap();
+        let content = "name,size\nmodel,12\n";
+        let fetched = LfsPointer::parse(&pointer(content.len())).unwrap();
+        assert_eq!(fetched.resolve(repo.path(), u64::MAX), None);
+
+        let path = fetched.object_path(repo.path());
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, content).unwrap();
+        assert_eq!(
+            fetched.resolve(repo.path(), u64::MAX).as_deref(),
+            Some(content.as_bytes())
+        );
+
+        // objects are held to the size limit of the files they stand for
+        assert_eq!(fetched.resolve(repo.path(), content.len() as u64 - 1), None);
+
+        // a partial download isn't the object
+        let truncated = LfsPointer::parse(&pointer(content.len() + 1)).unwrap();
+        assert_eq!(truncated.resolve(repo.path(), u64::MAX), None);
+    }
+}
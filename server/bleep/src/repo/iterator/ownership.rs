@@ -0,0 +1,217 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::{collections::BTreeMap, path::Path};
+use tracing::{trace, warn};
+
+/// A path pattern of a CODEOWNERS or tags file.
+///
+/// Patterns follow gitignore syntax, as GitHub's CODEOWNERS does. Patterns that end in a name
+/// without a wildcard also match everything under the directories of that name, while ones
+/// like `docs/*` only match the files directly in them.
+#[derive(Debug)]
+struct PathPattern {
+    pattern: Gitignore,
+    matches_contents: bool,
+}
+
+impl PathPattern {
+    fn new(pattern: &str) -> Option<Self> {
+        // GitHub doesn't support negative patterns in CODEOWNERS
+        if pattern.starts_with('!') {
+            trace!(pattern, "skipping negative ownership pattern");
+            return None;
+        }
+
+        let mut builder = GitignoreBuilder::new("");
+        if let Err(err) = builder.add_line(None, pattern) {
+            warn!(?err, pattern, "invalid ownership pattern");
+            return None;
+        }
+
+        let last = pattern.trim_end_matches('/').rsplit('/').next()?;
+        Some(Self {
+            pattern: builder.build().ok()?,
+            matches_contents: !last.contains('*'),
+        })
+    }
+
+    fn matches(&self, relative_path: &Path) -> bool {
+        if self.pattern.matched(relative_path, false).is_ignore() {
+            return true;
+        }
+
+        self.matches_contents
+            && relative_path
+                .ancestors()
+                .skip(1)
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .any(|dir| self.pattern.matched(dir, true).is_ignore())
+    }
+}
+
+/// The owners of the files of a repository, as set in its CODEOWNERS file.
+///
+/// Like GitHub, only the first of `.github/CODEOWNERS`, `CODEOWNERS` and `docs/CODEOWNERS` is
+/// read, and the last rule that matches a path sets all of its owners. Owners are lowercased,
+/// as GitHub compares them case-insensitively.
+#[derive(Debug, Default)]
+pub struct CodeOwners {
+    rules: Vec<(PathPattern, Vec<String>)>,
+}
+
+impl CodeOwners {
+    pub fn load(repo_disk_path: &Path) -> Self {
+        let mut owners = Self::default();
+
+        for file in [".github/CODEOWNERS", "CODEOWNERS", "docs/CODEOWNERS"] {
+            match std::fs::read_to_string(repo_disk_path.join(file)) {
+                Ok(contents) => {
+                    owners.extend(&contents);
+                    break;
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => warn!(?err, file, "failed to read code owners"),
+            }
+        }
+
+        owners
+    }
+
+    /// Add the rules of a CODEOWNERS file, which take precedence over the existing ones.
+    pub fn extend(&mut self, contents: &str) {
+        self.rules.extend(contents.lines().filter_map(|line| {
+            // `#` starts a comment, unless it's escaped
+            let line = match line
+                .match_indices('#')
+                .find(|(i, _)| !line[..*i].ends_with('\\'))
+            {
+                Some((i, _)) => &line[..i],
+                None => line,
+            };
+
+            let mut parts = line.split_whitespace();
+            // gitignore syntax takes care of the escape
+            let pattern = PathPattern::new(parts.next()?)?;
+            Some((pattern, parts.map(str::to_lowercase).collect()))
+        }));
+    }
+
+    /// The owners of a path relative to the repo root, which are none for paths without a
+    /// rule, or whose last rule lists no owners.
+    pub fn get(&self, relative_path: &Path) -> &[String] {
+        self.rules
+            .iter()
+            .rev()
+            .find(|(pattern, _)| pattern.matches(relative_path))
+            .map(|(_, owners)| owners.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+/// Tags of the files of a repository, as set in its `.bloop/tags.toml`.
+///
+/// Each key of the file is a tag, set to the patterns of the paths it applies to:
+///
+/// ```toml
+/// critical = ["src/payments/", "src/auth/**/*.rs"]
+/// docs = ["*.md"]
+/// ```
+///
+/// Paths get every tag with a matching pattern. Tags are lowercased.
+#[derive(Debug, Default)]
+pub struct PathTags {
+    rules: Vec<(String, Vec<PathPattern>)>,
+}
+
+impl PathTags {
+    pub fn load(repo_disk_path: &Path) -> Self {
+        let file = ".bloop/tags.toml";
+        match std::fs::read_to_string(repo_disk_path.join(file)) {
+            Ok(contents) => Self::parse(&contents).unwrap_or_else(|err| {
+                warn!(?err, file, "invalid path tags");
+                Self::default()
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(err) => {
+                warn!(?err, file, "failed to read path tags");
+                Self::default()
+            }
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, toml::de::Error> {
+        let tags = toml::from_str::<BTreeMap<String, Vec<String>>>(contents)?;
+        Ok(Self {
+            rules: tags
+                .into_iter()
+                .map(|(tag, patterns)| {
+                    let patterns = patterns
+                        .iter()
+                        .filter_map(|p| PathPattern::new(p))
+                        .collect();
+                    (tag.to_lowercase(), patterns)
+                })
+                .collect(),
+        })
+    }
+
+    /// The tags of a path relative to the repo root, sorted.
+    pub fn get(&self, relative_path: &Path) -> Vec<String> {
+        let mut tags = self
+            .rules
+            .iter()
+            .filter(|(_, patterns)| patterns.iter().any(|p| p.matches(relative_path)))
+            .map(|(tag, _)| tag.clone())
+            .collect::<Vec<_>>();
+        tags.sort();
+        tags.dedup();
+        tags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_matching_owner_rule_wins() {
+        let mut owners = CodeOwners::default();
+        owners.extend(
+            "# global owners\n\
+             *       @org/everyone\n\
+             *.rs    @org/Rust dev@example.com\n\
+             /docs/  @org/docs # the docs team\n\
+             docs/*  @org/writers\n\
+             /build/logs/\n\
+             apps/   @org/apps\n\
+             \\#notes @org/notes\n",
+        );
+
+        let get = |path: &str| owners.get(Path::new(path)).to_vec();
+        assert_eq!(get("README.md"), ["@org/everyone"]);
+        assert_eq!(get("src/main.rs"), ["@org/rust", "dev@example.com"]);
+        assert_eq!(get("docs/index.md"), ["@org/writers"]);
+        assert_eq!(get("docs/guide/setup.md"), ["@org/docs"]);
+        assert_eq!(get("server/apps/app.ts"), ["@org/apps"]);
+        assert_eq!(get("#notes"), ["@org/notes"]);
+
+        // a rule without owners leaves its files unowned
+        assert!(get("build/logs/today.log").is_empty());
+        assert!(CodeOwners::default().get(Path::new("README.md")).is_empty());
+    }
+
+    #[test]
+    fn paths_get_every_matching_tag() {
+        let tags = PathTags::parse(
+            "Critical = [\"src/payments/\", \"src/auth/**/*.rs\"]\n\
+             docs = [\"*.md\"]\n",
+        )
+        .unwrap();
+
+        let get = |path: &str| tags.get(Path::new(path));
+        assert_eq!(get("src/payments/README.md"), ["critical", "docs"]);
+        assert_eq!(get("src/auth/oauth/token.rs"), ["critical"]);
+        assert!(get("src/main.rs").is_empty());
+
+        assert!(PathTags::parse("critical = \"src/\"").is_err());
+    }
+}
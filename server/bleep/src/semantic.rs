@@ -1,6 +1,23 @@
-use std::{borrow::Cow, collections::HashMap, env, path::Path, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    env,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use crate::{query::parser::SemanticQuery, Configuration};
+use crate::{
+    cache::{CommitStats, FileCache, StoredChunk},
+    db::SqlDb,
+    query::parser::SemanticQuery,
+    repo::RepoRef,
+    symbol::SymbolLocations,
+    Configuration,
+};
 
 use ndarray::Axis;
 use ort::{
@@ -13,21 +30,28 @@ use qdrant_client::{
         point_id::PointIdOptions, r#match::MatchValue, vectors::VectorsOptions, vectors_config,
         with_payload_selector, with_vectors_selector, CollectionOperationResponse,
         CreateCollection, Distance, FieldCondition, FieldType, Filter, Match, PointId,
-        RetrievedPoint, ScoredPoint, SearchPoints, Value, VectorParams, Vectors, VectorsConfig,
-        WithPayloadSelector, WithVectorsSelector,
+        RetrievedPoint, ScoredPoint, SearchParams, SearchPoints, Value, VectorParams, Vectors,
+        VectorsConfig, WithPayloadSelector, WithVectorsSelector, WriteOrdering, WriteOrderingType,
     },
 };
 
 use futures::{stream, StreamExt, TryStreamExt};
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tracing::{debug, info, trace, warn};
 
+pub mod backfill;
 pub mod chunk;
+pub mod embedder;
 pub mod execute;
+pub mod hybrid;
+pub mod minhash;
 mod schema;
 
-pub use schema::{Embedding, Payload};
+pub use backfill::resume_backfill;
+pub use embedder::Embedder;
+pub use schema::{Embedding, Payload, PayloadSchema};
 
 pub(crate) const COLLECTION_NAME: &str = "documents";
 pub(crate) const EMBEDDING_DIM: usize = 384;
@@ -56,6 +80,8 @@ pub struct Semantic {
     qdrant: Arc<QdrantClient>,
     tokenizer: Arc<tokenizers::Tokenizer>,
     session: Arc<ort::Session>,
+    /// Name of the directory the model was loaded from
+    model_id: Arc<str>,
     config: Arc<Configuration>,
 }
 
@@ -68,7 +94,7 @@ macro_rules! val_parse_str(($hash:ident, $val:expr) => {
 });
 
 impl Payload {
-    pub fn from_qdrant(orig: ScoredPoint) -> Payload {
+    pub fn from_qdrant(orig: ScoredPoint, schema: &PayloadSchema) -> Payload {
         let ScoredPoint {
             id,
             payload,
@@ -77,10 +103,10 @@ impl Payload {
             ..
         } = orig;
 
-        parse_payload(id, vectors, payload, score)
+        parse_payload(schema, id, vectors, payload, score)
     }
 
-    pub fn from_scroll(orig: RetrievedPoint) -> Payload {
+    pub fn from_scroll(orig: RetrievedPoint, schema: &PayloadSchema) -> Payload {
         let RetrievedPoint {
             id,
             payload,
@@ -88,27 +114,52 @@ impl Payload {
             ..
         } = orig;
 
-        parse_payload(id, vectors, payload, 0.0)
+        parse_payload(schema, id, vectors, payload, 0.0)
     }
 
-    pub(crate) fn into_qdrant(self) -> HashMap<String, Value> {
-        HashMap::from([
-            ("lang".into(), self.lang.to_ascii_lowercase().into()),
-            ("repo_name".into(), self.repo_name.into()),
-            ("repo_ref".into(), self.repo_ref.into()),
-            ("relative_path".into(), self.relative_path.into()),
-            ("content_hash".into(), self.content_hash.into()),
-            ("snippet".into(), self.text.into()),
-            ("start_line".into(), self.start_line.to_string().into()),
-            ("end_line".into(), self.end_line.to_string().into()),
-            ("start_byte".into(), self.start_byte.to_string().into()),
-            ("end_byte".into(), self.end_byte.to_string().into()),
-            ("branches".into(), self.branches.into()),
-        ])
+    pub(crate) fn into_qdrant(self, schema: &PayloadSchema) -> HashMap<String, Value> {
+        // built-in fields always take precedence over user-defined ones
+        let mut payload = self
+            .extra
+            .into_iter()
+            .map(|(key, value)| (key, value_to_qdrant(value)))
+            .collect::<HashMap<_, _>>();
+
+        payload.extend([
+            (schema.lang.clone(), self.lang.to_ascii_lowercase().into()),
+            (schema.repo_name.clone(), self.repo_name.into()),
+            (schema.repo_ref.clone(), self.repo_ref.into()),
+            (schema.relative_path.clone(), self.relative_path.into()),
+            (schema.content_hash.clone(), self.content_hash.into()),
+            (schema.text.clone(), self.text.into()),
+            (
+                schema.start_line.clone(),
+                self.start_line.to_string().into(),
+            ),
+            (schema.end_line.clone(), self.end_line.to_string().into()),
+            (
+                schema.start_byte.clone(),
+                self.start_byte.to_string().into(),
+            ),
+            (schema.end_byte.clone(), self.end_byte.to_string().into()),
+            (schema.branches.clone(), self.branches.into()),
+        ]);
+
+        payload
     }
 }
 
+/// The partial payload written when only the list of branches a
+/// point is searchable on changes.
+pub(crate) fn branches_payload(
+    schema: &PayloadSchema,
+    branches: &[String],
+) -> HashMap<String, Value> {
+    HashMap::from([(schema.branches.clone(), branches.to_owned().into())])
+}
+
 fn parse_payload(
+    schema: &PayloadSchema,
     id: Option<PointId>,
     vectors: Option<Vectors>,
     payload: HashMap<String, Value>,
@@ -138,17 +189,20 @@ fn parse_payload(
         .collect::<HashMap<String, serde_json::Value>>();
 
     Payload {
-        lang: val_str!(converted, "lang"),
-        repo_name: val_str!(converted, "repo_name"),
-        repo_ref: val_str!(converted, "repo_ref"),
-        relative_path: val_str!(converted, "relative_path"),
-        content_hash: val_str!(converted, "content_hash"),
-        text: val_str!(converted, "snippet"),
-        branches: val_str!(converted, "branches"),
-        start_line: val_parse_str!(converted, "start_line"),
-        end_line: val_parse_str!(converted, "end_line"),
-        start_byte: val_parse_str!(converted, "start_byte"),
-        end_byte: val_parse_str!(converted, "end_byte"),
+        lang: val_str!(converted, &schema.lang),
+        repo_name: val_str!(converted, &schema.repo_name),
+        repo_ref: val_str!(converted, &schema.repo_ref),
+        relative_path: val_str!(converted, &schema.relative_path),
+        content_hash: val_str!(converted, &schema.content_hash),
+        text: val_str!(converted, &schema.text),
+        branches: val_str!(converted, &schema.branches),
+        start_line: val_parse_str!(converted, &schema.start_line),
+        end_line: val_parse_str!(converted, &schema.end_line),
+        start_byte: val_parse_str!(converted, &schema.start_byte),
+        end_byte: val_parse_str!(converted, &schema.end_byte),
+
+        // whatever is left over was supplied by the user
+        extra: converted,
 
         id: Some(id),
         score: Some(score),
@@ -172,11 +226,40 @@ fn kind_to_value(kind: Option<qdrant_client::qdrant::value::Kind>) -> serde_json
                 .map(|v| kind_to_value(v.kind))
                 .collect(),
         ),
-        Some(Kind::StructValue(_v)) => todo!(),
+        Some(Kind::StructValue(v)) => serde_json::Value::Object(
+            v.fields
+                .into_iter()
+                .map(|(k, v)| (k, kind_to_value(v.kind)))
+                .collect(),
+        ),
         None => serde_json::Value::Null,
     }
 }
 
+fn value_to_qdrant(value: serde_json::Value) -> Value {
+    use qdrant_client::qdrant::{value::Kind, ListValue, Struct};
+    let kind = match value {
+        serde_json::Value::Null => Kind::NullValue(0),
+        serde_json::Value::Bool(v) => Kind::BoolValue(v),
+        serde_json::Value::Number(v) => match v.as_i64() {
+            Some(i) => Kind::IntegerValue(i),
+            None => Kind::DoubleValue(v.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(v) => Kind::StringValue(v),
+        serde_json::Value::Array(v) => Kind::ListValue(ListValue {
+            values: v.into_iter().map(value_to_qdrant).collect(),
+        }),
+        serde_json::Value::Object(v) => Kind::StructValue(Struct {
+            fields: v
+                .into_iter()
+                .map(|(k, v)| (k, value_to_qdrant(v)))
+                .collect(),
+        }),
+    };
+
+    Value { kind: Some(kind) }
+}
+
 fn collection_config() -> CreateCollection {
     CreateCollection {
         collection_name: COLLECTION_NAME.to_string(),
@@ -191,6 +274,40 @@ fn collection_config() -> CreateCollection {
     }
 }
 
+/// How qdrant orders writes to the replicas of a collection before acknowledging them.
+///
+/// Stronger ordering keeps replicas consistent when qdrant nodes fail, and costs write latency:
+/// `medium` routes writes through a dynamically elected leader, and `strong` through the fixed
+/// leader of each shard, which has to be reachable for writes to succeed at all.
+#[derive(Serialize, Deserialize, clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteConsistency {
+    /// Writes are applied as they arrive, in no guaranteed order across replicas
+    #[default]
+    Weak,
+    /// Writes are ordered by a dynamically elected leader
+    Medium,
+    /// Writes are ordered by the permanent leader of the shard
+    Strong,
+}
+
+impl WriteConsistency {
+    /// The ordering sent with qdrant writes.
+    ///
+    /// Weak writes don't send one, leaving the choice to qdrant, whose default is weak.
+    pub fn ordering(self) -> Option<WriteOrdering> {
+        let ordering = match self {
+            Self::Weak => return None,
+            Self::Medium => WriteOrderingType::Medium,
+            Self::Strong => WriteOrderingType::Strong,
+        };
+
+        Some(WriteOrdering {
+            r#type: ordering.into(),
+        })
+    }
+}
+
 impl Semantic {
     pub async fn initialize(
         model_dir: &Path,
@@ -219,24 +336,23 @@ impl Semantic {
             Err(_) => return Err(SemanticError::QdrantInitializationError),
         }
 
-        qdrant
-            .create_field_index(COLLECTION_NAME, "repo_ref", FieldType::Text, None, None)
-            .await?;
-        qdrant
-            .create_field_index(COLLECTION_NAME, "content_hash", FieldType::Text, None, None)
-            .await?;
-        qdrant
-            .create_field_index(COLLECTION_NAME, "branches", FieldType::Text, None, None)
-            .await?;
-        qdrant
-            .create_field_index(
-                COLLECTION_NAME,
-                "relative_path",
-                FieldType::Text,
-                None,
-                None,
-            )
-            .await?;
+        let schema = &config.payload_schema;
+        for field in [
+            &schema.repo_ref,
+            &schema.content_hash,
+            &schema.branches,
+            &schema.relative_path,
+        ] {
+            qdrant
+                .create_field_index(
+                    COLLECTION_NAME,
+                    field,
+                    FieldType::Text,
+                    None,
+                    config.qdrant_write_consistency.ordering(),
+                )
+                .await?;
+        }
 
         if let Some(dylib_dir) = config.dylib_dir.as_ref() {
             init_ort_dylib(dylib_dir);
@@ -267,6 +383,11 @@ impl Semantic {
                 .with_intra_threads(threads)?
                 .with_model_from_file(model_dir.join("model.onnx"))?
                 .into(),
+            model_id: model_dir
+                .file_name()
+                .unwrap_or(model_dir.as_os_str())
+                .to_string_lossy()
+                .into(),
             config,
         })
     }
@@ -276,6 +397,18 @@ impl Semantic {
         Ok(())
     }
 
+    /// Have qdrant take a snapshot of the collection, returning the name of the snapshot.
+    ///
+    /// Snapshots are kept in qdrant's own storage, and restored through qdrant.
+    pub async fn create_snapshot(&self) -> anyhow::Result<String> {
+        self.qdrant
+            .create_snapshot(COLLECTION_NAME)
+            .await?
+            .snapshot_description
+            .map(|snapshot| snapshot.name)
+            .ok_or_else(|| anyhow::anyhow!("qdrant didn't describe the snapshot it took"))
+    }
+
     pub fn embed(&self, sequence: &str) -> anyhow::Result<Embedding> {
         let tokenizer_output = self.tokenizer.encode(sequence, true).unwrap();
 
@@ -312,6 +445,12 @@ impl Semantic {
         Ok(pooled.to_owned().as_slice().unwrap().to_vec())
     }
 
+    /// Search for the points nearest to `vector`.
+    ///
+    /// `ef` is the size of qdrant's HNSW candidate list for this search, and defaults to the
+    /// collection's configured value. Larger values find more of the true nearest neighbours, at
+    /// the cost of latency: interactive searches can lower it, while evaluations and batch jobs
+    /// can raise it to get closer to an exact search.
     pub async fn search_with<'a>(
         &self,
         parsed_query: &SemanticQuery<'a>,
@@ -319,32 +458,24 @@ impl Semantic {
         limit: u64,
         offset: u64,
         threshold: f32,
+        ef: Option<u64>,
     ) -> anyhow::Result<Vec<ScoredPoint>> {
         let response = self
             .qdrant
-            .search_points(&SearchPoints {
-                limit,
+            .search_points(&search_points(
                 vector,
-                collection_name: COLLECTION_NAME.to_string(),
-                offset: Some(offset),
-                score_threshold: Some(threshold),
-                with_payload: Some(WithPayloadSelector {
-                    selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
-                }),
-                filter: Some(Filter {
-                    must: build_conditions(parsed_query),
-                    ..Default::default()
-                }),
-                with_vectors: Some(WithVectorsSelector {
-                    selector_options: Some(with_vectors_selector::SelectorOptions::Enable(true)),
-                }),
-                ..Default::default()
-            })
+                build_conditions(parsed_query, self.payload_schema()),
+                limit,
+                offset,
+                threshold,
+                ef,
+            ))
             .await?;
 
         Ok(response.result)
     }
 
+    /// Search for the points nearest to each of `vectors`, as [`Semantic::search_with`] does.
     pub async fn batch_search_with<'a>(
         &self,
         parsed_queries: &[&SemanticQuery<'a>],
@@ -352,6 +483,7 @@ impl Semantic {
         limit: u64,
         offset: u64,
         threshold: f32,
+        ef: Option<u64>,
     ) -> anyhow::Result<Vec<ScoredPoint>> {
         // FIXME: This method uses `search_points` internally, and not `search_batch_points`. It's
         // not clear why, but it seems that the `batch` variant of the `qdrant` calls leads to
@@ -366,33 +498,11 @@ impl Semantic {
 
         // Queries should contain the same filters, so we get the first one
         let parsed_query = parsed_queries.first().unwrap();
-        let filters = &build_conditions(parsed_query);
+        let filters = &build_conditions(parsed_query, self.payload_schema());
 
         let responses = stream::iter(vectors.into_iter())
             .map(|vector| async move {
-                let points = SearchPoints {
-                    limit,
-                    vector,
-                    collection_name: COLLECTION_NAME.to_string(),
-                    offset: Some(offset),
-                    score_threshold: Some(threshold),
-                    with_payload: Some(WithPayloadSelector {
-                        selector_options: Some(with_payload_selector::SelectorOptions::Enable(
-                            true,
-                        )),
-                    }),
-                    filter: Some(Filter {
-                        must: filters.clone(),
-                        ..Default::default()
-                    }),
-                    with_vectors: Some(WithVectorsSelector {
-                        selector_options: Some(with_vectors_selector::SelectorOptions::Enable(
-                            true,
-                        )),
-                    }),
-                    ..Default::default()
-                };
-
+                let points = search_points(vector, filters.clone(), limit, offset, threshold, ef);
                 self.qdrant.search_points(&points).await
             })
             .buffered(10)
@@ -409,6 +519,7 @@ impl Semantic {
         offset: u64,
         threshold: f32,
         retrieve_more: bool,
+        ef: Option<u64>,
     ) -> anyhow::Result<Vec<Payload>> {
         let Some(query) = parsed_query.target() else {
             anyhow::bail!("no search target for query");
@@ -425,11 +536,12 @@ impl Semantic {
                 if retrieve_more { limit * 2 } else { limit }, // Retrieve double `limit` and deduplicate
                 offset,
                 threshold,
+                ef,
             )
             .await
             .map(|raw| {
                 raw.into_iter()
-                    .map(Payload::from_qdrant)
+                    .map(|point| Payload::from_qdrant(point, self.payload_schema()))
                     .collect::<Vec<_>>()
             })?;
         Ok(deduplicate_snippets(results, vector, limit))
@@ -442,6 +554,7 @@ impl Semantic {
         offset: u64,
         threshold: f32,
         retrieve_more: bool,
+        ef: Option<u64>,
     ) -> anyhow::Result<Vec<Payload>> {
         if parsed_queries.iter().any(|q| q.target().is_none()) {
             anyhow::bail!("no search target for query");
@@ -461,6 +574,7 @@ impl Semantic {
                 if retrieve_more { limit * 2 } else { limit }, // Retrieve double `limit` and deduplicate
                 offset,
                 threshold,
+                ef,
             )
             .await;
 
@@ -468,7 +582,7 @@ impl Semantic {
 
         let results = result?
             .into_iter()
-            .map(Payload::from_qdrant)
+            .map(|point| Payload::from_qdrant(point, self.payload_schema()))
             .collect::<Vec<_>>();
 
         // deduplicate with mmr with respect to the mean of query vectors
@@ -477,8 +591,27 @@ impl Semantic {
         Ok(deduplicate_snippets(results, target_vector, limit))
     }
 
+    /// Run a search for each of `queries`, one at a time, and time them.
+    ///
+    /// This is meant for after a restart, or after qdrant loaded the collection from disk, to
+    /// pull the collection into qdrant's caches and check the latency of searches before
+    /// serving any. Searches are unfiltered and return up to `limit` points, which are thrown
+    /// away, and nothing is written.
+    pub async fn prewarm(&self, queries: &[Embedding], limit: u64) -> anyhow::Result<PrewarmStats> {
+        time_searches(queries, |vector| async move {
+            self.qdrant
+                .search_points(&search_points(vector, vec![], limit, 0, 0.0, None))
+                .await
+                .map(drop)
+        })
+        .await
+    }
+
+    /// Embed the chunks of a file and store them in qdrant.
+    ///
+    /// Returns whether every chunk was embedded and stored.
     #[allow(clippy::too_many_arguments)]
-    #[tracing::instrument(skip(self, repo_name, buffer, chunk_cache))]
+    #[tracing::instrument(skip(self, repo_name, buffer, symbol_locations, chunk_cache))]
     pub async fn insert_points_for_buffer(
         &self,
         repo_name: &str,
@@ -487,23 +620,41 @@ impl Semantic {
         buffer: &str,
         lang_str: &str,
         branches: &[String],
+        symbol_locations: &SymbolLocations,
         chunk_cache: crate::cache::ChunkCache<'_>,
-    ) {
-        let chunks = chunk::by_tokens(
-            repo_name,
-            relative_path,
-            buffer,
-            &self.tokenizer,
-            50..self.config.max_chunk_tokens,
-            15,
-            self.overlap_strategy(),
-        );
+    ) -> bool {
+        let token_bounds = 50..self.config.max_chunk_tokens;
+        let chunks = match self.config.chunk_strategy {
+            chunk::ChunkStrategy::FixedWindow => chunk::by_tokens(
+                repo_name,
+                relative_path,
+                buffer,
+                &self.tokenizer,
+                token_bounds,
+                15,
+                self.overlap_strategy(),
+            ),
+            chunk::ChunkStrategy::SymbolAware => {
+                let symbols = symbol_locations
+                    .top_level()
+                    .into_iter()
+                    .map(|symbol| symbol.range)
+                    .collect::<Vec<_>>();
+                chunk::by_symbols(
+                    repo_name,
+                    relative_path,
+                    buffer,
+                    &self.tokenizer,
+                    token_bounds,
+                    15,
+                    self.overlap_strategy(),
+                    &symbols,
+                )
+            }
+        };
         debug!(chunk_count = chunks.len(), "found chunks");
 
-        let embedder = |c: &str| {
-            debug!("generating embedding");
-            self.embed(c)
-        };
+        let failed = AtomicBool::new(false);
         chunks.par_iter().for_each(|chunk| {
             let data = format!("{repo_name}\t{relative_path}\n{}", chunk.data,);
             let payload = Payload {
@@ -521,21 +672,31 @@ impl Semantic {
                 ..Default::default()
             };
 
-            let cached = chunk_cache.update_or_embed(&data, embedder, payload);
+            let cached = chunk_cache.update_or_embed(&data, self, payload);
             if let Err(err) = cached {
                 warn!(?err, %repo_name, %relative_path, "embedding failed");
+                failed.store(true, Ordering::Relaxed);
             }
         });
 
-        match chunk_cache.commit(&self.qdrant).await {
-            Ok((new, updated, deleted)) => {
+        match chunk_cache
+            .commit(&self.qdrant, self.write_ordering())
+            .await
+        {
+            Ok(CommitStats {
+                new,
+                updated,
+                deleted,
+            }) => {
                 info!(
                     repo_name,
                     relative_path, new, updated, deleted, "Successful commit"
-                )
+                );
+                !failed.into_inner()
             }
             Err(err) => {
-                warn!(repo_name, relative_path, ?err, "Failed to upsert vectors")
+                warn!(repo_name, relative_path, ?err, "Failed to upsert vectors");
+                false
             }
         }
     }
@@ -545,9 +706,10 @@ impl Semantic {
         repo_ref: &str,
         paths: impl Iterator<Item = String>,
     ) {
-        let repo_filter = make_kv_keyword_filter("repo_ref", repo_ref).into();
+        let schema = self.payload_schema();
+        let repo_filter = make_kv_keyword_filter(&schema.repo_ref, repo_ref).into();
         let file_filter = paths
-            .map(|p| make_kv_keyword_filter("content_hash", &p).into())
+            .map(|p| make_kv_keyword_filter(&schema.content_hash, &p).into())
             .collect::<Vec<_>>();
 
         let selector = Filter {
@@ -559,13 +721,63 @@ impl Semantic {
 
         let _ = self
             .qdrant
-            .delete_points(COLLECTION_NAME, &selector, None)
+            .delete_points(COLLECTION_NAME, &selector, self.write_ordering())
             .await;
     }
 
+    /// Make the chunks of `repo_ref` that are searchable on `from_branch`
+    /// searchable on `into_branch` too, without embedding them again.
+    ///
+    /// See [`FileCache::merge_branch`].
+    pub async fn index_branch_merge(
+        &self,
+        sql: &SqlDb,
+        repo_ref: &RepoRef,
+        from_branch: &str,
+        into_branch: &str,
+    ) -> anyhow::Result<usize> {
+        FileCache::for_repo(sql, repo_ref)
+            .merge_branch(
+                &self.qdrant,
+                self.write_ordering(),
+                self.payload_schema(),
+                from_branch,
+                into_branch,
+            )
+            .await
+    }
+
+    /// List what is embedded for the chunks of a file, as identified by its
+    /// chunk cache key.
+    ///
+    /// Meant for inspecting embeddings, see [`ChunkCache::stored_chunks`].
+    ///
+    /// [`ChunkCache::stored_chunks`]: crate::cache::ChunkCache::stored_chunks
+    pub async fn chunks_for_file_with_vectors(
+        &self,
+        sql: &SqlDb,
+        repo_ref: &RepoRef,
+        file_hash: &str,
+    ) -> anyhow::Result<Vec<StoredChunk>> {
+        FileCache::for_repo(sql, repo_ref)
+            .chunks_for_file(file_hash, self.payload_schema())
+            .await
+            .stored_chunks(&self.qdrant)
+            .await
+    }
+
+    pub fn payload_schema(&self) -> &PayloadSchema {
+        &self.config.payload_schema
+    }
+
     pub fn overlap_strategy(&self) -> chunk::OverlapStrategy {
         self.config.overlap.unwrap_or_default()
     }
+
+    /// The ordering of every write to qdrant, as configured.
+    pub fn write_ordering(&self) -> Option<WriteOrdering> {
+        self.config.qdrant_write_consistency.ordering()
+    }
 }
 
 /// Initialize the `ORT_DYLIB_PATH` variable, consumed by the `ort` crate.
@@ -615,7 +827,85 @@ fn make_kv_text_filter(key: &str, value: &str) -> FieldCondition {
     }
 }
 
-fn build_conditions(query: &SemanticQuery<'_>) -> Vec<qdrant_client::qdrant::Condition> {
+/// The latencies of the searches run by [`Semantic::prewarm`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrewarmStats {
+    /// The latency of each search, in the order of the queries
+    pub latencies: Vec<Duration>,
+}
+
+impl PrewarmStats {
+    /// The latency at `percentile`, between 0 and 100, using the nearest-rank method.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let mut sorted = self.latencies.clone();
+        sorted.sort();
+
+        let rank = (percentile.clamp(0.0, 100.0) / 100.0 * sorted.len() as f64).ceil() as usize;
+        sorted.get(rank.saturating_sub(1)).copied()
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        let total = self.latencies.iter().sum::<Duration>();
+        (!self.latencies.is_empty()).then(|| total / self.latencies.len() as u32)
+    }
+}
+
+/// Run `search` for each of `queries` in turn, timing each of them.
+///
+/// Searches run one at a time, so they don't compete with each other for qdrant.
+async fn time_searches<F, Fut, E>(queries: &[Embedding], search: F) -> Result<PrewarmStats, E>
+where
+    F: Fn(Embedding) -> Fut,
+    Fut: std::future::Future<Output = Result<(), E>>,
+{
+    let mut latencies = Vec::with_capacity(queries.len());
+    for query in queries {
+        let start = Instant::now();
+        search(query.clone()).await?;
+        latencies.push(start.elapsed());
+    }
+
+    debug!(queries = latencies.len(), "prewarmed semantic search");
+    Ok(PrewarmStats { latencies })
+}
+
+/// Build a search request, with the HNSW parameters of [`Semantic::search_with`].
+fn search_points(
+    vector: Embedding,
+    conditions: Vec<qdrant_client::qdrant::Condition>,
+    limit: u64,
+    offset: u64,
+    threshold: f32,
+    ef: Option<u64>,
+) -> SearchPoints {
+    SearchPoints {
+        limit,
+        vector,
+        collection_name: COLLECTION_NAME.to_string(),
+        offset: Some(offset),
+        score_threshold: Some(threshold),
+        with_payload: Some(WithPayloadSelector {
+            selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
+        }),
+        filter: Some(Filter {
+            must: conditions,
+            ..Default::default()
+        }),
+        with_vectors: Some(WithVectorsSelector {
+            selector_options: Some(with_vectors_selector::SelectorOptions::Enable(true)),
+        }),
+        params: ef.map(|hnsw_ef| SearchParams {
+            hnsw_ef: Some(hnsw_ef),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+fn build_conditions(
+    query: &SemanticQuery<'_>,
+    schema: &PayloadSchema,
+) -> Vec<qdrant_client::qdrant::Condition> {
     let repo_filter = {
         let conditions = query
             .repos()
@@ -626,7 +916,7 @@ fn build_conditions(query: &SemanticQuery<'_>) -> Vec<qdrant_client::qdrant::Con
                     r.to_string()
                 }
             })
-            .map(|r| make_kv_keyword_filter("repo_name", r.as_ref()).into())
+            .map(|r| make_kv_keyword_filter(&schema.repo_name, r.as_ref()).into())
             .collect::<Vec<_>>();
         // one of the above repos should match
         if conditions.is_empty() {
@@ -642,7 +932,7 @@ fn build_conditions(query: &SemanticQuery<'_>) -> Vec<qdrant_client::qdrant::Con
     let path_filter = {
         let conditions = query
             .paths()
-            .map(|r| make_kv_text_filter("relative_path", r.as_ref()).into())
+            .map(|r| make_kv_text_filter(&schema.relative_path, r.as_ref()).into())
             .collect::<Vec<_>>();
         if conditions.is_empty() {
             None
@@ -657,7 +947,7 @@ fn build_conditions(query: &SemanticQuery<'_>) -> Vec<qdrant_client::qdrant::Con
     let lang_filter = {
         let conditions = query
             .langs()
-            .map(|l| make_kv_keyword_filter("lang", l.as_ref()).into())
+            .map(|l| make_kv_keyword_filter(&schema.lang, l.as_ref()).into())
             .collect::<Vec<_>>();
         // one of the above langs should match
         if conditions.is_empty() {
@@ -673,7 +963,7 @@ fn build_conditions(query: &SemanticQuery<'_>) -> Vec<qdrant_client::qdrant::Con
     let branch_filter = {
         let conditions = query
             .branch()
-            .map(|l| make_kv_keyword_filter("branches", l.as_ref()).into())
+            .map(|l| make_kv_keyword_filter(&schema.branches, l.as_ref()).into())
             .collect::<Vec<_>>();
 
         if conditions.is_empty() {
@@ -863,3 +1153,180 @@ pub fn deduplicate_snippets(
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn payload_with_extras() -> Payload {
+        Payload {
+            lang: "rust".into(),
+            repo_name: "bloop".into(),
+            repo_ref: "local//bloop".into(),
+            relative_path: "src/main.rs".into(),
+            content_hash: "abc".into(),
+            text: "fn main() {}".into(),
+            start_line: 1,
+            end_line: 2,
+            start_byte: 0,
+            end_byte: 12,
+            branches: vec!["HEAD".into()],
+            extra: HashMap::from([
+                ("classification".into(), serde_json::json!("secret")),
+                ("reviewers".into(), serde_json::json!(["alice", "bob"])),
+                ("meta".into(), serde_json::json!({ "level": 3 })),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    fn point_id() -> Option<PointId> {
+        Some(PointId::from(uuid::Uuid::new_v4().to_string()))
+    }
+
+    #[test]
+    fn write_ordering_follows_config() {
+        let ordering = |args: &[&str]| {
+            let config = Configuration::parse_from([&["bleep"][..], args].concat());
+            config
+                .qdrant_write_consistency
+                .ordering()
+                .map(|o| WriteOrderingType::from_i32(o.r#type).unwrap())
+        };
+
+        // qdrant's default, as before the option existed
+        assert_eq!(ordering(&[]), None);
+        assert_eq!(
+            ordering(&["--qdrant-write-consistency", "medium"]),
+            Some(WriteOrderingType::Medium)
+        );
+        assert_eq!(
+            ordering(&["--qdrant-write-consistency", "strong"]),
+            Some(WriteOrderingType::Strong)
+        );
+    }
+
+    #[test]
+    fn search_ef_is_passed_to_qdrant() {
+        let request = |ef| search_points(vec![0.0; EMBEDDING_DIM], vec![], 10, 0, 0.0, ef);
+        let ef = |request: SearchPoints| request.params.and_then(|p| p.hnsw_ef);
+
+        // favouring latency, and recall
+        assert_eq!(ef(request(Some(16))), Some(16));
+        assert_eq!(ef(request(Some(512))), Some(512));
+
+        // qdrant's configured value
+        assert_eq!(request(None).params, None);
+    }
+
+    #[test]
+    fn extra_fields_round_trip() {
+        let payload = payload_with_extras();
+        let schema = PayloadSchema::default();
+        let parsed = parse_payload(
+            &schema,
+            point_id(),
+            None,
+            payload.clone().into_qdrant(&schema),
+            0.0,
+        );
+
+        assert_eq!(parsed, payload);
+    }
+
+    #[test]
+    fn renamed_payload_fields_change_the_semantic_version() {
+        let default = PayloadSchema::default();
+        assert_eq!(default.index_version(), crate::state::SEMANTIC_VERSION);
+
+        let renamed = PayloadSchema {
+            text: "content".into(),
+            ..Default::default()
+        };
+        assert_ne!(renamed.index_version(), default.index_version());
+        assert_eq!(renamed.index_version(), renamed.clone().index_version());
+    }
+
+    #[test]
+    fn extra_fields_do_not_override_builtins() {
+        let mut payload = payload_with_extras();
+        payload
+            .extra
+            .insert("relative_path".into(), serde_json::json!("elsewhere.rs"));
+
+        let schema = PayloadSchema::default();
+        let parsed = parse_payload(&schema, point_id(), None, payload.into_qdrant(&schema), 0.0);
+        assert_eq!(parsed.relative_path, "src/main.rs");
+    }
+
+    #[test]
+    fn extra_fields_survive_branch_update() {
+        let payload = payload_with_extras();
+        let branches = vec!["HEAD".to_string(), "feature".to_string()];
+
+        // qdrant merges `set_payload` keys into the stored payload
+        let schema = PayloadSchema::default();
+        let mut stored = payload.clone().into_qdrant(&schema);
+        stored.extend(branches_payload(&schema, &branches));
+
+        let parsed = parse_payload(&schema, point_id(), None, stored, 0.0);
+        assert_eq!(parsed.branches, branches);
+        assert_eq!(parsed.extra, payload.extra);
+    }
+
+    #[test]
+    fn renamed_fields_round_trip() {
+        let payload = payload_with_extras();
+        let branches = vec!["HEAD".to_string(), "feature".to_string()];
+        let schema = PayloadSchema {
+            text: "content".into(),
+            branches: "searchable_on".into(),
+            ..Default::default()
+        };
+
+        let mut stored = payload.clone().into_qdrant(&schema);
+        assert!(stored.contains_key("content"));
+        assert!(stored.contains_key("searchable_on"));
+        assert!(!stored.contains_key("snippet"));
+        assert!(!stored.contains_key("branches"));
+
+        stored.extend(branches_payload(&schema, &branches));
+        assert!(!stored.contains_key("branches"));
+
+        let parsed = parse_payload(&schema, point_id(), None, stored, 0.0);
+        assert_eq!(parsed.text, payload.text);
+        assert_eq!(parsed.branches, branches);
+        assert_eq!(parsed.extra, payload.extra);
+    }
+
+    #[tokio::test]
+    async fn prewarm_times_every_query() {
+        let queries = vec![vec![0.1; EMBEDDING_DIM], vec![0.2; EMBEDDING_DIM], vec![]];
+        let searched = std::sync::Mutex::new(vec![]);
+
+        let stats = time_searches(&queries, |vector| {
+            searched.lock().unwrap().push(vector);
+            async { Ok::<_, ()>(()) }
+        })
+        .await
+        .unwrap();
+        assert_eq!(stats.latencies.len(), queries.len());
+        assert_eq!(*searched.lock().unwrap(), queries);
+        assert!(stats.mean().is_some());
+        assert_eq!(
+            stats.percentile(100.0),
+            stats.latencies.iter().max().copied()
+        );
+
+        // a failed search fails the whole run
+        let failed = time_searches(&queries, |_| async { Err("unavailable") }).await;
+        assert_eq!(failed, Err("unavailable"));
+
+        let empty = time_searches(&[], |_| async { Ok::<_, ()>(()) })
+            .await
+            .unwrap();
+        assert_eq!(empty.mean(), None);
+        assert_eq!(empty.percentile(50.0), None);
+    }
+}
@@ -0,0 +1,307 @@
+//! Embedding the files of a repository that are already in the file index.
+
+use std::{collections::BTreeMap, future::Future};
+
+use anyhow::Result;
+use tantivy::{
+    collector::DocSetCollector,
+    query::TermQuery,
+    schema::{IndexRecordOption, Value},
+    Term,
+};
+use tracing::warn;
+
+use super::Semantic;
+use crate::{
+    cache::FileCache,
+    db::SqlDb,
+    indexes::{File, Indexer, Indexes},
+    repo::{RepoRef, Repository},
+    symbol::SymbolLocations,
+};
+
+/// A file to embed, as stored in the file index.
+#[derive(Debug, Clone)]
+struct BackfillFile {
+    /// Key of the file's chunks in the chunk cache
+    cache_key: String,
+    relative_path: String,
+    repo_name: String,
+    content: String,
+    lang: String,
+    branches: Vec<String>,
+    symbol_locations: SymbolLocations,
+}
+
+/// Embed every indexed file of a repository, resuming after the last file embedded by an
+/// interrupted run.
+///
+/// Files are embedded in the order of their chunk cache keys, and the progress is recorded in
+/// SQLite after each one. Files indexed after an interrupted run that sort before its last file are
+/// skipped on resume; the next index of the repository embeds them. The progress stops at the
+/// first file that fails to embed, so the next run retries it.
+///
+/// Repositories opted out of semantic search aren't embedded. Returns the number of files
+/// embedded by this run.
+pub async fn resume_backfill(
+    semantic: &Semantic,
+    sql: &SqlDb,
+    indexes: &Indexes,
+    repo_ref: &RepoRef,
+    repo: &Repository,
+) -> Result<usize> {
+    if !indexes.file.source.embeds(repo_ref, repo) {
+        return Ok(0);
+    }
+
+    let files = indexed_files(&indexes.file, repo_ref).await?;
+    let cache = &FileCache::for_repo(sql, repo_ref);
+    let repo_str = &repo_ref.to_string();
+
+    drive(cache, files, |file| async move {
+        let embedded = semantic
+            .insert_points_for_buffer(
+                &file.repo_name,
+                repo_str,
+                &file.relative_path,
+                &file.content,
+                &file.lang,
+                &file.branches,
+                &file.symbol_locations,
+                cache
+                    .chunks_for_file(&file.cache_key, semantic.payload_schema())
+                    .await,
+            )
+            .await;
+        Ok(embedded)
+    })
+    .await
+}
+
+/// Call `embed` on every file after the saved cursor, moving the cursor along.
+///
+/// `embed` returns whether the file was embedded. The cursor never moves past a file that
+/// wasn't, and the files after it are still embedded, so the next backfill only redoes the ones
+/// from the failure onwards. The cursor is cleared once all files are done, so the next backfill
+/// starts over.
+async fn drive<F, Fut>(
+    cache: &FileCache<'_>,
+    files: Vec<BackfillFile>,
+    mut embed: F,
+) -> Result<usize>
+where
+    F: FnMut(BackfillFile) -> Fut,
+    Fut: Future<Output = Result<bool>>,
+{
+    let cursor = cache.backfill_cursor().await?;
+    let pending = files.into_iter().filter(|file| {
+        cursor
+            .as_deref()
+            .map_or(true, |c| file.cache_key.as_str() > c)
+    });
+
+    let (mut embedded, mut failed) = (0, 0);
+    for file in pending {
+        let cache_key = file.cache_key.clone();
+        if !embed(file).await? {
+            failed += 1;
+            continue;
+        }
+
+        if failed == 0 {
+            cache.save_backfill_cursor(&cache_key).await?;
+        }
+        embedded += 1;
+    }
+
+    if failed > 0 {
+        warn!(failed, "files failed to embed, to be retried");
+    } else {
+        cache.clear_backfill_cursor().await?;
+    }
+
+    Ok(embedded)
+}
+
+/// List the files of a repository in the file index, sorted by cache key.
+async fn indexed_files(index: &Indexer<File>, repo_ref: &RepoRef) -> Result<Vec<BackfillFile>> {
+    let schema = &index.source;
+    let searcher = index.reader.read().await.searcher();
+    let query = TermQuery::new(
+        Term::from_field_text(schema.repo_ref, &repo_ref.to_string()),
+        IndexRecordOption::Basic,
+    );
+
+    // duplicates of a file share a cache key while it's being re-indexed
+    let mut files = BTreeMap::new();
+    for addr in searcher.search(&query, &DocSetCollector)? {
+        let doc = searcher.doc(addr)?;
+        let text = |field| {
+            doc.get_first(field)
+                .and_then(Value::as_text)
+                .unwrap_or_default()
+                .to_owned()
+        };
+
+        // directories have no chunks
+        let cache_key = text(schema.semantic_hash);
+        if cache_key.is_empty() {
+            continue;
+        }
+
+        // the shards of a large file share its cache key, and neither they nor files indexed
+        // by path only are embedded when indexing
+        let is_small = doc
+            .get_first(schema.is_small)
+            .and_then(|value| value.as_bool())
+            .unwrap_or_default();
+        if doc.get_first(schema.shard_index).is_some() || is_small {
+            continue;
+        }
+
+        let lang = doc
+            .get_first(schema.lang)
+            .and_then(Value::as_u64)
+            .and_then(|id| schema.langs.name(id))
+            .unwrap_or_default();
+
+        files.insert(
+            cache_key.clone(),
+            BackfillFile {
+                cache_key,
+                relative_path: text(schema.relative_path),
+                repo_name: text(schema.repo_name),
+                content: text(schema.content),
+                lang,
+                branches: text(schema.branches)
+                    .lines()
+                    .map(ToOwned::to_owned)
+                    .collect(),
+                symbol_locations: doc
+                    .get_first(schema.symbol_locations)
+                    .and_then(Value::as_bytes)
+                    .and_then(|bytes| bincode::deserialize(bytes).ok())
+                    .unwrap_or_default(),
+            },
+        );
+    }
+
+    Ok(files.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+    use std::sync::{Arc, Mutex};
+
+    async fn test_db() -> SqlDb {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        Arc::new(pool)
+    }
+
+    fn file(cache_key: &str) -> BackfillFile {
+        BackfillFile {
+            cache_key: cache_key.to_owned(),
+            relative_path: format!("{cache_key}.rs"),
+            repo_name: "bloop".to_owned(),
+            content: String::new(),
+            lang: "Rust".to_owned(),
+            branches: vec![],
+            symbol_locations: SymbolLocations::Empty,
+        }
+    }
+
+    #[tokio::test]
+    async fn interrupted_backfill_resumes_after_last_file() {
+        let db = test_db().await;
+        let reporef: RepoRef = "github.com/bloopai/bloop".parse().unwrap();
+        let cache = FileCache::for_repo(&db, &reporef);
+        let files = ["a", "b", "c", "d"].map(file).to_vec();
+
+        let embedded = Mutex::new(vec![]);
+        let embed = |fail_on: Option<&'static str>| {
+            let embedded = &embedded;
+            move |file: BackfillFile| async move {
+                if Some(file.cache_key.as_str()) == fail_on {
+                    anyhow::bail!("interrupted");
+                }
+                embedded.lock().unwrap().push(file.cache_key);
+                Ok(true)
+            }
+        };
+
+        // the first run stops while embedding `c`
+        assert!(drive(&cache, files.clone(), embed(Some("c")))
+            .await
+            .is_err());
+        assert_eq!(cache.backfill_cursor().await.unwrap().as_deref(), Some("b"));
+
+        // the second one picks up from there, and finishes
+        assert_eq!(drive(&cache, files.clone(), embed(None)).await.unwrap(), 2);
+        assert_eq!(*embedded.lock().unwrap(), ["a", "b", "c", "d"]);
+        assert_eq!(cache.backfill_cursor().await.unwrap(), None);
+
+        // a finished backfill starts over
+        assert_eq!(drive(&cache, files, embed(None)).await.unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn failed_files_are_retried() {
+        let db = test_db().await;
+        let reporef: RepoRef = "github.com/bloopai/bloop".parse().unwrap();
+        let cache = FileCache::for_repo(&db, &reporef);
+        let files = ["a", "b", "c", "d"].map(file).to_vec();
+
+        let embedded = Mutex::new(vec![]);
+        let embed = |fail_on: Option<&'static str>| {
+            let embedded = &embedded;
+            move |file: BackfillFile| async move {
+                if Some(file.cache_key.as_str()) == fail_on {
+                    return Ok(false);
+                }
+                embedded.lock().unwrap().push(file.cache_key);
+                Ok(true)
+            }
+        };
+
+        // `b` fails, but the files after it are still embedded
+        assert_eq!(
+            drive(&cache, files.clone(), embed(Some("b")))
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(*embedded.lock().unwrap(), ["a", "c", "d"]);
+        assert_eq!(cache.backfill_cursor().await.unwrap().as_deref(), Some("a"));
+
+        // the next run picks up at the failed file
+        embedded.lock().unwrap().clear();
+        assert_eq!(drive(&cache, files, embed(None)).await.unwrap(), 3);
+        assert_eq!(*embedded.lock().unwrap(), ["b", "c", "d"]);
+        assert_eq!(cache.backfill_cursor().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn backfill_cursors_are_per_repo() {
+        let db = test_db().await;
+        let reporef: RepoRef = "github.com/bloopai/bloop".parse().unwrap();
+        let other: RepoRef = "github.com/bloopai/other".parse().unwrap();
+        let cache = FileCache::for_repo(&db, &reporef);
+
+        cache.save_backfill_cursor("a").await.unwrap();
+        cache.save_backfill_cursor("b").await.unwrap();
+        assert_eq!(cache.backfill_cursor().await.unwrap().as_deref(), Some("b"));
+
+        let other_cache = FileCache::for_repo(&db, &other);
+        assert_eq!(other_cache.backfill_cursor().await.unwrap(), None);
+
+        cache.delete().await.unwrap();
+        assert_eq!(cache.backfill_cursor().await.unwrap(), None);
+    }
+}
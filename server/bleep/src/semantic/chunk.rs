@@ -155,6 +155,17 @@ impl Default for OverlapStrategy {
     }
 }
 
+/// How files are split into chunks for the semantic index
+#[derive(Serialize, Deserialize, clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkStrategy {
+    /// Fill windows of tokens, regardless of the code's structure
+    #[default]
+    FixedWindow,
+    /// A chunk for each top-level symbol, plus one for the head of the file
+    SymbolAware,
+}
+
 /// This should take care of [CLS], [SEP] etc. which could be introduced during per-chunk tokenization
 pub const DEDUCT_SPECIAL_TOKENS: usize = 2;
 
@@ -308,6 +319,105 @@ pub fn by_tokens<'s>(
     }
 }
 
+/// Split the code along its top-level symbols, so embeddings don't straddle definitions.
+///
+/// Every symbol gets the lines from its definition up to the next one, along with any comments
+/// or attributes directly above it. The lines before the first symbol, usually the imports and
+/// documentation of the module, make a chunk that summarizes the file. Symbols too large for a
+/// single chunk are windowed with [`by_tokens`], which is also used for files without symbols.
+#[allow(clippy::too_many_arguments)]
+pub fn by_symbols<'s>(
+    repo: &str,
+    file: &str,
+    src: &'s str,
+    tokenizer: &Tokenizer,
+    token_bounds: Range<usize>,
+    max_lines: usize,
+    strategy: OverlapStrategy,
+    symbols: &[TextRange],
+) -> Vec<Chunk<'s>> {
+    if symbols.is_empty() {
+        return by_tokens(
+            repo,
+            file,
+            src,
+            tokenizer,
+            token_bounds,
+            max_lines,
+            strategy,
+        );
+    }
+
+    let line_starts = std::iter::once(0)
+        .chain(src.match_indices('\n').map(|(i, _)| i + 1))
+        .collect::<Vec<_>>();
+    let line_of = |byte: usize| line_starts.partition_point(|&start| start <= byte) - 1;
+
+    let mut def_lines = symbols
+        .iter()
+        .map(|range| line_of(range.start.byte))
+        .collect::<Vec<_>>();
+    def_lines.sort_unstable();
+    def_lines.dedup();
+
+    // pull the comments and attributes of each symbol into its chunk
+    let mut starts = Vec::with_capacity(def_lines.len());
+    for (i, &def) in def_lines.iter().enumerate() {
+        let floor = if i == 0 { 0 } else { def_lines[i - 1] + 1 };
+        let mut start = def;
+        while start > floor && is_decoration(line_at(src, &line_starts, start - 1)) {
+            start -= 1;
+        }
+        starts.push(line_starts[start]);
+    }
+
+    let bounds = std::iter::once(0)
+        .chain(starts.iter().copied())
+        .chain(std::iter::once(src.len()))
+        .collect::<Vec<_>>();
+
+    let mut chunks = Vec::new();
+    for span in bounds.windows(2) {
+        let (span_start, span_end) = (span[0], span[1]);
+        let text = &src[span_start..span_end];
+        if text.trim().is_empty() {
+            continue;
+        }
+
+        let base = point(src, span_start, 0, 0);
+        let sub = by_tokens(
+            repo,
+            file,
+            text,
+            tokenizer,
+            1..token_bounds.end,
+            max_lines,
+            strategy,
+        );
+        chunks.extend(sub.into_iter().map(|chunk| {
+            let byte = span_start + chunk.range.start.byte;
+            let start = point(src, byte, base.line, base.byte);
+            let end = point(src, span_start + chunk.range.end.byte, base.line, base.byte);
+            Chunk::new(chunk.data, start, end)
+        }));
+    }
+
+    chunks
+}
+
+fn line_at<'s>(src: &'s str, line_starts: &[usize], line: usize) -> &'s str {
+    let end = line_starts.get(line + 1).copied().unwrap_or(src.len());
+    &src[line_starts[line]..end]
+}
+
+/// Whether `line` belongs to the definition below it, as a comment or attribute does.
+fn is_decoration(line: &str) -> bool {
+    let line = line.trim();
+    ["//", "/*", "*", "#", "@", "--"]
+        .iter()
+        .any(|prefix| line.starts_with(prefix))
+}
+
 pub fn by_lines(src: &str, size: usize) -> Vec<Chunk<'_>> {
     let ends = std::iter::once(0)
         .chain(src.match_indices('\n').map(|(i, _)| i))
@@ -484,6 +594,77 @@ mod tests {
         }
     }
 
+    #[test]
+    pub fn chunk_boundaries_by_strategy() {
+        let tokenizer = minilm();
+        let src = "//! Helpers for numbers, used all throughout the crate.
+
+/// Add two numbers, returning their sum.
+pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+#[inline]
+pub fn sub(a: i32, b: i32) -> i32 {
+    let difference = a - b;
+    difference
+}
+";
+        let symbols = crate::intelligence::TreeSitterFile::try_build(src.as_bytes(), "Rust")
+            .and_then(|file| file.scope_graph())
+            .map(crate::symbol::SymbolLocations::TreeSitter)
+            .unwrap()
+            .top_level()
+            .into_iter()
+            .map(|symbol| symbol.range)
+            .collect::<Vec<_>>();
+        let start_lines = |chunks: Vec<Chunk<'_>>| {
+            chunks
+                .iter()
+                .map(|chunk| chunk.range.start.line)
+                .collect::<Vec<_>>()
+        };
+
+        let fixed = super::by_tokens(
+            "bloop",
+            "src/numbers.rs",
+            src,
+            &tokenizer,
+            50..256,
+            15,
+            OverlapStrategy::Partial(0.5),
+        );
+        assert_eq!(start_lines(fixed), [0]);
+
+        let aware = super::by_symbols(
+            "bloop",
+            "src/numbers.rs",
+            src,
+            &tokenizer,
+            50..256,
+            15,
+            OverlapStrategy::Partial(0.5),
+            &symbols,
+        );
+        let data = aware.iter().map(|chunk| chunk.data).collect::<Vec<_>>();
+        assert_eq!(start_lines(aware), [0, 2, 7]);
+        assert!(data[1].starts_with("/// Add two numbers"));
+        assert!(data[2].starts_with("#[inline]\npub fn sub"));
+
+        // without symbols, chunks fall back to fixed windows
+        let fallback = super::by_symbols(
+            "bloop",
+            "src/numbers.rs",
+            src,
+            &tokenizer,
+            50..256,
+            15,
+            OverlapStrategy::Partial(0.5),
+            &[],
+        );
+        assert_eq!(start_lines(fallback), [0]);
+    }
+
     static SRC: &str = r#"
 use crate::{semantic::chunk::OverlapStrategy, state::StateSource};
 use anyhow::{Context, Result};
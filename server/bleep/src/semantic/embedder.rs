@@ -0,0 +1,96 @@
+//! Models that turn text into embeddings.
+
+use anyhow::Result;
+use tracing::debug;
+
+use super::{Embedding, Semantic, EMBEDDING_DIM};
+
+/// A model that embeds text into vectors of [`Embedder::dim`] dimensions.
+///
+/// Embedders are shared by the threads that chunk files, so they have to be `Sync`.
+pub trait Embedder: Send + Sync {
+    fn embed(&self, text: &str) -> Result<Embedding>;
+
+    /// Embed several texts at once, in order.
+    ///
+    /// By default, the texts are embedded one by one. Models that can run a batch faster than
+    /// its texts on their own should override this.
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Embedding>> {
+        texts.iter().map(|text| self.embed(text)).collect()
+    }
+
+    /// Identifies the model, so that embeddings of different models aren't mixed.
+    fn model_id(&self) -> &str;
+
+    /// Number of dimensions of the embeddings.
+    fn dim(&self) -> usize;
+}
+
+impl Embedder for Semantic {
+    fn embed(&self, text: &str) -> Result<Embedding> {
+        debug!("generating embedding");
+        Semantic::embed(self, text)
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    fn dim(&self) -> usize {
+        EMBEDDING_DIM
+    }
+}
+
+/// An embedder for tests, which embeds the same text into the same vector and counts its calls.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct StubEmbedder {
+    calls: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl StubEmbedder {
+    /// Number of texts embedded so far.
+    pub(crate) fn calls(&self) -> usize {
+        self.calls.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+impl Embedder for StubEmbedder {
+    fn embed(&self, text: &str) -> Result<Embedding> {
+        self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+        let hash = blake3::hash(text.as_bytes());
+        Ok(hash.as_bytes()[..self.dim()]
+            .iter()
+            .map(|&byte| byte as f32 / u8::MAX as f32)
+            .collect())
+    }
+
+    fn model_id(&self) -> &str {
+        "stub"
+    }
+
+    fn dim(&self) -> usize {
+        8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stub_embeds_deterministically() {
+        let stub = StubEmbedder::default();
+        let texts = ["fn main() {}", "fn exit() {}", "fn main() {}"];
+
+        let batch = stub.embed_batch(&texts).unwrap();
+        assert_eq!(stub.calls(), 3);
+        assert!(batch.iter().all(|embedding| embedding.len() == stub.dim()));
+        assert_eq!(batch[0], batch[2]);
+        assert_ne!(batch[0], batch[1]);
+        assert_eq!(stub.embed(texts[1]).unwrap(), batch[1]);
+    }
+}
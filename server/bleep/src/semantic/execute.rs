@@ -2,7 +2,9 @@ use std::collections::HashMap;
 
 use crate::{
     query::{
-        execute::{ApiQuery, PagingMetadata, QueryResponse, QueryResult, ResultStats},
+        execute::{
+            ApiQuery, PagingMetadata, QueryResponse, QueryResult, ResultStats, MAX_PAGE_SIZE,
+        },
         parser::SemanticQuery,
     },
     snippet::Snippet,
@@ -17,13 +19,17 @@ pub async fn execute(
     query: SemanticQuery<'_>,
     params: ApiQuery,
 ) -> Result<QueryResponse> {
+    params.check_paging()?;
+    let page_size = params.page_size.clamp(1, MAX_PAGE_SIZE);
+
     let results = semantic
         .search(
             &query,
-            params.page_size as u64,
-            ((params.page + 1) * params.page_size) as u64,
+            page_size as u64,
+            ((params.page + 1) * page_size) as u64,
             0.0,
             false,
+            params.hnsw_ef,
         )
         .await?;
 
@@ -59,7 +65,7 @@ pub async fn execute(
         .collect::<Vec<_>>();
     Ok(QueryResponse {
         count: data.len(),
-        metadata: PagingMetadata::new(params.page, params.page_size, None),
+        metadata: PagingMetadata::new(params.page, page_size, None),
         stats: ResultStats::default(),
         data,
     })
@@ -0,0 +1,222 @@
+//! Hybrid search, fusing semantic and lexical rankings of files.
+
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+use anyhow::Result;
+use tantivy::collector::{MultiCollector, TopDocs};
+
+use super::Semantic;
+use crate::{
+    indexes::{reader::ContentReader, Indexes},
+    query::parser::{Query, SemanticQuery, Target},
+};
+
+/// The `k` constant of reciprocal rank fusion, which dampens the weight of top ranks.
+///
+/// 60 is the value used in the original paper, and works well across datasets.
+const RRF_K: f32 = 60.0;
+
+/// A file ranked by [`hybrid_search`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FusedHit {
+    pub repo_ref: String,
+    pub relative_path: String,
+    pub score: f32,
+}
+
+/// Rank files by running both a semantic and a lexical search for `query`.
+///
+/// `lexical_weight` is the weight of the tantivy ranking in the fusion, between 0 and 1. At 0,
+/// the semantic order is kept; at 1, the lexical one.
+///
+/// Semantic results are chunks, identified by the hash of the file contents they were embedded
+/// from. That hash isn't stored in tantivy, so both sides are matched by repo and path instead,
+/// and a file is ranked as its best scoring chunk.
+pub async fn hybrid_search(
+    semantic: &Semantic,
+    indexes: &Indexes,
+    query: &SemanticQuery<'_>,
+    limit: usize,
+    lexical_weight: f32,
+) -> Result<Vec<FusedHit>> {
+    let semantic_ranking = semantic
+        .search(query, limit as u64, 0, 0.0, false, None)
+        .await?
+        .into_iter()
+        .map(|payload| (payload.repo_ref, payload.relative_path))
+        .collect::<Vec<_>>();
+
+    let lexical_queries = lexical_queries(query);
+    let lexical_ranking = indexes
+        .file
+        .query(
+            lexical_queries.iter(),
+            &ContentReader,
+            (TopDocs::with_limit(limit.max(1)), MultiCollector::new()),
+        )
+        .await?
+        .docs
+        .map(|doc| (doc.repo_ref, doc.relative_path))
+        .collect::<Vec<_>>();
+
+    Ok(
+        reciprocal_rank_fusion(&semantic_ranking, &lexical_ranking, lexical_weight)
+            .into_iter()
+            .take(limit)
+            .map(|((repo_ref, relative_path), score)| FusedHit {
+                repo_ref,
+                relative_path,
+                score,
+            })
+            .collect(),
+    )
+}
+
+/// Translate the filters of a semantic query into content queries.
+///
+/// Filters of the same kind are alternatives, so this produces a query for every combination.
+fn lexical_queries<'a>(query: &SemanticQuery<'a>) -> Vec<Query<'a>> {
+    fn alternatives<T: Clone>(set: impl ExactSizeIterator<Item = T>) -> Vec<Option<T>> {
+        if set.len() == 0 {
+            vec![None]
+        } else {
+            set.map(Some).collect()
+        }
+    }
+
+    let Some(target) = query.target.clone() else {
+        return vec![];
+    };
+
+    let mut queries = vec![Query {
+        target: Some(Target::Content(target)),
+        ..Default::default()
+    }];
+
+    let repos = alternatives(query.repos.iter().cloned());
+    queries = product(queries, &repos, |q, repo| Query { repo, ..q });
+
+    let paths = alternatives(query.paths.iter().cloned());
+    queries = product(queries, &paths, |q, path| Query { path, ..q });
+
+    let langs = alternatives(query.langs.iter().cloned());
+    queries = product(queries, &langs, |q, lang| Query { lang, ..q });
+
+    let branches = alternatives(query.branch.iter().cloned());
+    product(queries, &branches, |q, branch| Query { branch, ..q })
+}
+
+fn product<'a, T: Clone>(
+    queries: Vec<Query<'a>>,
+    values: &[T],
+    set: impl Fn(Query<'a>, T) -> Query<'a>,
+) -> Vec<Query<'a>> {
+    queries
+        .into_iter()
+        .flat_map(|q| values.iter().map(move |v| (q.clone(), v.clone())))
+        .map(|(q, v)| set(q, v))
+        .collect()
+}
+
+/// Fuse two rankings with weighted reciprocal rank fusion, best first.
+///
+/// Each list is ordered best first, and only the first occurrence of a key counts towards its
+/// rank. Keys with equal scores keep the order in which they first appear, semantic results
+/// first.
+fn reciprocal_rank_fusion<K: Clone + Eq + Hash>(
+    semantic: &[K],
+    lexical: &[K],
+    lexical_weight: f32,
+) -> Vec<(K, f32)> {
+    let lexical_weight = lexical_weight.clamp(0.0, 1.0);
+    let mut scores = HashMap::<&K, (usize, f32)>::new();
+
+    for (ranking, weight) in [(semantic, 1.0 - lexical_weight), (lexical, lexical_weight)] {
+        let mut seen = HashSet::new();
+        for key in ranking {
+            if !seen.insert(key) {
+                continue;
+            }
+
+            let rank = seen.len();
+            let order = scores.len();
+            scores.entry(key).or_insert((order, 0.0)).1 += weight / (RRF_K + rank as f32);
+        }
+    }
+
+    let mut fused = scores.into_iter().collect::<Vec<_>>();
+    fused.sort_by(|(_, (a_order, a)), (_, (b_order, b))| b.total_cmp(a).then(a_order.cmp(b_order)));
+
+    fused
+        .into_iter()
+        .map(|(key, (_, score))| (key.clone(), score))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::parser::Literal;
+    use std::borrow::Cow;
+
+    fn keys(fused: Vec<(&str, f32)>) -> Vec<&str> {
+        fused.into_iter().map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn fusion_promotes_exact_match() {
+        // the exact keyword match is only the third most similar file
+        let semantic = ["topical.rs", "related.rs", "exact.rs", "other.rs"];
+        let lexical = ["exact.rs", "other.rs"];
+
+        assert_eq!(
+            keys(reciprocal_rank_fusion(&semantic, &lexical, 0.5)),
+            ["exact.rs", "other.rs", "topical.rs", "related.rs"]
+        );
+
+        // without any lexical weight, the semantic order is kept
+        assert_eq!(
+            keys(reciprocal_rank_fusion(&semantic, &lexical, 0.0)),
+            semantic
+        );
+
+        // with all of it, lexical hits come first
+        assert_eq!(
+            keys(reciprocal_rank_fusion(&semantic, &lexical, 1.0)),
+            ["exact.rs", "other.rs", "topical.rs", "related.rs"]
+        );
+    }
+
+    #[test]
+    fn fusion_counts_files_once_per_ranking() {
+        // several chunks of the same file
+        let semantic = ["a.rs", "a.rs", "a.rs", "b.rs"];
+        let lexical = ["b.rs"];
+
+        let fused = reciprocal_rank_fusion(&semantic, &lexical, 0.5);
+        assert_eq!(keys(fused.clone()), ["b.rs", "a.rs"]);
+        assert_eq!(fused[1].1, 0.5 / (RRF_K + 1.0));
+    }
+
+    #[test]
+    fn semantic_filters_become_content_queries() {
+        let query = SemanticQuery {
+            repos: [Literal::from(&"bloop".to_owned())].into(),
+            langs: [Cow::from("rust"), Cow::from("go")].into(),
+            target: Some(Literal::from(&"parse query".to_owned())),
+            ..Default::default()
+        };
+
+        let queries = lexical_queries(&query);
+        assert_eq!(queries.len(), 2);
+        assert!(queries.iter().all(|q| {
+            q.repo.as_ref().and_then(Literal::as_plain).as_deref() == Some("bloop")
+                && q.target == Some(Target::Content(Literal::from(&"parse query".to_owned())))
+        }));
+
+        assert!(lexical_queries(&SemanticQuery::default()).is_empty());
+    }
+}
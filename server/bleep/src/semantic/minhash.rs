@@ -0,0 +1,290 @@
+//! Near-duplicate detection of chunks with minhash, to reuse the embeddings of boilerplate.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        RwLock,
+    },
+};
+
+use super::{Embedder, Embedding};
+
+/// Number of hash functions in a signature.
+const SIGNATURE_LEN: usize = 128;
+
+/// Number of consecutive hashes of a signature in each band of the LSH table.
+///
+/// With 32 bands of 4 rows, chunks that are 50% similar are candidates most of the time, and
+/// ones that are 80% similar virtually always, so thresholds in that range miss few duplicates.
+const BAND_ROWS: usize = 4;
+
+/// Number of consecutive words in a shingle.
+const SHINGLE_WORDS: usize = 3;
+
+/// Number of chunks a run remembers, after which the oldest ones are forgotten.
+///
+/// With 384-dimensional embeddings, this is about 20 MiB of signatures and embeddings.
+const MAX_CHUNKS: usize = 8192;
+
+type Signature = [u64; SIGNATURE_LEN];
+
+/// The embeddings of the chunks embedded so far, looked up by similarity.
+///
+/// Chunks are compared by the Jaccard similarity of their sets of word shingles, as estimated
+/// by minhash signatures. Candidates are found by hashing bands of the signatures, so a lookup
+/// doesn't compare against every chunk. A chunk that is at least `threshold` similar to one
+/// of the last [`MAX_CHUNKS`] embedded gets its embedding.
+pub struct NearDuplicates {
+    threshold: f32,
+    capacity: usize,
+    state: RwLock<State>,
+    reused: AtomicUsize,
+}
+
+#[derive(Default)]
+struct State {
+    chunks: Vec<(Box<Signature>, Embedding)>,
+    /// Ids of the chunks in `chunks`, by the index and hash of each of their bands
+    bands: HashMap<(usize, u64), Vec<usize>>,
+    /// The id the next chunk is stored at, replacing the oldest one once `chunks` is full
+    next: usize,
+}
+
+impl NearDuplicates {
+    /// `threshold` is clamped between 0 and 1, and 1 only matches chunks with the same shingles.
+    pub fn new(threshold: f32) -> Self {
+        Self::with_capacity(threshold, MAX_CHUNKS)
+    }
+
+    fn with_capacity(threshold: f32, capacity: usize) -> Self {
+        Self {
+            threshold: threshold.clamp(0.0, 1.0),
+            capacity: capacity.max(1),
+            state: Default::default(),
+            reused: AtomicUsize::new(0),
+        }
+    }
+
+    /// Embed `data` with `embedder`, unless a near-identical chunk was embedded before.
+    ///
+    /// Chunks without a single word have nothing to compare, so they're always embedded.
+    pub fn embed_with(&self, data: &str, embedder: &dyn Embedder) -> anyhow::Result<Embedding> {
+        let Some(signature) = signature(data) else {
+            return embedder.embed(data);
+        };
+        if let Some(embedding) = self.find(&signature) {
+            self.reused.fetch_add(1, Ordering::Relaxed);
+            return Ok(embedding);
+        }
+
+        let embedding = embedder.embed(data)?;
+        self.insert(signature, embedding.clone());
+        Ok(embedding)
+    }
+
+    /// Number of chunks that got the embedding of an earlier one so far.
+    pub fn reused(&self) -> usize {
+        self.reused.load(Ordering::Relaxed)
+    }
+
+    fn find(&self, signature: &Signature) -> Option<Embedding> {
+        let state = self.state.read().unwrap();
+        let (_, id) = bands(signature)
+            .filter_map(|band| state.bands.get(&band))
+            .flatten()
+            .map(|&id| (similarity(signature, &state.chunks[id].0), id))
+            .filter(|(similarity, _)| *similarity >= self.threshold)
+            .max_by(|(a, _), (b, _)| a.total_cmp(b))?;
+
+        Some(state.chunks[id].1.clone())
+    }
+
+    fn insert(&self, signature: Signature, embedding: Embedding) {
+        let mut state = self.state.write().unwrap();
+        let State {
+            chunks,
+            bands: by_band,
+            next,
+        } = &mut *state;
+
+        let id = *next;
+        *next = (id + 1) % self.capacity;
+        if let Some((oldest, _)) = chunks.get(id) {
+            for band in bands(oldest) {
+                if let Some(ids) = by_band.get_mut(&band) {
+                    ids.retain(|&other| other != id);
+                    if ids.is_empty() {
+                        by_band.remove(&band);
+                    }
+                }
+            }
+        }
+
+        for band in bands(&signature) {
+            by_band.entry(band).or_default().push(id);
+        }
+        let chunk = (Box::new(signature), embedding);
+        match chunks.get_mut(id) {
+            Some(oldest) => *oldest = chunk,
+            None => chunks.push(chunk),
+        }
+    }
+}
+
+/// The minhash signature of the word shingles of `data`, or `None` if it has no words.
+fn signature(data: &str) -> Option<Signature> {
+    let words = data
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>();
+    if words.is_empty() {
+        return None;
+    }
+
+    let seeds: Signature = std::array::from_fn(|i| mix(i as u64));
+    let mut signature = [u64::MAX; SIGNATURE_LEN];
+    for shingle in words.windows(SHINGLE_WORDS.min(words.len())) {
+        let mut hasher = DefaultHasher::new();
+        shingle.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        for (min, seed) in signature.iter_mut().zip(seeds) {
+            *min = (*min).min(mix(hash ^ seed));
+        }
+    }
+
+    Some(signature)
+}
+
+/// The estimated Jaccard similarity of the shingles of two signatures.
+fn similarity(a: &Signature, b: &Signature) -> f32 {
+    let equal = a.iter().zip(b).filter(|(a, b)| a == b).count();
+    equal as f32 / SIGNATURE_LEN as f32
+}
+
+fn bands(signature: &Signature) -> impl Iterator<Item = (usize, u64)> + '_ {
+    signature
+        .chunks(BAND_ROWS)
+        .enumerate()
+        .map(|(index, rows)| {
+            let mut hasher = DefaultHasher::new();
+            rows.hash(&mut hasher);
+            (index, hasher.finish())
+        })
+}
+
+/// The finalizer of splitmix64, which turns a hash into one of many independent ones.
+fn mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::semantic::embedder::StubEmbedder;
+
+    /// Accessors of a config struct, which only differ by the name and type of the field.
+    fn boilerplate(field: &str, ty: &str) -> String {
+        format!(
+            "repo\tsrc/config.rs\n\
+             /// Returns the {field} of the configuration, as it was loaded from disk.\n\
+             ///\n\
+             /// # Panics\n\
+             ///\n\
+             /// Panics if the configuration wasn't loaded yet, which only happens in tests that\n\
+             /// build the server by hand, as the binary always loads it before serving requests.\n\
+             #[inline]\n\
+             #[must_use]\n\
+             pub fn {field}(&self) -> &{ty} {{\n    \
+                 self.inner.read().unwrap().{field}.as_ref().expect(\"config is loaded\")\n\
+             }}\n"
+        )
+    }
+
+    #[test]
+    fn boilerplate_reuses_embeddings() {
+        let chunks = [
+            ("port", "u16"),
+            ("host", "str"),
+            ("timeout", "Duration"),
+            ("retries", "u32"),
+            ("user", "str"),
+        ]
+        .map(|(field, ty)| boilerplate(field, ty));
+        let distinct = "fn main() {\n    let args = std::env::args().collect::<Vec<_>>();\n    \
+                        run(&args[1..]).unwrap_or_else(|err| eprintln!(\"{err}\"));\n}\n";
+
+        let embeds = |near_duplicates: Option<&NearDuplicates>| {
+            let embedder = StubEmbedder::default();
+            for chunk in chunks.iter().map(String::as_str).chain([distinct]) {
+                match near_duplicates {
+                    Some(near_duplicates) => near_duplicates.embed_with(chunk, &embedder),
+                    None => embedder.embed(chunk),
+                }
+                .unwrap();
+            }
+            embedder.calls()
+        };
+
+        assert_eq!(embeds(None), 6);
+
+        // every accessor gets the embedding of the first, but the distinct chunk is embedded
+        let near_duplicates = NearDuplicates::new(0.5);
+        assert_eq!(embeds(Some(&near_duplicates)), 2);
+        assert_eq!(near_duplicates.reused(), 4);
+
+        // only identical shingles match at 1
+        let exact = NearDuplicates::new(1.0);
+        assert_eq!(embeds(Some(&exact)), 6);
+    }
+
+    #[test]
+    fn chunks_without_words_are_embedded() {
+        let embedder = StubEmbedder::default();
+        let near_duplicates = NearDuplicates::new(0.5);
+        for chunk in ["", "{\n}\n", "// ---\n", "{\n}\n"] {
+            near_duplicates.embed_with(chunk, &embedder).unwrap();
+        }
+
+        assert_eq!(embedder.calls(), 4);
+        assert_eq!(near_duplicates.reused(), 0);
+    }
+
+    #[test]
+    fn only_the_latest_chunks_are_kept() {
+        let embedder = StubEmbedder::default();
+        let near_duplicates = NearDuplicates::with_capacity(0.5, 2);
+        let embed = |chunk: &str| near_duplicates.embed_with(chunk, &embedder).unwrap();
+        let distinct = [
+            "fn main() { run(std::env::args()) }",
+            "SELECT name FROM users WHERE id = ?",
+        ];
+
+        embed(&boilerplate("port", "u16"));
+        embed(distinct[0]);
+        embed(distinct[1]);
+        assert_eq!(embedder.calls(), 3);
+
+        let state = near_duplicates.state.read().unwrap();
+        assert_eq!(state.chunks.len(), 2);
+        assert_eq!(
+            state.bands.values().map(Vec::len).sum::<usize>(),
+            2 * SIGNATURE_LEN / BAND_ROWS
+        );
+        drop(state);
+
+        // the oldest chunk was forgotten, so its near-duplicates are embedded again
+        embed(&boilerplate("host", "str"));
+        assert_eq!(embedder.calls(), 4);
+
+        // while the latest ones are still found
+        embed(distinct[1]);
+        assert_eq!(embedder.calls(), 4);
+        assert_eq!(near_duplicates.reused(), 1);
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 pub type Embedding = Vec<f32>;
 
 #[derive(Default, Clone, Debug, serde::Deserialize, serde::Serialize)]
@@ -14,6 +16,14 @@ pub struct Payload {
     pub end_byte: u64,
     pub branches: Vec<String>,
 
+    /// Additional user-defined fields, merged into the qdrant payload
+    /// alongside the fields above.
+    ///
+    /// These are never touched when the branch list of a point is
+    /// updated.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub extra: HashMap<String, serde_json::Value>,
+
     #[serde(skip)]
     pub id: Option<String>,
     #[serde(skip)]
@@ -35,8 +45,69 @@ impl PartialEq for Payload {
             && self.start_byte == other.start_byte
             && self.end_byte == other.end_byte
             && self.branches == other.branches
+            && self.extra == other.extra
 
         // ignoring deserialized fields that will not exist on a newly
         // created payload
     }
 }
+
+/// Names of the qdrant payload fields the built-in [`Payload`] fields are stored under.
+///
+/// Other tools sharing the collection may expect their own names. Every read and write of the
+/// payload, and every filter on it, goes through this.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct PayloadSchema {
+    pub lang: String,
+    pub repo_name: String,
+    pub repo_ref: String,
+    pub relative_path: String,
+    pub content_hash: String,
+    pub text: String,
+    pub start_line: String,
+    pub end_line: String,
+    pub start_byte: String,
+    pub end_byte: String,
+    pub branches: String,
+}
+
+impl Default for PayloadSchema {
+    fn default() -> Self {
+        Self {
+            lang: "lang".into(),
+            repo_name: "repo_name".into(),
+            repo_ref: "repo_ref".into(),
+            relative_path: "relative_path".into(),
+            content_hash: "content_hash".into(),
+            text: "snippet".into(),
+            start_line: "start_line".into(),
+            end_line: "end_line".into(),
+            start_byte: "start_byte".into(),
+            end_byte: "end_byte".into(),
+            branches: "branches".into(),
+        }
+    }
+}
+
+impl PayloadSchema {
+    /// The version of the semantic index, for points written with this schema.
+    ///
+    /// Points stored under other field names can't be read back, so a schema other than the
+    /// default adds a hash of its names to [`SEMANTIC_VERSION`], and changing it embeds every
+    /// file again.
+    ///
+    /// [`SEMANTIC_VERSION`]: crate::state::SEMANTIC_VERSION
+    pub fn index_version(&self) -> String {
+        if *self == Self::default() {
+            return crate::state::SEMANTIC_VERSION.to_owned();
+        }
+
+        let names = serde_json::to_string(self).expect("payload schema is serializable");
+        format!(
+            "{}-{}",
+            crate::state::SEMANTIC_VERSION,
+            blake3::hash(names.as_bytes())
+        )
+    }
+}
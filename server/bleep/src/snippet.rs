@@ -3,7 +3,7 @@ use regex::{Regex, RegexBuilder};
 use serde::Serialize;
 use smallvec::{smallvec, SmallVec};
 
-use crate::{indexes, symbol::Symbol};
+use crate::{indexes, symbol::Symbol, text_range::LineIndex};
 use std::ops::Range;
 
 #[derive(Serialize, Debug, PartialEq, Eq)]
@@ -102,6 +102,35 @@ impl SnippedFile {
             ..rhs
         }
     }
+
+    /// Keep only the `k` snippet windows with the most query matches.
+    ///
+    /// Windows with the same number of matches are ranked by density, so a
+    /// shorter window wins over a longer one. The remaining snippets keep
+    /// their order of appearance in the file. A `k` of 0 means no limit, and
+    /// keeps every snippet.
+    pub fn retain_top_windows(&mut self, k: usize) {
+        if k == 0 || self.snippets.len() <= k {
+            return;
+        }
+
+        let mut ranked = (0..self.snippets.len()).collect::<Vec<_>>();
+        ranked.sort_by_key(|&i| {
+            let snippet = &self.snippets[i];
+            let lines = snippet.line_range.end - snippet.line_range.start + 1;
+            (std::cmp::Reverse(snippet.highlights.len()), lines, i)
+        });
+        ranked.truncate(k);
+        ranked.sort_unstable();
+
+        let mut ranked = ranked.into_iter().peekable();
+        let mut index = 0;
+        self.snippets.retain(|_| {
+            let keep = ranked.next_if_eq(&index).is_some();
+            index += 1;
+            keep
+        });
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -227,7 +256,7 @@ impl Snipper {
         &'a self,
         mut highlights: impl Iterator<Item = Range<usize>> + 'a,
         text: &'a str,
-        line_ends: &'a [u32],
+        lines: &'a LineIndex,
     ) -> impl Iterator<Item = Location> + 'a {
         // We store the "next" location here, in case we run into an early split down below due to 2
         // locations not joining together.
@@ -240,7 +269,7 @@ impl Snipper {
             });
 
             for highlight in &mut highlights {
-                let next_loc = self.expand(highlight, text, line_ends);
+                let next_loc = self.expand(highlight, text, lines);
                 if let Err(next_loc) = loc.join(next_loc) {
                     next = Some(next_loc);
                     break;
@@ -259,7 +288,7 @@ impl Snipper {
         &'a self,
         highlight: Range<usize>,
         text: &'a str,
-        line_ends: &'a [u32],
+        lines: &'a LineIndex,
     ) -> Location {
         let start = text[..highlight.start]
             .rmatch_indices('\n')
@@ -273,17 +302,11 @@ impl Snipper {
             .map(|(i, _)| i + highlight.end)
             .unwrap_or(text.len());
 
-        let line_end = line_ends
-            .iter()
-            .position(|i| end <= *i as usize)
-            .unwrap_or(line_ends.len());
-
-        let line_start = line_ends
-            .iter()
-            .rev()
-            .position(|i| (*i as usize) < start)
-            .map(|i| line_ends.len() - i)
-            .unwrap_or(0);
+        // the lines ending before `start` precede the snippet, and the snippet's last line is
+        // the first one ending at or after `end`
+        let line_ends = lines.ends();
+        let line_end = line_ends.partition_point(|i| (*i as usize) < end);
+        let line_start = line_ends.partition_point(|i| (*i as usize) < start);
 
         Location {
             byte_range: start..end,
@@ -331,13 +354,9 @@ mod tests {
 
     /// Test helper to ensure a string is newline-terminated, and also return an array of newline
     /// indices.
-    fn with_line_ends(s: &str) -> (&str, Vec<u32>) {
+    fn with_line_ends(s: &str) -> (&str, LineIndex) {
         assert!(s.ends_with('\n'));
-        let line_ends = s
-            .match_indices('\n')
-            .map(|(i, _)| i as u32)
-            .collect::<Vec<_>>();
-        (s, line_ends)
+        (s, LineIndex::new(s))
     }
 
     #[test]
@@ -611,6 +630,75 @@ mod tests {
             .is_some());
     }
 
+    #[test]
+    fn top_windows_prefer_most_matches() {
+        let (text, line_end_indices) = with_line_ends(
+            "foo\n\
+             \n\n\n\n\
+             foo foo foo\n\
+             \n\n\n\n\
+             foo foo\n\
+             \n\n\n\n\
+             foo foo foo\n\
+             foo\n",
+        );
+        let doc = indexes::reader::ContentDocument {
+            content: text.into(),
+            line_end_indices,
+            ..Default::default()
+        };
+
+        let mut snipped = Snipper::default()
+            .context(1, 1)
+            .all_for_doc("foo", &doc)
+            .unwrap()
+            .unwrap();
+
+        let windows = |snipped: &SnippedFile| {
+            snipped
+                .snippets
+                .iter()
+                .map(|s| (s.line_range.clone(), s.highlights.len()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            windows(&snipped),
+            [(0..1, 1), (4..6, 3), (9..11, 2), (14..17, 4)]
+        );
+
+        snipped.retain_top_windows(2);
+        assert_eq!(windows(&snipped), [(4..6, 3), (14..17, 4)]);
+
+        // asking for more windows than available keeps everything
+        snipped.retain_top_windows(10);
+        assert_eq!(snipped.snippets.len(), 2);
+
+        // and so does asking for none at all
+        snipped.retain_top_windows(0);
+        assert_eq!(snipped.snippets.len(), 2);
+    }
+
+    #[test]
+    fn top_windows_tie_prefers_denser_window() {
+        let snippet = |line_range: Range<usize>, highlights: usize| Snippet {
+            data: String::new(),
+            highlights: vec![0..1; highlights],
+            symbols: vec![],
+            line_range,
+        };
+
+        let mut snipped = SnippedFile {
+            relative_path: String::new(),
+            repo_name: String::new(),
+            repo_ref: String::new(),
+            lang: None,
+            snippets: vec![snippet(0..4, 2), snippet(10..11, 2), snippet(20..21, 1)],
+        };
+
+        snipped.retain_top_windows(1);
+        assert_eq!(snipped.snippets, [snippet(10..11, 2)]);
+    }
+
     #[test]
     fn test_highlighted_string() {
         let mut s = HighlightedString::new("foo bar quux");
@@ -18,6 +18,25 @@ include!(concat!(env!("OUT_DIR"), "/schema_version.rs"));
 
 pub(crate) type RepositoryPool = Arc<scc::HashMap<RepoRef, Repository>>;
 
+/// The versions of the schemas the indexes on disk were written with.
+///
+/// They're tracked separately, so a change to the tantivy schema doesn't throw away the
+/// embeddings in qdrant.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct IndexVersion {
+    /// Version of the tantivy indexes
+    pub lexical: String,
+    /// Version of the chunks and embeddings of the semantic index
+    pub semantic: String,
+}
+
+/// Which indexes on disk were written with a different [`IndexVersion`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VersionMismatch {
+    pub lexical: bool,
+    pub semantic: bool,
+}
+
 #[derive(Serialize, Deserialize, Args, Debug, Clone, Default, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub struct StateSource {
@@ -239,14 +258,32 @@ impl StateSource {
         }
     }
 
-    pub fn index_version_mismatch(&self) -> bool {
-        let current: String = read_file_or_default(self.version_file.as_ref().unwrap()).unwrap();
-
-        !current.is_empty() && current != SCHEMA_VERSION
+    /// Which indexes on disk were written with a different schema than `version`.
+    ///
+    /// Version files written before the versions were split hold a single string, which counts
+    /// as a mismatch of both.
+    pub fn index_version_mismatch(&self, version: &IndexVersion) -> VersionMismatch {
+        let current: serde_json::Value =
+            read_file_or_default(self.version_file.as_ref().unwrap()).unwrap();
+
+        match current {
+            serde_json::Value::Null => VersionMismatch::default(),
+            serde_json::Value::String(legacy) => VersionMismatch {
+                lexical: !legacy.is_empty(),
+                semantic: !legacy.is_empty(),
+            },
+            current => {
+                let current: IndexVersion = serde_json::from_value(current).unwrap_or_default();
+                VersionMismatch {
+                    lexical: current.lexical != version.lexical,
+                    semantic: current.semantic != version.semantic,
+                }
+            }
+        }
     }
 
-    pub fn save_index_version(&self) -> Result<(), RepoError> {
-        pretty_write_file(self.version_file.as_ref().unwrap(), SCHEMA_VERSION)
+    pub fn save_index_version(&self, version: &IndexVersion) -> Result<(), RepoError> {
+        pretty_write_file(self.version_file.as_ref().unwrap(), version)
     }
 
     pub fn initialize_cookie_key(&self) -> Result<axum_extra::extract::cookie::Key> {
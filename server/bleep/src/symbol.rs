@@ -28,6 +28,14 @@ impl SymbolLocations {
         }
     }
 
+    /// The symbols defined at the top level of the file.
+    pub fn top_level(&self) -> Vec<Symbol> {
+        match self {
+            Self::TreeSitter(graph) => graph.top_level_symbols(),
+            Self::Empty => Vec::new(),
+        }
+    }
+
     pub fn scope_graph(&self) -> Option<&ScopeGraph> {
         match self {
             Self::TreeSitter(graph) => Some(graph),
@@ -1,4 +1,7 @@
-use std::cmp::{Ord, Ordering};
+use std::{
+    cmp::{Ord, Ordering},
+    ops::Range,
+};
 
 use serde::{Deserialize, Serialize};
 
@@ -32,19 +35,96 @@ impl Point {
         Self { byte, line, column }
     }
 
-    pub fn from_byte(byte: usize, line_end_indices: &[u32]) -> Self {
-        let line = line_end_indices
+    pub fn from_byte(byte: usize, lines: &LineIndex) -> Self {
+        let (line, column) = lines.byte_to_line_col(byte);
+        Self::new(byte, line, column)
+    }
+}
+
+/// The byte offsets at which the lines of a text end.
+///
+/// Lines end at their `\n`, except for a last line without one, which ends where the text does.
+/// Indexes store this as a sequence of little-endian `u32`s, see [`LineIndex::encode`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LineIndex(Vec<u32>);
+
+impl LineIndex {
+    pub fn new(text: &str) -> Self {
+        let mut ends = text
+            .match_indices('\n')
+            .map(|(i, _)| i as u32)
+            .collect::<Vec<_>>();
+        if !text.is_empty() && !text.ends_with('\n') {
+            ends.push(text.len() as u32);
+        }
+
+        Self(ends)
+    }
+
+    /// The line ends of `text`, in their stored form.
+    pub fn encode(text: &str) -> Vec<u8> {
+        Self::new(text)
+            .0
             .iter()
-            .position(|&line_end_byte| (line_end_byte as usize) > byte)
-            .unwrap_or(0);
+            .flat_map(|end| end.to_le_bytes())
+            .collect()
+    }
 
-        let column = line
-            .checked_sub(1)
-            .and_then(|idx| line_end_indices.get(idx))
-            .map(|&prev_line_end| byte.saturating_sub(prev_line_end as usize))
-            .unwrap_or(byte);
+    /// Read line ends written by [`LineIndex::encode`].
+    ///
+    /// A trailing partial `u32` is ignored.
+    pub fn decode(bytes: &[u8]) -> Self {
+        Self(
+            bytes
+                .chunks_exact(4)
+                .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                .collect(),
+        )
+    }
 
-        Self::new(byte, line, column)
+    /// The byte offset at which each line ends.
+    pub fn ends(&self) -> &[u32] {
+        &self.0
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.0.len()
+    }
+
+    /// The 0-indexed line and column of `byte`.
+    ///
+    /// Columns are byte offsets from the start of the line. A line's newline belongs to that
+    /// line, and bytes past the end of the text are on the last line.
+    pub fn byte_to_line_col(&self, byte: usize) -> (usize, usize) {
+        let line = self
+            .0
+            .partition_point(|&end| (end as usize) < byte)
+            .min(self.0.len().saturating_sub(1));
+        (line, byte.saturating_sub(self.line_start(line)))
+    }
+
+    /// The bytes of `line` in `text`, without its newline.
+    ///
+    /// Lines end at their `\n`, but the `\r` of a CRLF line ending is left out of the range as
+    /// well, as files are stored with the line endings they have on disk.
+    ///
+    /// # Panics
+    ///
+    /// If the text has no such line.
+    pub fn line_range(&self, text: &str, line: usize) -> Range<usize> {
+        let start = self.line_start(line);
+        let end = self.0[line] as usize;
+        if end > start && text.as_bytes().get(end - 1..=end) == Some(&b"\r\n"[..]) {
+            start..end - 1
+        } else {
+            start..end
+        }
+    }
+
+    fn line_start(&self, line: usize) -> usize {
+        line.checked_sub(1)
+            .and_then(|prev| self.0.get(prev))
+            .map_or(0, |&prev_end| prev_end as usize + 1)
     }
 }
 
@@ -90,9 +170,9 @@ impl TextRange {
         self.end.byte.saturating_sub(self.start.byte)
     }
 
-    pub fn from_byte_range(range: std::ops::Range<usize>, line_end_indices: &[u32]) -> Self {
-        let start = Point::from_byte(range.start, line_end_indices);
-        let end = Point::from_byte(range.end, line_end_indices);
+    pub fn from_byte_range(range: Range<usize>, lines: &LineIndex) -> Self {
+        let start = Point::from_byte(range.start, lines);
+        let end = Point::from_byte(range.end, lines);
         Self::new(start, end)
     }
 }
@@ -114,8 +194,73 @@ impl From<tree_sitter::Range> for TextRange {
     }
 }
 
-impl From<TextRange> for std::ops::Range<usize> {
-    fn from(r: TextRange) -> std::ops::Range<usize> {
+impl From<TextRange> for Range<usize> {
+    fn from(r: TextRange) -> Range<usize> {
         r.start.byte..r.end.byte
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_index_of_multiple_lines() {
+        let text = "fn main() {\n\n    run();\n}\n";
+        let lines = LineIndex::new(text);
+        assert_eq!(lines.ends(), [11, 12, 23, 25]);
+        assert_eq!(lines.line_count(), 4);
+        assert_eq!(LineIndex::decode(&LineIndex::encode(text)), lines);
+
+        assert_eq!(lines.byte_to_line_col(0), (0, 0));
+        assert_eq!(lines.byte_to_line_col(11), (0, 11));
+        assert_eq!(lines.byte_to_line_col(12), (1, 0));
+        assert_eq!(lines.byte_to_line_col(17), (2, 4));
+        assert_eq!(&text[lines.line_range(text, 2)], "    run();");
+        assert_eq!(lines.line_range(text, 1), 12..12);
+        assert_eq!(Point::from_byte(25, &lines), Point::new(25, 3, 1));
+    }
+
+    #[test]
+    fn line_index_of_empty_text() {
+        let lines = LineIndex::new("");
+        assert_eq!(lines.line_count(), 0);
+        assert!(LineIndex::encode("").is_empty());
+        assert_eq!(lines.byte_to_line_col(0), (0, 0));
+
+        let blank = LineIndex::new("\n");
+        assert_eq!(blank.line_count(), 1);
+        assert_eq!(blank.line_range("\n", 0), 0..0);
+    }
+
+    #[test]
+    fn line_ranges_leave_out_crlf_line_ends() {
+        let text = "foo\r\n\r\nbar\nbaz\r";
+        let lines = LineIndex::new(text);
+        assert_eq!(lines.ends(), [4, 6, 10, 15]);
+
+        let lines = (0..lines.line_count())
+            .map(|line| &text[lines.line_range(text, line)])
+            .collect::<Vec<_>>();
+
+        // a `\r` that isn't followed by a newline is part of the line
+        assert_eq!(lines, ["foo", "", "bar", "baz\r"]);
+    }
+
+    #[test]
+    fn line_index_without_trailing_newline() {
+        let text = "foo\nbar";
+        let lines = LineIndex::new(text);
+        assert_eq!(lines.ends(), [3, 7]);
+        assert_eq!(lines.line_count(), 2);
+        assert_eq!(&text[lines.line_range(text, 1)], "bar");
+        assert_eq!(lines.byte_to_line_col(5), (1, 1));
+        assert_eq!(lines.byte_to_line_col(7), (1, 3));
+        assert_eq!(lines.byte_to_line_col(100), (1, 96));
+
+        // partial values left over from a truncated buffer are dropped
+        let mut encoded = LineIndex::encode(text);
+        encoded.push(0);
+        assert_eq!(LineIndex::decode(&encoded), lines);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{env::Feature, Application};
+use crate::{env::Feature, indexes::file::FileIndexError, Application};
 
 use axum::{
     http::StatusCode,
@@ -198,6 +198,21 @@ impl From<anyhow::Error> for Error {
     }
 }
 
+impl From<FileIndexError> for Error {
+    fn from(err: FileIndexError) -> Self {
+        match err {
+            FileIndexError::NotFound => Error::user(err).with_status(StatusCode::NOT_FOUND),
+            FileIndexError::Ambiguous { .. } => Error::user(err),
+            FileIndexError::BadRange { .. } => {
+                Error::user(err).with_status(StatusCode::RANGE_NOT_SATISFIABLE)
+            }
+            FileIndexError::Corrupt(_)
+            | FileIndexError::QueryParse(_)
+            | FileIndexError::Search { .. } => Error::internal(err),
+        }
+    }
+}
+
 impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
         (self.status, self.body).into_response()
@@ -256,10 +271,39 @@ impl<'a> From<EndpointError<'a>> for Response<'a> {
     }
 }
 
-async fn health(Extension(app): Extension<Application>) {
+/// A subsystem that failed the health check
+#[derive(thiserror::Error, Debug)]
+enum HealthError {
+    #[error("file index unavailable: {0}")]
+    Index(FileIndexError),
+    #[error("qdrant unavailable: {0}")]
+    Semantic(anyhow::Error),
+}
+
+impl From<HealthError> for Error {
+    fn from(err: HealthError) -> Self {
+        let kind = match err {
+            HealthError::Index(_) => ErrorKind::Internal,
+            HealthError::Semantic(_) => ErrorKind::UpstreamService,
+        };
+
+        Error::new(kind, err.to_string()).with_status(StatusCode::SERVICE_UNAVAILABLE)
+    }
+}
+
+async fn health(Extension(app): Extension<Application>) -> Result<()> {
+    app.indexes
+        .file
+        .healthcheck()
+        .await
+        .map_err(HealthError::Index)?;
+
     if let Some(ref semantic) = app.semantic {
-        // panic is fine here, we don't need exact reporting of
-        // subsystem checks at this stage
-        semantic.health_check().await.unwrap()
+        semantic
+            .health_check()
+            .await
+            .map_err(HealthError::Semantic)?;
     }
+
+    Ok(())
 }
@@ -31,7 +31,10 @@ use super::middleware::User;
 use crate::{
     analytics::{EventData, QueryEvent},
     db::QueryLog,
-    indexes::reader::{ContentDocument, FileDocument},
+    indexes::{
+        file::FileIndexError,
+        reader::{ContentDocument, FileDocument},
+    },
     query::parser::{self, Literal, SemanticQuery},
     repo::RepoRef,
     semantic, Application,
@@ -427,7 +430,6 @@ pub async fn explain(
         .by_path(&virtual_req.repo_ref, &params.relative_path, None)
         .await
         .context("file retrieval failed")?
-        .context("did not find requested file")?
         .content;
 
     let snippet = file_content
@@ -724,7 +726,7 @@ impl Agent {
         // First, perform a lexical search for the path
         let mut paths = self
             .fuzzy_path_search(query)
-            .await
+            .await?
             .map(|c| c.relative_path)
             .collect::<HashSet<_>>() // TODO: This shouldn't be necessary. Path search should return unique results.
             .into_iter()
@@ -1460,7 +1462,7 @@ impl Agent {
             .semantic
             .as_ref()
             .unwrap()
-            .search(&query, limit, offset, threshold, retrieve_more)
+            .search(&query, limit, offset, threshold, retrieve_more, None)
             .await
     }
 
@@ -1489,7 +1491,14 @@ impl Agent {
             .semantic
             .as_ref()
             .unwrap()
-            .batch_search(queries.as_slice(), limit, offset, threshold, retrieve_more)
+            .batch_search(
+                queries.as_slice(),
+                limit,
+                offset,
+                threshold,
+                retrieve_more,
+                None,
+            )
             .await
     }
 
@@ -1497,18 +1506,23 @@ impl Agent {
         let branch = self.last_exchange().query.first_branch();
 
         debug!(%self.repo_ref, path, ?branch, %self.thread_id, "executing file search");
-        self.app
+        match self
+            .app
             .indexes
             .file
             .by_path(&self.repo_ref, path, branch.as_deref())
             .await
-            .with_context(|| format!("failed to read path: {}", path))
+        {
+            Ok(doc) => Ok(Some(doc)),
+            Err(FileIndexError::NotFound) => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("failed to read path: {}", path)),
+        }
     }
 
     async fn fuzzy_path_search<'a>(
         &'a self,
         query: &str,
-    ) -> impl Iterator<Item = FileDocument> + 'a {
+    ) -> Result<impl Iterator<Item = FileDocument> + 'a> {
         let branch = self.last_exchange().query.first_branch();
 
         debug!(%self.repo_ref, query, ?branch, %self.thread_id, "executing fuzzy search");
@@ -3,7 +3,7 @@ use std::{path::PathBuf, sync::Arc};
 use anyhow::Context;
 use axum::{extract::Query, Extension, Json};
 
-use crate::repo::RepoRef;
+use crate::{repo::RepoRef, text_range::LineIndex};
 
 use super::prelude::*;
 
@@ -39,9 +39,7 @@ pub(super) async fn handle<'a>(
             params.path.to_str().context("invalid file path")?,
             params.branch.as_deref(),
         )
-        .await
-        .map_err(Error::internal)?
-        .ok_or_else(|| Error::user("file not found").with_status(StatusCode::NOT_FOUND))?;
+        .await?;
 
     Ok(json(FileResponse {
         contents: split_by_lines(&doc.content, &doc.line_end_indices, &params)?.to_string(),
@@ -49,25 +47,29 @@ pub(super) async fn handle<'a>(
     }))
 }
 
-fn split_by_lines<'a>(text: &'a str, indices: &[u32], params: &Params) -> Result<&'a str, Error> {
-    let char_start = match params.line_start {
-        Some(line_start) if line_start == 1 => 0,
-        Some(line_start) if line_start > 1 => {
-            (indices
-                .get(line_start as usize - 2)
-                .ok_or_else(|| Error::user("invalid line number"))?
-                + 1) as usize
-        }
+fn split_by_lines<'a>(text: &'a str, lines: &LineIndex, params: &Params) -> Result<&'a str, Error> {
+    let line_start = match params.line_start {
+        Some(line_start) if line_start >= 1 => line_start as usize - 1,
         Some(_) => return Err(Error::user("line numbers are 1-indexed!")),
-        _ => 0,
+        None => 0,
     };
 
-    let line_end = params.line_end.unwrap_or(indices.len()) - 1;
-    let char_end = *indices
-        .get(line_end)
-        .ok_or_else(|| Error::user("invalid line number"))? as usize;
+    let line_end = match params.line_end {
+        Some(0) => return Err(Error::user("line numbers are 1-indexed!")),
+        Some(line_end) => line_end,
+        None if lines.line_count() == 0 => return Ok(text),
+        None => lines.line_count(),
+    };
+
+    if line_start >= line_end || line_end > lines.line_count() {
+        return Err(Error::user("invalid line number"));
+    }
 
-    Ok(&text[char_start..=char_end])
+    // the last line keeps its newline, if it has one
+    let char_start = lines.line_range(text, line_start).start;
+    let char_end = (lines.ends()[line_end - 1] as usize + 1).min(text.len());
+
+    Ok(&text[char_start..char_end])
 }
 
 #[cfg(test)]
@@ -81,12 +83,7 @@ bbbbbb
 cccccc
 "#;
 
-        let indices = text
-            .match_indices('\n')
-            .map(|(i, _)| i as u32)
-            .collect::<Vec<_>>();
-
-        println!("{indices:?}");
+        let indices = LineIndex::new(text);
 
         assert_eq!(
             split_by_lines(
@@ -33,15 +33,10 @@ pub(super) async fn handle(
 ) -> impl IntoResponse {
     let repo_ref = &payload.repo_ref.parse::<RepoRef>().map_err(Error::user)?;
 
-    let document = match indexes
+    let document = indexes
         .file
         .by_path(repo_ref, &payload.relative_path, payload.branch.as_deref())
-        .await
-    {
-        Ok(Some(doc)) => doc,
-        Ok(None) => return Err(Error::user("file not found").with_status(StatusCode::NOT_FOUND)),
-        Err(e) => return Err(Error::user(e)),
-    };
+        .await?;
 
     let ranges = document
         .hoverable_ranges()
@@ -2,7 +2,7 @@ use std::{ops::Not, sync::Arc};
 
 use super::prelude::*;
 use crate::{
-    indexes::{reader::ContentDocument, Indexes},
+    indexes::{file::Pagination, reader::ContentDocument, Indexes},
     intelligence::{
         code_navigation::{CodeNavigationContext, FileSymbols, Occurrence, OccurrenceKind, Token},
         Language, NodeKind, TSLanguage,
@@ -61,23 +61,28 @@ pub(super) async fn handle(
     let source_document = indexes
         .file
         .by_path(&repo_ref, &payload.relative_path, payload.branch.as_deref())
-        .await
-        .map_err(Error::user)?
-        .ok_or_else(|| Error::user("path not found").with_status(StatusCode::NOT_FOUND))?;
+        .await?;
     let lang = source_document.lang.as_deref();
     let all_docs = {
         let associated_langs = match lang.map(TSLanguage::from_id) {
             Some(Language::Supported(config)) => config.language_ids,
             _ => &[],
         };
+        // navigation only looks through the first files of the repo, to bound its cost
+        let page = Pagination {
+            limit: 500,
+            offset: 0,
+        };
         indexes
             .file
             .by_repo(
                 &repo_ref,
                 associated_langs.iter(),
                 payload.branch.as_deref(),
+                page,
             )
-            .await
+            .await?
+            .docs
     };
 
     let source_document_idx = all_docs
@@ -170,12 +175,9 @@ async fn search_nav(
             .chain(std::iter::once(Box::new(BooleanQuery::union(
                 associated_langs
                     .iter()
-                    .map(|l| {
-                        Term::from_field_bytes(
-                            indexer.source.lang,
-                            l.to_ascii_lowercase().as_bytes(),
-                        )
-                    })
+                    // languages without an id have no documents to match
+                    .filter_map(|l| indexer.source.langs.id(&l.to_ascii_lowercase()))
+                    .map(|id| Term::from_field_u64(indexer.source.lang, id))
                     .map(|l| {
                         Box::new(TermQuery::new(l, IndexRecordOption::Basic))
                             as Box<dyn tantivy::query::Query>
@@ -4,11 +4,12 @@ use super::prelude::*;
 use crate::{db::QueryLog, query::execute::ApiQuery, Application};
 
 pub(super) async fn handle(
-    Query(api_params): Query<ApiQuery>,
+    Query(mut api_params): Query<ApiQuery>,
     Extension(indexes): Extension<Arc<Indexes>>,
     State(app): State<Application>,
 ) -> impl IntoResponse {
     QueryLog::new(&app.sql).insert(&api_params.q).await?;
+    api_params.resolve_dirty_repo(&app.repo_pool).await?;
 
     Arc::new(api_params)
         .query(indexes)
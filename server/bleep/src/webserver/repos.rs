@@ -2,7 +2,7 @@ use std::{collections::HashSet, hash::Hash, time::Duration};
 
 use crate::{
     background::QueuedRepoStatus,
-    repo::{Backend, BranchFilter, RepoRef, Repository, SyncStatus},
+    repo::{Backend, BranchFilter, RepoRef, Repository, SemanticStatus, SyncStatus},
     state::RepositoryPool,
     Application,
 };
@@ -36,6 +36,7 @@ pub(crate) struct Repo {
     pub(super) most_common_lang: Option<String>,
     pub(super) branch_filter: BranchFilter,
     pub(super) branches: Vec<Branch>,
+    pub(super) semantic_status: SemanticStatus,
 }
 
 impl From<(&RepoRef, &Repository)> for Repo {
@@ -134,6 +135,7 @@ impl From<(&RepoRef, &Repository)> for Repo {
             most_common_lang: repo.most_common_lang.clone(),
             branch_filter,
             branches,
+            semantic_status: repo.semantic_status,
         }
     }
 }
@@ -155,6 +157,7 @@ impl Repo {
             most_common_lang: None,
             branch_filter: crate::repo::BranchFilter::Select(vec![]),
             branches: vec![],
+            semantic_status: SemanticStatus::default(),
         }
     }
 }
@@ -473,6 +476,9 @@ mod test {
                     last_index_unix_secs: 123456,
                     most_common_lang: None,
                     branch_filter: Default::default(),
+                    semantic: true,
+                    semantic_status: Default::default(),
+                    path_root: None,
                 },
             )
             .unwrap();
@@ -491,6 +497,9 @@ mod test {
                     last_index_unix_secs: 123456,
                     most_common_lang: None,
                     branch_filter: Default::default(),
+                    semantic: true,
+                    semantic_status: Default::default(),
+                    path_root: None,
                 },
             )
             .unwrap();
@@ -511,6 +520,9 @@ mod test {
                     last_index_unix_secs: 0,
                     most_common_lang: None,
                     branch_filter: Default::default(),
+                    semantic: true,
+                    semantic_status: Default::default(),
+                    path_root: None,
                 },
             )
                 .into(),
@@ -530,6 +542,9 @@ mod test {
                 last_index_unix_secs: 0,
                 most_common_lang: None,
                 branch_filter: Default::default(),
+                semantic: true,
+                semantic_status: Default::default(),
+                path_root: None,
             },
         )
             .into();